@@ -0,0 +1,158 @@
+use std::{collections::HashMap, fs, path::Path};
+
+/// The default number of labeled neighbors that vote on each sample's class
+pub const DEFAULT_NUM_NEIGHBORS: usize = 10;
+
+/// Keeps neighbors at a distance of zero from outweighing every other vote
+const MIN_DISTANCE: f32 = 1e-6;
+
+pub struct ClassifyOptions {
+    /// The name of the index whose nearest neighbors vote on each sample's class
+    pub index: String,
+    pub num_neighbors: usize,
+}
+
+impl Default for ClassifyOptions {
+    fn default() -> Self {
+        ClassifyOptions {
+            index: "timbre".to_string(),
+            num_neighbors: DEFAULT_NUM_NEIGHBORS,
+        }
+    }
+}
+
+/// A class predicted for a sample from its labeled neighbors
+#[derive(Clone, Debug, PartialEq)]
+pub struct Prediction {
+    pub sample_id: i64,
+    pub label: String,
+    /// The share of the neighbors' votes that went to the label, from 0 to 1
+    pub confidence: f32,
+}
+
+/// Returns the label with the most votes from (label, distance) neighbors, and its share of
+/// the votes. Each neighbor's vote is weighted by its inverse distance, so close neighbors
+/// count for more than distant ones.
+pub fn vote<'a>(neighbors: impl Iterator<Item = (&'a str, f32)>) -> Option<(String, f32)> {
+    let mut votes: HashMap<&str, f32> = HashMap::new();
+    let mut total = 0.0;
+    for (label, distance) in neighbors {
+        let weight = 1.0 / distance.max(MIN_DISTANCE);
+        *votes.entry(label).or_default() += weight;
+        total += weight;
+    }
+    votes
+        .into_iter()
+        .max_by(|(a_label, a), (b_label, b)| a.total_cmp(b).then(b_label.cmp(a_label)))
+        .map(|(label, weight)| (label.to_string(), weight / total))
+}
+
+/// Labels are compared case-insensitively and stored in lowercase
+pub fn normalize_label(label: &str) -> String {
+    label.trim().to_lowercase()
+}
+
+/// Reads (sample, label) pairs from a CSV file with a sample ID or path in its first column
+/// and a label in its second. A first row whose second column is `label` is skipped as a
+/// header, even if it's preceded by blank lines.
+pub fn read_labels(path: &Path) -> Result<Vec<(String, String)>, String> {
+    let contents =
+        fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {e}", path.display()))?;
+    let mut labels = Vec::new();
+    let mut is_first_row = true;
+    for (line_number, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let is_header_row = std::mem::take(&mut is_first_row);
+        let fields = csv_record(line);
+        let (Some(sample), Some(label)) = (fields.first(), fields.get(1)) else {
+            return Err(format!(
+                "Expected SAMPLE,LABEL on line {} of {}",
+                line_number + 1,
+                path.display()
+            ));
+        };
+        let label = normalize_label(label);
+        if is_header_row && label == "label" {
+            continue;
+        }
+        if label.is_empty() {
+            continue;
+        }
+        labels.push((sample.trim().to_string(), label));
+    }
+    Ok(labels)
+}
+
+/// Splits a line of a CSV file into its fields, unquoting quoted fields
+fn csv_record(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, in_quotes) {
+            ('"', true) if chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            ('"', _) => in_quotes = !in_quotes,
+            (',', false) => fields.push(std::mem::take(&mut field)),
+            _ => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn close_neighbors_outvote_distant_ones() {
+        let neighbors = [("kick", 0.1), ("snare", 0.5), ("snare", 0.5)];
+        let (label, confidence) = vote(neighbors.into_iter()).unwrap();
+        assert_eq!(label, "kick");
+        assert!((confidence - 10.0 / 14.0).abs() < 1e-6);
+        assert_eq!(vote(std::iter::empty()), None);
+    }
+
+    #[test]
+    fn quoted_fields_are_unquoted() {
+        assert_eq!(csv_record(r#"12,"a, ""b""",c"#), ["12", r#"a, "b""#, "c"]);
+    }
+
+    #[test]
+    fn the_header_is_skipped_after_leading_blank_lines() {
+        let path = std::env::temp_dir().join(format!("labels_test_{}.csv", std::process::id()));
+        fs::write(
+            &path,
+            "\n  \nsample,label\n12, Kick\n\n/lib/a.wav,SNARE\n13,\n",
+        )
+        .unwrap();
+        let labels = read_labels(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            labels.unwrap(),
+            [
+                ("12".to_string(), "kick".to_string()),
+                ("/lib/a.wav".to_string(), "snare".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn rows_without_a_label_column_are_rejected() {
+        let path = std::env::temp_dir().join(format!("labels_error_{}.csv", std::process::id()));
+        fs::write(&path, "12,kick\n13\n").unwrap();
+        let labels = read_labels(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert!(labels
+            .unwrap_err()
+            .starts_with("Expected SAMPLE,LABEL on line 2"));
+    }
+}
@@ -0,0 +1,575 @@
+use rand::{rngs::StdRng, seq::index, Rng, SeedableRng};
+
+/// The seed for k-means initialization and sampling, so clustering the same library twice
+/// gives the same clusters
+const SEED: u64 = 0;
+
+/// Lloyd iterations stop after this many iterations if assignments haven't settled
+const MAX_ITERATIONS: usize = 100;
+
+/// Lloyd's algorithm is run from this many k-means++ seedings, keeping the clustering with
+/// the lowest within-cluster sum of squares, since a single run can get stuck with outliers
+/// as centroids
+const NUM_RESTARTS: usize = 4;
+
+/// Choosing k clusters a random sample of this many vectors for each candidate k, since
+/// silhouette scores take quadratic time
+const SAMPLE_SIZE: usize = 2000;
+
+/// The default largest k tried when k is chosen automatically
+pub const DEFAULT_MAX_K: usize = 20;
+
+/// The default smallest group of samples HDBSCAN considers a cluster
+pub const DEFAULT_MIN_CLUSTER_SIZE: usize = 5;
+
+/// The number of nearest neighbors of each sample that HDBSCAN's spanning tree is built from.
+/// More neighbors than min_cluster_size are needed to keep the tree connected.
+pub const HDBSCAN_NUM_NEIGHBORS: usize = 15;
+
+pub struct ClusterOptions {
+    /// The name of the index whose vectors are clustered
+    pub index: String,
+    pub method: ClusterMethod,
+}
+
+impl Default for ClusterOptions {
+    fn default() -> Self {
+        ClusterOptions {
+            index: "timbre".to_string(),
+            method: ClusterMethod::KMeans(KSelection::Silhouette {
+                max_k: DEFAULT_MAX_K,
+            }),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum ClusterMethod {
+    /// Assigns every sample to one of k clusters
+    KMeans(KSelection),
+    /// Finds clusters of any shape and number, leaving samples that don't belong to a dense
+    /// group unassigned as noise
+    Hdbscan { min_cluster_size: usize },
+}
+
+/// How the number of k-means clusters is chosen
+#[derive(Clone, Copy, Debug)]
+pub enum KSelection {
+    Fixed(usize),
+    /// The k between 2 and max_k with the highest mean silhouette score
+    Silhouette {
+        max_k: usize,
+    },
+    /// The k between 1 and max_k at the elbow of the within-cluster sum of squares curve,
+    /// found as the point farthest below the line between its ends
+    Elbow {
+        max_k: usize,
+    },
+}
+
+/// Returns the cluster of each vector and the centroid of each cluster. Vectors are compared
+/// by euclidean distance.
+pub fn kmeans(vectors: &[Vec<f32>], selection: KSelection) -> (Vec<usize>, Vec<Vec<f32>>) {
+    let mut rng = StdRng::seed_from_u64(SEED);
+    let k = match selection {
+        KSelection::Fixed(k) => k,
+        KSelection::Silhouette { max_k } => {
+            let sample = random_sample(vectors, &mut rng);
+            (2..=max_k.min(sample.len().saturating_sub(1)))
+                .map(|k| {
+                    let (assignments, _, _) = lloyd(&sample, k, &mut rng);
+                    (k, mean_silhouette(&sample, &assignments, k))
+                })
+                .max_by(|(_, a), (_, b)| a.total_cmp(b))
+                .map_or(1, |(k, _)| k)
+        }
+        KSelection::Elbow { max_k } => {
+            let sample = random_sample(vectors, &mut rng);
+            let inertias: Vec<f32> = (1..=max_k.min(sample.len()))
+                .map(|k| lloyd(&sample, k, &mut rng).2)
+                .collect();
+            elbow(&inertias) + 1
+        }
+    };
+    let (assignments, centroids, _) = lloyd(vectors, k.clamp(1, vectors.len().max(1)), &mut rng);
+    (assignments, centroids)
+}
+
+fn random_sample(vectors: &[Vec<f32>], rng: &mut StdRng) -> Vec<Vec<f32>> {
+    if vectors.len() <= SAMPLE_SIZE {
+        return vectors.to_vec();
+    }
+    index::sample(rng, vectors.len(), SAMPLE_SIZE)
+        .into_iter()
+        .map(|i| vectors[i].clone())
+        .collect()
+}
+
+fn squared_distance(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| (x - y) * (x - y)).sum()
+}
+
+/// Returns the index of the centroid closest to vector and its squared distance
+fn nearest_centroid(vector: &[f32], centroids: &[Vec<f32>]) -> (usize, f32) {
+    centroids
+        .iter()
+        .map(|centroid| squared_distance(vector, centroid))
+        .enumerate()
+        .min_by(|(_, a), (_, b)| a.total_cmp(b))
+        .unwrap_or((0, 0.0))
+}
+
+/// Runs Lloyd's algorithm from NUM_RESTARTS k-means++ seedings, returning the assignments,
+/// centroids and within-cluster sum of squares of the best run
+fn lloyd(vectors: &[Vec<f32>], k: usize, rng: &mut StdRng) -> (Vec<usize>, Vec<Vec<f32>>, f32) {
+    (0..NUM_RESTARTS)
+        .map(|_| lloyd_run(vectors, k, rng))
+        .min_by(|(_, _, a), (_, _, b)| a.total_cmp(b))
+        .unwrap_or_default()
+}
+
+fn lloyd_run(vectors: &[Vec<f32>], k: usize, rng: &mut StdRng) -> (Vec<usize>, Vec<Vec<f32>>, f32) {
+    let mut centroids = kmeans_plus_plus(vectors, k, rng);
+    let mut assignments = vec![usize::MAX; vectors.len()];
+    for _ in 0..MAX_ITERATIONS {
+        let mut changed = false;
+        for (vector, assignment) in vectors.iter().zip(assignments.iter_mut()) {
+            let (nearest, _) = nearest_centroid(vector, &centroids);
+            if *assignment != nearest {
+                *assignment = nearest;
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+
+        let dimensions = vectors[0].len();
+        let mut sums = vec![vec![0.0; dimensions]; k];
+        let mut counts = vec![0; k];
+        for (vector, assignment) in vectors.iter().zip(&assignments) {
+            for (sum, x) in sums[*assignment].iter_mut().zip(vector) {
+                *sum += x;
+            }
+            counts[*assignment] += 1;
+        }
+        for (cluster, (sum, count)) in sums.into_iter().zip(counts).enumerate() {
+            if count > 0 {
+                centroids[cluster] = sum.into_iter().map(|x| x / count as f32).collect();
+            } else {
+                // Reseed empty clusters with the vector farthest from its centroid
+                let farthest = vectors
+                    .iter()
+                    .map(|vector| nearest_centroid(vector, &centroids).1)
+                    .enumerate()
+                    .max_by(|(_, a), (_, b)| a.total_cmp(b))
+                    .map_or(0, |(i, _)| i);
+                centroids[cluster] = vectors[farthest].clone();
+            }
+        }
+    }
+    let inertia = vectors
+        .iter()
+        .zip(&assignments)
+        .map(|(vector, assignment)| squared_distance(vector, &centroids[*assignment]))
+        .sum();
+    (assignments, centroids, inertia)
+}
+
+/// Picks k initial centroids, each chosen with probability proportional to its squared
+/// distance from the closest centroid chosen so far
+fn kmeans_plus_plus(vectors: &[Vec<f32>], k: usize, rng: &mut StdRng) -> Vec<Vec<f32>> {
+    let mut centroids = vec![vectors[rng.gen_range(0..vectors.len())].clone()];
+    let mut distances: Vec<f32> = vectors
+        .iter()
+        .map(|vector| squared_distance(vector, &centroids[0]))
+        .collect();
+    while centroids.len() < k {
+        let total: f32 = distances.iter().sum();
+        let next = if total > 0.0 {
+            let mut target = rng.gen_range(0.0..total);
+            distances
+                .iter()
+                .position(|distance| {
+                    target -= distance;
+                    target < 0.0
+                })
+                .unwrap_or(vectors.len() - 1)
+        } else {
+            // Every vector coincides with a centroid
+            rng.gen_range(0..vectors.len())
+        };
+        centroids.push(vectors[next].clone());
+        for (distance, vector) in distances.iter_mut().zip(vectors) {
+            *distance = distance.min(squared_distance(vector, &centroids[centroids.len() - 1]));
+        }
+    }
+    centroids
+}
+
+/// Returns the mean silhouette score of the clustering: how much closer each vector is to the
+/// rest of its cluster than to the nearest other cluster, from -1 to 1
+fn mean_silhouette(vectors: &[Vec<f32>], assignments: &[usize], k: usize) -> f32 {
+    let mut counts = vec![0usize; k];
+    for assignment in assignments {
+        counts[*assignment] += 1;
+    }
+    let mut total = 0.0;
+    for (i, vector) in vectors.iter().enumerate() {
+        let own = assignments[i];
+        // Vectors alone in their cluster score 0
+        if counts[own] < 2 {
+            continue;
+        }
+        let mut distance_sums = vec![0.0; k];
+        for (other, assignment) in vectors.iter().zip(assignments) {
+            distance_sums[*assignment] += squared_distance(vector, other).sqrt();
+        }
+        let a = distance_sums[own] / (counts[own] - 1) as f32;
+        let b = (0..k)
+            .filter(|cluster| *cluster != own && counts[*cluster] > 0)
+            .map(|cluster| distance_sums[cluster] / counts[cluster] as f32)
+            .fold(f32::INFINITY, f32::min);
+        if b.is_finite() && a.max(b) > 0.0 {
+            total += (b - a) / a.max(b);
+        }
+    }
+    total / vectors.len() as f32
+}
+
+/// Returns the index of the elbow of a decreasing curve
+fn elbow(values: &[f32]) -> usize {
+    let (first, last) = match (values.first(), values.last()) {
+        (Some(first), Some(last)) if values.len() > 2 && first > last => (*first, *last),
+        _ => return 0,
+    };
+    // With both axes scaled to [0, 1], the line between the ends is x + y = 1, and points
+    // below it are farther from it the smaller x + y is
+    let steps = (values.len() - 1) as f32;
+    values
+        .iter()
+        .enumerate()
+        .map(|(i, value)| (i, i as f32 / steps + (value - last) / (first - last)))
+        .min_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map_or(0, |(i, _)| i)
+}
+
+/// Clusters num_points points with HDBSCAN, given each point's nearest neighbors as
+/// (point, neighbor, distance) edges. Returns each point's cluster, or None for noise.
+/// Clusters with fewer than min_cluster_size points are treated as noise, and a point's core
+/// distance is the distance to its min_cluster_size-th nearest neighbor.
+///
+/// Mutual reachability distances are only calculated along the provided edges, so the
+/// minimum spanning tree is an approximation that may be a forest. Separate trees are joined
+/// at an infinite distance.
+pub fn hdbscan(
+    num_points: usize,
+    edges: &[(usize, usize, f32)],
+    min_cluster_size: usize,
+) -> Vec<Option<usize>> {
+    let min_cluster_size = min_cluster_size.max(2);
+    let mut neighbor_distances = vec![Vec::new(); num_points];
+    for (a, _, distance) in edges {
+        neighbor_distances[*a].push(*distance);
+    }
+    // The point itself counts as its first neighbor
+    let core_distances: Vec<f32> = neighbor_distances
+        .iter_mut()
+        .map(|distances| {
+            distances.sort_by(f32::total_cmp);
+            distances
+                .get(min_cluster_size - 2)
+                .copied()
+                .unwrap_or(f32::INFINITY)
+        })
+        .collect();
+    let mut reachability: Vec<(usize, usize, f32)> = edges
+        .iter()
+        .map(|(a, b, distance)| {
+            let distance = distance.max(core_distances[*a]).max(core_distances[*b]);
+            (*a, *b, distance)
+        })
+        .collect();
+    reachability.sort_by(|a, b| a.2.total_cmp(&b.2));
+
+    let dendrogram = single_linkage(num_points, &reachability);
+    let tree = condense(&dendrogram, num_points, min_cluster_size);
+    let selected = select_clusters(&tree);
+
+    let mut labels = vec![None; num_points];
+    for (label, cluster) in selected.into_iter().enumerate() {
+        let mut stack = vec![cluster];
+        while let Some(cluster) = stack.pop() {
+            for point in tree[cluster].points.iter() {
+                labels[*point] = Some(label);
+            }
+            stack.extend(tree[cluster].children.iter().copied());
+        }
+    }
+    labels
+}
+
+/// Distances are clamped to this before being inverted into lambdas
+const MIN_DISTANCE: f32 = 1e-6;
+
+/// A merge of two nodes of a single linkage dendrogram. Nodes below num_points are points, and
+/// node num_points + i is the ith merge.
+struct Merge {
+    left: usize,
+    right: usize,
+    distance: f32,
+    size: usize,
+}
+
+/// Builds a single linkage dendrogram from edges sorted by distance, with Kruskal's algorithm
+fn single_linkage(num_points: usize, edges: &[(usize, usize, f32)]) -> Vec<Merge> {
+    let mut parents: Vec<usize> = (0..num_points).collect();
+    // The dendrogram node each union-find root currently represents
+    let mut nodes: Vec<usize> = (0..num_points).collect();
+    let mut sizes = vec![1; num_points];
+    let mut merges = Vec::with_capacity(num_points.saturating_sub(1));
+    let find = |parents: &mut Vec<usize>, mut point: usize| {
+        while parents[point] != point {
+            parents[point] = parents[parents[point]];
+            point = parents[point];
+        }
+        point
+    };
+    let mut merge = |a: usize, b: usize, distance: f32, merges: &mut Vec<Merge>| {
+        let (a, b) = (find(&mut parents, a), find(&mut parents, b));
+        if a == b {
+            return;
+        }
+        merges.push(Merge {
+            left: nodes[a],
+            right: nodes[b],
+            distance,
+            size: sizes[a] + sizes[b],
+        });
+        parents[b] = a;
+        sizes[a] += sizes[b];
+        nodes[a] = num_points + merges.len() - 1;
+    };
+    for (a, b, distance) in edges {
+        merge(*a, *b, *distance, &mut merges);
+    }
+    // Join any separate trees
+    for point in 1..num_points {
+        merge(0, point, f32::INFINITY, &mut merges);
+    }
+    merges
+}
+
+/// A cluster of the condensed tree
+struct CondensedCluster {
+    /// The lambda (1 / distance) at which the cluster split from its parent
+    birth: f32,
+    stability: f32,
+    children: Vec<usize>,
+    /// The points that fell out of this cluster, rather than one of its children
+    points: Vec<usize>,
+}
+
+/// Condenses a dendrogram into the clusters that have at least min_cluster_size points,
+/// recording each cluster's stability. The first cluster is the root.
+fn condense(
+    dendrogram: &[Merge],
+    num_points: usize,
+    min_cluster_size: usize,
+) -> Vec<CondensedCluster> {
+    let size = |node: usize| match node.checked_sub(num_points) {
+        Some(merge) => dendrogram[merge].size,
+        None => 1,
+    };
+    let leaves = |node: usize| -> Vec<usize> {
+        let mut leaves = Vec::new();
+        let mut stack = vec![node];
+        while let Some(node) = stack.pop() {
+            match node.checked_sub(num_points) {
+                Some(merge) => stack.extend([dendrogram[merge].left, dendrogram[merge].right]),
+                None => leaves.push(node),
+            }
+        }
+        leaves
+    };
+
+    let mut tree = vec![CondensedCluster {
+        birth: 0.0,
+        stability: 0.0,
+        children: Vec::new(),
+        points: Vec::new(),
+    }];
+    let Some(root) = (num_points + dendrogram.len()).checked_sub(1) else {
+        return tree;
+    };
+    let mut stack = vec![(root, 0)];
+    while let Some((node, cluster)) = stack.pop() {
+        let Some(merge) = node.checked_sub(num_points).map(|merge| &dendrogram[merge]) else {
+            tree[cluster].points.push(node);
+            continue;
+        };
+        // Identical points merge at a distance of zero, which would make their cluster
+        // infinitely stable
+        let lambda = 1.0 / merge.distance.max(MIN_DISTANCE);
+        let birth = tree[cluster].birth;
+        let (left, right) = (merge.left, merge.right);
+        match (
+            size(left) >= min_cluster_size,
+            size(right) >= min_cluster_size,
+        ) {
+            (true, true) => {
+                // A true split: every point leaves this cluster for one of two new ones
+                tree[cluster].stability += merge.size as f32 * (lambda - birth);
+                for child in [left, right] {
+                    tree.push(CondensedCluster {
+                        birth: lambda,
+                        stability: 0.0,
+                        children: Vec::new(),
+                        points: Vec::new(),
+                    });
+                    let child_cluster = tree.len() - 1;
+                    tree[cluster].children.push(child_cluster);
+                    stack.push((child, child_cluster));
+                }
+            }
+            (left_is_cluster, right_is_cluster) => {
+                // Points of small children fall out of the cluster, which continues as the
+                // large child if there is one
+                for (child, is_cluster) in [(left, left_is_cluster), (right, right_is_cluster)] {
+                    if is_cluster {
+                        stack.push((child, cluster));
+                    } else {
+                        let points = leaves(child);
+                        tree[cluster].stability += points.len() as f32 * (lambda - birth);
+                        tree[cluster].points.extend(points);
+                    }
+                }
+            }
+        }
+    }
+    tree
+}
+
+/// Selects the clusters of the condensed tree with excess of mass: a cluster is selected
+/// unless its descendants are more stable in total. The root is never selected.
+fn select_clusters(tree: &[CondensedCluster]) -> Vec<usize> {
+    let mut subtree_stability = vec![0.0; tree.len()];
+    let mut is_selected = vec![false; tree.len()];
+    // Children are always created after their parents
+    for cluster in (1..tree.len()).rev() {
+        let children_stability: f32 = tree[cluster]
+            .children
+            .iter()
+            .map(|child| subtree_stability[*child])
+            .sum();
+        if tree[cluster].children.is_empty() || tree[cluster].stability >= children_stability {
+            is_selected[cluster] = true;
+            subtree_stability[cluster] = tree[cluster].stability;
+        } else {
+            subtree_stability[cluster] = children_stability;
+        }
+    }
+    // Keep only the highest selected cluster on each path from the root
+    let mut selected = Vec::new();
+    let mut stack = tree
+        .first()
+        .map_or(Vec::new(), |root| root.children.clone());
+    while let Some(cluster) = stack.pop() {
+        if is_selected[cluster] {
+            selected.push(cluster);
+        } else {
+            stack.extend(tree[cluster].children.iter().copied());
+        }
+    }
+    selected.sort();
+    selected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns num_points 2D points spread in a small square around each center
+    fn blobs(centers: &[(f32, f32)], num_points: usize) -> Vec<Vec<f32>> {
+        centers
+            .iter()
+            .flat_map(|(x, y)| {
+                (0..num_points).map(move |i| {
+                    let (dx, dy) = ((i % 3) as f32 * 0.1, (i / 3) as f32 * 0.1);
+                    vec![x + dx, y + dy]
+                })
+            })
+            .collect()
+    }
+
+    /// Returns each point's num_neighbors nearest neighbors as (point, neighbor, distance)
+    fn knn_edges(points: &[Vec<f32>], num_neighbors: usize) -> Vec<(usize, usize, f32)> {
+        let mut edges = Vec::new();
+        for (i, point) in points.iter().enumerate() {
+            let mut neighbors: Vec<(usize, f32)> = (0..points.len())
+                .filter(|j| *j != i)
+                .map(|j| (j, squared_distance(point, &points[j]).sqrt()))
+                .collect();
+            neighbors.sort_by(|a, b| a.1.total_cmp(&b.1));
+            edges.extend(
+                neighbors
+                    .into_iter()
+                    .take(num_neighbors)
+                    .map(|(j, distance)| (i, j, distance)),
+            );
+        }
+        edges
+    }
+
+    #[test]
+    fn kmeans_separates_blobs() {
+        let vectors = blobs(&[(0.0, 0.0), (10.0, 10.0)], 6);
+        let (assignments, centroids) = kmeans(&vectors, KSelection::Fixed(2));
+        assert_eq!(centroids.len(), 2);
+        assert!(assignments[..6].iter().all(|a| *a == assignments[0]));
+        assert!(assignments[6..].iter().all(|a| *a == assignments[6]));
+        assert_ne!(assignments[0], assignments[6]);
+    }
+
+    #[test]
+    fn silhouette_and_elbow_find_the_number_of_blobs() {
+        let vectors = blobs(&[(0.0, 0.0), (10.0, 0.0), (0.0, 10.0)], 6);
+        for selection in [
+            KSelection::Silhouette { max_k: 6 },
+            KSelection::Elbow { max_k: 6 },
+        ] {
+            let (_, centroids) = kmeans(&vectors, selection);
+            assert_eq!(centroids.len(), 3, "{selection:?}");
+        }
+    }
+
+    #[test]
+    fn elbow_is_where_the_curve_flattens() {
+        assert_eq!(elbow(&[100.0, 20.0, 15.0, 12.0, 10.0]), 1);
+        assert_eq!(elbow(&[100.0, 90.0, 80.0, 10.0, 9.0]), 3);
+        assert_eq!(elbow(&[5.0, 5.0, 5.0]), 0);
+    }
+
+    #[test]
+    fn hdbscan_finds_dense_groups_and_noise() {
+        let mut points = blobs(&[(0.0, 0.0), (10.0, 10.0)], 9);
+        points.push(vec![5.0, -20.0]);
+        let edges = knn_edges(&points, HDBSCAN_NUM_NEIGHBORS);
+        let labels = hdbscan(points.len(), &edges, 5);
+        assert!(labels[0].is_some());
+        assert!(labels[..9].iter().all(|label| *label == labels[0]));
+        assert!(labels[9].is_some());
+        assert!(labels[9..18].iter().all(|label| *label == labels[9]));
+        assert_ne!(labels[0], labels[9]);
+        assert_eq!(labels[18], None);
+    }
+
+    #[test]
+    fn hdbscan_joins_disconnected_neighbor_graphs() {
+        // With few neighbors, the two groups share no edges
+        let points = blobs(&[(0.0, 0.0), (100.0, 100.0)], 9);
+        let labels = hdbscan(points.len(), &knn_edges(&points, 4), 3);
+        assert!(labels.iter().all(|label| label.is_some()));
+        assert_ne!(labels[0], labels[9]);
+    }
+}
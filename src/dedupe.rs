@@ -0,0 +1,426 @@
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    fmt, fs,
+    path::{Component, Path, PathBuf},
+};
+
+use crate::{file_utils, fingerprint, metadata_db::AudioFile};
+
+/// The default maximum distance between two samples in the timbre index for them to be
+/// considered near duplicates. Re-encoded or slightly trimmed copies of a sample usually land
+/// well within it, while different takes of the same sound don't.
+pub const DEFAULT_MAX_DISTANCE: f32 = 0.05;
+
+/// The number of nearest neighbors of each sample that are checked for near duplicates
+pub const NUM_NEIGHBORS: usize = 10;
+
+/// Codecs that store audio without loss, whose copies are preferred as keepers
+const LOSSLESS_CODECS: [&str; 3] = ["pcm", "float", "flac"];
+
+pub struct DedupeOptions {
+    /// The name of the index whose distances decide which samples are near duplicates
+    pub index: String,
+    /// The maximum distance between near duplicates, or None to only find exact duplicates
+    pub max_distance: Option<f32>,
+    /// Confirms near duplicates by comparing their acoustic fingerprints, which rules out
+    /// samples that have similar features but are different recordings
+    pub verify_audio: bool,
+}
+
+impl Default for DedupeOptions {
+    fn default() -> Self {
+        DedupeOptions {
+            index: "timbre".to_string(),
+            max_distance: Some(DEFAULT_MAX_DISTANCE),
+            verify_audio: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DuplicateKind {
+    /// The file's contents are identical to the keeper's
+    Exact,
+    /// The file sounds like the keeper, at the given distance from it in the index
+    Near { distance: f32 },
+}
+
+pub struct Duplicate {
+    pub file: AudioFile,
+    pub kind: DuplicateKind,
+}
+
+/// A group of samples that duplicate each other, with the one that's suggested to keep
+pub struct DuplicateCluster {
+    pub keeper: AudioFile,
+    pub duplicates: Vec<Duplicate>,
+}
+
+pub struct DedupeReport {
+    pub clusters: Vec<DuplicateCluster>,
+    /// The paths of files that couldn't be read to check whether they're duplicates, and why
+    pub unreadable: Vec<(String, String)>,
+}
+
+/// What to do with the duplicates in each cluster. Keepers are never touched.
+#[derive(Clone, Debug)]
+pub enum DuplicateAction {
+    /// Moves duplicates into a directory, mirroring their original paths within it
+    Move { to: PathBuf },
+    /// Replaces exact duplicates with hard links to their keeper, which frees their space
+    /// without breaking projects that refer to them. Near duplicates are left alone.
+    HardLink,
+}
+
+/// A single change to the file system made to resolve duplicates
+#[derive(Clone, Debug)]
+pub enum FileOperation {
+    Move { from: String, to: PathBuf },
+    HardLink { keeper: String, duplicate: String },
+}
+
+impl fmt::Display for FileOperation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileOperation::Move { from, to } => write!(f, "move {} -> {}", from, to.display()),
+            FileOperation::HardLink { keeper, duplicate } => {
+                write!(f, "link {} -> {}", duplicate, keeper)
+            }
+        }
+    }
+}
+
+/// Splits groups of samples that share a content hash into groups whose files are identical,
+/// since hashes can collide. Files that can't be read are left out, and returned with the
+/// reason they couldn't be compared.
+pub fn confirm_exact_duplicates(
+    files: &HashMap<i64, AudioFile>,
+    hash_groups: Vec<Vec<i64>>,
+) -> (Vec<Vec<i64>>, Vec<(String, String)>) {
+    let mut confirmed = Vec::new();
+    let mut unreadable = Vec::new();
+    for group in hash_groups {
+        let mut identical: Vec<Vec<i64>> = Vec::new();
+        for id in group {
+            let Some(file) = files.get(&id) else {
+                continue;
+            };
+            let path = Path::new(file.path());
+            let mut matched = false;
+            for candidates in identical.iter_mut() {
+                let representative = Path::new(files[&candidates[0]].path());
+                match file_utils::files_are_identical(representative, path) {
+                    Ok(true) => {
+                        candidates.push(id);
+                        matched = true;
+                        break;
+                    }
+                    Ok(false) => {}
+                    Err(e) => {
+                        unreadable.push((file.path().to_string(), e));
+                        matched = true;
+                        break;
+                    }
+                }
+            }
+            if !matched {
+                identical.push(vec![id]);
+            }
+        }
+        confirmed.extend(identical.into_iter().filter(|group| group.len() > 1));
+    }
+    (confirmed, unreadable)
+}
+
+/// Removes (id, neighbor, distance) pairs whose acoustic fingerprints show they aren't the
+/// same recording. Pairs without fingerprints are removed too.
+pub fn verify_near_duplicates(
+    fingerprints: &HashMap<i64, Vec<u32>>,
+    pairs: Vec<(u32, u32, f32)>,
+) -> Vec<(u32, u32, f32)> {
+    pairs
+        .into_iter()
+        .filter(|(a, b, _)| {
+            match (
+                fingerprints.get(&(*a as i64)),
+                fingerprints.get(&(*b as i64)),
+            ) {
+                (Some(a), Some(b)) => fingerprint::compare(a, b)
+                    .is_some_and(|(similarity, _)| fingerprint::is_match(similarity)),
+                _ => false,
+            }
+        })
+        .collect()
+}
+
+/// Groups samples linked by exact duplicate groups or near duplicate (id, neighbor, distance)
+/// pairs into clusters around the best copy, their keeper. A sample only joins a keeper's
+/// cluster if it's an exact copy of the keeper or paired with the keeper or one of its exact
+/// copies, since chains of pairs can link samples that are far apart. Linked samples left over
+/// form clusters around the next best keeper.
+pub fn cluster(
+    mut files: HashMap<i64, AudioFile>,
+    exact_groups: &[Vec<i64>],
+    near_pairs: &[(u32, u32, f32)],
+) -> Vec<DuplicateCluster> {
+    let mut parents: HashMap<i64, i64> = HashMap::new();
+    let mut exact_group_of: HashMap<i64, usize> = HashMap::new();
+    for (group_index, group) in exact_groups.iter().enumerate() {
+        for id in group {
+            exact_group_of.insert(*id, group_index);
+            union(&mut parents, group[0], *id);
+        }
+    }
+    let mut pair_distances: HashMap<(i64, i64), f32> = HashMap::new();
+    for (a, b, distance) in near_pairs {
+        let (a, b) = (*a as i64, *b as i64);
+        union(&mut parents, a, b);
+        pair_distances.insert((a.min(b), a.max(b)), *distance);
+    }
+
+    let mut members: HashMap<i64, Vec<i64>> = HashMap::new();
+    let ids: Vec<i64> = parents.keys().copied().collect();
+    for id in ids {
+        let root = find(&mut parents, id);
+        members.entry(root).or_default().push(id);
+    }
+
+    let mut clusters = Vec::new();
+    for ids in members.into_values() {
+        let mut remaining: Vec<AudioFile> = ids.iter().filter_map(|id| files.remove(id)).collect();
+        remaining.sort_by(compare_keepers);
+        while remaining.len() > 1 {
+            let keeper = remaining.remove(0);
+            let keeper_group = exact_group_of.get(&keeper.id());
+            // Exact copies share their vectors, so a pair with a copy of the keeper counts as
+            // a pair with the keeper
+            let keeper_copies: Vec<i64> = match keeper_group {
+                Some(group) => exact_groups[*group].clone(),
+                None => vec![keeper.id()],
+            };
+            let mut duplicates = Vec::new();
+            let mut unclustered = Vec::new();
+            for file in remaining {
+                let id = file.id();
+                let kind = if keeper_group.is_some() && exact_group_of.get(&id) == keeper_group {
+                    Some(DuplicateKind::Exact)
+                } else {
+                    keeper_copies
+                        .iter()
+                        .filter_map(|copy| pair_distances.get(&(id.min(*copy), id.max(*copy))))
+                        .min_by(|a, b| a.total_cmp(b))
+                        .map(|distance| DuplicateKind::Near {
+                            distance: *distance,
+                        })
+                };
+                match kind {
+                    Some(kind) => duplicates.push(Duplicate { file, kind }),
+                    None => unclustered.push(file),
+                }
+            }
+            if !duplicates.is_empty() {
+                clusters.push(DuplicateCluster { keeper, duplicates });
+            }
+            remaining = unclustered;
+        }
+    }
+    clusters.sort_by(|a, b| a.keeper.path().cmp(b.keeper.path()));
+    clusters
+}
+
+fn find(parents: &mut HashMap<i64, i64>, id: i64) -> i64 {
+    let parent = *parents.entry(id).or_insert(id);
+    if parent == id {
+        return id;
+    }
+    let root = find(parents, parent);
+    parents.insert(id, root);
+    root
+}
+
+fn union(parents: &mut HashMap<i64, i64>, a: i64, b: i64) {
+    let (a, b) = (find(parents, a), find(parents, b));
+    if a != b {
+        parents.insert(b.max(a), b.min(a));
+    }
+}
+
+/// Orders files from the best to the worst copy to keep. Lossless formats come first, then
+/// higher bit depths and sample rates, then longer files since copies are more often trimmed
+/// than extended, then shorter paths.
+fn compare_keepers(a: &AudioFile, b: &AudioFile) -> Ordering {
+    match (a.properties(), b.properties()) {
+        (Some(a_properties), Some(b_properties)) => {
+            let is_lossless = |codec: &str| LOSSLESS_CODECS.contains(&codec);
+            is_lossless(&b_properties.codec)
+                .cmp(&is_lossless(&a_properties.codec))
+                .then(b_properties.bit_depth.cmp(&a_properties.bit_depth))
+                .then(b_properties.sample_rate.cmp(&a_properties.sample_rate))
+                .then(b_properties.duration.total_cmp(&a_properties.duration))
+        }
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    }
+    .then(a.path().len().cmp(&b.path().len()))
+    .then(a.path().cmp(b.path()))
+}
+
+/// Returns the file operations that resolve clusters with action
+pub fn plan(clusters: &[DuplicateCluster], action: &DuplicateAction) -> Vec<FileOperation> {
+    let mut operations = Vec::new();
+    for cluster in clusters {
+        for duplicate in cluster.duplicates.iter() {
+            match action {
+                DuplicateAction::Move { to } => operations.push(FileOperation::Move {
+                    from: duplicate.file.path().to_string(),
+                    to: mirrored_path(to, Path::new(duplicate.file.path())),
+                }),
+                DuplicateAction::HardLink => {
+                    if duplicate.kind == DuplicateKind::Exact {
+                        operations.push(FileOperation::HardLink {
+                            keeper: cluster.keeper.path().to_string(),
+                            duplicate: duplicate.file.path().to_string(),
+                        });
+                    }
+                }
+            }
+        }
+    }
+    operations
+}
+
+/// Returns the path of file within dir, keeping every component of its original path so
+/// duplicates with the same name can't collide
+fn mirrored_path(dir: &Path, file: &Path) -> PathBuf {
+    let mut path = dir.to_path_buf();
+    for component in file.components() {
+        if let Component::Normal(part) = component {
+            path.push(part);
+        }
+    }
+    path
+}
+
+/// Performs operation on the file system
+pub fn apply(operation: &FileOperation) -> Result<(), String> {
+    match operation {
+        FileOperation::Move { from, to } => {
+            if to.exists() {
+                return Err(format!("{} already exists", to.display()));
+            }
+            if let Some(parent) = to.parent() {
+                fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+            }
+            // Renaming fails across file systems, so fall back to copying
+            if fs::rename(from, to).is_err() {
+                fs::copy(from, to).map_err(|e| e.to_string())?;
+                fs::remove_file(from).map_err(|e| e.to_string())?;
+            }
+            Ok(())
+        }
+        FileOperation::HardLink { keeper, duplicate } => {
+            let duplicate = Path::new(duplicate);
+            // Link to a temporary name first, so the duplicate is only replaced once the link
+            // exists
+            let file_name = duplicate.file_name().unwrap_or_default().to_string_lossy();
+            let link = duplicate.with_file_name(format!(".{file_name}.dedupe"));
+            fs::hard_link(keeper, &link).map_err(|e| e.to_string())?;
+            fs::rename(&link, duplicate).map_err(|e| {
+                let _ = fs::remove_file(&link);
+                e.to_string()
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn audio_file(id: i64, path: &str) -> AudioFile {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "path": path,
+            "properties": null,
+            "rating": null,
+            "favorite": false,
+        }))
+        .unwrap()
+    }
+
+    fn library(paths: &[(i64, &str)]) -> HashMap<i64, AudioFile> {
+        paths
+            .iter()
+            .map(|(id, path)| (*id, audio_file(*id, path)))
+            .collect()
+    }
+
+    fn cluster_ids(clusters: &[DuplicateCluster]) -> Vec<(i64, Vec<i64>)> {
+        clusters
+            .iter()
+            .map(|cluster| {
+                let mut ids: Vec<i64> = cluster.duplicates.iter().map(|d| d.file.id()).collect();
+                ids.sort();
+                (cluster.keeper.id(), ids)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn chained_pairs_only_join_the_keeper_they_are_paired_with() {
+        // 1 and 3 are only linked through 2, so 3 isn't a duplicate of the keeper 1
+        let files = library(&[(1, "/a/1.wav"), (2, "/a/22.wav"), (3, "/a/333.wav")]);
+        let clusters = cluster(files, &[], &[(1, 2, 0.04), (2, 3, 0.04)]);
+        assert_eq!(cluster_ids(&clusters), [(1, vec![2])]);
+    }
+
+    #[test]
+    fn leftover_samples_cluster_around_the_next_keeper() {
+        let files = library(&[
+            (1, "/a/1.wav"),
+            (2, "/a/22.wav"),
+            (3, "/a/333.wav"),
+            (4, "/a/4444.wav"),
+        ]);
+        let clusters = cluster(files, &[], &[(1, 2, 0.01), (2, 3, 0.02), (3, 4, 0.03)]);
+        assert_eq!(cluster_ids(&clusters), [(1, vec![2]), (3, vec![4])]);
+    }
+
+    #[test]
+    fn pairs_with_exact_copies_of_the_keeper_count() {
+        let files = library(&[(1, "/a/1.wav"), (2, "/b/1.wav"), (3, "/a/333.wav")]);
+        let clusters = cluster(files, &[vec![1, 2]], &[(2, 3, 0.03)]);
+        assert_eq!(cluster_ids(&clusters), [(1, vec![2, 3])]);
+        let kinds: Vec<DuplicateKind> = clusters[0].duplicates.iter().map(|d| d.kind).collect();
+        assert!(kinds.contains(&DuplicateKind::Exact));
+        assert!(kinds.contains(&DuplicateKind::Near { distance: 0.03 }));
+    }
+
+    #[test]
+    fn mirrored_paths_keep_every_component() {
+        let path = mirrored_path(Path::new("/dupes"), Path::new("/lib/kicks/kick.wav"));
+        assert_eq!(path, Path::new("/dupes/lib/kicks/kick.wav"));
+    }
+
+    #[test]
+    fn hard_links_are_only_planned_for_exact_duplicates() {
+        let clusters = vec![DuplicateCluster {
+            keeper: audio_file(1, "/a/1.wav"),
+            duplicates: vec![
+                Duplicate {
+                    file: audio_file(2, "/b/1.wav"),
+                    kind: DuplicateKind::Exact,
+                },
+                Duplicate {
+                    file: audio_file(3, "/a/1.mp3"),
+                    kind: DuplicateKind::Near { distance: 0.01 },
+                },
+            ],
+        }];
+        let operations = plan(&clusters, &DuplicateAction::HardLink);
+        assert_eq!(operations.len(), 1);
+        assert_eq!(operations[0].to_string(), "link /b/1.wav -> /a/1.wav");
+    }
+}
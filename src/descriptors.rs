@@ -0,0 +1,238 @@
+use realfft::RealFftPlanner;
+
+pub const NUM_PITCH_CLASSES: usize = 12;
+
+/// The tempo range covered by the rhythm descriptor
+const MIN_BPM: f32 = 50.0;
+const MAX_BPM: f32 = 200.0;
+
+/// How many standard deviations above the mean onset strength a peak must be to count as an
+/// onset
+const ONSET_THRESHOLD: f32 = 1.5;
+
+/// The frequency range folded into the chroma descriptor. Energy below this range is mostly
+/// sub-bass rumble, and above it is mostly noise and upper harmonics.
+const MIN_CHROMA_FREQ: f32 = 55.0;
+const MAX_CHROMA_FREQ: f32 = 5000.0;
+
+/// Returns the onset strength of each hop: the half-wave rectified difference of log energy
+/// between consecutive hops
+fn onset_strength_envelope(buffer: &[f32], hop_size: usize) -> Vec<f32> {
+    let log_energy: Vec<f32> = buffer
+        .chunks(hop_size)
+        .map(|block| {
+            let rms = (block.iter().map(|s| s * s).sum::<f32>() / block.len() as f32).sqrt();
+            (1.0 + 1000.0 * rms).ln()
+        })
+        .collect();
+    log_energy
+        .windows(2)
+        .map(|pair| (pair[1] - pair[0]).max(0.0))
+        .collect()
+}
+
+/// Returns the sample positions of detected onsets. An onset is a local maximum of the onset
+/// strength envelope that exceeds the envelope's mean by ONSET_THRESHOLD standard deviations.
+pub fn detect_onsets(buffer: &[f32], hop_size: usize) -> Vec<usize> {
+    let envelope = onset_strength_envelope(buffer, hop_size);
+    if envelope.len() < 3 {
+        return Vec::new();
+    }
+    let mean = envelope.iter().sum::<f32>() / envelope.len() as f32;
+    let variance =
+        envelope.iter().map(|e| (e - mean) * (e - mean)).sum::<f32>() / envelope.len() as f32;
+    let threshold = mean + ONSET_THRESHOLD * variance.sqrt();
+
+    envelope
+        .windows(3)
+        .enumerate()
+        .filter(|(_, w)| w[1] > threshold && w[1] >= w[0] && w[1] > w[2])
+        // envelope[i] is the change between hops i and i + 1, so the onset for window i
+        // starts at hop i + 2
+        .map(|(i, _)| (i + 2) * hop_size)
+        .collect()
+}
+
+/// Calculates a rhythm descriptor from the autocorrelation of the onset strength envelope,
+/// sampled at num_tempo_bins log-spaced tempos between MIN_BPM and MAX_BPM. Each element is
+/// the normalized autocorrelation at that tempo's beat period, so files with a strong pulse
+/// at the same tempo produce similar vectors regardless of their timbre.
+pub fn calculate_rhythm(
+    buffer: &[f32],
+    sample_rate: u32,
+    hop_size: usize,
+    num_tempo_bins: usize,
+) -> Vec<f32> {
+    let mut rhythm = vec![0.0; num_tempo_bins];
+
+    let mut envelope = onset_strength_envelope(buffer, hop_size);
+    if envelope.len() < 2 {
+        return rhythm;
+    }
+    let mean = envelope.iter().sum::<f32>() / envelope.len() as f32;
+    envelope.iter_mut().for_each(|e| *e -= mean);
+
+    let energy: f32 = envelope.iter().map(|e| e * e).sum();
+    if energy <= f32::EPSILON {
+        return rhythm;
+    }
+    let autocorrelation = |lag: usize| -> f32 {
+        if lag >= envelope.len() {
+            return 0.0;
+        }
+        envelope
+            .iter()
+            .zip(&envelope[lag..])
+            .map(|(a, b)| a * b)
+            .sum::<f32>()
+            / energy
+    };
+
+    let frames_per_second = sample_rate as f32 / hop_size as f32;
+    for (bin, value) in rhythm.iter_mut().enumerate() {
+        let position = bin as f32 / (num_tempo_bins.max(2) - 1) as f32;
+        let bpm = MIN_BPM * (MAX_BPM / MIN_BPM).powf(position);
+        // Beat periods rarely land on a whole number of frames, so interpolate between the
+        // neighboring lags
+        let lag = frames_per_second * 60.0 / bpm;
+        let fraction = lag.fract();
+        let lower = lag.floor() as usize;
+        *value = autocorrelation(lower) * (1.0 - fraction) + autocorrelation(lower + 1) * fraction;
+    }
+    rhythm
+}
+
+/// Calculates the mean chroma vector: the magnitude spectrum of each fft_size frame folded
+/// into 12 pitch classes, averaged over the file and normalized to sum to 1.
+pub fn calculate_chroma(
+    buffer: &[f32],
+    sample_rate: u32,
+    fft_size: usize,
+) -> Result<Vec<f32>, String> {
+    let mut planner = RealFftPlanner::<f32>::new();
+    let fft = planner.plan_fft_forward(fft_size);
+    let mut frame = fft.make_input_vec();
+    let mut spectrum = fft.make_output_vec();
+
+    // Hann window to reduce leakage between neighboring pitch classes
+    let window: Vec<f32> = (0..fft_size)
+        .map(|i| 0.5 - 0.5 * (2.0 * std::f32::consts::PI * i as f32 / fft_size as f32).cos())
+        .collect();
+
+    // Precompute the pitch class of each bin in the analyzed frequency range
+    let bin_pitch_classes: Vec<Option<usize>> = (0..spectrum.len())
+        .map(|bin| {
+            let freq = bin as f32 * sample_rate as f32 / fft_size as f32;
+            if !(MIN_CHROMA_FREQ..=MAX_CHROMA_FREQ).contains(&freq) {
+                return None;
+            }
+            let midi_note = 69.0 + 12.0 * (freq / 440.0).log2();
+            Some(midi_note.round() as usize % NUM_PITCH_CLASSES)
+        })
+        .collect();
+
+    let mut chroma = vec![0.0; NUM_PITCH_CLASSES];
+    let hop_size = fft_size / 2;
+    let mut start = 0;
+    loop {
+        let end = (start + fft_size).min(buffer.len());
+        frame.fill(0.0);
+        for ((out, sample), w) in frame.iter_mut().zip(&buffer[start..end]).zip(&window) {
+            *out = sample * w;
+        }
+        fft.process(&mut frame, &mut spectrum)
+            .map_err(|e| e.to_string())?;
+        for (bin, pitch_class) in spectrum.iter().zip(&bin_pitch_classes) {
+            if let Some(pitch_class) = pitch_class {
+                chroma[*pitch_class] += bin.norm();
+            }
+        }
+
+        start += hop_size;
+        if start + fft_size > buffer.len() {
+            break;
+        }
+    }
+
+    let total: f32 = chroma.iter().sum();
+    if total <= f32::EPSILON {
+        // Silence has no pitch content; a uniform profile keeps the vector usable with the
+        // angular metric
+        return Ok(vec![1.0 / NUM_PITCH_CLASSES as f32; NUM_PITCH_CLASSES]);
+    }
+    chroma.iter_mut().for_each(|c| *c /= total);
+    Ok(chroma)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 22050;
+    const HOP_SIZE: usize = 512;
+
+    /// Returns num_clicks short bursts of noise, period samples apart, starting after one
+    /// period of silence
+    fn click_train(period: usize, num_clicks: usize) -> Vec<f32> {
+        let mut buffer = vec![0.0; period * (num_clicks + 1)];
+        for click in 1..=num_clicks {
+            for i in 0..HOP_SIZE / 2 {
+                buffer[click * period + i] = if i % 2 == 0 { 0.8 } else { -0.8 };
+            }
+        }
+        buffer
+    }
+
+    #[test]
+    fn onsets_are_found_at_clicks() {
+        let period = 20 * HOP_SIZE;
+        let onsets = detect_onsets(&click_train(period, 8), HOP_SIZE);
+        assert_eq!(onsets.len(), 8);
+        for (click, onset) in (1..).zip(onsets) {
+            assert!(onset.abs_diff(click * period) <= HOP_SIZE, "{onset}");
+        }
+    }
+
+    #[test]
+    fn rhythm_peaks_at_the_click_rate() {
+        let period = 22 * HOP_SIZE;
+        let bpm = 60.0 * SAMPLE_RATE as f32 / period as f32;
+        let num_tempo_bins = 64;
+        let buffer = click_train(period, 16);
+        let rhythm = calculate_rhythm(&buffer, SAMPLE_RATE, HOP_SIZE, num_tempo_bins);
+        let peak = (0..num_tempo_bins)
+            .max_by(|a, b| rhythm[*a].total_cmp(&rhythm[*b]))
+            .unwrap();
+        let peak_bpm =
+            MIN_BPM * (MAX_BPM / MIN_BPM).powf(peak as f32 / (num_tempo_bins - 1) as f32);
+        assert!((peak_bpm / bpm - 1.0).abs() < 0.05, "{peak_bpm} {bpm}");
+    }
+
+    #[test]
+    fn a440_is_pitch_class_a() {
+        let buffer: Vec<f32> = (0..SAMPLE_RATE)
+            .map(|i| (2.0 * std::f32::consts::PI * 440.0 * i as f32 / SAMPLE_RATE as f32).sin())
+            .collect();
+        let chroma = calculate_chroma(&buffer, SAMPLE_RATE, 4096).unwrap();
+        let dominant = (0..NUM_PITCH_CLASSES)
+            .max_by(|a, b| chroma[*a].total_cmp(&chroma[*b]))
+            .unwrap();
+        // MIDI note 69
+        assert_eq!(dominant, 9);
+        assert!((chroma.iter().sum::<f32>() - 1.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn silence_has_no_onsets_and_finite_descriptors() {
+        let silence = vec![0.0; SAMPLE_RATE as usize];
+        assert!(detect_onsets(&silence, HOP_SIZE).is_empty());
+        let rhythm = calculate_rhythm(&silence, SAMPLE_RATE, HOP_SIZE, 16);
+        assert!(rhythm.iter().all(|value| value.is_finite()));
+        let chroma = calculate_chroma(&silence, SAMPLE_RATE, 4096).unwrap();
+        assert!(chroma.iter().all(|value| value.is_finite()));
+        assert!(detect_onsets(&[], HOP_SIZE).is_empty());
+        assert!(calculate_rhythm(&[], SAMPLE_RATE, HOP_SIZE, 16)
+            .iter()
+            .all(|value| value.is_finite()));
+    }
+}
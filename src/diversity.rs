@@ -0,0 +1,95 @@
+/// Diverse results are chosen from this many times as many of the nearest samples as were
+/// asked for
+pub const CANDIDATE_FACTOR: usize = 5;
+
+/// How search results are varied, so they aren't all near copies of one sample
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Diversity {
+    /// Reranks results with maximal marginal relevance. 1 ranks purely by similarity to the
+    /// query, and lower values increasingly favor results unlike those ranked above them.
+    pub lambda: Option<f32>,
+    /// Keeps only the most similar result from each folder, e.g. one sample per pack
+    pub one_per_folder: bool,
+}
+
+impl Diversity {
+    pub fn is_enabled(&self) -> bool {
+        self.lambda.is_some() || self.one_per_folder
+    }
+}
+
+/// Returns the indices of up to num_results candidates chosen with maximal marginal
+/// relevance, in the order they were chosen. Each pick maximizes
+/// lambda * -(distance to the query) + (1 - lambda) * (distance to the nearest pick so far),
+/// so candidates close to an earlier pick are passed over for slightly less similar ones.
+/// distance returns the distance between two candidates.
+pub fn mmr(
+    query_distances: &[f32],
+    distance: impl Fn(usize, usize) -> f32,
+    lambda: f32,
+    num_results: usize,
+) -> Vec<usize> {
+    let mut remaining: Vec<usize> = (0..query_distances.len()).collect();
+    // The distance from each candidate to its nearest pick, which is 0 before the first pick
+    // so it's chosen by similarity alone
+    let mut nearest_pick = vec![0.0f32; query_distances.len()];
+    let mut picks = Vec::new();
+    while picks.len() < num_results && !remaining.is_empty() {
+        let score = |i: usize| -lambda * query_distances[i] + (1.0 - lambda) * nearest_pick[i];
+        // Ties go to the candidate nearest the query
+        let best = (0..remaining.len())
+            .max_by(|a, b| {
+                score(remaining[*a])
+                    .total_cmp(&score(remaining[*b]))
+                    .then(b.cmp(a))
+            })
+            .unwrap_or(0);
+        let pick = remaining.remove(best);
+        for i in remaining.iter() {
+            let distance = distance(*i, pick);
+            nearest_pick[*i] = if picks.is_empty() {
+                distance
+            } else {
+                nearest_pick[*i].min(distance)
+            };
+        }
+        picks.push(pick);
+    }
+    picks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Candidates on a line, with the query at 0
+    fn on_line(positions: &[f32]) -> (Vec<f32>, impl Fn(usize, usize) -> f32 + '_) {
+        let query_distances = positions.iter().map(|p| p.abs()).collect();
+        (query_distances, move |a: usize, b: usize| {
+            (positions[a] - positions[b]).abs()
+        })
+    }
+
+    #[test]
+    fn lambda_of_one_ranks_by_similarity() {
+        let positions = [3.0, 1.0, 1.1, 2.0];
+        let (query_distances, distance) = on_line(&positions);
+        assert_eq!(mmr(&query_distances, distance, 1.0, 4), [1, 2, 3, 0]);
+    }
+
+    #[test]
+    fn near_copies_of_a_pick_are_passed_over() {
+        // 1 and 2 are near copies, while 3 is on the other side of the query
+        let positions = [5.0, 1.0, 1.05, -1.2];
+        let (query_distances, distance) = on_line(&positions);
+        assert_eq!(mmr(&query_distances, distance, 0.5, 2), [1, 3]);
+    }
+
+    #[test]
+    fn picks_stop_when_candidates_run_out() {
+        let positions = [1.0, 2.0];
+        let (query_distances, distance) = on_line(&positions);
+        assert_eq!(mmr(&query_distances, distance, 0.5, 5).len(), 2);
+        assert!(mmr(&[], |_, _| 0.0, 0.5, 5).is_empty());
+    }
+}
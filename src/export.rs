@@ -0,0 +1,402 @@
+use std::{borrow::Cow, io::Write, path::Path};
+
+use serde::Serialize;
+
+use crate::{
+    graph::{GraphEdge, GraphNode, KnnGraph},
+    metadata_db::MapPoint,
+    sequence::SequenceStep,
+};
+
+/// A file format the library's map can be exported in
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MapFormat {
+    /// An array of objects with id, path, x, y and cluster fields
+    Json,
+    /// A header row followed by one id,path,x,y,cluster row per sample
+    Csv,
+}
+
+impl MapFormat {
+    pub fn from_name(name: &str) -> Result<MapFormat, String> {
+        match name {
+            "json" => Ok(MapFormat::Json),
+            "csv" => Ok(MapFormat::Csv),
+            _ => Err(format!(
+                "Unknown map format {name}. Available formats: json, csv"
+            )),
+        }
+    }
+}
+
+/// Writes points to writer in format
+pub fn write_map(
+    points: &[MapPoint],
+    format: MapFormat,
+    writer: &mut impl Write,
+) -> Result<(), String> {
+    match format {
+        MapFormat::Json => {
+            serde_json::to_writer_pretty(&mut *writer, points).map_err(|e| e.to_string())?;
+            writeln!(writer).map_err(|e| e.to_string())
+        }
+        MapFormat::Csv => {
+            writeln!(writer, "id,path,x,y,cluster").map_err(|e| e.to_string())?;
+            for point in points {
+                let cluster = point.cluster.map(|c| c.to_string()).unwrap_or_default();
+                writeln!(
+                    writer,
+                    "{},{},{},{},{}",
+                    point.id,
+                    csv_field(&point.path),
+                    point.x,
+                    point.y,
+                    cluster
+                )
+                .map_err(|e| e.to_string())?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// A file format the k-nearest neighbor graph can be exported in
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GraphFormat {
+    GraphMl,
+    Gexf,
+    /// A header row followed by one source,target,distance,weight row per edge
+    Csv,
+    /// The node-link format read by networkx's node_link_graph
+    Json,
+}
+
+impl GraphFormat {
+    pub fn from_name(name: &str) -> Result<GraphFormat, String> {
+        match name {
+            "graphml" => Ok(GraphFormat::GraphMl),
+            "gexf" => Ok(GraphFormat::Gexf),
+            "csv" => Ok(GraphFormat::Csv),
+            "json" => Ok(GraphFormat::Json),
+            _ => Err(format!(
+                "Unknown graph format {name}. Available formats: graphml, gexf, csv, json"
+            )),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct NodeLinkGraph<'a> {
+    directed: bool,
+    multigraph: bool,
+    nodes: &'a [GraphNode],
+    links: &'a [GraphEdge],
+}
+
+/// Writes graph to writer in format. Nodes are identified by sample ID, and carry their path
+/// and cluster.
+pub fn write_graph(
+    graph: &KnnGraph,
+    format: GraphFormat,
+    writer: &mut impl Write,
+) -> Result<(), String> {
+    match format {
+        GraphFormat::GraphMl => write_graphml(graph, writer),
+        GraphFormat::Gexf => write_gexf(graph, writer),
+        GraphFormat::Csv => {
+            writeln!(writer, "source,target,distance,weight").map_err(|e| e.to_string())?;
+            for edge in graph.edges.iter() {
+                writeln!(
+                    writer,
+                    "{},{},{},{}",
+                    edge.source, edge.target, edge.distance, edge.weight
+                )
+                .map_err(|e| e.to_string())?;
+            }
+            Ok(())
+        }
+        GraphFormat::Json => {
+            let node_link = NodeLinkGraph {
+                directed: true,
+                multigraph: false,
+                nodes: &graph.nodes,
+                links: &graph.edges,
+            };
+            serde_json::to_writer(&mut *writer, &node_link).map_err(|e| e.to_string())?;
+            writeln!(writer).map_err(|e| e.to_string())
+        }
+    }
+}
+
+fn write_graphml(graph: &KnnGraph, writer: &mut impl Write) -> Result<(), String> {
+    let mut write = || -> std::io::Result<()> {
+        writeln!(writer, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+        writeln!(
+            writer,
+            r#"<graphml xmlns="http://graphml.graphdrawing.org/xmlns">"#
+        )?;
+        writeln!(
+            writer,
+            r#"  <key id="path" for="node" attr.name="path" attr.type="string"/>"#
+        )?;
+        writeln!(
+            writer,
+            r#"  <key id="cluster" for="node" attr.name="cluster" attr.type="long"/>"#
+        )?;
+        writeln!(
+            writer,
+            r#"  <key id="distance" for="edge" attr.name="distance" attr.type="float"/>"#
+        )?;
+        writeln!(
+            writer,
+            r#"  <key id="weight" for="edge" attr.name="weight" attr.type="float"/>"#
+        )?;
+        writeln!(writer, r#"  <graph id="G" edgedefault="directed">"#)?;
+        for node in graph.nodes.iter() {
+            write!(
+                writer,
+                r#"    <node id="{}"><data key="path">{}</data>"#,
+                node.id,
+                xml_escape(&node.path)
+            )?;
+            if let Some(cluster) = node.cluster {
+                write!(writer, r#"<data key="cluster">{cluster}</data>"#)?;
+            }
+            writeln!(writer, "</node>")?;
+        }
+        for edge in graph.edges.iter() {
+            writeln!(
+                writer,
+                r#"    <edge source="{}" target="{}"><data key="distance">{}</data><data key="weight">{}</data></edge>"#,
+                edge.source, edge.target, edge.distance, edge.weight
+            )?;
+        }
+        writeln!(writer, "  </graph>")?;
+        writeln!(writer, "</graphml>")
+    };
+    write().map_err(|e| e.to_string())
+}
+
+fn write_gexf(graph: &KnnGraph, writer: &mut impl Write) -> Result<(), String> {
+    let mut write = || -> std::io::Result<()> {
+        writeln!(writer, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+        writeln!(
+            writer,
+            r#"<gexf xmlns="http://gexf.net/1.3" version="1.3">"#
+        )?;
+        writeln!(writer, r#"  <graph defaultedgetype="directed">"#)?;
+        writeln!(writer, r#"    <attributes class="node">"#)?;
+        writeln!(
+            writer,
+            r#"      <attribute id="path" title="path" type="string"/>"#
+        )?;
+        writeln!(
+            writer,
+            r#"      <attribute id="cluster" title="cluster" type="long"/>"#
+        )?;
+        writeln!(writer, "    </attributes>")?;
+        writeln!(writer, r#"    <attributes class="edge">"#)?;
+        writeln!(
+            writer,
+            r#"      <attribute id="distance" title="distance" type="float"/>"#
+        )?;
+        writeln!(writer, "    </attributes>")?;
+        writeln!(writer, "    <nodes>")?;
+        for node in graph.nodes.iter() {
+            let name = Path::new(&node.path)
+                .file_name()
+                .map(|name| name.to_string_lossy())
+                .unwrap_or_default();
+            write!(
+                writer,
+                r#"      <node id="{}" label="{}"><attvalues><attvalue for="path" value="{}"/>"#,
+                node.id,
+                xml_escape(&name),
+                xml_escape(&node.path)
+            )?;
+            if let Some(cluster) = node.cluster {
+                write!(writer, r#"<attvalue for="cluster" value="{cluster}"/>"#)?;
+            }
+            writeln!(writer, "</attvalues></node>")?;
+        }
+        writeln!(writer, "    </nodes>")?;
+        writeln!(writer, "    <edges>")?;
+        for (i, edge) in graph.edges.iter().enumerate() {
+            writeln!(
+                writer,
+                r#"      <edge id="{}" source="{}" target="{}" weight="{}"><attvalues><attvalue for="distance" value="{}"/></attvalues></edge>"#,
+                i, edge.source, edge.target, edge.weight, edge.distance
+            )?;
+        }
+        writeln!(writer, "    </edges>")?;
+        writeln!(writer, "  </graph>")?;
+        writeln!(writer, "</gexf>")
+    };
+    write().map_err(|e| e.to_string())
+}
+
+/// A file format a sequence of samples can be exported in
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SequenceFormat {
+    /// An extended M3U playlist of the samples' paths
+    M3u,
+    /// An array of objects with id, path, duration and distance fields
+    Json,
+}
+
+impl SequenceFormat {
+    pub fn from_name(name: &str) -> Result<SequenceFormat, String> {
+        match name {
+            "m3u" => Ok(SequenceFormat::M3u),
+            "json" => Ok(SequenceFormat::Json),
+            _ => Err(format!(
+                "Unknown sequence format {name}. Available formats: m3u, json"
+            )),
+        }
+    }
+}
+
+/// Writes steps to writer in format, in order
+pub fn write_sequence(
+    steps: &[SequenceStep],
+    format: SequenceFormat,
+    writer: &mut impl Write,
+) -> Result<(), String> {
+    match format {
+        SequenceFormat::M3u => {
+            let mut write = || -> std::io::Result<()> {
+                writeln!(writer, "#EXTM3U")?;
+                for step in steps {
+                    // M3U durations are whole seconds, and -1 when unknown
+                    let duration = step
+                        .duration
+                        .map(|duration| duration.ceil() as i64)
+                        .unwrap_or(-1);
+                    let name = Path::new(&step.path)
+                        .file_name()
+                        .map(|name| name.to_string_lossy())
+                        .unwrap_or_default();
+                    writeln!(writer, "#EXTINF:{duration},{name}")?;
+                    writeln!(writer, "{}", step.path)?;
+                }
+                Ok(())
+            };
+            write().map_err(|e| e.to_string())
+        }
+        SequenceFormat::Json => {
+            serde_json::to_writer_pretty(&mut *writer, steps).map_err(|e| e.to_string())?;
+            writeln!(writer).map_err(|e| e.to_string())
+        }
+    }
+}
+
+/// Escapes text for an XML attribute or element
+fn xml_escape(text: &str) -> Cow<'_, str> {
+    if !text.contains(['&', '<', '>', '"', '\'']) {
+        return Cow::Borrowed(text);
+    }
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(c),
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+/// Quotes field for a CSV file if it contains a delimiter, quote or line break
+fn csv_field(field: &str) -> Cow<'_, str> {
+    if field.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_fields_are_quoted_only_when_needed() {
+        assert_eq!(csv_field("kicks/kick.wav"), "kicks/kick.wav");
+        assert_eq!(
+            csv_field("kicks, hard/kick.wav"),
+            "\"kicks, hard/kick.wav\""
+        );
+        assert_eq!(
+            csv_field("the \"best\" kick.wav"),
+            "\"the \"\"best\"\" kick.wav\""
+        );
+        assert_eq!(csv_field("two\nlines.wav"), "\"two\nlines.wav\"");
+    }
+
+    #[test]
+    fn map_csv_has_a_row_per_point() {
+        let points = [
+            MapPoint {
+                id: 1,
+                path: "a, b.wav".to_string(),
+                x: 0.5,
+                y: -1.0,
+                cluster: Some(2),
+            },
+            MapPoint {
+                id: 2,
+                path: "c.wav".to_string(),
+                x: 1.0,
+                y: 0.0,
+                cluster: None,
+            },
+        ];
+        let mut output = Vec::new();
+        write_map(&points, MapFormat::Csv, &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "id,path,x,y,cluster\n1,\"a, b.wav\",0.5,-1,2\n2,c.wav,1,0,\n"
+        );
+    }
+
+    #[test]
+    fn xml_special_characters_are_escaped() {
+        assert!(matches!(xml_escape("kick.wav"), Cow::Borrowed("kick.wav")));
+        assert_eq!(
+            xml_escape("<drums & \"bass\">/it's.wav"),
+            "&lt;drums &amp; &quot;bass&quot;&gt;/it&apos;s.wav"
+        );
+    }
+
+    #[test]
+    fn graphml_escapes_node_paths() {
+        let graph = KnnGraph {
+            nodes: vec![
+                GraphNode {
+                    id: 1,
+                    path: "R&B/kick.wav".to_string(),
+                    cluster: Some(0),
+                },
+                GraphNode {
+                    id: 2,
+                    path: "snare.wav".to_string(),
+                    cluster: None,
+                },
+            ],
+            edges: vec![GraphEdge::new(1, 2, 1.0)],
+        };
+        let mut output = Vec::new();
+        write_graph(&graph, GraphFormat::GraphMl, &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains(
+            r#"<node id="1"><data key="path">R&amp;B/kick.wav</data><data key="cluster">0</data></node>"#
+        ));
+        assert!(output.contains(r#"<node id="2"><data key="path">snare.wav</data></node>"#));
+        assert!(output.contains(
+            r#"<edge source="1" target="2"><data key="distance">1</data><data key="weight">0.5</data></edge>"#
+        ));
+    }
+}
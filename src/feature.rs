@@ -1,19 +1,69 @@
+use crate::feature_extractor::AudioProperties;
+use crate::vector_db::{VectorIndex, SEGMENT_INDEX};
+
+/// The properties, embedded tags and extracted features of a single file
+pub struct AnalyzedFile {
+    pub path: String,
+    pub properties: AudioProperties,
+    /// Key/value pairs read from the file's ID3, RIFF or Vorbis comment tags
+    pub tags: Vec<(String, String)>,
+    /// A hash of the file's contents, used to find exact duplicates. None if the file couldn't
+    /// be read for hashing.
+    pub content_hash: Option<u64>,
+    /// The file's acoustic fingerprint, if it was requested
+    pub fingerprint: Option<Vec<u32>>,
+    /// Whether segmentation was requested, in which case the file's segment features, if it
+    /// was long enough to produce any, are among features
+    pub segmented: bool,
+    /// The IDs of the segments the file had before it was segmented again, which are set when
+    /// it's stored and must be removed from the segment index
+    pub replaced_segments: Vec<i64>,
+    pub features: Vec<Feature>,
+}
+
 #[derive(Clone)]
 pub struct Feature {
     feature_vector: Vec<f32>,
     source_file: String,
+    index: &'static VectorIndex,
+    /// The start and end time in seconds of the segment of source_file this feature describes,
+    /// or None if it describes the whole file
+    segment: Option<(f32, f32)>,
     id: Option<i64>,
 }
 
 impl Feature {
-    pub fn new(feature_vector: Vec<f32>, source_file: String, id: Option<i64>) -> Self {
+    pub fn new(
+        feature_vector: Vec<f32>,
+        source_file: String,
+        index: &'static VectorIndex,
+        id: Option<i64>,
+    ) -> Self {
         Self {
             feature_vector,
             source_file,
+            index,
+            segment: None,
             id,
         }
     }
 
+    /// Creates a feature for the segment of source_file between start_time and end_time
+    pub fn new_segment(
+        feature_vector: Vec<f32>,
+        source_file: String,
+        start_time: f32,
+        end_time: f32,
+    ) -> Self {
+        Self {
+            feature_vector,
+            source_file,
+            index: &SEGMENT_INDEX,
+            segment: Some((start_time, end_time)),
+            id: None,
+        }
+    }
+
     pub fn feature_vector(&self) -> &[f32] {
         &self.feature_vector
     }
@@ -22,6 +72,16 @@ impl Feature {
         &self.source_file
     }
 
+    /// The index this feature's vector belongs to
+    pub fn index(&self) -> &'static VectorIndex {
+        self.index
+    }
+
+    pub fn segment(&self) -> Option<(f32, f32)> {
+        self.segment
+    }
+
+    /// The row id of the sample, or of the segment for segment features
     pub fn id(&self) -> &Option<i64> {
         &self.id
     }
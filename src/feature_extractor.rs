@@ -1,21 +1,31 @@
 use rodio::{source::Source, Decoder};
 use rubato::Resampler;
 use rubato::{SincFixedIn, SincInterpolationParameters, SincInterpolationType};
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 use std::fs::File;
 use std::io::BufReader;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::mpsc;
 use threadpool::ThreadPool;
 use walkdir::WalkDir;
 
-use crate::feature::Feature;
+use crate::descriptors;
+use crate::feature::{AnalyzedFile, Feature};
+use crate::file_utils;
+use crate::fingerprint::{self, FINGERPRINT_SAMPLE_RATE};
+use crate::preprocessing;
+use crate::riff;
+use crate::tags;
+use crate::vector_db::{VectorIndex, SEGMENT_INDEX};
 
 fn get_audio_files(root_dir: &str) -> Vec<String> {
     let path = PathBuf::from(root_dir);
 
     let supported_extensions = ["wav", "mp3"];
+    // Sorting keeps the analysis order, and therefore sample IDs, stable between runs
     WalkDir::new(path)
+        .sort_by_file_name()
         .into_iter()
         .filter_map(|d| d.ok())
         .map(|d| d.path().to_owned())
@@ -29,87 +39,591 @@ fn get_audio_files(root_dir: &str) -> Vec<String> {
         .collect()
 }
 
+#[derive(Clone, Copy, Debug)]
 pub enum RunMode {
     SingleThreaded,
-    Parallel,
+    /// Distributes analysis across a pool of `num_threads` worker threads
+    Parallel { num_threads: usize },
+}
+
+impl RunMode {
+    /// Returns the run mode for the given number of jobs. A single job runs on the calling
+    /// thread.
+    pub fn with_jobs(num_jobs: usize) -> Self {
+        if num_jobs <= 1 {
+            RunMode::SingleThreaded
+        } else {
+            RunMode::Parallel {
+                num_threads: num_jobs,
+            }
+        }
+    }
+}
+
+impl Default for RunMode {
+    fn default() -> Self {
+        RunMode::Parallel {
+            num_threads: num_cpus::get(),
+        }
+    }
+}
+
+/// Splits long files into segments that are indexed individually, so matches can be located
+/// within a recording. Lengths are in seconds.
+#[derive(Clone, Copy, Debug)]
+pub enum Segmentation {
+    /// Consecutive segments of a fixed length
+    Fixed { length: f32 },
+    /// Segments starting at detected onsets. Segments shorter than min_length are merged with
+    /// the following segment, and segments longer than max_length are split evenly.
+    Onsets { min_length: f32, max_length: f32 },
+}
+
+/// Loudness normalization applied before analysis, so that level differences between files
+/// don't dominate their MFCCs
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Normalization {
+    /// Scale to the target RMS level in dBFS
+    Rms { target_db: f32 },
+    /// Scale to the target integrated loudness in LUFS
+    Lufs { target_lufs: f32 },
+}
+
+/// Processing applied to decoded audio before features are calculated. Features are only
+/// comparable when they were calculated with the same preprocessing, so a library uses a
+/// single configuration for all of its samples.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Preprocessing {
+    /// Subtract the mean of the signal before any other processing
+    pub remove_dc_offset: bool,
+    /// Trim leading and trailing audio quieter than this level in dBFS
+    pub trim_silence_below_db: Option<f32>,
+    pub normalization: Option<Normalization>,
+    /// Only analyze this many seconds of each file, measured after trimming
+    pub max_duration: Option<f32>,
+}
+
+impl Preprocessing {
+    /// Returns a suffix identifying this configuration, which is appended to feature set
+    /// identifiers. The default configuration has an empty suffix so that libraries analyzed
+    /// without preprocessing keep their identifiers.
+    pub fn id(&self) -> String {
+        let mut id = String::new();
+        if self.remove_dc_offset {
+            id.push_str("+dc");
+        }
+        if let Some(threshold) = self.trim_silence_below_db {
+            id.push_str(&format!("+trim{threshold}"));
+        }
+        match self.normalization {
+            Some(Normalization::Rms { target_db }) => id.push_str(&format!("+rms{target_db}")),
+            Some(Normalization::Lufs { target_lufs }) => {
+                id.push_str(&format!("+lufs{target_lufs}"))
+            }
+            None => {}
+        }
+        if let Some(max_duration) = self.max_duration {
+            id.push_str(&format!("+max{max_duration}"));
+        }
+        id
+    }
+}
+
+/// Options controlling how files are analyzed
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AnalysisOptions {
+    pub run_mode: RunMode,
+    /// When set, files long enough to produce more than one segment also have each segment
+    /// indexed in the segment index
+    pub segmentation: Option<Segmentation>,
+    /// When set, replaces the library's preprocessing configuration. Otherwise the library's
+    /// current configuration is used.
+    pub preprocessing: Option<Preprocessing>,
+}
+
+/// Properties of an audio file as stored on disk, before it's decoded and resampled
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AudioProperties {
+    /// The duration in seconds
+    pub duration: f32,
+    pub sample_rate: u32,
+    pub channels: u16,
+    /// Bits per sample, for formats that store it
+    pub bit_depth: Option<u16>,
+    /// The audio encoding, e.g. `pcm`, `float` or `mp3`
+    pub codec: String,
+    /// The size of the file in bytes
+    pub file_size: u64,
 }
 
 pub const NUM_DIMENSIONS: usize = 13;
 
+/// The hop size used to detect onsets for segmentation, which is fine enough to place segment
+/// boundaries within ~25ms of an onset at 22050 Hz
+const ONSET_HOP_SIZE: usize = 512;
+
+/// The algorithm and parameters used to turn decoded audio into a feature vector
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Extractor {
+    /// MFCCs averaged across all fft_size blocks of a file. Captures timbre.
+    Mfcc {
+        fft_size: usize,
+        num_coefficients: usize,
+        num_filters: usize,
+    },
+    /// Periodicity of the onset strength envelope sampled at log-spaced tempos. Captures
+    /// rhythm independently of timbre.
+    Rhythm {
+        hop_size: usize,
+        num_tempo_bins: usize,
+    },
+    /// Spectral energy folded into the 12 pitch classes and averaged across the file.
+    /// Captures harmonic and tonal content.
+    Chroma { fft_size: usize },
+}
+
+/// Describes the extractor and parameters that produced a feature vector. Vectors are only
+/// comparable when they were produced by the same feature set, so any parameter change results
+/// in a new identifier. Bump `version` when changing the extraction algorithm itself.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FeatureSet {
+    pub extractor: Extractor,
+    pub version: u32,
+    pub sample_rate: u32,
+}
+
+impl FeatureSet {
+    /// Returns the identifier that's stored alongside vectors produced by this feature set
+    pub fn id(&self) -> String {
+        match self.extractor {
+            Extractor::Mfcc {
+                fft_size,
+                num_coefficients,
+                num_filters,
+            } => format!(
+                "mfcc-v{}-sr{}-fft{}-c{}-f{}",
+                self.version, self.sample_rate, fft_size, num_coefficients, num_filters
+            ),
+            Extractor::Rhythm {
+                hop_size,
+                num_tempo_bins,
+            } => format!(
+                "rhythm-v{}-sr{}-hop{}-b{}",
+                self.version, self.sample_rate, hop_size, num_tempo_bins
+            ),
+            Extractor::Chroma { fft_size } => {
+                format!("chroma-v{}-sr{}-fft{}", self.version, self.sample_rate, fft_size)
+            }
+        }
+    }
+
+    pub fn dimensions(&self) -> usize {
+        match self.extractor {
+            Extractor::Mfcc {
+                num_coefficients, ..
+            } => num_coefficients,
+            Extractor::Rhythm { num_tempo_bins, .. } => num_tempo_bins,
+            Extractor::Chroma { .. } => descriptors::NUM_PITCH_CLASSES,
+        }
+    }
+}
+
+/// MFCCs averaged across all blocks of a file
+pub const MFCC_FEATURE_SET: FeatureSet = FeatureSet {
+    extractor: Extractor::Mfcc {
+        fft_size: 2048,
+        num_coefficients: NUM_DIMENSIONS,
+        num_filters: 40,
+    },
+    // Version 2 analyzes buffers shorter than one FFT block as a single zero padded block,
+    // where version 1 produced NaN vectors for them
+    version: 2,
+    sample_rate: 22050,
+};
+
+pub const RHYTHM_FEATURE_SET: FeatureSet = FeatureSet {
+    extractor: Extractor::Rhythm {
+        hop_size: 512,
+        num_tempo_bins: 16,
+    },
+    version: 1,
+    sample_rate: 22050,
+};
+
+pub const CHROMA_FEATURE_SET: FeatureSet = FeatureSet {
+    extractor: Extractor::Chroma { fft_size: 4096 },
+    version: 1,
+    sample_rate: 22050,
+};
+
+/// The number of newly analyzed files accumulated before they're handed off to the batch
+/// callback
+const BATCH_SIZE: usize = 256;
+
+/// Extracts features for each of indexes from every supported audio file in asset_dir that
+/// isn't present in cached_files. Analyzed files are passed to batch_callback in batches as
+/// they become available, so callers can persist them while analysis is still in progress.
+/// Returns all of the newly analyzed files.
 pub fn extract_features(
-    run_mode: RunMode,
+    options: AnalysisOptions,
+    indexes: &[&'static VectorIndex],
     asset_dir: &str,
-    cached_features: &HashMap<String, Feature>,
+    cached_files: &HashSet<String>,
     progress_callback: impl Fn(f32),
-) -> Result<Vec<Feature>, String> {
+    batch_callback: impl FnMut(&mut [AnalyzedFile]) -> Result<(), String>,
+) -> Result<Vec<AnalyzedFile>, String> {
     let files = get_audio_files(asset_dir);
-    let num_files = files.len();
-    if num_files == 0 {
+    if files.is_empty() {
         return Err(format!("No files found in {asset_dir}"));
     }
+    let files_to_analyze: Vec<String> = files
+        .into_iter()
+        .filter(|f| !cached_files.contains(f))
+        .collect();
+    extract_features_for_files(
+        options,
+        indexes,
+        files_to_analyze,
+        progress_callback,
+        batch_callback,
+    )
+}
 
-    let mut features: Vec<Feature> = Vec::with_capacity(files.len());
+/// Extracts features for each of indexes from each of the provided files, passing them to
+/// batch_callback in batches as they become available. Each file is decoded once and yields
+/// one feature per index, plus one feature per segment when segmentation is enabled. Files
+/// that fail to decode are skipped. Returns all of the analyzed files.
+pub fn extract_features_for_files(
+    options: AnalysisOptions,
+    indexes: &[&'static VectorIndex],
+    files_to_analyze: Vec<String>,
+    progress_callback: impl Fn(f32),
+    mut batch_callback: impl FnMut(&mut [AnalyzedFile]) -> Result<(), String>,
+) -> Result<Vec<AnalyzedFile>, String> {
+    let mut files: Vec<AnalyzedFile> = Vec::with_capacity(files_to_analyze.len());
+    let mut batch_start = 0;
+    let mut progress = 0.0;
+    let progress_increment = 1.0 / files_to_analyze.len() as f32;
+
+    // Failed extractions are passed as None so progress accounts for every file
+    let mut on_extracted = |file: Option<AnalyzedFile>| -> Result<(), String> {
+        progress += progress_increment;
+        progress_callback(progress);
+
+        if let Some(file) = file {
+            files.push(file);
+        }
+        if files.len() - batch_start >= BATCH_SIZE {
+            batch_callback(&mut files[batch_start..])?;
+            batch_start = files.len();
+        }
+        Ok(())
+    };
 
-    match run_mode {
+    let segmentation = options.segmentation;
+    let preprocessing = options.preprocessing.unwrap_or_default();
+    match options.run_mode {
         RunMode::SingleThreaded => {
-            for file in files.iter() {
-                if let Ok(mfcc) = decode_and_calculate_mfcc(file, 22050) {
-                    features.push(Feature::new(mfcc, file.to_string(), None));
-                }
+            for file in files_to_analyze {
+                on_extracted(extract_file_features(
+                    file,
+                    indexes,
+                    segmentation,
+                    &preprocessing,
+                ))?;
             }
         }
-        RunMode::Parallel => {
-            let num_threads = num_cpus::get();
+        RunMode::Parallel { num_threads } => {
             println!("Running with {num_threads} threads");
             let thread_pool = ThreadPool::new(num_threads);
 
-            let (sender, receiver) = mpsc::channel::<Feature>();
+            let (sender, receiver) = mpsc::channel::<Option<AnalyzedFile>>();
 
-            for file in files.iter() {
-                let f = file.to_string();
+            for file in files_to_analyze {
                 let sender = sender.clone();
-                if cached_features.get(&f).is_some() {
-                    continue;
-                }
+                let indexes = indexes.to_vec();
                 thread_pool.execute(move || {
-                    if let Ok(mfcc) = decode_and_calculate_mfcc(&f, 22050) {
-                        sender.send(Feature::new(mfcc, f, None)).unwrap();
-                    } else {
-                        println!("Failed to extract features for {f}");
-                    }
+                    // The receiver only hangs up early if the batch callback failed
+                    let _ = sender.send(extract_file_features(
+                        file,
+                        &indexes,
+                        segmentation,
+                        &preprocessing,
+                    ));
                 });
             }
+            // Drop the original sender so the receiver stops blocking once every job has
+            // completed and dropped its clone
+            drop(sender);
 
-            let mut progress = 0.0;
-            let progress_increment = 1.0 / files.len() as f32;
-            while thread_pool.active_count() > 0 || thread_pool.queued_count() > 0 {
-                if let Ok(feature) = receiver.try_recv() {
-                    features.push(feature);
-                    progress += progress_increment;
-                    progress_callback(progress);
-                }
+            for file in receiver {
+                on_extracted(file)?;
             }
         }
     }
-    Ok(features)
+    if batch_start < files.len() {
+        batch_callback(&mut files[batch_start..])?;
+    }
+    Ok(files)
 }
 
-fn decode_and_calculate_mfcc(path: &str, output_sample_rate: u32) -> Result<Vec<f32>, String> {
-    let mut decoded = decode_and_resample_file(path, output_sample_rate).unwrap();
-    let mfcc = calculate_mfcc(&mut decoded, 22050);
-    match mfcc {
-        Ok(mfcc) => {
-            println!("MFCC: {:?}", mfcc);
-            Ok(mfcc)
+/// Calculates a feature for each of indexes from the file at path without adding it to the
+/// library, so files that haven't been analyzed can be used as search queries. preprocessing
+/// should match the library's so the features are comparable.
+pub fn extract_query_features(
+    path: &str,
+    indexes: &[&'static VectorIndex],
+    preprocessing: &Preprocessing,
+) -> Result<Vec<Feature>, String> {
+    let decoded = decode_and_calculate_features(path, indexes, None, preprocessing, false)?;
+    Ok(decoded.features)
+}
+
+/// Calculates the acoustic fingerprint of the file at path without adding it to the library,
+/// so it can be looked up. preprocessing should match the library's.
+pub fn extract_query_fingerprint(
+    path: &str,
+    preprocessing: &Preprocessing,
+) -> Result<Vec<u32>, String> {
+    let decoded = decode_and_calculate_features(path, &[], None, preprocessing, true)?;
+    decoded
+        .fingerprint
+        .ok_or_else(|| format!("Failed to fingerprint {path}"))
+}
+
+fn extract_file_features(
+    path: String,
+    indexes: &[&'static VectorIndex],
+    segmentation: Option<Segmentation>,
+    preprocessing: &Preprocessing,
+) -> Option<AnalyzedFile> {
+    match decode_and_calculate_features(&path, indexes, segmentation, preprocessing, true) {
+        Ok(DecodedFeatures {
+            properties,
+            features,
+            fingerprint,
+        }) => {
+            // Tags are informational, so a malformed tag doesn't prevent indexing the file
+            let tags = tags::read_tags(Path::new(&path)).unwrap_or_else(|e| {
+                println!("Failed to read tags for {path}: {e}");
+                Vec::new()
+            });
+            let content_hash = file_utils::content_hash(Path::new(&path))
+                .map_err(|e| println!("Failed to hash {path}: {e}"))
+                .ok();
+            Some(AnalyzedFile {
+                path,
+                properties,
+                tags,
+                content_hash,
+                fingerprint,
+                segmented: segmentation.is_some(),
+                replaced_segments: Vec::new(),
+                features,
+            })
         }
         Err(e) => {
-            println!("{}", e);
-            Err(e)
+            println!("Failed to extract features for {path}: {e}");
+            None
+        }
+    }
+}
+
+/// A file decoded and preprocessed at a particular sample rate
+struct DecodedBuffer {
+    sample_rate: u32,
+    samples: Vec<f32>,
+    /// The number of samples trimmed from the start of the file during preprocessing
+    start_offset: usize,
+    properties: AudioProperties,
+}
+
+/// The properties of a decoded file and the features calculated from it
+struct DecodedFeatures {
+    properties: AudioProperties,
+    features: Vec<Feature>,
+    /// The file's acoustic fingerprint, if it was requested
+    fingerprint: Option<Vec<u32>>,
+}
+
+/// Decodes the file at path and calculates a feature for each of indexes, followed by a
+/// feature for each segment, and its fingerprint if requested. The file is only decoded once
+/// per distinct sample rate.
+fn decode_and_calculate_features(
+    path: &str,
+    indexes: &[&'static VectorIndex],
+    segmentation: Option<Segmentation>,
+    preprocessing: &Preprocessing,
+    fingerprint: bool,
+) -> Result<DecodedFeatures, String> {
+    let mut decoded: Vec<DecodedBuffer> = Vec::new();
+    let mut features = Vec::with_capacity(indexes.len());
+    for index in indexes {
+        let feature_set = &index.feature_set;
+        let buffer = decoded_buffer(&mut decoded, path, feature_set.sample_rate, preprocessing)?;
+        let vector = calculate_features(&mut buffer.samples, feature_set)?;
+        features.push(Feature::new(vector, path.to_string(), index, None));
+    }
+
+    if let Some(segmentation) = segmentation {
+        let feature_set = &SEGMENT_INDEX.feature_set;
+        let sample_rate = feature_set.sample_rate;
+        let buffer = decoded_buffer(&mut decoded, path, sample_rate, preprocessing)?;
+        // Segment times are relative to the file rather than the trimmed buffer
+        let to_seconds =
+            |position: usize| (position + buffer.start_offset) as f32 / sample_rate as f32;
+        for (start, end) in segment_boundaries(&buffer.samples, sample_rate, segmentation) {
+            let mut segment = buffer.samples[start..end].to_vec();
+            let vector = calculate_features(&mut segment, feature_set)?;
+            features.push(Feature::new_segment(
+                vector,
+                path.to_string(),
+                to_seconds(start),
+                to_seconds(end),
+            ));
+        }
+    }
+    let fingerprint = if fingerprint {
+        let buffer = decoded_buffer(&mut decoded, path, FINGERPRINT_SAMPLE_RATE, preprocessing)?;
+        Some(fingerprint::calculate(&buffer.samples)?)
+    } else {
+        None
+    };
+    let properties = decoded
+        .into_iter()
+        .next()
+        .ok_or("No features were requested")?
+        .properties;
+    Ok(DecodedFeatures {
+        properties,
+        features,
+        fingerprint,
+    })
+}
+
+/// Returns the buffer for path decoded and preprocessed at sample_rate, decoding it if it isn't
+/// already present in decoded
+fn decoded_buffer<'a>(
+    decoded: &'a mut Vec<DecodedBuffer>,
+    path: &str,
+    sample_rate: u32,
+    preprocessing: &Preprocessing,
+) -> Result<&'a mut DecodedBuffer, String> {
+    let position = match decoded.iter().position(|b| b.sample_rate == sample_rate) {
+        Some(position) => position,
+        None => {
+            let (mut samples, properties) = decode_and_resample_file(path, sample_rate)?;
+            let start_offset = preprocessing::preprocess(&mut samples, sample_rate, preprocessing);
+            decoded.push(DecodedBuffer {
+                sample_rate,
+                samples,
+                start_offset,
+                properties,
+            });
+            decoded.len() - 1
+        }
+    };
+    Ok(&mut decoded[position])
+}
+
+/// Returns the start and end sample positions of each segment of buffer. Returns no segments
+/// if the buffer would only produce a single segment, since that's equivalent to the whole
+/// file's feature.
+fn segment_boundaries(
+    buffer: &[f32],
+    sample_rate: u32,
+    segmentation: Segmentation,
+) -> Vec<(usize, usize)> {
+    let seconds_to_samples = |seconds: f32| ((seconds * sample_rate as f32) as usize).max(1);
+    let mut segments: Vec<(usize, usize)> = Vec::new();
+    match segmentation {
+        Segmentation::Fixed { length } => {
+            let length = seconds_to_samples(length);
+            let mut start = 0;
+            while start < buffer.len() {
+                let end = (start + length).min(buffer.len());
+                match segments.last_mut() {
+                    // Fold a short trailing segment into the previous one
+                    Some(last) if end - start < length / 2 => last.1 = end,
+                    _ => segments.push((start, end)),
+                }
+                start = end;
+            }
+        }
+        Segmentation::Onsets {
+            min_length,
+            max_length,
+        } => {
+            let min_length = seconds_to_samples(min_length);
+            let max_length = seconds_to_samples(max_length);
+            let mut start = 0;
+            let onsets = descriptors::detect_onsets(buffer, ONSET_HOP_SIZE);
+            for boundary in onsets.into_iter().chain(std::iter::once(buffer.len())) {
+                if boundary >= start + min_length {
+                    segments.push((start, boundary.min(buffer.len())));
+                    start = boundary;
+                }
+            }
+            if start < buffer.len() {
+                match segments.last_mut() {
+                    Some(last) => last.1 = buffer.len(),
+                    None => segments.push((start, buffer.len())),
+                }
+            }
+
+            segments = segments
+                .into_iter()
+                .flat_map(|(start, end)| {
+                    let num_splits = (end - start).div_ceil(max_length);
+                    let split_length = (end - start).div_ceil(num_splits);
+                    (start..end)
+                        .step_by(split_length)
+                        .map(move |s| (s, (s + split_length).min(end)))
+                })
+                .collect();
         }
     }
+
+    if segments.len() < 2 {
+        return Vec::new();
+    }
+    segments
 }
 
-fn decode_and_resample_file(path: &str, output_sample_rate: u32) -> Result<Vec<f32>, String> {
+fn calculate_features(buffer: &mut Vec<f32>, feature_set: &FeatureSet) -> Result<Vec<f32>, String> {
+    match feature_set.extractor {
+        Extractor::Mfcc {
+            fft_size,
+            num_coefficients,
+            num_filters,
+        } => calculate_mfcc(
+            buffer,
+            feature_set.sample_rate,
+            fft_size,
+            num_coefficients,
+            num_filters,
+        ),
+        Extractor::Rhythm {
+            hop_size,
+            num_tempo_bins,
+        } => Ok(descriptors::calculate_rhythm(
+            buffer,
+            feature_set.sample_rate,
+            hop_size,
+            num_tempo_bins,
+        )),
+        Extractor::Chroma { fft_size } => {
+            descriptors::calculate_chroma(buffer, feature_set.sample_rate, fft_size)
+        }
+    }
+}
+
+/// Decodes the file at path, sums it to mono and resamples it to output_sample_rate. Returns
+/// the samples along with the properties of the original file.
+fn decode_and_resample_file(
+    path: &str,
+    output_sample_rate: u32,
+) -> Result<(Vec<f32>, AudioProperties), String> {
     let file = BufReader::new(File::open(path).map_err(|e| e.to_string())?);
     let decoder = Decoder::new(file).map_err(|e| e.to_string())?;
     let num_channels = decoder.channels();
@@ -128,12 +642,50 @@ fn decode_and_resample_file(path: &str, output_sample_rate: u32) -> Result<Vec<f
     } else {
         return Err("Unsupported channel count".to_string());
     }
+    let properties = read_audio_properties(
+        Path::new(path),
+        samples.len() as f32 / sample_rate as f32,
+        sample_rate,
+        num_channels,
+    )?;
 
     if sample_rate != output_sample_rate {
         samples = resample_buffer(&samples, sample_rate as f64, output_sample_rate as f64);
     }
     // TODO: write to file to verify quality
-    Ok(samples)
+    Ok((samples, properties))
+}
+
+/// Collects the properties of the file at path. The duration, sample rate and channel count
+/// come from the decoder, while the codec and bit depth are read from the file's header.
+fn read_audio_properties(
+    path: &Path,
+    duration: f32,
+    sample_rate: u32,
+    channels: u16,
+) -> Result<AudioProperties, String> {
+    let file_size = std::fs::metadata(path).map_err(|e| e.to_string())?.len();
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+    let (codec, bit_depth) = match extension.as_str() {
+        "wav" => riff::read_chunks(path)
+            .ok()
+            .and_then(|chunks| riff::wave_format(&chunks))
+            .map(|(codec, bit_depth)| (codec.to_string(), Some(bit_depth)))
+            .unwrap_or(("unknown".to_string(), None)),
+        _ => (extension, None),
+    };
+    Ok(AudioProperties {
+        duration,
+        sample_rate,
+        channels,
+        bit_depth,
+        codec,
+        file_size,
+    })
 }
 
 fn resample_buffer(buffer: &Vec<f32>, source_sr: f64, dest_sr: f64) -> Vec<f32> {
@@ -176,15 +728,18 @@ fn resample_buffer(buffer: &Vec<f32>, source_sr: f64, dest_sr: f64) -> Vec<f32>
     resampled_buffer
 }
 
-fn calculate_mfcc(buffer: &mut Vec<f32>, sample_rate: u32) -> Result<Vec<f32>, String> {
-    let fft_size = 2048;
-    let num_coefficients = NUM_DIMENSIONS;
-    let num_filters = 40;
-
+fn calculate_mfcc(
+    buffer: &mut Vec<f32>,
+    sample_rate: u32,
+    fft_size: usize,
+    num_coefficients: usize,
+    num_filters: usize,
+) -> Result<Vec<f32>, String> {
     // Pad with zeros if the buffer isn't large enough to hold a full fft block
-    let num_blocks = (buffer.len() as f32 / fft_size as f32).floor() as usize;
+    let mut num_blocks = buffer.len() / fft_size;
     if num_blocks == 0 {
         buffer.resize(fft_size, 0.0);
+        num_blocks = 1;
     }
 
     let mut fft = aubio_rs::FFT::new(fft_size).map_err(|e| e.to_string())?;
@@ -213,3 +768,67 @@ fn calculate_mfcc(buffer: &mut Vec<f32>, sample_rate: u32) -> Result<Vec<f32>, S
     }
     Ok(mean_mfcc)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vector_db::INDEXES;
+
+    #[test]
+    fn cached_files_are_skipped_in_every_run_mode() {
+        let dir = std::env::temp_dir().join(format!("extractor_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut cached_files = HashSet::new();
+        for name in ["kick.wav", "snare.mp3"] {
+            let path = dir.join(name);
+            // Not decodable, so the test fails if extraction is attempted
+            std::fs::write(&path, b"not audio").unwrap();
+            cached_files.insert(path.into_os_string().into_string().unwrap());
+        }
+        let asset_dir = dir.to_str().unwrap();
+
+        for run_mode in [RunMode::SingleThreaded, RunMode::with_jobs(2)] {
+            let options = AnalysisOptions {
+                run_mode,
+                ..Default::default()
+            };
+            let features = extract_features(
+                options,
+                &INDEXES,
+                asset_dir,
+                &cached_files,
+                |_| {},
+                |_| Err("No batches expected".to_string()),
+            )
+            .unwrap();
+            assert!(features.is_empty());
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fixed_segments_fold_a_short_remainder_into_the_last_segment() {
+        let buffer = vec![0.0; 10 * 100 + 40];
+        let segments = segment_boundaries(&buffer, 100, Segmentation::Fixed { length: 2.0 });
+        assert_eq!(
+            segments,
+            [(0, 200), (200, 400), (400, 600), (600, 800), (800, 1040)]
+        );
+    }
+
+    #[test]
+    fn fixed_segments_keep_a_long_remainder() {
+        let buffer = vec![0.0; 500];
+        let segments = segment_boundaries(&buffer, 100, Segmentation::Fixed { length: 2.0 });
+        assert_eq!(segments, [(0, 200), (200, 400), (400, 500)]);
+    }
+
+    #[test]
+    fn files_shorter_than_two_segments_are_not_segmented() {
+        for length in [0, 100, 299] {
+            let buffer = vec![0.0; length];
+            let segmentation = Segmentation::Fixed { length: 2.0 };
+            assert!(segment_boundaries(&buffer, 100, segmentation).is_empty());
+        }
+    }
+}
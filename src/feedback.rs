@@ -0,0 +1,152 @@
+use crate::vector_db::Metric;
+
+/// The Rocchio weights of the original query, the centroid of relevant results and the
+/// centroid of irrelevant results. These are the commonly used defaults, which trust positive
+/// feedback more than negative feedback.
+const QUERY_WEIGHT: f32 = 1.0;
+const RELEVANT_WEIGHT: f32 = 0.75;
+const IRRELEVANT_WEIGHT: f32 = 0.15;
+
+/// Learned weights are clamped to this range before normalization, so a dimension can't be
+/// ignored entirely or dominate the distance on the strength of a few judgements
+const MIN_WEIGHT: f32 = 0.1;
+const MAX_WEIGHT: f32 = 10.0;
+
+/// Moves query towards the centroid of relevant results and away from the centroid of
+/// irrelevant results, using the Rocchio algorithm. Either centroid may be None if there's no
+/// feedback of that kind.
+pub fn rocchio(query: &[f32], relevant: Option<&[f32]>, irrelevant: Option<&[f32]>) -> Vec<f32> {
+    let mut refined: Vec<f32> = query.iter().map(|x| x * QUERY_WEIGHT).collect();
+    if let Some(relevant) = relevant {
+        for (x, r) in refined.iter_mut().zip(relevant) {
+            *x += RELEVANT_WEIGHT * r;
+        }
+    }
+    if let Some(irrelevant) = irrelevant {
+        for (x, n) in refined.iter_mut().zip(irrelevant) {
+            *x -= IRRELEVANT_WEIGHT * n;
+        }
+    }
+    refined
+}
+
+/// Learns a weight for each dimension from (query, result, relevant) judgements. Dimensions in
+/// which irrelevant results differ from their query much more than relevant results do are
+/// good at telling them apart, and get higher weights. Weights are normalized to a mean of 1.
+/// Returns None unless there's at least one relevant and one irrelevant judgement.
+pub fn learn_weights(judgements: &[(Vec<f32>, Vec<f32>, bool)]) -> Option<Vec<f32>> {
+    let dimensions = judgements.first()?.0.len();
+    let mut relevant_spread = vec![0.0; dimensions];
+    let mut irrelevant_spread = vec![0.0; dimensions];
+    let (mut num_relevant, mut num_irrelevant) = (0, 0);
+    for (query, result, relevant) in judgements {
+        let (spread, count) = if *relevant {
+            (&mut relevant_spread, &mut num_relevant)
+        } else {
+            (&mut irrelevant_spread, &mut num_irrelevant)
+        };
+        for ((total, q), r) in spread.iter_mut().zip(query).zip(result) {
+            *total += (q - r) * (q - r);
+        }
+        *count += 1;
+    }
+    if num_relevant == 0 || num_irrelevant == 0 {
+        return None;
+    }
+
+    // Avoids dividing by zero for dimensions that are identical across judgements
+    let epsilon = 1e-6;
+    let weights: Vec<f32> = relevant_spread
+        .iter()
+        .zip(irrelevant_spread.iter())
+        .map(|(relevant, irrelevant)| {
+            let relevant = relevant / num_relevant as f32;
+            let irrelevant = irrelevant / num_irrelevant as f32;
+            ((irrelevant + epsilon) / (relevant + epsilon)).clamp(MIN_WEIGHT, MAX_WEIGHT)
+        })
+        .collect();
+    let mean = weights.iter().sum::<f32>() / dimensions as f32;
+    Some(weights.iter().map(|w| w / mean).collect())
+}
+
+/// Returns the distance between a and b under metric, with each dimension scaled by its
+/// weight
+pub fn weighted_distance(metric: Metric, weights: &[f32], a: &[f32], b: &[f32]) -> f32 {
+    // Scaling both vectors by the square root of the weights scales each dimension's squared
+    // difference, and its contribution to the dot product, by its weight. Manhattan distances
+    // sum absolute differences, which scale linearly.
+    let scale = |vector: &[f32]| -> Vec<f32> {
+        vector
+            .iter()
+            .zip(weights)
+            .map(|(x, w)| match metric {
+                Metric::Angular | Metric::Euclidean => x * w.sqrt(),
+                Metric::Manhattan => x * w,
+            })
+            .collect()
+    };
+    metric.distance(&scale(a), &scale(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rocchio_moves_towards_relevant_and_away_from_irrelevant_results() {
+        let query = [1.0, 0.0];
+        assert_eq!(rocchio(&query, None, None), [1.0, 0.0]);
+        assert_eq!(rocchio(&query, Some(&[0.0, 1.0]), None), [1.0, 0.75]);
+        assert_eq!(rocchio(&query, None, Some(&[0.0, 1.0])), [1.0, -0.15]);
+        assert_eq!(
+            rocchio(&query, Some(&[0.0, 1.0]), Some(&[2.0, 0.0])),
+            [0.7, 0.75]
+        );
+    }
+
+    #[test]
+    fn weights_need_relevant_and_irrelevant_judgements() {
+        assert_eq!(learn_weights(&[]), None);
+        let relevant = (vec![0.0, 0.0], vec![1.0, 0.0], true);
+        let irrelevant = (vec![0.0, 0.0], vec![0.0, 1.0], false);
+        assert_eq!(learn_weights(&[relevant.clone(), relevant]), None);
+        assert_eq!(learn_weights(&[irrelevant.clone(), irrelevant]), None);
+    }
+
+    #[test]
+    fn weights_favor_dimensions_that_separate_irrelevant_results() {
+        // Relevant results differ from the query in the first dimension, irrelevant ones in
+        // the second, and every result differs equally in the third
+        let judgements = [
+            (vec![0.0, 0.0, 0.0], vec![0.5, 0.0, 1.0], true),
+            (vec![0.0, 0.0, 0.0], vec![0.4, 0.1, 1.0], true),
+            (vec![0.0, 0.0, 0.0], vec![0.0, 2.0, 1.0], false),
+        ];
+        let weights = learn_weights(&judgements).unwrap();
+        let mean = weights.iter().sum::<f32>() / weights.len() as f32;
+        assert!((mean - 1.0).abs() < 1e-5);
+        assert!(weights[1] > weights[2]);
+        assert!(weights[2] > weights[0]);
+    }
+
+    #[test]
+    fn unit_weights_leave_distances_unchanged() {
+        let (a, b) = ([1.0, 2.0, -1.0], [0.5, -1.0, 3.0]);
+        for metric in [Metric::Angular, Metric::Euclidean, Metric::Manhattan] {
+            let weighted = weighted_distance(metric, &[1.0; 3], &a, &b);
+            assert!(
+                (weighted - metric.distance(&a, &b)).abs() < 1e-6,
+                "{metric:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn weighted_dimensions_count_more() {
+        let (a, b) = ([0.0, 0.0], [1.0, 1.0]);
+        let distance = weighted_distance(Metric::Euclidean, &[4.0, 0.0], &a, &b);
+        assert!((distance - 2.0).abs() < 1e-6);
+        let distance = weighted_distance(Metric::Manhattan, &[3.0, 0.5], &a, &b);
+        assert!((distance - 3.5).abs() < 1e-6);
+    }
+}
@@ -1,4 +1,8 @@
-use std::{fs, path::PathBuf};
+use std::{
+    fs::{self, File},
+    io::{BufReader, Read},
+    path::{Path, PathBuf},
+};
 
 use directories::ProjectDirs;
 
@@ -20,3 +24,90 @@ pub fn data_directory() -> Result<PathBuf, String> {
 pub fn metadata_db_path() -> Result<PathBuf, String> {
     Ok(data_directory()?.join("md.db"))
 }
+
+/// Hashes the contents of the file at path with 64-bit FNV-1a. This is fast and good enough to
+/// group candidate duplicates, but isn't collision resistant, so matches should be confirmed with
+/// files_are_identical before acting on them.
+pub fn content_hash(path: &Path) -> Result<u64, String> {
+    const OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const PRIME: u64 = 0x100000001b3;
+
+    let mut reader = BufReader::new(File::open(path).map_err(|e| e.to_string())?);
+    let mut buffer = [0u8; 64 * 1024];
+    let mut hash = OFFSET_BASIS;
+    loop {
+        let read = reader.read(&mut buffer).map_err(|e| e.to_string())?;
+        if read == 0 {
+            return Ok(hash);
+        }
+        for byte in &buffer[..read] {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(PRIME);
+        }
+    }
+}
+
+/// Returns true if the files at a and b have the same contents
+pub fn files_are_identical(a: &Path, b: &Path) -> Result<bool, String> {
+    let size = |path: &Path| {
+        fs::metadata(path)
+            .map(|m| m.len())
+            .map_err(|e| e.to_string())
+    };
+    if size(a)? != size(b)? {
+        return Ok(false);
+    }
+    let mut a = File::open(a).map_err(|e| e.to_string())?;
+    let mut b = File::open(b).map_err(|e| e.to_string())?;
+    let mut a_buffer = vec![0u8; 64 * 1024];
+    let mut b_buffer = vec![0u8; 64 * 1024];
+    loop {
+        let read = fill(&mut a, &mut a_buffer)?;
+        if fill(&mut b, &mut b_buffer)? != read || a_buffer[..read] != b_buffer[..read] {
+            return Ok(false);
+        }
+        if read == 0 {
+            return Ok(true);
+        }
+    }
+}
+
+/// Reads from reader until buffer is full or the end of the file, returning how many bytes
+/// were read
+fn fill(reader: &mut impl Read, buffer: &mut [u8]) -> Result<usize, String> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn files_are_compared_by_contents() {
+        let dir = std::env::temp_dir().join(format!("file_utils_test_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        // Larger than one read buffer, so the comparison spans several chunks
+        let contents: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let mut changed = contents.clone();
+        changed[150_000] ^= 1;
+        let (a, b, c) = (dir.join("a"), dir.join("b"), dir.join("c"));
+        fs::write(&a, &contents).unwrap();
+        fs::write(&b, &contents).unwrap();
+        fs::write(&c, &changed).unwrap();
+
+        assert!(files_are_identical(&a, &b).unwrap());
+        assert!(!files_are_identical(&a, &c).unwrap());
+        assert_eq!(content_hash(&a).unwrap(), content_hash(&b).unwrap());
+        assert_ne!(content_hash(&a).unwrap(), content_hash(&c).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,257 @@
+use std::collections::HashMap;
+
+use realfft::RealFftPlanner;
+
+use crate::{descriptors::NUM_PITCH_CLASSES, metadata_db::AudioFile};
+
+/// The version of the fingerprint algorithm. Fingerprints calculated by other versions aren't
+/// comparable, and are recalculated by reanalyze.
+pub const FINGERPRINT_VERSION: u32 = 1;
+
+/// The sample rate fingerprints are calculated at, which matches the feature sets so files
+/// don't need to be decoded twice
+pub const FINGERPRINT_SAMPLE_RATE: u32 = 22050;
+
+/// The frame and hop sizes of the spectrogram fingerprints are calculated from. Short hops
+/// keep one-shots of a few hundred milliseconds long enough to match.
+const FRAME_SIZE: usize = 2048;
+const HOP_SIZE: usize = 512;
+
+/// Each 32 bit subfingerprint holds one bit per pair of adjacent bands, plus one bit per
+/// pitch class
+const NUM_BANDS: usize = 21;
+const MIN_BAND_FREQ: f32 = 55.0;
+const MAX_BAND_FREQ: f32 = 5500.0;
+
+/// The maximum fraction of differing bits between two aligned fingerprints for them to come
+/// from the same recording. Unrelated audio differs in about half of its bits.
+const MAX_BIT_ERROR_RATE: f32 = 0.25;
+
+/// The minimum number of subfingerprints two fingerprints must overlap by to be compared
+const MIN_OVERLAP: usize = 4;
+
+/// Fingerprints whose lengths multiply to at most this are compared at every offset. Longer
+/// ones are only compared at the offsets where most of their subfingerprints match exactly.
+const FULL_SEARCH_LIMIT: usize = 250_000;
+const NUM_CANDIDATE_OFFSETS: usize = 8;
+
+/// A sample that's the same recording as a query
+pub struct FingerprintMatch {
+    pub file: AudioFile,
+    /// The fraction of fingerprint bits that agree where the sample and query overlap
+    pub similarity: f32,
+    /// The time in seconds at which the sample's audio starts in the query. Negative when the
+    /// sample has audio before the start of the query.
+    pub offset: f32,
+}
+
+/// Calculates the acoustic fingerprint of a mono buffer at FINGERPRINT_SAMPLE_RATE, with one
+/// 32 bit subfingerprint per hop. Like Chromaprint and Haitsma-Kalker fingerprints, each bit
+/// records the sign of an energy difference rather than an energy, so fingerprints are
+/// unaffected by gain changes and survive lossy encoding.
+pub fn calculate(buffer: &[f32]) -> Result<Vec<u32>, String> {
+    let mut planner = RealFftPlanner::<f32>::new();
+    let fft = planner.plan_fft_forward(FRAME_SIZE);
+    let mut frame = fft.make_input_vec();
+    let mut spectrum = fft.make_output_vec();
+
+    let window: Vec<f32> = (0..FRAME_SIZE)
+        .map(|i| 0.5 - 0.5 * (2.0 * std::f32::consts::PI * i as f32 / FRAME_SIZE as f32).cos())
+        .collect();
+
+    // Precompute the log-spaced band and pitch class of each bin in the analyzed range
+    let bin_freq = |bin: usize| bin as f32 * FINGERPRINT_SAMPLE_RATE as f32 / FRAME_SIZE as f32;
+    let band_ratio = (MAX_BAND_FREQ / MIN_BAND_FREQ).ln();
+    let bin_bands: Vec<Option<(usize, usize)>> = (0..spectrum.len())
+        .map(|bin| {
+            let freq = bin_freq(bin);
+            if !(MIN_BAND_FREQ..MAX_BAND_FREQ).contains(&freq) {
+                return None;
+            }
+            let band = ((freq / MIN_BAND_FREQ).ln() / band_ratio * NUM_BANDS as f32) as usize;
+            let midi_note = 69.0 + 12.0 * (freq / 440.0).log2();
+            let pitch_class = midi_note.round() as usize % NUM_PITCH_CLASSES;
+            Some((band.min(NUM_BANDS - 1), pitch_class))
+        })
+        .collect();
+
+    let mut fingerprint = Vec::with_capacity(buffer.len() / HOP_SIZE);
+    let mut previous_bands: Option<[f32; NUM_BANDS]> = None;
+    let mut start = 0;
+    while start < buffer.len() {
+        let end = (start + FRAME_SIZE).min(buffer.len());
+        frame.fill(0.0);
+        for ((out, sample), w) in frame.iter_mut().zip(&buffer[start..end]).zip(&window) {
+            *out = sample * w;
+        }
+        fft.process(&mut frame, &mut spectrum)
+            .map_err(|e| e.to_string())?;
+
+        let mut bands = [0.0; NUM_BANDS];
+        let mut chroma = [0.0; NUM_PITCH_CLASSES];
+        for (bin, band) in spectrum.iter().zip(&bin_bands) {
+            if let Some((band, pitch_class)) = band {
+                bands[*band] += bin.norm_sqr();
+                chroma[*pitch_class] += bin.norm_sqr();
+            }
+        }
+        // Band bits compare the change in energy between adjacent bands over time, so they
+        // need the previous frame
+        if let Some(previous) = previous_bands {
+            let mut subfingerprint = 0u32;
+            for band in 0..NUM_BANDS - 1 {
+                let difference =
+                    (bands[band] - bands[band + 1]) - (previous[band] - previous[band + 1]);
+                subfingerprint = (subfingerprint << 1) | (difference > 0.0) as u32;
+            }
+            for pitch_class in 0..NUM_PITCH_CLASSES {
+                let next = chroma[(pitch_class + 1) % NUM_PITCH_CLASSES];
+                subfingerprint = (subfingerprint << 1) | (chroma[pitch_class] > next) as u32;
+            }
+            fingerprint.push(subfingerprint);
+        }
+        previous_bands = Some(bands);
+        start += HOP_SIZE;
+    }
+    Ok(fingerprint)
+}
+
+/// Aligns fingerprints a and b and returns the fraction of their bits that agree where they
+/// overlap, along with the offset of b in a in subfingerprints. Returns None if they're too
+/// short to overlap by at least MIN_OVERLAP subfingerprints, or by half of the shorter one.
+pub fn compare(a: &[u32], b: &[u32]) -> Option<(f32, isize)> {
+    let min_overlap = MIN_OVERLAP.max(a.len().min(b.len()) / 2);
+    if a.len() < min_overlap || b.len() < min_overlap {
+        return None;
+    }
+    // b[i] is aligned with a[i + offset]
+    let min_offset = min_overlap as isize - b.len() as isize;
+    let max_offset = (a.len() - min_overlap) as isize;
+    let offsets: Vec<isize> = if a.len() * b.len() <= FULL_SEARCH_LIMIT {
+        (min_offset..=max_offset).collect()
+    } else {
+        candidate_offsets(a, b)
+    };
+
+    let mut best: Option<(f32, isize)> = None;
+    for offset in offsets {
+        let a_start = offset.max(0) as usize;
+        let b_start = (-offset).max(0) as usize;
+        let overlap = (a.len() - a_start).min(b.len() - b_start);
+        if overlap < min_overlap {
+            continue;
+        }
+        let differing_bits: u32 = a[a_start..a_start + overlap]
+            .iter()
+            .zip(&b[b_start..b_start + overlap])
+            .map(|(x, y)| (x ^ y).count_ones())
+            .sum();
+        let similarity = 1.0 - differing_bits as f32 / (overlap * 32) as f32;
+        match best {
+            Some((best_similarity, _)) if best_similarity >= similarity => {}
+            _ => best = Some((similarity, offset)),
+        }
+    }
+    best
+}
+
+/// Returns the offsets of b in a at which the most subfingerprints match exactly. Enough
+/// subfingerprints survive re-encoding intact for the true alignment to collect the most
+/// votes.
+fn candidate_offsets(a: &[u32], b: &[u32]) -> Vec<isize> {
+    let mut positions: HashMap<u32, Vec<usize>> = HashMap::new();
+    // Silence fingerprints as all zeros, and would align any two files' silent passages
+    for (i, subfingerprint) in a.iter().enumerate().filter(|(_, s)| **s != 0) {
+        positions.entry(*subfingerprint).or_default().push(i);
+    }
+    let mut votes: HashMap<isize, usize> = HashMap::new();
+    for (j, subfingerprint) in b.iter().enumerate() {
+        for i in positions.get(subfingerprint).into_iter().flatten() {
+            *votes.entry(*i as isize - j as isize).or_default() += 1;
+        }
+    }
+    let mut votes: Vec<(isize, usize)> = votes.into_iter().collect();
+    votes.sort_by(|(a_offset, a_votes), (b_offset, b_votes)| {
+        b_votes.cmp(a_votes).then(a_offset.cmp(b_offset))
+    });
+    votes
+        .into_iter()
+        .take(NUM_CANDIDATE_OFFSETS)
+        .map(|(offset, _)| offset)
+        .collect()
+}
+
+/// Returns true if similarity, as returned by compare, means both fingerprints come from the
+/// same recording
+pub fn is_match(similarity: f32) -> bool {
+    similarity >= 1.0 - MAX_BIT_ERROR_RATE
+}
+
+/// Converts an offset between fingerprints to seconds
+pub fn offset_seconds(offset: isize) -> f32 {
+    (offset * HOP_SIZE as isize) as f32 / FINGERPRINT_SAMPLE_RATE as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns len pseudorandom subfingerprints
+    fn random_fingerprint(len: usize, seed: u32) -> Vec<u32> {
+        let mut state = seed.max(1);
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state
+            })
+            .collect()
+    }
+
+    #[test]
+    fn excerpts_align_at_their_offset() {
+        let a = random_fingerprint(100, 1);
+        assert_eq!(compare(&a, &a[10..60]), Some((1.0, 10)));
+        assert_eq!(compare(&a[10..60], &a), Some((1.0, -10)));
+    }
+
+    #[test]
+    fn long_excerpts_align_at_their_offset() {
+        // Too long to compare at every offset
+        let a = random_fingerprint(1000, 2);
+        assert!(a.len() * 300 > FULL_SEARCH_LIMIT);
+        assert_eq!(compare(&a, &a[200..500]), Some((1.0, 200)));
+    }
+
+    #[test]
+    fn unrelated_fingerprints_dont_match() {
+        let a = random_fingerprint(100, 3);
+        let b = random_fingerprint(100, 4);
+        let (similarity, _) = compare(&a, &b).unwrap();
+        assert!(!is_match(similarity));
+    }
+
+    #[test]
+    fn fingerprints_too_short_to_overlap_arent_compared() {
+        let a = random_fingerprint(100, 5);
+        assert_eq!(compare(&a, &a[..MIN_OVERLAP - 1]), None);
+    }
+
+    #[test]
+    fn fingerprints_are_unaffected_by_gain() {
+        let buffer: Vec<f32> = (0..FINGERPRINT_SAMPLE_RATE as usize)
+            .map(|i| {
+                let t = i as f32 / FINGERPRINT_SAMPLE_RATE as f32;
+                let envelope = (-3.0 * t).exp();
+                envelope * (2.0 * std::f32::consts::PI * 220.0 * t * (1.0 + t)).sin()
+                    + 0.3 * (2.0 * std::f32::consts::PI * 1337.0 * t).sin()
+            })
+            .collect();
+        let quieter: Vec<f32> = buffer.iter().map(|sample| sample * 0.25).collect();
+        let (similarity, offset) =
+            compare(&calculate(&buffer).unwrap(), &calculate(&quieter).unwrap()).unwrap();
+        assert!(is_match(similarity));
+        assert_eq!(offset, 0);
+    }
+}
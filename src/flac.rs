@@ -0,0 +1,91 @@
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+pub const VORBIS_COMMENT: u8 = 4;
+
+/// A metadata block from the header of a FLAC file
+pub struct MetadataBlock {
+    pub block_type: u8,
+    pub data: Vec<u8>,
+}
+
+/// Reads the metadata blocks that precede the audio frames of the FLAC file at path
+pub fn read_metadata_blocks(path: &Path) -> Result<Vec<MetadataBlock>, String> {
+    let mut reader = BufReader::new(File::open(path).map_err(|e| e.to_string())?);
+    let mut marker = [0u8; 4];
+    reader
+        .read_exact(&mut marker)
+        .map_err(|e| format!("Failed to read FLAC header: {}", e))?;
+    if &marker != b"fLaC" {
+        return Err("Not a FLAC file".to_string());
+    }
+
+    let mut blocks = Vec::new();
+    loop {
+        let mut header = [0u8; 4];
+        reader
+            .read_exact(&mut header)
+            .map_err(|e| format!("Failed to read FLAC metadata: {}", e))?;
+        let is_last = header[0] & 0x80 != 0;
+        let block_type = header[0] & 0x7F;
+        let size = u32::from_be_bytes([0, header[1], header[2], header[3]]) as u64;
+        let mut data = Vec::new();
+        let read = (&mut reader)
+            .take(size)
+            .read_to_end(&mut data)
+            .map_err(|e| format!("Failed to read FLAC metadata: {}", e))?;
+        if read as u64 != size {
+            return Err("Failed to read FLAC metadata: file is truncated".to_string());
+        }
+        blocks.push(MetadataBlock { block_type, data });
+        if is_last {
+            break;
+        }
+    }
+    Ok(blocks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_flac(name: &str, blocks: &[(u8, bool, u32, &[u8])]) -> std::path::PathBuf {
+        let mut contents = b"fLaC".to_vec();
+        for (block_type, is_last, size, data) in blocks {
+            let size = size.to_be_bytes();
+            contents.push(block_type | if *is_last { 0x80 } else { 0 });
+            contents.extend(&size[1..]);
+            contents.extend(*data);
+        }
+        let path = std::env::temp_dir().join(format!("flac_test_{}_{name}", std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn blocks_are_read_until_the_last_one() {
+        let path = write_flac(
+            "blocks.flac",
+            &[(0, false, 3, b"abc"), (VORBIS_COMMENT, true, 2, b"de")],
+        );
+        let blocks = read_metadata_blocks(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[1].block_type, VORBIS_COMMENT);
+        assert_eq!(blocks[1].data, b"de");
+    }
+
+    #[test]
+    fn blocks_longer_than_the_file_are_rejected() {
+        let path = write_flac(
+            "truncated.flac",
+            &[(VORBIS_COMMENT, true, 0xFF_FFFF, b"abc")],
+        );
+        let result = read_metadata_blocks(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,278 @@
+use std::{
+    cmp::Reverse,
+    collections::{hash_map::Entry, BinaryHeap, HashMap},
+};
+
+use serde::Serialize;
+
+/// The default number of nearest neighbors each sample is linked to
+pub const DEFAULT_NUM_NEIGHBORS: usize = 10;
+
+/// Neighbors are queried for this many samples at a time, which bounds how long the index's
+/// read transaction stays open and lets progress be reported
+pub const BATCH_SIZE: usize = 1000;
+
+pub struct GraphOptions {
+    /// The name of the index whose nearest neighbors are linked
+    pub index: String,
+    pub num_neighbors: usize,
+    /// Neighbors farther than this aren't linked
+    pub max_distance: Option<f32>,
+}
+
+impl Default for GraphOptions {
+    fn default() -> Self {
+        GraphOptions {
+            index: "timbre".to_string(),
+            num_neighbors: DEFAULT_NUM_NEIGHBORS,
+            max_distance: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct GraphNode {
+    pub id: i64,
+    pub path: String,
+    /// The cluster the sample was assigned to by the most recent clustering
+    pub cluster: Option<i64>,
+}
+
+/// A directed edge from a sample to one of its nearest neighbors
+#[derive(Clone, Debug, Serialize)]
+pub struct GraphEdge {
+    pub source: u32,
+    pub target: u32,
+    pub distance: f32,
+    /// 1 / (1 + distance), for tools that treat larger weights as stronger links
+    pub weight: f32,
+}
+
+impl GraphEdge {
+    pub fn new(source: u32, target: u32, distance: f32) -> GraphEdge {
+        GraphEdge {
+            source,
+            target,
+            distance,
+            weight: 1.0 / (1.0 + distance),
+        }
+    }
+}
+
+/// The k-nearest neighbor graph of the library, with an edge from each sample to each of its
+/// nearest neighbors
+pub struct KnnGraph {
+    pub nodes: Vec<GraphNode>,
+    pub edges: Vec<GraphEdge>,
+}
+
+impl KnnGraph {
+    /// Returns the (neighbor id, distance) links of each sample, following every edge in both
+    /// directions. A sample is as close to the samples listing it as a neighbor as to the ones
+    /// it lists, even though it may not be among their nearest neighbors in return.
+    pub fn neighbor_lists(&self) -> HashMap<u32, Vec<(u32, f32)>> {
+        let mut neighbors: HashMap<u32, Vec<(u32, f32)>> = HashMap::new();
+        for edge in self.edges.iter() {
+            for (a, b) in [(edge.source, edge.target), (edge.target, edge.source)] {
+                let links = neighbors.entry(a).or_default();
+                if !links.iter().any(|(neighbor, _)| *neighbor == b) {
+                    links.push((b, edge.distance));
+                }
+            }
+        }
+        neighbors
+    }
+}
+
+/// Returns the ids on the shortest path from start to end through the nearest neighbor graph,
+/// or None if end can't be reached. neighbors returns the (neighbor id, distance) links of an
+/// id. The search spreads outward from both ends at once, so only the samples nearer to one
+/// of them than the middle of the path are looked up. The start's side follows links from a
+/// sample to its neighbors, and the end's side follows them backwards.
+pub fn shortest_path(
+    start: u32,
+    end: u32,
+    mut neighbors: impl FnMut(u32) -> Result<Vec<(u32, f32)>, String>,
+) -> Result<Option<Vec<u32>>, String> {
+    if start == end {
+        return Ok(Some(vec![start]));
+    }
+    // Bidirectional Dijkstra's algorithm, with the start's side first and the end's second.
+    // Distances are non-negative, so their bits order the same way as their values.
+    let mut distances = [
+        HashMap::from([(start, 0.0f32)]),
+        HashMap::from([(end, 0.0f32)]),
+    ];
+    let mut previous: [HashMap<u32, u32>; 2] = [HashMap::new(), HashMap::new()];
+    let mut queues = [BinaryHeap::new(), BinaryHeap::new()];
+    queues[0].push(Reverse((0.0f32.to_bits(), start)));
+    queues[1].push(Reverse((0.0f32.to_bits(), end)));
+    let mut looked_up: HashMap<u32, Vec<(u32, f32)>> = HashMap::new();
+    let mut shortest = f32::INFINITY;
+    let mut meeting = None;
+    loop {
+        let nearest = |side: usize| {
+            queues[side]
+                .peek()
+                .map(|Reverse((distance, _))| f32::from_bits(*distance))
+        };
+        let side = match (nearest(0), nearest(1)) {
+            (None, None) => break,
+            // No path through an unexpanded sample can be shorter than the nearest sample on
+            // each side
+            (a, b) if a.unwrap_or(0.0) + b.unwrap_or(0.0) >= shortest => break,
+            (Some(a), Some(b)) if b < a => 1,
+            (Some(_), _) => 0,
+            (None, Some(_)) => 1,
+        };
+        let Some(Reverse((distance, id))) = queues[side].pop() else {
+            break;
+        };
+        let distance = f32::from_bits(distance);
+        if distance > distances[side][&id] {
+            continue;
+        }
+        if let Entry::Vacant(entry) = looked_up.entry(id) {
+            entry.insert(neighbors(id)?);
+        }
+        for (neighbor, edge_distance) in looked_up[&id].iter() {
+            if *neighbor == id {
+                continue;
+            }
+            let next_distance = distance + edge_distance.max(0.0);
+            if next_distance < *distances[side].get(neighbor).unwrap_or(&f32::INFINITY) {
+                distances[side].insert(*neighbor, next_distance);
+                previous[side].insert(*neighbor, id);
+                queues[side].push(Reverse((next_distance.to_bits(), *neighbor)));
+            }
+            if let Some(other_distance) = distances[1 - side].get(neighbor) {
+                let total = distances[side][neighbor] + other_distance;
+                if total < shortest {
+                    shortest = total;
+                    meeting = Some(*neighbor);
+                }
+            }
+        }
+    }
+    let Some(meeting) = meeting else {
+        return Ok(None);
+    };
+
+    let mut path = vec![meeting];
+    while let Some(id) = previous[0].get(&path[path.len() - 1]) {
+        path.push(*id);
+    }
+    path.reverse();
+    while let Some(id) = previous[1].get(&path[path.len() - 1]) {
+        path.push(*id);
+    }
+    Ok(Some(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Looks up the links of each id in (id, neighbor, distance) links
+    fn graph(links: &[(u32, u32, f32)]) -> impl Fn(u32) -> Result<Vec<(u32, f32)>, String> + '_ {
+        move |id| {
+            Ok(links
+                .iter()
+                .filter(|(a, _, _)| *a == id)
+                .map(|(_, b, distance)| (*b, *distance))
+                .collect())
+        }
+    }
+
+    #[test]
+    fn shortest_path_takes_the_cheapest_route() {
+        let links = [
+            (0, 1, 1.0),
+            (1, 3, 1.0),
+            (0, 2, 0.5),
+            (2, 3, 2.0),
+            (3, 4, 1.0),
+        ];
+        assert_eq!(
+            shortest_path(0, 4, graph(&links)).unwrap(),
+            Some(vec![0, 1, 3, 4])
+        );
+    }
+
+    #[test]
+    fn shortest_path_follows_the_ends_links_backwards() {
+        // Nothing links to 3, but it links to 1
+        let links = [(0, 1, 1.0), (3, 1, 1.0), (1, 2, 0.1)];
+        assert_eq!(
+            shortest_path(0, 3, graph(&links)).unwrap(),
+            Some(vec![0, 1, 3])
+        );
+    }
+
+    #[test]
+    fn shortest_path_is_none_between_unconnected_samples() {
+        let links = [(0, 1, 1.0), (2, 3, 1.0)];
+        assert_eq!(shortest_path(0, 3, graph(&links)).unwrap(), None);
+    }
+
+    #[test]
+    fn shortest_path_only_looks_up_samples_near_the_ends() {
+        // A long chain, with a far off branch hanging from its start
+        let mut links: Vec<(u32, u32, f32)> = (0..10).map(|i| (i, i + 1, 1.0)).collect();
+        links.push((0, 100, 50.0));
+        links.push((100, 101, 1.0));
+        let looked_up = std::cell::RefCell::new(Vec::new());
+        let lookup = graph(&links);
+        let path = shortest_path(0, 10, |id| {
+            looked_up.borrow_mut().push(id);
+            lookup(id)
+        })
+        .unwrap();
+        assert_eq!(path, Some((0..=10).collect()));
+        assert!(!looked_up.borrow().contains(&100));
+    }
+
+    fn knn_graph(links: &[(u32, u32, f32)]) -> KnnGraph {
+        KnnGraph {
+            nodes: Vec::new(),
+            edges: links
+                .iter()
+                .map(|(source, target, distance)| GraphEdge::new(*source, *target, *distance))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn a_sample_is_its_own_path() {
+        let links = [(0, 1, 1.0)];
+        assert_eq!(shortest_path(0, 0, graph(&links)).unwrap(), Some(vec![0]));
+        assert_eq!(shortest_path(5, 5, graph(&links)).unwrap(), Some(vec![5]));
+    }
+
+    #[test]
+    fn neighbor_lists_follow_edges_both_ways() {
+        // 1 and 2 list each other, while only 0 lists 1
+        let graph = knn_graph(&[(0, 1, 1.0), (1, 2, 0.5), (2, 1, 0.5)]);
+        let mut neighbors: Vec<(u32, Vec<(u32, f32)>)> =
+            graph.neighbor_lists().into_iter().collect();
+        neighbors.sort_by_key(|(id, _)| *id);
+        assert_eq!(
+            neighbors,
+            [
+                (0, vec![(1, 1.0)]),
+                (1, vec![(0, 1.0), (2, 0.5)]),
+                (2, vec![(1, 0.5)]),
+            ]
+        );
+    }
+
+    #[test]
+    fn paths_through_a_graph_take_edges_either_way() {
+        // Reaching 3 from 0 means following 2's edge to 1 backwards from 1
+        let graph = knn_graph(&[(0, 1, 1.0), (2, 1, 1.0), (2, 3, 1.0), (4, 5, 1.0)]);
+        let neighbors = graph.neighbor_lists();
+        let lookup = |id: u32| Ok(neighbors.get(&id).cloned().unwrap_or_default());
+        assert_eq!(shortest_path(0, 3, lookup).unwrap(), Some(vec![0, 1, 2, 3]));
+        assert_eq!(shortest_path(0, 5, lookup).unwrap(), None);
+    }
+}
@@ -1,67 +1,1672 @@
 #![feature(iter_array_chunks)]
 #![feature(fs_try_exists)]
 
-use std::time::Instant;
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    time::Instant,
+};
 
+use classification::{ClassifyOptions, Prediction};
+use clustering::{ClusterMethod, ClusterOptions};
+use dedupe::{DedupeOptions, DedupeReport, DuplicateAction, DuplicateCluster, FileOperation};
+use diversity::Diversity;
 use feature::Feature;
-use metadata_db::{AudioFile, MetadataDatabase};
-use vector_db::VectorDatabase;
+use feature_extractor::AnalysisOptions;
+use fingerprint::FingerprintMatch;
+use graph::{GraphEdge, GraphNode, GraphOptions, KnnGraph};
+use metadata_db::{
+    AudioFile, Cluster, ListOptions, MapPoint, MetadataDatabase, Page, SampleFilter, Segment,
+};
+use outliers::{Grouping, IsolatedSample, MisplacedSample, OutlierOptions, OutlierReport};
+use projection::{ProjectionMethod, ProjectionOptions};
+use roaring::RoaringBitmap;
+use sequence::{SequenceMethod, SequenceOptions, SequenceStep};
+use vector_db::{Metric, VectorDatabase, VectorIndex, INDEXES, SEGMENT_INDEX, TIMBRE_INDEX};
 
+pub mod classification;
+pub mod clustering;
+pub mod dedupe;
+mod descriptors;
+pub mod diversity;
+pub mod export;
 mod feature;
 pub mod feature_extractor;
+mod feedback;
 mod file_utils;
+pub mod fingerprint;
+mod flac;
+pub mod graph;
 pub mod metadata_db;
+mod migrations;
+pub mod outliers;
+mod preprocessing;
+pub mod projection;
+mod riff;
+pub mod sequence;
+mod tags;
 pub mod vector_db;
 
 pub fn analyze_and_build_db(
     asset_dir: &str,
+    options: AnalysisOptions,
     progress_callback: impl Fn(f32),
 ) -> Result<VectorDatabase, String> {
     let start_time = Instant::now();
 
-    let metadata_db = MetadataDatabase::load_from_disk()?;
-    // We cache feature vectors in the SQLite db to avoid re-analyzing samples
-    let cached_features = metadata_db.get_all_features()?;
-    let mut features: Vec<Feature> = feature_extractor::extract_features(
-        feature_extractor::RunMode::Parallel,
+    let mut metadata_db = MetadataDatabase::load_from_disk()?;
+    if let Some(preprocessing) = options.preprocessing {
+        if preprocessing != metadata_db.preprocessing() {
+            if metadata_db.count_samples()? > 0 {
+                return Err("The library was analyzed with different preprocessing options. Run reanalyze with the new options to update it.".to_string());
+            }
+            metadata_db.set_preprocessing(preprocessing)?;
+        }
+    }
+    let options = AnalysisOptions {
+        preprocessing: Some(metadata_db.preprocessing()),
+        ..options
+    };
+    // Vectors from different feature sets aren't comparable, so refuse to add new vectors
+    // to an index containing outdated ones
+    ensure_indexes_are_current(&metadata_db)?;
+
+    let dir_id = metadata_db.initialize(asset_dir)?;
+    // We cache feature vectors in the SQLite db to avoid re-analyzing samples. A file is only
+    // cached if it has a vector for every index, and has been segmented if segmentation was
+    // requested.
+    let mut cached_files = get_cached_files(&metadata_db)?;
+    if options.segmentation.is_some() {
+        let segmented_files = metadata_db.get_segmented_files()?;
+        cached_files.retain(|path| segmented_files.contains(path));
+    }
+    // Newly analyzed files are streamed into the metadata db as they arrive
+    let files = feature_extractor::extract_features(
+        options,
+        &INDEXES,
         asset_dir,
-        &cached_features,
+        &cached_files,
         progress_callback,
+        |batch| metadata_db.insert_samples(batch, dir_id),
     )?;
+    let stale_segments: Vec<u32> = files
+        .iter()
+        .flat_map(|file| file.replaced_segments.iter().map(|id| *id as u32))
+        .collect();
+    let features: Vec<Feature> = files.into_iter().flat_map(|file| file.features).collect();
 
     let elapsed = start_time.elapsed();
-    println!("Took {:.1?} to extract features", elapsed);
+    println!("Took {:.1?} to extract and store features", elapsed);
 
     let start_time = Instant::now();
-    // Add the newly extracted features to the metadata db
-    let dir_id = metadata_db.initialize(asset_dir)?;
-    for feature in features.iter_mut() {
-        let id = metadata_db.insert_sample_metadata(
-            feature.source_file(),
-            dir_id,
-            feature.feature_vector(),
-        )?;
-        feature.set_id(id);
-    }
-
     // Combine previously cached features with the new ones
     let db = VectorDatabase::load_from_disk()?;
-    db.add_features_to_index(&features, feature_extractor::NUM_DIMENSIONS)?;
+    db.add_features_to_index(&features, &stale_segments)?;
+    for index in INDEXES {
+        metadata_db.register_index(index)?;
+    }
+    if options.segmentation.is_some() {
+        metadata_db.register_index(&SEGMENT_INDEX)?;
+    }
     let elapsed = start_time.elapsed();
     println!("Took {:.1?} to build database", elapsed);
 
     Ok(db)
 }
 
+/// Re-analyzes every sample that's missing a vector from the current feature set of any index,
+/// its embedded tags, or a fingerprint from the current algorithm, along with samples whose
+/// segments are from an outdated feature set, then rebuilds the indexes from the updated
+/// vectors. Samples that can no longer be analyzed, e.g. because the file was removed, are
+/// reported and kept, or dropped from the library along with their annotations when prune is
+/// set. When options specify new preprocessing, the library switches to it and every sample is
+/// re-analyzed.
+pub fn reanalyze(
+    options: AnalysisOptions,
+    prune: bool,
+    progress_callback: impl Fn(f32),
+) -> Result<VectorDatabase, String> {
+    let start_time = Instant::now();
+
+    let mut metadata_db = MetadataDatabase::load_from_disk()?;
+    if let Some(preprocessing) = options.preprocessing {
+        metadata_db.set_preprocessing(preprocessing)?;
+    }
+    let options = AnalysisOptions {
+        preprocessing: Some(metadata_db.preprocessing()),
+        ..options
+    };
+    let mut outdated = metadata_db.get_outdated_samples(&INDEXES)?;
+    // Segments can only be recalculated when segmentation is requested, since the library
+    // doesn't record how each file was segmented
+    let outdated_segments = metadata_db.get_samples_with_outdated_segments()?;
+    if !outdated_segments.is_empty() && options.segmentation.is_none() {
+        return Err("The library contains samples segmented with an outdated feature set. Pass --segment to re-segment them.".to_string());
+    }
+    // Samples analyzed before audio properties, embedded tags or fingerprints were recorded, or
+    // with an older fingerprint algorithm, are re-analyzed to fill them in
+    let missing_properties = metadata_db.get_samples_missing_properties()?;
+    let missing_tags = metadata_db.get_samples_missing_tags()?;
+    let missing_fingerprints = metadata_db.get_samples_missing_fingerprint()?;
+    for (dir_id, paths) in missing_properties
+        .into_iter()
+        .chain(missing_tags)
+        .chain(missing_fingerprints)
+        .chain(outdated_segments)
+    {
+        let dir_paths = outdated.entry(dir_id).or_default();
+        let known: HashSet<String> = dir_paths.iter().cloned().collect();
+        dir_paths.extend(paths.into_iter().filter(|path| !known.contains(path)));
+    }
+    let num_dirs = outdated.len();
+    println!(
+        "Re-analyzing {} outdated samples",
+        outdated.values().map(|paths| paths.len()).sum::<usize>()
+    );
+
+    let mut num_failed = 0;
+    for (dir_index, (dir_id, paths)) in outdated.into_iter().enumerate() {
+        let files = feature_extractor::extract_features_for_files(
+            options,
+            &INDEXES,
+            paths.clone(),
+            |progress| progress_callback((dir_index as f32 + progress) / num_dirs as f32),
+            |batch| metadata_db.insert_samples(batch, dir_id),
+        )?;
+
+        let analyzed: HashSet<&str> = files.iter().map(|file| file.path.as_str()).collect();
+        if analyzed.len() < paths.len() {
+            let failed: Vec<String> = paths
+                .iter()
+                .filter(|path| !analyzed.contains(path.as_str()))
+                .cloned()
+                .collect();
+            // A file can fail because its drive isn't mounted or it's locked, so it's only
+            // removed, along with its tags, ratings and other annotations, when asked to
+            if prune {
+                for path in failed.iter() {
+                    println!("Removing {path} since it could not be re-analyzed");
+                }
+                metadata_db.delete_samples(&failed)?;
+            } else {
+                for path in failed.iter() {
+                    println!("Could not re-analyze {path}");
+                }
+                num_failed += failed.len();
+            }
+        }
+    }
+    if num_failed > 0 {
+        println!(
+            "Kept {num_failed} samples that could not be re-analyzed. Run reanalyze --prune to remove them."
+        );
+    }
+
+    let elapsed = start_time.elapsed();
+    println!("Took {:.1?} to re-analyze features", elapsed);
+
+    let start_time = Instant::now();
+    let db = rebuild_indexes(&metadata_db)?;
+    let elapsed = start_time.elapsed();
+    println!("Took {:.1?} to rebuild database", elapsed);
+
+    Ok(db)
+}
+
+/// Rebuilds every index from the vectors stored in the metadata db, which drops vectors of
+/// samples that have been removed from the library
+fn rebuild_indexes(metadata_db: &MetadataDatabase) -> Result<VectorDatabase, String> {
+    let db = VectorDatabase::load_from_disk()?;
+    for index in INDEXES {
+        let features: Vec<Feature> = metadata_db.get_all_features(index)?.into_values().collect();
+        db.rebuild_index(index, &features)?;
+        metadata_db.register_index(index)?;
+    }
+    db.rebuild_index(&SEGMENT_INDEX, &metadata_db.get_all_segment_features()?)?;
+    metadata_db.register_index(&SEGMENT_INDEX)?;
+    Ok(db)
+}
+
+fn ensure_indexes_are_current(metadata_db: &MetadataDatabase) -> Result<(), String> {
+    let count = |samples: HashMap<i64, Vec<String>>| -> usize {
+        samples.values().map(|paths| paths.len()).sum()
+    };
+    let num_outdated = count(metadata_db.get_outdated_samples(&INDEXES)?);
+    let num_outdated_segments = count(metadata_db.get_samples_with_outdated_segments()?);
+    let mut outdated_indexes = Vec::new();
+    for index in INDEXES.into_iter().chain([&SEGMENT_INDEX]) {
+        let index_feature_set = metadata_db.get_index_feature_set(index.id)?;
+        if index_feature_set.is_some_and(|set| set != metadata_db.feature_set_id(index)) {
+            outdated_indexes.push(index.name);
+        }
+    }
+    if num_outdated > 0 || num_outdated_segments > 0 || !outdated_indexes.is_empty() {
+        return Err(format!(
+            "The library contains {} samples analyzed with an outdated feature set{}{}. Run reanalyze to update them.",
+            num_outdated,
+            if num_outdated_segments == 0 {
+                String::new()
+            } else {
+                format!(
+                    ", {} samples segmented with an outdated feature set",
+                    num_outdated_segments
+                )
+            },
+            if outdated_indexes.is_empty() {
+                String::new()
+            } else {
+                format!(" and outdated indexes: {}", outdated_indexes.join(", "))
+            }
+        ));
+    }
+    Ok(())
+}
+
+fn get_cached_files(metadata_db: &MetadataDatabase) -> Result<HashSet<String>, String> {
+    let mut cached_files: Option<HashSet<String>> = None;
+    for index in INDEXES {
+        let paths: HashSet<String> = metadata_db.get_all_features(index)?.into_keys().collect();
+        cached_files = Some(match cached_files {
+            Some(cached) => cached.intersection(&paths).cloned().collect(),
+            None => paths,
+        });
+    }
+    Ok(cached_files.unwrap_or_default())
+}
+
 pub fn find_similar(source_id: u32, num_results: usize) -> Result<Vec<AudioFile>, String> {
+    find_similar_in_indexes(
+        source_id,
+        &[(TIMBRE_INDEX.name, 1.0)],
+        &SampleFilter::default(),
+        num_results,
+        &Diversity::default(),
+    )
+}
+
+/// Finds the segments of long files that sound most similar to the sample source_id, so
+/// matching moments can be located within recordings. Segments of source_id itself are
+/// excluded.
+pub fn find_similar_segments(source_id: u32, num_results: usize) -> Result<Vec<Segment>, String> {
+    let vec_db = VectorDatabase::load_from_disk()?;
+    let query = vec_db
+        .item_vector(&TIMBRE_INDEX, source_id)?
+        .ok_or(format!("No analyzed sample with ID {source_id}"))?;
+    let md_db = MetadataDatabase::load_from_disk()?;
+    // Fetch enough results to fill num_results after removing the source's own segments
+    let num_source_segments = md_db.count_segments(source_id as i64)?;
+    let ids = vec_db.find_similar_to_vector(
+        &SEGMENT_INDEX,
+        &query,
+        num_results + num_source_segments,
+        None,
+    )?;
+    let mut segments = md_db.get_segments_for_ids(&ids)?;
+    segments.retain(|segment| segment.file().id() != source_id as i64);
+    segments.truncate(num_results);
+    Ok(segments)
+}
+
+/// Finds samples similar to source_id across one or more named indexes, e.g. "timbre" or
+/// "rhythm". When several indexes are provided, results are ranked by the weighted sum of
+/// their normalized distances in each index. A single index is searched with the dimension
+/// weights learned for it, if any. Only samples matching filter are returned, and results are
+/// varied as set by diversity.
+pub fn find_similar_in_indexes(
+    source_id: u32,
+    index_weights: &[(&str, f32)],
+    filter: &SampleFilter,
+    num_results: usize,
+    diversity: &Diversity,
+) -> Result<Vec<AudioFile>, String> {
+    if let Some(lambda) = diversity.lambda {
+        if !(0.0..=1.0).contains(&lambda) {
+            return Err(format!(
+                "The diversity lambda must be between 0 and 1, not {lambda}"
+            ));
+        }
+    }
+    let index_weights = index_weights
+        .iter()
+        .map(|(name, weight)| Ok((vector_db::index_named(name)?, *weight)))
+        .collect::<Result<Vec<_>, String>>()?;
+    let md_db = MetadataDatabase::load_from_disk()?;
+    let candidates = candidates_matching(&md_db, filter)?;
     // Otherwise, load the existing db from disk and query it
     let vec_db = VectorDatabase::load_from_disk()?;
-    let ids = vec_db.find_similar(source_id, num_results)?;
+    // Searches of a single index are reranked with the dimension weights learned for it
+    let learned_weights = match index_weights.as_slice() {
+        [(index, _)] => match md_db.dimension_weights(index)? {
+            Some(weights) => {
+                let query = vec_db
+                    .item_vector(index, source_id)?
+                    .ok_or(format!("No analyzed sample with ID {source_id}"))?;
+                Some((weights, query))
+            }
+            None => None,
+        },
+        _ => None,
+    };
+    let mut num_candidates = if diversity.is_enabled() {
+        num_results * diversity::CANDIDATE_FACTOR
+    } else {
+        num_results
+    };
+    let mut files = loop {
+        let ids = match (index_weights.as_slice(), &learned_weights) {
+            ([(index, _)], Some((weights, query))) => {
+                let ids = vec_db.find_similar(
+                    index,
+                    source_id,
+                    num_candidates * RERANK_OVERSAMPLING,
+                    candidates.as_ref(),
+                )?;
+                let mut ids = rerank_with_weights(&vec_db, index, weights, query, &ids)?;
+                ids.truncate(num_candidates);
+                ids
+            }
+            ([(index, _)], None) => {
+                vec_db.find_similar(index, source_id, num_candidates, candidates.as_ref())?
+            }
+            _ => vec_db.find_similar_weighted(
+                &index_weights,
+                source_id,
+                num_candidates,
+                candidates.as_ref(),
+            )?,
+        };
+        let mut files = md_db.get_audio_files_for_ids(&ids)?;
+        if !diversity.one_per_folder {
+            break files;
+        }
+        let mut folders = HashSet::new();
+        files.retain(|file| folders.insert(Path::new(file.path()).parent().map(Path::to_owned)));
+        // A folder of similar samples can fill the nearest candidates, so the search widens
+        // until there are enough folders or every candidate has been found
+        if files.len() >= num_results || ids.len() < num_candidates {
+            break files;
+        }
+        num_candidates *= 2;
+    };
+    let Some(lambda) = diversity.lambda else {
+        files.truncate(num_results);
+        return Ok(files);
+    };
+
+    // Compare results with the same weighted distance they were ranked by, scaling each
+    // index's distances by the farthest result's so indexes with larger distances don't
+    // dominate
+    let ids: Vec<u32> = std::iter::once(source_id)
+        .chain(files.iter().map(|file| file.id() as u32))
+        .collect();
+    let mut index_vectors = Vec::with_capacity(index_weights.len());
+    for (index, weight) in index_weights.iter() {
+        let vectors = vec_db
+            .item_vectors(index, &ids)?
+            .into_iter()
+            .map(|vector| vector.ok_or("A search result has no vector"))
+            .collect::<Result<Vec<Vec<f32>>, &str>>()?;
+        let farthest = vectors[1..]
+            .iter()
+            .map(|vector| index.metric.distance(&vectors[0], vector))
+            .fold(0.0, f32::max);
+        let scale = if farthest > 0.0 {
+            weight / farthest
+        } else {
+            0.0
+        };
+        index_vectors.push((index, scale, vectors));
+    }
+    let distance = |a: usize, b: usize| -> f32 {
+        index_vectors
+            .iter()
+            .map(|(index, scale, vectors)| scale * index.metric.distance(&vectors[a], &vectors[b]))
+            .sum()
+    };
+    let query_distances: Vec<f32> = (1..ids.len()).map(|i| distance(0, i)).collect();
+    let picks = diversity::mmr(
+        &query_distances,
+        |a, b| distance(a + 1, b + 1),
+        lambda,
+        num_results,
+    );
+    Ok(picks.into_iter().map(|i| files[i].clone()).collect())
+}
+
+/// An example to search for similar samples to
+#[derive(Clone, Debug)]
+pub enum Example {
+    /// An analyzed sample, by ID
+    Sample(u32),
+    /// An audio file, which doesn't need to have been analyzed
+    File(String),
+}
+
+/// How the results of searching for several examples are combined
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Fusion {
+    /// Search once for the centroid of the examples' vectors, finding samples that share what
+    /// the examples have in common
+    #[default]
+    Centroid,
+    /// Search for each example separately and combine the rankings with reciprocal rank
+    /// fusion, finding samples that closely match any of the examples
+    ReciprocalRank,
+}
+
+impl Fusion {
+    pub fn from_name(name: &str) -> Result<Fusion, String> {
+        match name {
+            "centroid" => Ok(Fusion::Centroid),
+            "rrf" => Ok(Fusion::ReciprocalRank),
+            _ => Err(format!(
+                "Unknown fusion method {name}. Available methods: centroid, rrf"
+            )),
+        }
+    }
+}
+
+/// How negative examples steer results away from them
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Steering {
+    /// Moves each query vector P away from the centroid N of the negatives, to
+    /// P + alpha * (P - N). With positives A and C, a negative B and an alpha of 1, the
+    /// centroid query points in the direction of A + (C - B).
+    Subtract { alpha: f32 },
+    /// Searches for the positives alone, then reranks the results by their distance to the
+    /// query minus alpha times their distance to the closest negative
+    Penalize { alpha: f32 },
+}
+
+impl Default for Steering {
+    fn default() -> Self {
+        Steering::Subtract { alpha: 1.0 }
+    }
+}
+
+/// A search for samples like the positive examples and unlike the negative ones
+#[derive(Clone, Debug, Default)]
+pub struct ExampleQuery {
+    pub positives: Vec<Example>,
+    /// Examples of a character results should avoid, e.g. too much room reverb
+    pub negatives: Vec<Example>,
+    pub fusion: Fusion,
+    /// How negatives are applied. Ignored when there are no negatives.
+    pub steering: Steering,
+}
+
+/// The rank offset of reciprocal rank fusion, which dampens the influence of the top few
+/// results of each ranking. 60 is the value from the original paper.
+const RECIPROCAL_RANK_OFFSET: f32 = 60.0;
+
+/// The number of results fetched per requested result when reranking them to penalize
+/// negatives or apply learned dimension weights
+const RERANK_OVERSAMPLING: usize = 5;
+
+/// Finds samples similar to the positive examples of query across one or more named indexes,
+/// steered away from its negative examples. Indexes are weighted as in
+/// find_similar_in_indexes. The examples themselves are never returned, and only samples
+/// matching filter are returned.
+pub fn find_similar_to_examples(
+    query: &ExampleQuery,
+    index_weights: &[(&str, f32)],
+    filter: &SampleFilter,
+    num_results: usize,
+) -> Result<Vec<AudioFile>, String> {
+    if query.positives.is_empty() {
+        return Err("At least one positive example is required".to_string());
+    }
+    let index_weights = index_weights
+        .iter()
+        .map(|(name, weight)| Ok((vector_db::index_named(name)?, *weight)))
+        .collect::<Result<Vec<_>, String>>()?;
+    let indexes: Vec<&'static VectorIndex> =
+        index_weights.iter().map(|(index, _)| *index).collect();
     let md_db = MetadataDatabase::load_from_disk()?;
+    let vec_db = VectorDatabase::load_from_disk()?;
+
+    let mut example_ids: HashSet<u32> = HashSet::new();
+    let positives = example_vectors(
+        &md_db,
+        &vec_db,
+        &indexes,
+        &query.positives,
+        &mut example_ids,
+    )?;
+    let negatives = example_vectors(
+        &md_db,
+        &vec_db,
+        &indexes,
+        &query.negatives,
+        &mut example_ids,
+    )?;
+    let steering = (!negatives.is_empty()).then_some(query.steering);
+
+    // A single query for the centroid of the positives, or one per positive to be fused
+    let mut queries: Vec<Vec<Vec<f32>>> = match query.fusion {
+        Fusion::Centroid => vec![centroids(&indexes, &positives)],
+        Fusion::ReciprocalRank => positives
+            .iter()
+            .map(|positive| centroids(&indexes, std::slice::from_ref(positive)))
+            .collect(),
+    };
+    if let Some(Steering::Subtract { alpha }) = steering {
+        let negative_centroids = centroids(&indexes, &negatives);
+        for query_vectors in queries.iter_mut() {
+            for (vector, negative) in query_vectors.iter_mut().zip(negative_centroids.iter()) {
+                steer_away(vector, negative, alpha);
+            }
+        }
+    }
+
+    let mut candidates = candidates_matching(&md_db, filter)?;
+    if let Some(candidates) = candidates.as_mut() {
+        for id in example_ids.iter() {
+            candidates.remove(*id);
+        }
+    }
+    // Without a filter the examples can't be excluded up front, so fetch enough results to
+    // fill num_results after removing them
+    let num_candidates = match steering {
+        Some(Steering::Penalize { .. }) => num_results * RERANK_OVERSAMPLING,
+        _ => num_results,
+    } + example_ids.len();
+    let search = |vectors: &[Vec<f32>]| -> Result<Vec<u32>, String> {
+        match index_weights.as_slice() {
+            [(index, _)] => vec_db.find_similar_to_vector(
+                index,
+                &vectors[0],
+                num_candidates,
+                candidates.as_ref(),
+            ),
+            _ => {
+                let index_vectors: Vec<(&VectorIndex, f32, &[f32])> = index_weights
+                    .iter()
+                    .zip(vectors)
+                    .map(|((index, weight), vector)| (*index, *weight, vector.as_slice()))
+                    .collect();
+                vec_db.find_similar_to_vectors_weighted(
+                    &index_vectors,
+                    num_candidates,
+                    candidates.as_ref(),
+                )
+            }
+        }
+    };
+    let mut rankings = queries
+        .iter()
+        .map(|vectors| search(vectors))
+        .collect::<Result<Vec<_>, String>>()?;
+    let mut ids = match rankings.len() {
+        1 => rankings.pop().unwrap(),
+        _ => reciprocal_rank_fusion(rankings),
+    };
+    ids.retain(|id| !example_ids.contains(id));
+
+    if let Some(Steering::Penalize { alpha }) = steering {
+        ids = penalize_negatives(&vec_db, &index_weights, &queries, &negatives, ids, alpha)?;
+    }
+    ids.truncate(num_results);
     md_db.get_audio_files_for_ids(&ids)
 }
 
-pub fn list_audio_files(start_offset: u32, num_results: u32) -> Result<Vec<AudioFile>, String> {
+/// Returns one vector per index for each of examples, adding the IDs of examples that are
+/// analyzed samples to ids
+fn example_vectors(
+    md_db: &MetadataDatabase,
+    vec_db: &VectorDatabase,
+    indexes: &[&'static VectorIndex],
+    examples: &[Example],
+    ids: &mut HashSet<u32>,
+) -> Result<Vec<Vec<Vec<f32>>>, String> {
+    let mut example_vectors = Vec::with_capacity(examples.len());
+    for example in examples {
+        let vectors = match example {
+            Example::Sample(id) => {
+                ids.insert(*id);
+                sample_vectors(vec_db, indexes, *id)?
+            }
+            // Files that have already been analyzed use their stored vectors
+            Example::File(path) => match md_db.get_sample_id(path)? {
+                Some(id) => {
+                    ids.insert(id as u32);
+                    sample_vectors(vec_db, indexes, id as u32)?
+                }
+                None => {
+                    feature_extractor::extract_query_features(path, indexes, &md_db.preprocessing())
+                        .map_err(|e| format!("Failed to analyze {path}: {e}"))?
+                        .into_iter()
+                        .map(|feature| feature.feature_vector().to_vec())
+                        .collect()
+                }
+            },
+        };
+        example_vectors.push(vectors);
+    }
+    Ok(example_vectors)
+}
+
+/// Returns the vector stored for the sample id in each of indexes
+fn sample_vectors(
+    vec_db: &VectorDatabase,
+    indexes: &[&VectorIndex],
+    id: u32,
+) -> Result<Vec<Vec<f32>>, String> {
+    indexes
+        .iter()
+        .map(|index| {
+            vec_db
+                .item_vector(index, id)?
+                .ok_or(format!("No analyzed sample with ID {id}"))
+        })
+        .collect()
+}
+
+/// Returns the centroid of the examples' vectors in each of indexes
+fn centroids(indexes: &[&VectorIndex], examples: &[Vec<Vec<f32>>]) -> Vec<Vec<f32>> {
+    indexes
+        .iter()
+        .enumerate()
+        .map(|(i, index)| centroid(index, examples.iter().map(|vectors| &vectors[i])))
+        .collect()
+}
+
+/// Returns the mean of vectors. Vectors in angular indexes are normalized first, since only
+/// their direction is meaningful.
+fn centroid<'a>(index: &VectorIndex, vectors: impl Iterator<Item = &'a Vec<f32>>) -> Vec<f32> {
+    let mut sum = vec![0.0; index.dimensions()];
+    let mut count = 0;
+    for vector in vectors {
+        let scale = match index.metric {
+            Metric::Angular => {
+                let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+                if norm > 0.0 {
+                    1.0 / norm
+                } else {
+                    0.0
+                }
+            }
+            Metric::Euclidean | Metric::Manhattan => 1.0,
+        };
+        for (total, x) in sum.iter_mut().zip(vector) {
+            *total += x * scale;
+        }
+        count += 1;
+    }
+    sum.iter()
+        .map(|total| total / count.max(1) as f32)
+        .collect()
+}
+
+/// Combines rankings into one, scoring each id by the sum of 1 / (k + rank) over the rankings
+/// it appears in
+fn reciprocal_rank_fusion(rankings: Vec<Vec<u32>>) -> Vec<u32> {
+    let mut scores: HashMap<u32, f32> = HashMap::new();
+    for ranking in rankings {
+        for (rank, id) in ranking.into_iter().enumerate() {
+            *scores.entry(id).or_default() += 1.0 / (RECIPROCAL_RANK_OFFSET + rank as f32 + 1.0);
+        }
+    }
+    let mut ranked: Vec<(u32, f32)> = scores.into_iter().collect();
+    // Break ties by ID so results are deterministic
+    ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+    ranked.into_iter().map(|(id, _)| id).collect()
+}
+
+/// Reranks ids by their weighted distance to the closest of queries minus alpha times their
+/// distance to the closest of negatives. Distances are normalized per index, as in weighted
+/// searches.
+fn penalize_negatives(
+    vec_db: &VectorDatabase,
+    index_weights: &[(&VectorIndex, f32)],
+    queries: &[Vec<Vec<f32>>],
+    negatives: &[Vec<Vec<f32>>],
+    ids: Vec<u32>,
+    alpha: f32,
+) -> Result<Vec<u32>, String> {
+    let mut index_distances = Vec::with_capacity(index_weights.len());
+    for (i, (index, weight)) in index_weights.iter().enumerate() {
+        let closest = |vector: &[f32], examples: &[Vec<Vec<f32>>]| {
+            examples
+                .iter()
+                .map(|vectors| index.metric.distance(vector, &vectors[i]))
+                .fold(f32::MAX, f32::min)
+        };
+        let mut distances = Vec::with_capacity(ids.len());
+        for vector in vec_db.item_vectors(index, &ids)? {
+            let vector = vector.ok_or("A search result has no vector")?;
+            distances.push((closest(&vector, queries), closest(&vector, negatives)));
+        }
+        index_distances.push((*weight, distances));
+    }
+    Ok(penalized_order(&index_distances, alpha)
+        .into_iter()
+        .map(|position| ids[position])
+        .collect())
+}
+
+/// Returns the positions of candidates from best to worst, given the weight of each index and
+/// every candidate's (distance to the query, distance to the closest negative) in it. Each
+/// candidate scores its query distance minus alpha times its negative distance, scaled so
+/// the largest distance in each index is 1, and summed across indexes by weight.
+fn penalized_order(index_distances: &[(f32, Vec<(f32, f32)>)], alpha: f32) -> Vec<usize> {
+    let num_candidates = index_distances
+        .first()
+        .map_or(0, |(_, distances)| distances.len());
+    let mut scores = vec![0.0; num_candidates];
+    for (weight, distances) in index_distances {
+        let max_distance = distances
+            .iter()
+            .map(|(query, negative)| query.max(*negative))
+            .fold(0.0, f32::max);
+        let scale = if max_distance > 0.0 {
+            1.0 / max_distance
+        } else {
+            1.0
+        };
+        for (score, (query, negative)) in scores.iter_mut().zip(distances) {
+            *score += weight * scale * (query - alpha * negative);
+        }
+    }
+    let mut order: Vec<usize> = (0..num_candidates).collect();
+    order.sort_by(|a, b| scores[*a].total_cmp(&scores[*b]));
+    order
+}
+
+/// Moves vector alpha times its offset from negative further away from it
+fn steer_away(vector: &mut [f32], negative: &[f32], alpha: f32) {
+    for (x, n) in vector.iter_mut().zip(negative) {
+        *x += alpha * (*x - n);
+    }
+}
+
+/// Records whether results of a search for query_id were relevant. Feedback refines later
+/// searches for query_id with find_similar_with_feedback, and is used to learn dimension
+/// weights with learn_dimension_weights.
+pub fn record_feedback(query_id: u32, relevant: &[u32], irrelevant: &[u32]) -> Result<(), String> {
+    let mut md_db = MetadataDatabase::load_from_disk()?;
+    let to_i64 = |ids: &[u32]| ids.iter().map(|id| *id as i64).collect::<Vec<_>>();
+    md_db.add_feedback(query_id as i64, &to_i64(relevant), true)?;
+    md_db.add_feedback(query_id as i64, &to_i64(irrelevant), false)
+}
+
+/// Finds samples similar to source_id in the named index, refined by the feedback recorded on
+/// earlier results for it. The query vector is moved towards relevant results and away from
+/// irrelevant ones, and results are reranked with the index's learned dimension weights if
+/// there are any. source_id and samples marked irrelevant are never returned.
+pub fn find_similar_with_feedback(
+    source_id: u32,
+    index_name: &str,
+    filter: &SampleFilter,
+    num_results: usize,
+) -> Result<Vec<AudioFile>, String> {
+    let index = vector_db::index_named(index_name)?;
+    let md_db = MetadataDatabase::load_from_disk()?;
+    let vec_db = VectorDatabase::load_from_disk()?;
+    let query = vec_db
+        .item_vector(index, source_id)?
+        .ok_or(format!("No analyzed sample with ID {source_id}"))?;
+
+    let judgements = md_db.get_feedback(source_id as i64)?;
+    let judged_ids = |relevant: bool| -> Vec<u32> {
+        judgements
+            .iter()
+            .filter(|(_, r)| *r == relevant)
+            .map(|(id, _)| *id as u32)
+            .collect()
+    };
+    let (relevant_ids, irrelevant_ids) = (judged_ids(true), judged_ids(false));
+    let judged_centroid = |ids: &[u32]| -> Result<Option<Vec<f32>>, String> {
+        let vectors: Vec<Vec<f32>> = vec_db
+            .item_vectors(index, ids)?
+            .into_iter()
+            .flatten()
+            .collect();
+        Ok((!vectors.is_empty()).then(|| centroid(index, vectors.iter())))
+    };
+    let refined = feedback::rocchio(
+        &centroid(index, std::iter::once(&query)),
+        judged_centroid(&relevant_ids)?.as_deref(),
+        judged_centroid(&irrelevant_ids)?.as_deref(),
+    );
+
+    let excluded: HashSet<u32> = irrelevant_ids
+        .into_iter()
+        .chain(std::iter::once(source_id))
+        .collect();
+    let mut candidates = candidates_matching(&md_db, filter)?;
+    if let Some(candidates) = candidates.as_mut() {
+        for id in excluded.iter() {
+            candidates.remove(*id);
+        }
+    }
+    let weights = md_db.dimension_weights(index)?;
+    let num_candidates = match weights {
+        Some(_) => num_results * RERANK_OVERSAMPLING,
+        None => num_results,
+    } + excluded.len();
+    let mut ids =
+        vec_db.find_similar_to_vector(index, &refined, num_candidates, candidates.as_ref())?;
+    ids.retain(|id| !excluded.contains(id));
+
+    if let Some(weights) = weights {
+        ids = rerank_with_weights(&vec_db, index, &weights, &refined, &ids)?;
+    }
+    ids.truncate(num_results);
+    md_db.get_audio_files_for_ids(&ids)
+}
+
+/// Sorts ids by their distance to query in index, with each dimension scaled by the learned
+/// weights. Ids without a vector are dropped.
+fn rerank_with_weights(
+    vec_db: &VectorDatabase,
+    index: &VectorIndex,
+    weights: &[f32],
+    query: &[f32],
+    ids: &[u32],
+) -> Result<Vec<u32>, String> {
+    let mut ranked: Vec<(u32, f32)> = ids
+        .iter()
+        .zip(vec_db.item_vectors(index, ids)?)
+        .filter_map(|(id, vector)| {
+            let distance = feedback::weighted_distance(index.metric, weights, query, &vector?);
+            Some((*id, distance))
+        })
+        .collect();
+    ranked.sort_by(|a, b| a.1.total_cmp(&b.1));
+    Ok(ranked.into_iter().map(|(id, _)| id).collect())
+}
+
+/// Learns per-dimension weights for the named index from all recorded feedback and stores
+/// them, so searches of the index emphasize the dimensions that tell relevant results apart
+/// from irrelevant ones. Returns the learned weights.
+pub fn learn_dimension_weights(index_name: &str) -> Result<Vec<f32>, String> {
+    let index = vector_db::index_named(index_name)?;
+    let md_db = MetadataDatabase::load_from_disk()?;
+    let vec_db = VectorDatabase::load_from_disk()?;
+    let judgements = md_db.get_all_feedback()?;
+
+    let ids: Vec<u32> = judgements
+        .iter()
+        .flat_map(|(query_id, sample_id, _)| [*query_id as u32, *sample_id as u32])
+        .collect::<HashSet<u32>>()
+        .into_iter()
+        .collect();
+    // Vectors in angular indexes are normalized, since only their direction is meaningful
+    let vectors: HashMap<u32, Vec<f32>> = ids
+        .iter()
+        .zip(vec_db.item_vectors(index, &ids)?)
+        .filter_map(|(id, vector)| Some((*id, centroid(index, std::iter::once(&vector?)))))
+        .collect();
+    let judgements: Vec<(Vec<f32>, Vec<f32>, bool)> = judgements
+        .into_iter()
+        .filter_map(|(query_id, sample_id, relevant)| {
+            let query = vectors.get(&(query_id as u32))?;
+            let sample = vectors.get(&(sample_id as u32))?;
+            Some((query.clone(), sample.clone(), relevant))
+        })
+        .collect();
+
+    let weights = feedback::learn_weights(&judgements).ok_or(
+        "Mark at least one search result as relevant and one as irrelevant before learning weights",
+    )?;
+    md_db.set_dimension_weights(index, Some(&weights))?;
+    Ok(weights)
+}
+
+/// Removes the learned dimension weights of the named index
+pub fn clear_dimension_weights(index_name: &str) -> Result<(), String> {
+    let index = vector_db::index_named(index_name)?;
+    let md_db = MetadataDatabase::load_from_disk()?;
+    md_db.set_dimension_weights(index, None)
+}
+
+/// Returns the ids of samples matching filter, or None if the filter is empty and every
+/// sample is a candidate
+fn candidates_matching(
+    md_db: &MetadataDatabase,
+    filter: &SampleFilter,
+) -> Result<Option<RoaringBitmap>, String> {
+    if filter.is_empty() {
+        return Ok(None);
+    }
+    let ids = md_db.get_sample_ids_matching(filter)?;
+    Ok(Some(ids.into_iter().collect()))
+}
+
+/// Lists a page of analyzed samples. Pass the returned page's next_cursor in options to fetch
+/// the following page.
+pub fn list_audio_files(options: &ListOptions) -> Result<Page, String> {
     let db = MetadataDatabase::load_from_disk()?;
-    db.list_audio_files(start_offset, Some(num_results))
+    db.list_audio_files(options)
+}
+
+/// Finds samples that duplicate each other, either byte for byte or, when options set a
+/// max_distance, by sounding nearly the same in the options' index. Each cluster suggests the
+/// best copy to keep. Files that can't be read are reported rather than failing the search.
+pub fn find_duplicates(options: &DedupeOptions) -> Result<DedupeReport, String> {
+    let index = vector_db::index_named(&options.index)?;
+    let mut md_db = MetadataDatabase::load_from_disk()?;
+    // Samples analyzed before content hashes were recorded are hashed the first time they're
+    // needed
+    let unhashed = md_db.get_samples_missing_content_hash()?;
+    let mut unreadable = Vec::new();
+    if !unhashed.is_empty() {
+        println!("Hashing {} samples", unhashed.len());
+        let mut hashes: Vec<(i64, u64)> = Vec::new();
+        for (id, path) in unhashed {
+            match file_utils::content_hash(Path::new(&path)) {
+                Ok(hash) => hashes.push((id, hash)),
+                Err(e) => unreadable.push((path, e)),
+            }
+        }
+        md_db.set_content_hashes(&hashes)?;
+    }
+
+    let files: HashMap<i64, AudioFile> = md_db
+        .get_audio_files_matching(&SampleFilter::default())?
+        .into_iter()
+        .map(|file| (file.id(), file))
+        .collect();
+    let (exact_groups, unreadable_copies) =
+        dedupe::confirm_exact_duplicates(&files, md_db.get_content_hash_groups()?);
+    unreadable.extend(unreadable_copies);
+
+    let vec_db = VectorDatabase::load_from_disk()?;
+    let mut near_pairs = Vec::new();
+    if let Some(max_distance) = options.max_distance {
+        let ids: Vec<u32> = files.keys().map(|id| *id as u32).collect();
+        near_pairs = vec_db.neighbors_within(index, &ids, dedupe::NUM_NEIGHBORS, max_distance)?;
+        if options.verify_audio {
+            let fingerprints = md_db.get_all_fingerprints()?.into_iter().collect();
+            near_pairs = dedupe::verify_near_duplicates(&fingerprints, near_pairs);
+        }
+    }
+
+    Ok(DedupeReport {
+        clusters: dedupe::cluster(files, &exact_groups, &near_pairs),
+        unreadable,
+    })
+}
+
+/// Resolves duplicate clusters with action, returning the file operations that were made, or
+/// that would be made when dry_run is true. Operations that fail are reported and skipped.
+/// Moved duplicates are removed from the library.
+pub fn resolve_duplicates(
+    clusters: &[DuplicateCluster],
+    action: &DuplicateAction,
+    dry_run: bool,
+) -> Result<Vec<FileOperation>, String> {
+    let operations = dedupe::plan(clusters, action);
+    if dry_run {
+        return Ok(operations);
+    }
+
+    let mut completed = Vec::with_capacity(operations.len());
+    for operation in operations {
+        match dedupe::apply(&operation) {
+            Ok(()) => completed.push(operation),
+            Err(e) => println!("Failed to {operation}: {e}"),
+        }
+    }
+    let moved: Vec<String> = completed
+        .iter()
+        .filter_map(|operation| match operation {
+            FileOperation::Move { from, .. } => Some(from.clone()),
+            FileOperation::HardLink { .. } => None,
+        })
+        .collect();
+    if !moved.is_empty() {
+        let mut md_db = MetadataDatabase::load_from_disk()?;
+        md_db.delete_samples(&moved)?;
+        rebuild_indexes(&md_db)?;
+    }
+    Ok(completed)
+}
+
+/// Finds samples that are the same recording as example, even if they've been re-encoded,
+/// trimmed or changed in level, ordered from the closest match. Unlike similarity searches,
+/// this doesn't return samples that merely sound alike. The example itself is excluded.
+pub fn find_fingerprint_matches(example: &Example) -> Result<Vec<FingerprintMatch>, String> {
+    let md_db = MetadataDatabase::load_from_disk()?;
+    let stored_fingerprint = |id: i64| -> Result<Vec<u32>, String> {
+        md_db.get_fingerprint(id)?.ok_or(format!(
+            "No fingerprint for sample {id}. Run reanalyze to calculate it."
+        ))
+    };
+    let (query_id, query) = match example {
+        Example::Sample(id) => (Some(*id as i64), stored_fingerprint(*id as i64)?),
+        // Files that have already been analyzed use their stored fingerprints
+        Example::File(path) => match md_db.get_sample_id(path)? {
+            Some(id) => (Some(id), stored_fingerprint(id)?),
+            None => (
+                None,
+                feature_extractor::extract_query_fingerprint(path, &md_db.preprocessing())
+                    .map_err(|e| format!("Failed to analyze {path}: {e}"))?,
+            ),
+        },
+    };
+
+    let mut matches: Vec<(i64, f32, isize)> = md_db
+        .get_all_fingerprints()?
+        .into_iter()
+        .filter(|(id, _)| Some(*id) != query_id)
+        .filter_map(|(id, fingerprint)| {
+            let (similarity, offset) = fingerprint::compare(&query, &fingerprint)?;
+            fingerprint::is_match(similarity).then_some((id, similarity, offset))
+        })
+        .collect();
+    matches.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+
+    let ids: Vec<u32> = matches.iter().map(|(id, _, _)| *id as u32).collect();
+    let mut files: HashMap<i64, AudioFile> = md_db
+        .get_audio_files_for_ids(&ids)?
+        .into_iter()
+        .map(|file| (file.id(), file))
+        .collect();
+    Ok(matches
+        .into_iter()
+        .filter_map(|(id, similarity, offset)| {
+            Some(FingerprintMatch {
+                file: files.remove(&id)?,
+                similarity,
+                offset: fingerprint::offset_seconds(offset),
+            })
+        })
+        .collect())
+}
+
+/// Clusters the samples by their vectors in an index, replacing any earlier clustering, and
+/// returns the clusters from largest to smallest. Samples analyzed later aren't assigned to a
+/// cluster until the library is clustered again.
+pub fn cluster_library(options: &ClusterOptions) -> Result<Vec<Cluster>, String> {
+    let index = vector_db::index_named(&options.index)?;
+    let mut md_db = MetadataDatabase::load_from_disk()?;
+    let (ids, vectors) = library_vectors(&md_db, index)?;
+
+    let labels: Vec<Option<usize>> = match options.method {
+        ClusterMethod::KMeans(selection) => {
+            // k-means compares vectors by euclidean distance, so vectors in angular indexes
+            // are normalized to compare their directions
+            let normalized: Vec<Vec<f32>> = vectors
+                .iter()
+                .map(|vector| centroid(index, std::iter::once(vector)))
+                .collect();
+            let (assignments, _) = clustering::kmeans(&normalized, selection);
+            assignments.into_iter().map(Some).collect()
+        }
+        ClusterMethod::Hdbscan { min_cluster_size } => {
+            let num_neighbors = clustering::HDBSCAN_NUM_NEIGHBORS.max(min_cluster_size);
+            let edges = neighbor_edges(index, &ids, num_neighbors)?;
+            clustering::hdbscan(ids.len(), &edges, min_cluster_size)
+        }
+    };
+
+    let mut members: Vec<Vec<usize>> = Vec::new();
+    for (i, label) in labels.into_iter().enumerate() {
+        if let Some(label) = label {
+            if members.len() <= label {
+                members.resize(label + 1, Vec::new());
+            }
+            members[label].push(i);
+        }
+    }
+    members.retain(|members| !members.is_empty());
+    members.sort_by_key(|members| std::cmp::Reverse(members.len()));
+    let clusters: Vec<(Vec<f32>, i64, Vec<i64>)> = members
+        .iter()
+        .filter_map(|members| {
+            let centroid = centroid(index, members.iter().map(|i| &vectors[*i]));
+            // The medoid is the member closest to the centroid, which represents the cluster
+            // with a real sample
+            let medoid = members.iter().min_by(|a, b| {
+                let a_distance = index.metric.distance(&vectors[**a], &centroid);
+                a_distance.total_cmp(&index.metric.distance(&vectors[**b], &centroid))
+            })?;
+            Some((
+                centroid,
+                ids[*medoid],
+                members.iter().map(|i| ids[*i]).collect(),
+            ))
+        })
+        .collect();
+    md_db.set_clusters(index, &clusters)?;
+    md_db.list_clusters()
+}
+
+/// Returns the clusters found by the most recent clustering, from largest to smallest
+pub fn list_clusters() -> Result<Vec<Cluster>, String> {
+    MetadataDatabase::load_from_disk()?.list_clusters()
+}
+
+/// Returns the IDs of the samples with vectors in index and their vectors, ordered by ID
+fn library_vectors(
+    md_db: &MetadataDatabase,
+    index: &'static VectorIndex,
+) -> Result<(Vec<i64>, Vec<Vec<f32>>), String> {
+    ensure_indexes_are_current(md_db)?;
+    let mut samples: Vec<(i64, Vec<f32>)> = md_db
+        .get_all_features(index)?
+        .into_values()
+        .filter_map(|feature| Some(((*feature.id())?, feature.feature_vector().to_vec())))
+        .collect();
+    if samples.is_empty() {
+        return Err("The library has no analyzed samples".to_string());
+    }
+    samples.sort_by_key(|(id, _)| *id);
+    Ok(samples.into_iter().unzip())
+}
+
+/// Returns the edges from each of ids to its num_neighbors nearest neighbors in index, as
+/// (position, neighbor position, distance) where positions are indices into ids
+fn neighbor_edges(
+    index: &VectorIndex,
+    ids: &[i64],
+    num_neighbors: usize,
+) -> Result<Vec<(usize, usize, f32)>, String> {
+    let positions: HashMap<u32, usize> = ids
+        .iter()
+        .enumerate()
+        .map(|(i, id)| (*id as u32, i))
+        .collect();
+    let item_ids: Vec<u32> = ids.iter().map(|id| *id as u32).collect();
+    Ok(VectorDatabase::load_from_disk()?
+        .neighbors_within(index, &item_ids, num_neighbors, f32::INFINITY)?
+        .into_iter()
+        .filter_map(|(a, b, distance)| Some((*positions.get(&a)?, *positions.get(&b)?, distance)))
+        .collect())
+}
+
+/// Projects the samples' vectors in an index onto a 2D map, replacing any earlier map, and
+/// returns each sample's position. Coordinates are scaled to fit between 0 and 1. Samples
+/// analyzed later aren't on the map until the library is projected again.
+pub fn project_library(options: &ProjectionOptions) -> Result<Vec<MapPoint>, String> {
+    let index = vector_db::index_named(&options.index)?;
+    let mut md_db = MetadataDatabase::load_from_disk()?;
+    let (ids, mut vectors) = library_vectors(&md_db, index)?;
+    // Only the directions of vectors in angular indexes are meaningful
+    if index.metric == Metric::Angular {
+        vectors = vectors
+            .iter()
+            .map(|vector| centroid(index, std::iter::once(vector)))
+            .collect();
+    }
+
+    let layout = projection::pca(&vectors);
+    let layout = match options.method {
+        ProjectionMethod::Pca => layout,
+        ProjectionMethod::Umap { num_neighbors } => {
+            let edges = neighbor_edges(index, &ids, num_neighbors)?;
+            projection::umap(ids.len(), &edges, &layout)
+        }
+    };
+    let coordinates: Vec<(i64, [f32; 2])> = ids
+        .into_iter()
+        .zip(projection::scaled(&layout, 1.0))
+        .collect();
+    md_db.set_map_coordinates(&coordinates)?;
+    md_db.get_map_points()
+}
+
+/// Returns the position of each sample on the most recent map
+pub fn map_points() -> Result<Vec<MapPoint>, String> {
+    MetadataDatabase::load_from_disk()?.get_map_points()
+}
+
+/// Labels samples with the sound types in a CSV file of sample IDs or paths and labels, as
+/// read by classification::read_labels. Returns how many samples were labeled. Rows for
+/// samples that aren't in the library are reported and skipped.
+pub fn import_labels(path: &Path) -> Result<usize, String> {
+    let mut md_db = MetadataDatabase::load_from_disk()?;
+    let mut labels = Vec::new();
+    for (sample, label) in classification::read_labels(path)? {
+        let id = match sample.parse::<u32>() {
+            Ok(id) => md_db
+                .get_audio_files_for_ids(&[id])?
+                .first()
+                .map(|file| file.id()),
+            Err(_) => md_db.get_sample_id(&sample)?,
+        };
+        match id {
+            Some(id) => labels.push((id, label)),
+            None => println!("Skipping {sample}: not an analyzed sample"),
+        }
+    }
+    md_db.set_user_labels(&labels)?;
+    Ok(labels.len())
+}
+
+/// Labels a sample with a sound type, or removes its label when label is None. User labels
+/// are never overwritten by predictions, and are used as examples the next time the library
+/// is classified.
+pub fn set_label(id: u32, label: Option<&str>) -> Result<(), String> {
+    let mut md_db = MetadataDatabase::load_from_disk()?;
+    md_db
+        .get_audio_files_for_ids(&[id])?
+        .pop()
+        .ok_or(format!("No sample with ID {id}"))?;
+    match label {
+        Some(label) => {
+            md_db.set_user_labels(&[(id as i64, classification::normalize_label(label))])
+        }
+        None => md_db.remove_label(id as i64),
+    }
+}
+
+/// Predicts the sound type of every sample the user hasn't labeled by a vote among its
+/// nearest labeled samples in an index, replacing earlier predictions
+pub fn classify_library(options: &ClassifyOptions) -> Result<Vec<Prediction>, String> {
+    let index = vector_db::index_named(&options.index)?;
+    let mut md_db = MetadataDatabase::load_from_disk()?;
+    ensure_indexes_are_current(&md_db)?;
+    let user_labels = md_db.get_user_labels()?;
+    if user_labels.is_empty() {
+        return Err(
+            "No labeled samples to learn from. Pass --labels with a CSV file of sample IDs or paths and their labels."
+                .to_string(),
+        );
+    }
+    let labeled: RoaringBitmap = user_labels.keys().map(|id| *id as u32).collect();
+    let unlabeled: Vec<u32> = md_db
+        .get_sample_ids_matching(&SampleFilter::default())?
+        .into_iter()
+        .filter(|id| !labeled.contains(*id))
+        .collect();
+
+    let mut neighbors: HashMap<u32, Vec<(&str, f32)>> = HashMap::new();
+    for (id, neighbor, distance) in VectorDatabase::load_from_disk()?.neighbors_among(
+        index,
+        &unlabeled,
+        options.num_neighbors,
+        &labeled,
+    )? {
+        if let Some(label) = user_labels.get(&(neighbor as i64)) {
+            neighbors.entry(id).or_default().push((label, distance));
+        }
+    }
+    let mut predictions: Vec<Prediction> = neighbors
+        .into_iter()
+        .filter_map(|(id, neighbors)| {
+            let (label, confidence) = classification::vote(neighbors.into_iter())?;
+            Some(Prediction {
+                sample_id: id as i64,
+                label,
+                confidence,
+            })
+        })
+        .collect();
+    predictions.sort_by_key(|prediction| prediction.sample_id);
+
+    let rows: Vec<(i64, String, f32)> = predictions
+        .iter()
+        .map(|prediction| {
+            (
+                prediction.sample_id,
+                prediction.label.clone(),
+                prediction.confidence,
+            )
+        })
+        .collect();
+    md_db.set_predicted_labels(&rows)?;
+    Ok(predictions)
+}
+
+/// Finds samples that are far from every other sample in an index, and samples whose nearest
+/// neighbors mostly belong to a different folder or tag than their own
+pub fn find_outliers(options: &OutlierOptions) -> Result<OutlierReport, String> {
+    let index = vector_db::index_named(&options.index)?;
+    let md_db = MetadataDatabase::load_from_disk()?;
+    ensure_indexes_are_current(&md_db)?;
+    let mut files: HashMap<i64, AudioFile> = md_db
+        .get_audio_files_matching(&SampleFilter::default())?
+        .into_iter()
+        .map(|file| (file.id(), file))
+        .collect();
+    let ids: Vec<u32> = files.keys().map(|id| *id as u32).collect();
+
+    let mut neighbors: HashMap<u32, Vec<(u32, f32)>> = HashMap::new();
+    for (id, neighbor, distance) in VectorDatabase::load_from_disk()?.neighbors_within(
+        index,
+        &ids,
+        options.num_neighbors,
+        f32::INFINITY,
+    )? {
+        neighbors.entry(id).or_default().push((neighbor, distance));
+    }
+
+    let mean_distances: Vec<(u32, f32)> = neighbors
+        .iter()
+        .map(|(id, neighbors)| {
+            let total: f32 = neighbors.iter().map(|(_, distance)| distance).sum();
+            (*id, total / neighbors.len() as f32)
+        })
+        .collect();
+    let mean_distance_of: HashMap<u32, f32> = mean_distances.iter().copied().collect();
+    let isolated_ids = outliers::isolation_scores(&mean_distances, options.min_isolation);
+
+    let groups: HashMap<u32, Vec<String>> = match options.grouping {
+        Grouping::Folder => files
+            .values()
+            .filter_map(|file| {
+                let folder = Path::new(file.path()).parent()?;
+                Some((file.id() as u32, vec![folder.display().to_string()]))
+            })
+            .collect(),
+        Grouping::Tag => md_db
+            .get_all_user_tags()?
+            .into_iter()
+            .map(|(id, tags)| {
+                let tags = tags.iter().map(|tag| tag.to_lowercase()).collect();
+                (id as u32, tags)
+            })
+            .collect(),
+    };
+    let neighbor_ids: HashMap<u32, Vec<u32>> = neighbors
+        .iter()
+        .map(|(id, neighbors)| (*id, neighbors.iter().map(|(n, _)| *n).collect()))
+        .collect();
+    let misplaced_ids =
+        outliers::misplaced_samples(&groups, &neighbor_ids, options.min_disagreement);
+
+    let isolated = isolated_ids
+        .into_iter()
+        .filter_map(|(id, score)| {
+            Some(IsolatedSample {
+                file: files.get(&(id as i64))?.clone(),
+                score,
+                mean_distance: mean_distance_of[&id],
+            })
+        })
+        .collect();
+    let misplaced = misplaced_ids
+        .into_iter()
+        .filter_map(|(id, group, neighbor_group, disagreement)| {
+            Some(MisplacedSample {
+                file: files.remove(&(id as i64))?,
+                group,
+                neighbor_group,
+                disagreement,
+            })
+        })
+        .collect();
+    Ok(OutlierReport {
+        isolated,
+        misplaced,
+    })
+}
+
+/// Builds the k-nearest neighbor graph of the samples in an index, linking each sample to its
+/// nearest neighbors. Neighbors are queried in batches of graph::BATCH_SIZE samples, and
+/// progress_callback is called with the share of samples done after each batch.
+pub fn knn_graph(
+    options: &GraphOptions,
+    progress_callback: impl Fn(f32),
+) -> Result<KnnGraph, String> {
+    let index = vector_db::index_named(&options.index)?;
+    let md_db = MetadataDatabase::load_from_disk()?;
+    ensure_indexes_are_current(&md_db)?;
+    let mut files = md_db.get_audio_files_matching(&SampleFilter::default())?;
+    files.sort_by_key(|file| file.id());
+    let clusters = md_db.get_cluster_assignments()?;
+    let ids: Vec<u32> = files.iter().map(|file| file.id() as u32).collect();
+
+    let vec_db = VectorDatabase::load_from_disk()?;
+    let max_distance = options.max_distance.unwrap_or(f32::INFINITY);
+    let edges = vec_db
+        .neighbors_within_batched(
+            index,
+            &ids,
+            options.num_neighbors,
+            max_distance,
+            graph::BATCH_SIZE,
+            |done| progress_callback(done as f32 / ids.len() as f32),
+        )?
+        .into_iter()
+        .map(|(source, target, distance)| GraphEdge::new(source, target, distance))
+        .collect();
+
+    let nodes = files
+        .into_iter()
+        .map(|file| GraphNode {
+            id: file.id(),
+            path: file.path().to_string(),
+            cluster: clusters.get(&file.id()).copied(),
+        })
+        .collect();
+    Ok(KnnGraph { nodes, edges })
+}
+
+/// Builds a sequence of options.length samples that moves from start_id to end_id through
+/// the similar samples between them in an index, e.g. for a sample chain that morphs from one
+/// sound into another. No sample appears more than once. The sequence is shorter than
+/// options.length if the library runs out of samples.
+pub fn sample_sequence(
+    start_id: u32,
+    end_id: u32,
+    options: &SequenceOptions,
+) -> Result<Vec<SequenceStep>, String> {
+    if start_id == end_id {
+        return Err("The start and end samples must be different".to_string());
+    }
+    if options.length < 2 {
+        return Err("A sequence needs at least 2 samples".to_string());
+    }
+    let index = vector_db::index_named(&options.index)?;
+    let md_db = MetadataDatabase::load_from_disk()?;
+    ensure_indexes_are_current(&md_db)?;
+    let files: HashMap<i64, AudioFile> = md_db
+        .get_audio_files_matching(&SampleFilter::default())?
+        .into_iter()
+        .map(|file| (file.id(), file))
+        .collect();
+    let vec_db = VectorDatabase::load_from_disk()?;
+    // Only the directions of vectors in angular indexes are meaningful, so interpolate
+    // between their normalized vectors
+    let vectors_of = |ids: &[u32]| -> Result<Vec<Vec<f32>>, String> {
+        vec_db
+            .item_vectors(index, ids)?
+            .into_iter()
+            .zip(ids)
+            .map(|(vector, id)| {
+                let vector = vector.ok_or(format!("No analyzed sample with ID {id}"))?;
+                Ok(centroid(index, std::iter::once(&vector)))
+            })
+            .collect()
+    };
+    let mut candidates: RoaringBitmap = files.keys().map(|id| *id as u32).collect();
+    candidates.remove(start_id);
+    candidates.remove(end_id);
+    // Returns the nearest candidate to vector, and removes it from the candidates
+    let snap = |vector: &[f32], candidates: &mut RoaringBitmap| -> Result<Option<u32>, String> {
+        let nearest = vec_db
+            .find_similar_to_vector(index, vector, 1, Some(candidates))?
+            .first()
+            .copied();
+        if let Some(id) = nearest {
+            candidates.remove(id);
+        }
+        Ok(nearest)
+    };
+
+    let ids = match options.method {
+        SequenceMethod::Interpolate => {
+            let ends = vectors_of(&[start_id, end_id])?;
+            let mut ids = vec![start_id];
+            for i in 1..options.length - 1 {
+                let t = i as f32 / (options.length - 1) as f32;
+                if let Some(id) = snap(
+                    &sequence::interpolate(&ends[0], &ends[1], t),
+                    &mut candidates,
+                )? {
+                    ids.push(id);
+                }
+            }
+            ids.push(end_id);
+            ids
+        }
+        SequenceMethod::GraphWalk { num_neighbors } => {
+            for id in [start_id, end_id] {
+                if !files.contains_key(&(id as i64)) {
+                    return Err(format!("No analyzed sample with ID {id}"));
+                }
+            }
+            let path = vec_db
+                .with_neighbor_lookup(index, num_neighbors, |lookup| {
+                    graph::shortest_path(start_id, end_id, |id| {
+                        Ok(lookup(id)?
+                            .into_iter()
+                            .filter(|(neighbor, _)| files.contains_key(&(*neighbor as i64)))
+                            .collect())
+                    })
+                })??
+                .ok_or(format!(
+                    "Sample {end_id} can't be reached from sample {start_id} through their {num_neighbors} nearest neighbors. Try more neighbors, or interpolate instead."
+                ))?;
+            let vectors = vectors_of(&path)?;
+            let gaps: Vec<f32> = vectors
+                .windows(2)
+                .map(|pair| index.metric.distance(&pair[0], &pair[1]))
+                .collect();
+            if path.len() >= options.length {
+                sequence::evenly_spaced(&gaps, options.length)
+                    .into_iter()
+                    .map(|step| path[step])
+                    .collect()
+            } else {
+                for id in path.iter() {
+                    candidates.remove(*id);
+                }
+                let counts = sequence::split_gaps(&gaps, options.length - path.len());
+                let mut ids = Vec::new();
+                for (i, count) in counts.into_iter().enumerate() {
+                    ids.push(path[i]);
+                    for j in 1..=count {
+                        let t = j as f32 / (count + 1) as f32;
+                        let vector = sequence::interpolate(&vectors[i], &vectors[i + 1], t);
+                        if let Some(id) = snap(&vector, &mut candidates)? {
+                            ids.push(id);
+                        }
+                    }
+                }
+                ids.push(end_id);
+                ids
+            }
+        }
+    };
+
+    let vectors = vectors_of(&ids)?;
+    ids.iter()
+        .enumerate()
+        .map(|(i, id)| {
+            let file = files
+                .get(&(*id as i64))
+                .ok_or(format!("No sample with ID {id}"))?;
+            let distance = match i {
+                0 => 0.0,
+                _ => index.metric.distance(&vectors[i - 1], &vectors[i]),
+            };
+            Ok(SequenceStep {
+                id: file.id(),
+                path: file.path().to_string(),
+                duration: file.properties().map(|properties| properties.duration),
+                distance,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use feature::AnalyzedFile;
+    use feature_extractor::AudioProperties;
+    use std::path::Path;
+    use vector_db::VectorIndex;
+
+    fn analyzed_file(path: &str, indexes: &[&'static VectorIndex]) -> AnalyzedFile {
+        AnalyzedFile {
+            path: path.to_string(),
+            properties: AudioProperties {
+                duration: 1.0,
+                sample_rate: 44100,
+                channels: 2,
+                bit_depth: Some(16),
+                codec: "pcm".to_string(),
+                file_size: 176444,
+            },
+            tags: Vec::new(),
+            content_hash: None,
+            fingerprint: None,
+            segmented: false,
+            replaced_segments: Vec::new(),
+            features: indexes
+                .iter()
+                .map(|index| Feature::new(vec![1.0], path.to_string(), index, None))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn files_missing_a_vector_from_any_index_arent_cached() {
+        let mut db = MetadataDatabase::open(Path::new(":memory:")).unwrap();
+        let dir_id = db.initialize("/lib").unwrap();
+        let mut files = [
+            analyzed_file("/lib/kick.wav", &INDEXES),
+            analyzed_file("/lib/snare.wav", &[&TIMBRE_INDEX]),
+        ];
+        db.insert_samples(&mut files, dir_id).unwrap();
+
+        let cached = get_cached_files(&db).unwrap();
+        assert_eq!(cached, HashSet::from(["/lib/kick.wav".to_string()]));
+    }
+
+    #[test]
+    fn reciprocal_rank_fusion_favors_ids_ranked_highly_by_several_rankings() {
+        let fused = reciprocal_rank_fusion(vec![vec![1, 2, 3], vec![3, 2, 4], vec![5, 2]]);
+        assert_eq!(fused, [2, 3, 1, 5, 4]);
+    }
+
+    #[test]
+    fn reciprocal_rank_fusion_breaks_ties_by_id() {
+        let fused = reciprocal_rank_fusion(vec![vec![7, 1], vec![1, 7], vec![9]]);
+        assert_eq!(fused, [1, 7, 9]);
+    }
+
+    #[test]
+    fn candidates_near_a_negative_are_ranked_lower() {
+        // Candidate 0 is nearest the query but right next to the negative
+        let distances = vec![(1.0, vec![(0.2, 0.1), (0.3, 0.9), (0.5, 0.5)])];
+        assert_eq!(penalized_order(&distances, 0.5), [1, 0, 2]);
+        assert_eq!(penalized_order(&distances, 0.0), [0, 1, 2]);
+    }
+
+    #[test]
+    fn penalties_are_weighted_per_index() {
+        // Index distances are scaled to the same range, so only the weights decide
+        let distances = vec![
+            (1.0, vec![(1.0, 0.0), (0.0, 0.0)]),
+            (3.0, vec![(0.0, 0.0), (100.0, 0.0)]),
+        ];
+        assert_eq!(penalized_order(&distances, 1.0), [0, 1]);
+        assert!(penalized_order(&[], 1.0).is_empty());
+    }
+
+    #[test]
+    fn steering_moves_queries_away_from_negatives() {
+        let mut vector = [1.0, 2.0];
+        steer_away(&mut vector, &[0.0, 2.0], 0.5);
+        assert_eq!(vector, [1.5, 2.0]);
+        steer_away(&mut vector, &[0.0, 0.0], 0.0);
+        assert_eq!(vector, [1.5, 2.0]);
+    }
+
+    #[test]
+    fn centroids_of_angular_vectors_ignore_their_length() {
+        let mut a = vec![0.0; TIMBRE_INDEX.dimensions()];
+        let mut b = a.clone();
+        a[0] = 2.0;
+        b[1] = 10.0;
+        let mean = centroid(&TIMBRE_INDEX, [a, b].iter());
+        assert_eq!(&mean[..3], [0.5, 0.5, 0.0]);
+    }
 }
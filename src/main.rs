@@ -1,6 +1,35 @@
-use audio_similarity_search::{analyze_and_build_db, metadata_db, vector_db::VectorDatabase};
-use clap::{Parser, Subcommand};
-use metadata_db::MetadataDatabase;
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use audio_similarity_search::{
+    analyze_and_build_db,
+    classification::{self, ClassifyOptions},
+    classify_library, clear_dimension_weights, cluster_library,
+    clustering::{
+        ClusterMethod, ClusterOptions, KSelection, DEFAULT_MAX_K, DEFAULT_MIN_CLUSTER_SIZE,
+    },
+    dedupe::{DedupeOptions, DuplicateAction, DuplicateKind, DEFAULT_MAX_DISTANCE},
+    diversity::Diversity,
+    export::{self, GraphFormat, MapFormat, SequenceFormat},
+    feature_extractor::{AnalysisOptions, Normalization, Preprocessing, RunMode, Segmentation},
+    find_duplicates, find_fingerprint_matches, find_outliers, find_similar_in_indexes,
+    find_similar_segments, find_similar_to_examples, find_similar_with_feedback,
+    graph::{self, GraphOptions},
+    import_labels, knn_graph, learn_dimension_weights, list_audio_files, list_clusters, map_points,
+    metadata_db::{
+        Cluster, ListOptions, MetadataDatabase, SampleFilter, SortDirection, SortKey, MAX_RATING,
+    },
+    outliers::{self, Grouping, OutlierOptions},
+    project_library,
+    projection::{ProjectionMethod, ProjectionOptions, DEFAULT_NUM_NEIGHBORS},
+    reanalyze, record_feedback, resolve_duplicates, sample_sequence,
+    sequence::{self, SequenceMethod, SequenceOptions},
+    set_label, Example, ExampleQuery, Fusion, Steering,
+};
+use clap::{Args, Parser, Subcommand};
 
 #[derive(Parser, Debug)]
 #[command(
@@ -18,49 +47,1285 @@ enum Commands {
     Analyze {
         #[arg(value_name = "SOURCE_DIR")]
         source_dir: String,
+        #[command(flatten)]
+        analysis: AnalysisArgs,
+    },
+    /// Re-analyze samples whose feature vectors were produced by an outdated version of the
+    /// feature extractor, then rebuild the vector database.
+    Reanalyze {
+        #[command(flatten)]
+        analysis: AnalysisArgs,
+        /// OPTIONAL: Remove samples that can't be re-analyzed, e.g. because their file was
+        /// deleted, along with their tags, ratings and other annotations. By default they're
+        /// reported and kept.
+        #[arg(long)]
+        prune: bool,
     },
-    /// Run similarity search for a given sample
+    /// Run similarity search for a given sample, or for several examples with --id and --file
     Search {
         /// The source sample ID
+        #[arg(value_name = "SAMPLE_ID", required_unless_present_any = ["ids", "files"])]
+        id: Option<u32>,
+        /// OPTIONAL: How many results to return
+        #[arg(short = 'n', long, value_name = "NUM_RESULTS", default_value_t = 10)]
+        num_results: usize,
+        #[command(flatten)]
+        examples: ExampleArgs,
+        /// OPTIONAL: The index to search, optionally with a weight, e.g. `--index rhythm` or
+        /// `--index timbre=0.7 --index rhythm=0.3`. Available indexes are timbre, rhythm and
+        /// pitch. Results from multiple indexes are combined by weighted distance. Defaults
+        /// to timbre.
+        #[arg(long = "index", value_name = "NAME[=WEIGHT]", value_parser = parse_index_weight)]
+        indexes: Vec<(String, f32)>,
+        /// OPTIONAL: Search the segments of long files instead of whole samples, printing the
+        /// matching time range of each result. Requires files analyzed with --segment.
+        #[arg(long, conflicts_with_all = ["indexes", "filter", "examples"])]
+        segments: bool,
+        /// OPTIONAL: Refine the search with the feedback recorded for SAMPLE_ID using the
+        /// feedback command, and the dimension weights learned with learn-weights. Searches a
+        /// single index.
+        #[arg(long, conflicts_with_all = ["examples", "segments"])]
+        refine: bool,
+        /// OPTIONAL: Vary the results so they aren't near copies of each other, using maximal
+        /// marginal relevance. LAMBDA from 0 to 1 trades similarity to SAMPLE_ID (1) against
+        /// variety (0); 0.5 balances the two.
+        #[arg(long, value_name = "LAMBDA", conflicts_with_all = ["examples", "segments", "refine"])]
+        diversify: Option<f32>,
+        /// OPTIONAL: Return only the most similar result from each folder, e.g. one sample per
+        /// pack
+        #[arg(long, conflicts_with_all = ["examples", "segments", "refine"])]
+        one_per_folder: bool,
+        #[command(flatten)]
+        filter: FilterArgs,
+    },
+    /// Marks results of a search for a sample as relevant or irrelevant, so later searches
+    /// with --refine move towards the relevant ones. Prints the recorded feedback when no
+    /// results are given.
+    Feedback {
+        /// The sample ID that was searched for
         #[arg(value_name = "SAMPLE_ID")]
         id: u32,
-        /// How many results to return
-        #[arg(value_name = "NUM_RESULTS")]
-        num_results: usize,
+        /// OPTIONAL: A result that is similar to SAMPLE_ID. Can be repeated.
+        #[arg(long, value_name = "SAMPLE_ID")]
+        relevant: Vec<u32>,
+        /// OPTIONAL: A result that isn't similar to SAMPLE_ID. Can be repeated.
+        #[arg(long, value_name = "SAMPLE_ID")]
+        irrelevant: Vec<u32>,
+        /// OPTIONAL: Remove the feedback recorded for SAMPLE_ID before recording any new
+        /// feedback
+        #[arg(long)]
+        clear: bool,
+    },
+    /// Learns which dimensions of an index tell relevant results apart from irrelevant ones
+    /// using all recorded feedback, and weighs them accordingly when search is given a single
+    /// SAMPLE_ID and searches only that index. Searches for several examples or indexes, and
+    /// the other commands, use unweighted distances.
+    LearnWeights {
+        /// OPTIONAL: The index to learn weights for
+        #[arg(long, value_name = "NAME", default_value = "timbre")]
+        index: String,
+        /// OPTIONAL: Remove the learned weights instead
+        #[arg(long)]
+        clear: bool,
     },
     /// Lists all analyzed sample paths and their IDs
     List {
         /// OPTIONAL: The maximum number of samples to return
         #[arg(value_name = "LIMIT")]
         limit: Option<u32>,
+        /// OPTIONAL: The number of samples to skip
+        #[arg(long, default_value_t = 0)]
+        offset: u32,
+        /// OPTIONAL: Continue from the cursor printed after the previous page
+        #[arg(long)]
+        cursor: Option<String>,
+        /// OPTIONAL: The order to list samples in: path, name, duration, added or bpm
+        #[arg(long, default_value = "path", value_parser = SortKey::from_name)]
+        sort: SortKey,
+        /// OPTIONAL: List samples in descending order
+        #[arg(long)]
+        desc: bool,
+        #[command(flatten)]
+        filter: FilterArgs,
+    },
+    /// Finds samples whose file name, directory or tags contain the given words, ordered by
+    /// relevance, e.g. `find "snare tight"`. Words match as prefixes, and misspelled words are
+    /// matched to similarly spelled ones when nothing else matches.
+    Find {
+        #[arg(value_name = "TEXT")]
+        text: String,
+        /// OPTIONAL: The maximum number of samples to return
+        #[arg(value_name = "LIMIT")]
+        limit: Option<u32>,
+    },
+    /// Finds samples that are the same recording as a sample or file, even if they've been
+    /// re-encoded, trimmed or changed in level. Prints each match's similarity and the time in
+    /// seconds at which it starts in the query.
+    Identify {
+        /// The sample ID
+        #[arg(value_name = "SAMPLE_ID", required_unless_present = "file")]
+        id: Option<u32>,
+        /// OPTIONAL: A file to identify instead of a sample. It doesn't need to be analyzed.
+        #[arg(long, value_name = "PATH", conflicts_with = "id")]
+        file: Option<String>,
+    },
+    /// Prints the audio properties, tags, rating and collections of a sample
+    Info {
+        /// The sample ID
+        #[arg(value_name = "SAMPLE_ID")]
+        id: u32,
+    },
+    /// Adds user tags to a sample
+    Tag {
+        #[arg(value_name = "SAMPLE_ID")]
+        id: u32,
+        #[arg(value_name = "TAG", required = true)]
+        tags: Vec<String>,
+    },
+    /// Removes user tags from a sample
+    Untag {
+        #[arg(value_name = "SAMPLE_ID")]
+        id: u32,
+        #[arg(value_name = "TAG", required = true)]
+        tags: Vec<String>,
+    },
+    /// Lists all user tags and how many samples have each
+    Tags,
+    /// Rates a sample from 1 to 5 stars. A rating of 0 clears it.
+    Rate {
+        #[arg(value_name = "SAMPLE_ID")]
+        id: u32,
+        #[arg(value_name = "STARS", value_parser = clap::value_parser!(u8).range(0..=MAX_RATING as i64))]
+        stars: u8,
+    },
+    /// Marks a sample as a favorite
+    Favorite {
+        #[arg(value_name = "SAMPLE_ID")]
+        id: u32,
+        /// Unmark the sample instead
+        #[arg(long)]
+        remove: bool,
+    },
+    /// Manages named collections of samples
+    Collection {
+        #[command(subcommand)]
+        command: CollectionCommands,
+    },
+    /// Finds exact and near duplicate samples and suggests which copy of each to keep,
+    /// preferring lossless formats and higher bit depths. Duplicates can be moved or replaced
+    /// with hard links, which is only previewed unless --apply is passed.
+    Dedupe {
+        /// OPTIONAL: The index used to find near duplicates
+        #[arg(long, value_name = "NAME", default_value = "timbre")]
+        index: String,
+        /// OPTIONAL: The maximum distance between near duplicates in the index
+        #[arg(long, value_name = "DISTANCE", default_value_t = DEFAULT_MAX_DISTANCE)]
+        threshold: f32,
+        /// OPTIONAL: Only find files with identical contents
+        #[arg(long, conflicts_with_all = ["threshold", "verify_audio"])]
+        exact_only: bool,
+        /// OPTIONAL: Confirm near duplicates by comparing their audio. This is much slower.
+        #[arg(long)]
+        verify_audio: bool,
+        /// OPTIONAL: Move duplicates into DIR, mirroring their original paths
+        #[arg(long = "move", value_name = "DIR", conflicts_with = "hardlink")]
+        move_to: Option<PathBuf>,
+        /// OPTIONAL: Replace exact duplicates with hard links to the file that's kept
+        #[arg(long)]
+        hardlink: bool,
+        /// OPTIONAL: Make the changes instead of previewing them
+        #[arg(long)]
+        apply: bool,
+    },
+    /// Groups the library into clusters of similar samples, replacing the previous clustering,
+    /// and prints each cluster's ID, size and medoid, the sample that best represents it.
+    /// Searches can be limited to a cluster with --cluster.
+    Cluster {
+        /// OPTIONAL: The index whose vectors are clustered
+        #[arg(long, value_name = "NAME", default_value = "timbre")]
+        index: String,
+        /// OPTIONAL: The number of k-means clusters, or how to choose it: silhouette picks the
+        /// best separated clusters, and elbow the point where more clusters stop helping
+        #[arg(long, value_name = "N|silhouette|elbow", default_value = "silhouette", value_parser = parse_k_selection)]
+        k: KSelection,
+        /// OPTIONAL: The largest k tried when choosing k
+        #[arg(long, value_name = "N", default_value_t = DEFAULT_MAX_K)]
+        max_k: usize,
+        /// OPTIONAL: Use HDBSCAN instead of k-means, which finds the number of clusters itself
+        /// and leaves samples that don't belong to any as noise
+        #[arg(long, conflicts_with_all = ["k", "max_k"])]
+        hdbscan: bool,
+        /// OPTIONAL: The smallest group of samples HDBSCAN considers a cluster
+        #[arg(long, value_name = "N", default_value_t = DEFAULT_MIN_CLUSTER_SIZE, requires = "hdbscan")]
+        min_cluster_size: usize,
+        /// OPTIONAL: Print the clusters from the previous clustering instead
+        #[arg(long, conflicts_with_all = ["index", "k", "max_k", "hdbscan"])]
+        list: bool,
+    },
+    /// Lays the library out on a 2D map where similar samples are close together, and exports
+    /// it for sample browsers
+    Map {
+        #[command(subcommand)]
+        command: MapCommands,
+    },
+    /// Predicts the sound type of every sample, e.g. kick, snare or vocal, from a few labeled
+    /// examples of each. Samples vote on the types of their nearest labeled neighbors, and
+    /// predictions can be searched with --class.
+    Classify {
+        /// OPTIONAL: A CSV file of sample IDs or paths and their labels, e.g. `12,kick`, to
+        /// learn from in addition to labels given earlier
+        #[arg(long, value_name = "PATH")]
+        labels: Option<PathBuf>,
+        /// OPTIONAL: The index whose neighbors vote on each sample's type
+        #[arg(long, value_name = "NAME", default_value = "timbre")]
+        index: String,
+        /// OPTIONAL: The number of labeled neighbors that vote on each sample's type
+        #[arg(long, value_name = "N", default_value_t = classification::DEFAULT_NUM_NEIGHBORS)]
+        neighbors: usize,
+    },
+    /// Labels a sample's sound type, correcting its prediction. Labels are used as examples
+    /// the next time classify runs.
+    Label {
+        #[arg(value_name = "SAMPLE_ID")]
+        id: u32,
+        #[arg(value_name = "LABEL", required_unless_present = "remove")]
+        label: Option<String>,
+        /// Remove the sample's label instead
+        #[arg(long, conflicts_with = "label")]
+        remove: bool,
+    },
+    /// Reports samples that are far from every other sample, which are often corrupt or
+    /// silent, and samples whose nearest neighbors mostly live in a different folder, e.g. a
+    /// snare in a folder of kicks. Each list is ranked from the most suspicious sample.
+    Outliers {
+        /// OPTIONAL: The index whose nearest neighbors samples are compared with
+        #[arg(long, value_name = "NAME", default_value = "timbre")]
+        index: String,
+        /// OPTIONAL: The number of nearest neighbors each sample is compared with
+        #[arg(long, value_name = "N", default_value_t = outliers::DEFAULT_NUM_NEIGHBORS)]
+        neighbors: usize,
+        /// OPTIONAL: What neighbors are expected to share: `folder`, or `tag` to compare user
+        /// tags
+        #[arg(long, value_name = "GROUPING", default_value = "folder", value_parser = Grouping::from_name)]
+        group_by: Grouping,
+        /// OPTIONAL: The share of neighbors from other groups at which a sample is reported
+        /// as misplaced
+        #[arg(long, value_name = "SHARE", default_value_t = outliers::DEFAULT_MIN_DISAGREEMENT)]
+        min_disagreement: f32,
+        /// OPTIONAL: How many deviations above the median distance to its neighbors a sample
+        /// must be to be reported as isolated
+        #[arg(long, value_name = "SCORE", default_value_t = outliers::DEFAULT_MIN_ISOLATION)]
+        min_isolation: f32,
+    },
+    /// Exports the k-nearest neighbor graph of the library, with an edge from each sample to
+    /// each of its nearest neighbors, for graph tools like Gephi or networkx
+    Graph {
+        /// OPTIONAL: The index whose nearest neighbors are linked
+        #[arg(long, value_name = "NAME", default_value = "timbre")]
+        index: String,
+        /// OPTIONAL: The number of nearest neighbors each sample is linked to
+        #[arg(long, value_name = "N", default_value_t = graph::DEFAULT_NUM_NEIGHBORS)]
+        neighbors: usize,
+        /// OPTIONAL: Don't link neighbors farther than this
+        #[arg(long, value_name = "DISTANCE")]
+        max_distance: Option<f32>,
+        /// OPTIONAL: The output format: graphml, gexf, csv for an edge list, or json for
+        /// networkx's node-link format
+        #[arg(long, value_name = "FORMAT", default_value = "json", value_parser = GraphFormat::from_name)]
+        format: GraphFormat,
+        /// OPTIONAL: Write the graph to PATH instead of printing it
+        #[arg(long, value_name = "PATH")]
+        output: Option<PathBuf>,
+    },
+    /// Builds a sequence of samples that morphs from one sample into another through the
+    /// similar samples between them, e.g. for a sample chain. No sample is repeated.
+    Path {
+        #[arg(value_name = "START_ID")]
+        start_id: u32,
+        #[arg(value_name = "END_ID")]
+        end_id: u32,
+        /// OPTIONAL: The number of samples in the sequence, including the start and end
+        #[arg(long, value_name = "N", default_value_t = sequence::DEFAULT_LENGTH)]
+        length: usize,
+        /// OPTIONAL: The index whose vectors the sequence moves through
+        #[arg(long, value_name = "NAME", default_value = "timbre")]
+        index: String,
+        /// OPTIONAL: `interpolate` steps evenly between the start and end, taking the nearest
+        /// sample at each step, while `graph` walks through nearest neighbors so each sample
+        /// is close to the one before it
+        #[arg(long, value_name = "METHOD", default_value = "interpolate", value_parser = ["interpolate", "graph"])]
+        method: String,
+        /// OPTIONAL: The number of nearest neighbors each sample is linked to by `--method
+        /// graph`. More neighbors connect more of the library.
+        #[arg(long, value_name = "N", default_value_t = sequence::DEFAULT_NUM_NEIGHBORS)]
+        neighbors: usize,
+        /// OPTIONAL: The output format, m3u or json
+        #[arg(long, value_name = "FORMAT", default_value = "m3u", value_parser = SequenceFormat::from_name)]
+        format: SequenceFormat,
+        /// OPTIONAL: Write the sequence to PATH instead of printing it
+        #[arg(long, value_name = "PATH")]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum MapCommands {
+    /// Projects every sample onto the map, replacing the previous map
+    Build {
+        /// OPTIONAL: The index whose vectors are projected
+        #[arg(long, value_name = "NAME", default_value = "timbre")]
+        index: String,
+        /// OPTIONAL: `umap` separates groups of similar samples, while `pca` is faster and
+        /// keeps distances between groups meaningful
+        #[arg(long, value_name = "METHOD", default_value = "umap", value_parser = ["umap", "pca"])]
+        method: String,
+        /// OPTIONAL: The number of neighbors UMAP keeps close to each sample. Higher values
+        /// favor the overall layout over local detail.
+        #[arg(long, value_name = "N", default_value_t = DEFAULT_NUM_NEIGHBORS)]
+        neighbors: usize,
+    },
+    /// Prints the map's id, path, x, y and cluster of each sample. Coordinates range from 0 to
+    /// 1, and samples not in any cluster have no cluster.
+    Export {
+        /// OPTIONAL: The output format, json or csv
+        #[arg(long, value_name = "FORMAT", default_value = "json", value_parser = MapFormat::from_name)]
+        format: MapFormat,
+        /// OPTIONAL: Write the map to PATH instead of printing it
+        #[arg(long, value_name = "PATH")]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum CollectionCommands {
+    /// Adds samples to a collection, creating it if necessary
+    Add {
+        #[arg(value_name = "NAME")]
+        name: String,
+        #[arg(value_name = "SAMPLE_ID", required = true)]
+        ids: Vec<u32>,
+    },
+    /// Removes samples from a collection
+    Remove {
+        #[arg(value_name = "NAME")]
+        name: String,
+        #[arg(value_name = "SAMPLE_ID", required = true)]
+        ids: Vec<u32>,
+    },
+    /// Deletes a collection, leaving its samples untouched
+    Delete {
+        #[arg(value_name = "NAME")]
+        name: String,
     },
+    /// Lists all collections, or the samples in a collection when NAME is provided
+    List {
+        #[arg(value_name = "NAME")]
+        name: Option<String>,
+    },
+}
+
+/// Additional examples to search for, and examples whose character results should avoid
+#[derive(Args, Debug)]
+#[group(id = "examples", multiple = true)]
+struct ExampleArgs {
+    /// OPTIONAL: Another sample ID to use as an example, e.g. `--id 12 --id 40`. Can be
+    /// repeated. Examples are excluded from the results.
+    #[arg(long = "id", value_name = "SAMPLE_ID")]
+    ids: Vec<u32>,
+    /// OPTIONAL: An audio file to use as an example, which doesn't need to have been
+    /// analyzed. Can be repeated.
+    #[arg(long = "file", value_name = "PATH")]
+    files: Vec<String>,
+    /// OPTIONAL: How results for several examples are combined: `centroid` searches for
+    /// what the examples have in common, and `rrf` finds samples that closely match any
+    /// of them using reciprocal rank fusion
+    #[arg(long, value_name = "METHOD", default_value = "centroid", value_parser = Fusion::from_name)]
+    fusion: Fusion,
+    /// OPTIONAL: A sample ID whose character results should avoid, e.g. one with too much
+    /// room reverb. Can be repeated.
+    #[arg(long = "not-id", value_name = "SAMPLE_ID")]
+    not_ids: Vec<u32>,
+    /// OPTIONAL: An audio file whose character results should avoid. Can be repeated.
+    #[arg(long = "not-file", value_name = "PATH")]
+    not_files: Vec<String>,
+    /// OPTIONAL: How strongly negative examples steer results away from them
+    #[arg(long, value_name = "ALPHA", default_value_t = 1.0)]
+    alpha: f32,
+    /// OPTIONAL: Rerank results by penalizing those close to a negative example, rather
+    /// than moving the query away from the negatives
+    #[arg(long)]
+    penalize: bool,
+}
+
+impl ExampleArgs {
+    /// Returns the query for these examples, with id as the first positive example
+    fn query(&self, id: Option<u32>) -> ExampleQuery {
+        let examples = |ids: &[u32], files: &[String]| -> Vec<Example> {
+            ids.iter()
+                .map(|id| Example::Sample(*id))
+                .chain(files.iter().map(|path| Example::File(path.clone())))
+                .collect()
+        };
+        let ids: Vec<u32> = id.iter().chain(self.ids.iter()).copied().collect();
+        ExampleQuery {
+            positives: examples(&ids, &self.files),
+            negatives: examples(&self.not_ids, &self.not_files),
+            fusion: self.fusion,
+            steering: if self.penalize {
+                Steering::Penalize { alpha: self.alpha }
+            } else {
+                Steering::Subtract { alpha: self.alpha }
+            },
+        }
+    }
+}
+
+/// Restricts search results to samples with matching audio properties and tags
+#[derive(Args, Debug)]
+#[group(id = "filter", multiple = true)]
+struct FilterArgs {
+    /// OPTIONAL: Only return samples at least this many seconds long
+    #[arg(long, value_name = "SECONDS")]
+    min_duration: Option<f32>,
+    /// OPTIONAL: Only return samples at most this many seconds long
+    #[arg(long, value_name = "SECONDS")]
+    max_duration: Option<f32>,
+    /// OPTIONAL: Only return samples with this sample rate in Hz
+    #[arg(long, value_name = "HZ")]
+    sample_rate: Option<u32>,
+    /// OPTIONAL: Only return samples with this many channels
+    #[arg(long, value_name = "N")]
+    channels: Option<u16>,
+    /// OPTIONAL: Only return samples with this bit depth
+    #[arg(long, value_name = "BITS")]
+    bit_depth: Option<u16>,
+    /// OPTIONAL: Only return samples with this codec, e.g. pcm, float or mp3
+    #[arg(long, value_name = "CODEC")]
+    codec: Option<String>,
+    /// OPTIONAL: Only return samples with an embedded tag containing VALUE, e.g.
+    /// `--meta genre=house` or `--meta artist="Some Artist"`. Can be repeated.
+    #[arg(long = "meta", value_name = "KEY=VALUE", value_parser = parse_tag)]
+    tags: Vec<(String, String)>,
+    /// OPTIONAL: Only return samples with this user tag. Can be repeated.
+    #[arg(long = "tag", value_name = "TAG")]
+    user_tags: Vec<String>,
+    /// OPTIONAL: Only return samples rated at least this many stars
+    #[arg(long, value_name = "STARS", value_parser = clap::value_parser!(u8).range(1..=MAX_RATING as i64))]
+    min_rating: Option<u8>,
+    /// OPTIONAL: Only return favorites
+    #[arg(long)]
+    favorites: bool,
+    /// OPTIONAL: Only return samples in this collection
+    #[arg(long, value_name = "NAME")]
+    collection: Option<String>,
+    /// OPTIONAL: Only return samples whose file name, directory or tags contain all of these
+    /// words, e.g. `--text "snare tight"`
+    #[arg(long, value_name = "TEXT")]
+    text: Option<String>,
+    /// OPTIONAL: Only return samples found when analyzing this directory
+    #[arg(long = "root", value_name = "DIR")]
+    root_dir: Option<String>,
+    /// OPTIONAL: Only return samples in this cluster, as numbered by the cluster command
+    #[arg(long, value_name = "CLUSTER_ID")]
+    cluster: Option<i64>,
+    /// OPTIONAL: Only return samples labeled or predicted to be this sound type by classify
+    #[arg(long = "class", value_name = "LABEL")]
+    label: Option<String>,
+}
+
+impl FilterArgs {
+    fn filter(&self) -> SampleFilter {
+        SampleFilter {
+            min_duration: self.min_duration,
+            max_duration: self.max_duration,
+            sample_rate: self.sample_rate,
+            channels: self.channels,
+            bit_depth: self.bit_depth,
+            codec: self.codec.clone(),
+            tags: self.tags.clone(),
+            user_tags: self.user_tags.clone(),
+            min_rating: self.min_rating,
+            favorites_only: self.favorites,
+            collection: self.collection.clone(),
+            text: self.text.clone(),
+            root_dir: self.root_dir.clone(),
+            cluster: self.cluster,
+            label: self.label.clone(),
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+struct AnalysisArgs {
+    /// OPTIONAL: The number of files to analyze in parallel. Defaults to the number of
+    /// CPUs. Pass 1 for a deterministic, single-threaded run.
+    #[arg(short, long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    jobs: Option<u32>,
+    /// OPTIONAL: Split long files into segments that can be searched individually. Pass a
+    /// length in seconds for fixed length segments, or `onsets` for segments aligned to
+    /// detected onsets.
+    #[arg(long, value_name = "SECONDS|onsets", value_parser = parse_segmentation)]
+    segment: Option<Segmentation>,
+    /// OPTIONAL: Remove any DC offset before analysis.
+    #[arg(long)]
+    remove_dc: bool,
+    /// OPTIONAL: Trim leading and trailing audio quieter than this level in dBFS, e.g. -60.
+    #[arg(long, value_name = "DB", allow_hyphen_values = true)]
+    trim_silence: Option<f32>,
+    /// OPTIONAL: Normalize loudness before analysis to an RMS level in dBFS or an integrated
+    /// loudness in LUFS, e.g. `rms:-20` or `lufs:-23`.
+    #[arg(long, value_name = "rms:DB|lufs:LUFS", value_parser = parse_normalization)]
+    normalize: Option<Normalization>,
+    /// OPTIONAL: Only analyze the first SECONDS of each file after trimming.
+    #[arg(long, value_name = "SECONDS")]
+    max_duration: Option<f32>,
+    /// OPTIONAL: Disable all preprocessing. Preprocessing options apply to the whole library,
+    /// and are remembered between runs. Changing them requires running reanalyze.
+    #[arg(
+        long,
+        conflicts_with_all = ["remove_dc", "trim_silence", "normalize", "max_duration"]
+    )]
+    no_preprocessing: bool,
+}
+
+impl AnalysisArgs {
+    fn options(&self) -> AnalysisOptions {
+        AnalysisOptions {
+            run_mode: self
+                .jobs
+                .map(|jobs| RunMode::with_jobs(jobs as usize))
+                .unwrap_or_default(),
+            segmentation: self.segment,
+            preprocessing: self.preprocessing(),
+        }
+    }
+
+    /// Returns the preprocessing requested on the command line, or None to keep the library's
+    /// current configuration
+    fn preprocessing(&self) -> Option<Preprocessing> {
+        let preprocessing = Preprocessing {
+            remove_dc_offset: self.remove_dc,
+            trim_silence_below_db: self.trim_silence,
+            normalization: self.normalize,
+            max_duration: self.max_duration,
+        };
+        if self.no_preprocessing || preprocessing != Preprocessing::default() {
+            Some(preprocessing)
+        } else {
+            None
+        }
+    }
 }
 
 fn main() {
     let cli = Cli::parse();
 
     match &cli.command {
-        Commands::Analyze { source_dir } => {
-            let _ = analyze_and_build_db(&source_dir, |_| {}).unwrap();
-        }
-        Commands::Search { id, num_results } => {
-            let db = VectorDatabase::load_from_disk().unwrap();
-            if let Ok(results) = db.find_similar(*id, *num_results) {
-                for result in results.iter() {
-                    println!("{result}");
+        Commands::Analyze {
+            source_dir,
+            analysis,
+        } => {
+            let _ = analyze_and_build_db(source_dir, analysis.options(), |_| {}).unwrap();
+        }
+        Commands::Reanalyze { analysis, prune } => {
+            let _ = reanalyze(analysis.options(), *prune, |_| {}).unwrap();
+        }
+        Commands::Search {
+            id: Some(id),
+            num_results,
+            segments: true,
+            ..
+        } => match find_similar_segments(*id, *num_results) {
+            Ok(results) => {
+                for segment in results.iter() {
+                    println!(
+                        "{} {:.2}s-{:.2}s {}",
+                        segment.file().id(),
+                        segment.start_time(),
+                        segment.end_time(),
+                        segment.file().path()
+                    );
                 }
             }
+            Err(e) => eprintln!("{e}"),
+        },
+        Commands::Search {
+            id,
+            num_results,
+            examples,
+            indexes,
+            filter,
+            refine,
+            diversify,
+            one_per_folder,
+            ..
+        } => {
+            let index_weights: Vec<(&str, f32)> = if indexes.is_empty() {
+                vec![("timbre", 1.0)]
+            } else {
+                indexes
+                    .iter()
+                    .map(|(name, weight)| (name.as_str(), *weight))
+                    .collect()
+            };
+            let query = examples.query(*id);
+            let results = match (query.positives.as_slice(), query.negatives.is_empty()) {
+                ([Example::Sample(id)], true) if *refine => match index_weights.as_slice() {
+                    [(index, _)] => {
+                        find_similar_with_feedback(*id, index, &filter.filter(), *num_results)
+                    }
+                    _ => Err("--refine searches a single index".to_string()),
+                },
+                ([Example::Sample(id)], true) => {
+                    let diversity = Diversity {
+                        lambda: *diversify,
+                        one_per_folder: *one_per_folder,
+                    };
+                    find_similar_in_indexes(
+                        *id,
+                        &index_weights,
+                        &filter.filter(),
+                        *num_results,
+                        &diversity,
+                    )
+                }
+                _ => {
+                    find_similar_to_examples(&query, &index_weights, &filter.filter(), *num_results)
+                }
+            };
+            match results {
+                Ok(results) => {
+                    for result in results.iter() {
+                        println!("{}", result.id());
+                    }
+                }
+                Err(e) => eprintln!("{e}"),
+            }
         }
-        Commands::List { limit } => {
-            list_samples(*limit);
+        Commands::Feedback {
+            id,
+            relevant,
+            irrelevant,
+            clear,
+        } => {
+            if let Err(e) = run_feedback_command(*id, relevant, irrelevant, *clear) {
+                eprintln!("{e}");
+            }
+        }
+        Commands::LearnWeights { index, clear: true } => {
+            if let Err(e) = clear_dimension_weights(index) {
+                eprintln!("{e}");
+            }
+        }
+        Commands::LearnWeights {
+            index,
+            clear: false,
+        } => match learn_dimension_weights(index) {
+            Ok(weights) => {
+                let weights: Vec<String> = weights.iter().map(|w| format!("{w:.2}")).collect();
+                println!("{}", weights.join(" "));
+            }
+            Err(e) => eprintln!("{e}"),
+        },
+        Commands::List {
+            limit,
+            offset,
+            cursor,
+            sort,
+            desc,
+            filter,
+        } => {
+            let options = ListOptions {
+                sort: *sort,
+                direction: if *desc {
+                    SortDirection::Descending
+                } else {
+                    SortDirection::Ascending
+                },
+                filter: filter.filter(),
+                cursor: cursor.clone(),
+                offset: *offset,
+                limit: *limit,
+            };
+            if let Err(e) = list_samples(&options) {
+                eprintln!("{e}");
+            }
+        }
+        Commands::Find { text, limit } => {
+            match MetadataDatabase::load_from_disk().and_then(|db| db.search_text(text, *limit)) {
+                Ok(files) => {
+                    for file in files {
+                        println!("{} {}", file.id(), file.path());
+                    }
+                }
+                Err(e) => eprintln!("{e}"),
+            }
         }
+        Commands::Identify { id, file } => {
+            let example = match (id, file) {
+                (_, Some(path)) => Example::File(path.clone()),
+                (Some(id), None) => Example::Sample(*id),
+                (None, None) => unreachable!("clap requires an ID or file"),
+            };
+            match find_fingerprint_matches(&example) {
+                Ok(matches) => {
+                    for m in matches {
+                        println!(
+                            "{} {:.2} {:.2} {}",
+                            m.file.id(),
+                            m.similarity,
+                            m.offset,
+                            m.file.path()
+                        );
+                    }
+                }
+                Err(e) => eprintln!("{e}"),
+            }
+        }
+        Commands::Info { id } => {
+            if let Err(e) = print_sample_info(*id) {
+                eprintln!("{e}");
+            }
+        }
+        Commands::Tag { id, tags } => {
+            let result = MetadataDatabase::load_from_disk()
+                .and_then(|mut db| db.add_user_tags(*id as i64, tags));
+            if let Err(e) = result {
+                eprintln!("{e}");
+            }
+        }
+        Commands::Untag { id, tags } => {
+            let result = MetadataDatabase::load_from_disk()
+                .and_then(|mut db| db.remove_user_tags(*id as i64, tags));
+            if let Err(e) = result {
+                eprintln!("{e}");
+            }
+        }
+        Commands::Tags => match MetadataDatabase::load_from_disk().and_then(|db| db.list_user_tags())
+        {
+            Ok(tags) => {
+                for (tag, count) in tags {
+                    println!("{tag} ({count})");
+                }
+            }
+            Err(e) => eprintln!("{e}"),
+        },
+        Commands::Rate { id, stars } => {
+            let rating = (*stars > 0).then_some(*stars);
+            let result =
+                MetadataDatabase::load_from_disk().and_then(|db| db.set_rating(*id as i64, rating));
+            if let Err(e) = result {
+                eprintln!("{e}");
+            }
+        }
+        Commands::Favorite { id, remove } => {
+            let result = MetadataDatabase::load_from_disk()
+                .and_then(|db| db.set_favorite(*id as i64, !remove));
+            if let Err(e) = result {
+                eprintln!("{e}");
+            }
+        }
+        Commands::Collection { command } => {
+            if let Err(e) = run_collection_command(command) {
+                eprintln!("{e}");
+            }
+        }
+        Commands::Dedupe {
+            index,
+            threshold,
+            exact_only,
+            verify_audio,
+            move_to,
+            hardlink,
+            apply,
+        } => {
+            let options = DedupeOptions {
+                index: index.clone(),
+                max_distance: (!exact_only).then_some(*threshold),
+                verify_audio: *verify_audio,
+            };
+            let action = match (move_to, hardlink) {
+                (Some(dir), _) => Some(DuplicateAction::Move { to: dir.clone() }),
+                (None, true) => Some(DuplicateAction::HardLink),
+                (None, false) => None,
+            };
+            if let Err(e) = run_dedupe_command(&options, action.as_ref(), *apply) {
+                eprintln!("{e}");
+            }
+        }
+        Commands::Cluster {
+            index,
+            k,
+            max_k,
+            hdbscan,
+            min_cluster_size,
+            list,
+        } => {
+            let method = if *hdbscan {
+                ClusterMethod::Hdbscan {
+                    min_cluster_size: *min_cluster_size,
+                }
+            } else {
+                ClusterMethod::KMeans(match k {
+                    KSelection::Fixed(k) => KSelection::Fixed(*k),
+                    KSelection::Silhouette { .. } => KSelection::Silhouette { max_k: *max_k },
+                    KSelection::Elbow { .. } => KSelection::Elbow { max_k: *max_k },
+                })
+            };
+            let options = ClusterOptions {
+                index: index.clone(),
+                method,
+            };
+            let result = if *list {
+                list_clusters()
+            } else {
+                cluster_library(&options)
+            };
+            match result {
+                Ok(clusters) => print_clusters(&clusters),
+                Err(e) => eprintln!("{e}"),
+            }
+        }
+        Commands::Map { command } => {
+            if let Err(e) = run_map_command(command) {
+                eprintln!("{e}");
+            }
+        }
+        Commands::Classify {
+            labels,
+            index,
+            neighbors,
+        } => {
+            let options = ClassifyOptions {
+                index: index.clone(),
+                num_neighbors: *neighbors,
+            };
+            if let Err(e) = run_classify_command(labels.as_deref(), &options) {
+                eprintln!("{e}");
+            }
+        }
+        Commands::Outliers {
+            index,
+            neighbors,
+            group_by,
+            min_disagreement,
+            min_isolation,
+        } => {
+            let options = OutlierOptions {
+                index: index.clone(),
+                num_neighbors: *neighbors,
+                grouping: *group_by,
+                min_disagreement: *min_disagreement,
+                min_isolation: *min_isolation,
+            };
+            if let Err(e) = run_outliers_command(&options) {
+                eprintln!("{e}");
+            }
+        }
+        Commands::Graph {
+            index,
+            neighbors,
+            max_distance,
+            format,
+            output,
+        } => {
+            let options = GraphOptions {
+                index: index.clone(),
+                num_neighbors: *neighbors,
+                max_distance: *max_distance,
+            };
+            if let Err(e) = run_graph_command(&options, *format, output.as_deref()) {
+                eprintln!("{e}");
+            }
+        }
+        Commands::Path {
+            start_id,
+            end_id,
+            length,
+            index,
+            method,
+            neighbors,
+            format,
+            output,
+        } => {
+            let method = match method.as_str() {
+                "graph" => SequenceMethod::GraphWalk {
+                    num_neighbors: *neighbors,
+                },
+                _ => SequenceMethod::Interpolate,
+            };
+            let options = SequenceOptions {
+                index: index.clone(),
+                length: *length,
+                method,
+            };
+            if let Err(e) =
+                run_path_command(*start_id, *end_id, &options, *format, output.as_deref())
+            {
+                eprintln!("{e}");
+            }
+        }
+        Commands::Label { id, label, remove } => {
+            let label = if *remove { None } else { label.as_deref() };
+            if let Err(e) = set_label(*id, label) {
+                eprintln!("{e}");
+            }
+        }
+    }
+}
+
+fn run_dedupe_command(
+    options: &DedupeOptions,
+    action: Option<&DuplicateAction>,
+    apply: bool,
+) -> Result<(), String> {
+    let report = find_duplicates(options)?;
+    for (path, e) in report.unreadable.iter() {
+        eprintln!("Failed to read {path}: {e}");
+    }
+    let clusters = report.clusters;
+    for cluster in clusters.iter() {
+        println!("keep {} {}", cluster.keeper.id(), cluster.keeper.path());
+        for duplicate in cluster.duplicates.iter() {
+            let kind = match duplicate.kind {
+                DuplicateKind::Exact => "exact".to_string(),
+                DuplicateKind::Near { distance } => format!("near ({distance:.3})"),
+            };
+            println!("  {kind} {} {}", duplicate.file.id(), duplicate.file.path());
+        }
+    }
+    let num_duplicates: usize = clusters.iter().map(|c| c.duplicates.len()).sum();
+    eprintln!("{num_duplicates} duplicates in {} clusters", clusters.len());
+
+    let Some(action) = action else {
+        return Ok(());
+    };
+    let operations = resolve_duplicates(&clusters, action, !apply)?;
+    if !apply && !operations.is_empty() {
+        eprintln!("Dry run. Pass --apply to make these changes:");
+    }
+    for operation in operations {
+        println!("{operation}");
+    }
+    Ok(())
+}
+
+fn print_clusters(clusters: &[Cluster]) {
+    for cluster in clusters {
+        match cluster.medoid() {
+            Some(medoid) => println!(
+                "{} ({} samples) {} {}",
+                cluster.id(),
+                cluster.size(),
+                medoid.id(),
+                medoid.path()
+            ),
+            None => println!("{} ({} samples)", cluster.id(), cluster.size()),
+        }
+    }
+    let num_clustered: usize = clusters.iter().map(|cluster| cluster.size()).sum();
+    eprintln!("{num_clustered} samples in {} clusters", clusters.len());
+}
+
+fn run_classify_command(labels: Option<&Path>, options: &ClassifyOptions) -> Result<(), String> {
+    if let Some(path) = labels {
+        let num_labeled = import_labels(path)?;
+        eprintln!("Labeled {num_labeled} samples");
+    }
+    let predictions = classify_library(options)?;
+    eprintln!("Classified {} samples", predictions.len());
+    for (label, num_labeled, num_predicted) in MetadataDatabase::load_from_disk()?.count_labels()? {
+        println!("{label}: {num_labeled} labeled, {num_predicted} predicted");
+    }
+    Ok(())
+}
+
+fn run_outliers_command(options: &OutlierOptions) -> Result<(), String> {
+    let report = find_outliers(options)?;
+    println!("Isolated samples:");
+    for sample in report.isolated.iter() {
+        println!(
+            "{:.1} {} {} (mean distance {:.3})",
+            sample.score,
+            sample.file.id(),
+            sample.file.path(),
+            sample.mean_distance
+        );
+    }
+    println!("Misplaced samples:");
+    for sample in report.misplaced.iter() {
+        println!(
+            "{:.0}% {} {} (in {}, neighbors mostly in {})",
+            sample.disagreement * 100.0,
+            sample.file.id(),
+            sample.file.path(),
+            sample.group,
+            sample.neighbor_group
+        );
+    }
+    Ok(())
+}
+
+fn run_graph_command(
+    options: &GraphOptions,
+    format: GraphFormat,
+    output: Option<&Path>,
+) -> Result<(), String> {
+    let graph = knn_graph(options, |progress| {
+        eprint!("\rFinding neighbors: {:.0}%", progress * 100.0);
+    })?;
+    eprintln!(
+        "\rLinked {} samples with {} edges",
+        graph.nodes.len(),
+        graph.edges.len()
+    );
+    match output {
+        Some(path) => {
+            let file = File::create(path)
+                .map_err(|e| format!("Failed to create {}: {e}", path.display()))?;
+            let mut writer = BufWriter::new(file);
+            export::write_graph(&graph, format, &mut writer)?;
+            writer
+                .flush()
+                .map_err(|e| format!("Failed to write {}: {e}", path.display()))
+        }
+        None => export::write_graph(&graph, format, &mut std::io::stdout().lock()),
+    }
+}
+
+fn run_path_command(
+    start_id: u32,
+    end_id: u32,
+    options: &SequenceOptions,
+    format: SequenceFormat,
+    output: Option<&Path>,
+) -> Result<(), String> {
+    let steps = sample_sequence(start_id, end_id, options)?;
+    match output {
+        Some(path) => {
+            let file = File::create(path)
+                .map_err(|e| format!("Failed to create {}: {e}", path.display()))?;
+            let mut writer = BufWriter::new(file);
+            export::write_sequence(&steps, format, &mut writer)?;
+            writer
+                .flush()
+                .map_err(|e| format!("Failed to write {}: {e}", path.display()))
+        }
+        None => export::write_sequence(&steps, format, &mut std::io::stdout().lock()),
     }
 }
 
-fn list_samples(limit: Option<u32>) {
-    let db = MetadataDatabase::load_from_disk().unwrap();
-    let files = db.list_audio_files(0, limit).unwrap();
-    for file in files.iter() {
-        println!("{}", file.path());
+fn run_map_command(command: &MapCommands) -> Result<(), String> {
+    match command {
+        MapCommands::Build {
+            index,
+            method,
+            neighbors,
+        } => {
+            let method = match method.as_str() {
+                "pca" => ProjectionMethod::Pca,
+                _ => ProjectionMethod::Umap {
+                    num_neighbors: *neighbors,
+                },
+            };
+            let options = ProjectionOptions {
+                index: index.clone(),
+                method,
+            };
+            let points = project_library(&options)?;
+            eprintln!("Mapped {} samples", points.len());
+            Ok(())
+        }
+        MapCommands::Export { format, output } => {
+            let points = map_points()?;
+            match output {
+                Some(path) => {
+                    let file = File::create(path)
+                        .map_err(|e| format!("Failed to create {}: {e}", path.display()))?;
+                    let mut writer = BufWriter::new(file);
+                    export::write_map(&points, *format, &mut writer)?;
+                    writer
+                        .flush()
+                        .map_err(|e| format!("Failed to write {}: {e}", path.display()))
+                }
+                None => export::write_map(&points, *format, &mut std::io::stdout().lock()),
+            }
+        }
+    }
+}
+
+fn run_collection_command(command: &CollectionCommands) -> Result<(), String> {
+    let mut db = MetadataDatabase::load_from_disk()?;
+    let to_sample_ids = |ids: &[u32]| -> Vec<i64> { ids.iter().map(|id| *id as i64).collect() };
+    match command {
+        CollectionCommands::Add { name, ids } => db.add_to_collection(name, &to_sample_ids(ids)),
+        CollectionCommands::Remove { name, ids } => {
+            db.remove_from_collection(name, &to_sample_ids(ids))?;
+            Ok(())
+        }
+        CollectionCommands::Delete { name } => db.delete_collection(name),
+        CollectionCommands::List { name: None } => {
+            for (name, count) in db.list_collections()? {
+                println!("{name} ({count})");
+            }
+            Ok(())
+        }
+        CollectionCommands::List { name: Some(name) } => {
+            let filter = SampleFilter {
+                collection: Some(name.clone()),
+                ..Default::default()
+            };
+            for file in db.get_audio_files_matching(&filter)? {
+                println!("{} {}", file.id(), file.path());
+            }
+            Ok(())
+        }
+    }
+}
+
+fn parse_tag(arg: &str) -> Result<(String, String), String> {
+    let (key, value) = arg
+        .split_once('=')
+        .ok_or(format!("Expected KEY=VALUE, got {arg}"))?;
+    Ok((key.to_string(), value.to_string()))
+}
+
+fn print_sample_info(id: u32) -> Result<(), String> {
+    let db = MetadataDatabase::load_from_disk()?;
+    let file = db
+        .get_audio_files_for_ids(&[id])?
+        .pop()
+        .ok_or(format!("No sample with ID {id}"))?;
+    println!("path: {}", file.path());
+    if let Some(properties) = file.properties() {
+        println!("duration: {:.3}s", properties.duration);
+        println!("sample rate: {} Hz", properties.sample_rate);
+        println!("channels: {}", properties.channels);
+        if let Some(bit_depth) = properties.bit_depth {
+            println!("bit depth: {bit_depth}");
+        }
+        println!("codec: {}", properties.codec);
+        println!("file size: {} bytes", properties.file_size);
+    }
+    if let Some(rating) = file.rating() {
+        println!("rating: {rating}/{MAX_RATING}");
+    }
+    if file.is_favorite() {
+        println!("favorite: yes");
+    }
+    if let Some(label) = db.get_label(file.id())? {
+        if label.is_user_label {
+            println!("class: {}", label.label);
+        } else {
+            println!(
+                "class: {} (predicted, {:.0}% confidence)",
+                label.label,
+                label.confidence * 100.0
+            );
+        }
+    }
+    let user_tags = db.get_user_tags(file.id())?;
+    if !user_tags.is_empty() {
+        println!("user tags: {}", user_tags.join(", "));
+    }
+    let collections = db.get_collections_for_sample(file.id())?;
+    if !collections.is_empty() {
+        println!("collections: {}", collections.join(", "));
+    }
+    for (key, value) in db.get_tags(file.id())? {
+        println!("{key}: {value}");
+    }
+    Ok(())
+}
+
+fn parse_segmentation(arg: &str) -> Result<Segmentation, String> {
+    if arg == "onsets" {
+        return Ok(Segmentation::Onsets {
+            min_length: 0.5,
+            max_length: 10.0,
+        });
+    }
+    let length: f32 = arg
+        .parse()
+        .map_err(|_| format!("Expected a segment length in seconds or `onsets`, got {arg}"))?;
+    if length <= 0.0 {
+        return Err("Segment length must be positive".to_string());
+    }
+    Ok(Segmentation::Fixed { length })
+}
+
+fn parse_normalization(arg: &str) -> Result<Normalization, String> {
+    let invalid = || format!("Expected rms:DB or lufs:LUFS, got {arg}");
+    let (kind, level) = arg.split_once(':').ok_or_else(invalid)?;
+    let level: f32 = level.parse().map_err(|_| invalid())?;
+    match kind {
+        "rms" => Ok(Normalization::Rms { target_db: level }),
+        "lufs" => Ok(Normalization::Lufs { target_lufs: level }),
+        _ => Err(invalid()),
+    }
+}
+
+fn parse_k_selection(arg: &str) -> Result<KSelection, String> {
+    match arg {
+        "silhouette" => Ok(KSelection::Silhouette {
+            max_k: DEFAULT_MAX_K,
+        }),
+        "elbow" => Ok(KSelection::Elbow {
+            max_k: DEFAULT_MAX_K,
+        }),
+        _ => match arg.parse() {
+            Ok(k) if k > 0 => Ok(KSelection::Fixed(k)),
+            _ => Err(format!(
+                "Expected a positive number of clusters, silhouette or elbow, got {arg}"
+            )),
+        },
+    }
+}
+
+fn parse_index_weight(arg: &str) -> Result<(String, f32), String> {
+    match arg.split_once('=') {
+        Some((name, weight)) => {
+            let weight: f32 = weight
+                .parse()
+                .map_err(|_| format!("Invalid weight for index {name}: {weight}"))?;
+            Ok((name.to_string(), weight))
+        }
+        None => Ok((arg.to_string(), 1.0)),
+    }
+}
+
+fn run_feedback_command(
+    id: u32,
+    relevant: &[u32],
+    irrelevant: &[u32],
+    clear: bool,
+) -> Result<(), String> {
+    if clear {
+        let db = MetadataDatabase::load_from_disk()?;
+        db.clear_feedback(id as i64)?;
+    }
+    if !relevant.is_empty() || !irrelevant.is_empty() {
+        return record_feedback(id, relevant, irrelevant);
+    }
+    if !clear {
+        let db = MetadataDatabase::load_from_disk()?;
+        for (sample_id, relevant) in db.get_feedback(id as i64)? {
+            let judgement = if relevant { "relevant" } else { "irrelevant" };
+            println!("{sample_id} {judgement}");
+        }
+    }
+    Ok(())
+}
+
+fn list_samples(options: &ListOptions) -> Result<(), String> {
+    let page = list_audio_files(options)?;
+    for file in page.files.iter() {
+        println!("{} {}", file.id(), file.path());
+    }
+    // Print paging details to stderr so the listing itself can be piped
+    eprintln!("{} samples", page.total);
+    if let Some(cursor) = page.next_cursor {
+        eprintln!("Next page: --cursor {cursor}");
     }
+    Ok(())
 }
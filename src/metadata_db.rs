@@ -1,17 +1,42 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+};
 
-use crate::{feature::Feature, file_utils};
-use rusqlite::{params, Connection, Result};
+use crate::{
+    feature::{AnalyzedFile, Feature},
+    feature_extractor::{AudioProperties, Preprocessing},
+    file_utils,
+    fingerprint::FINGERPRINT_VERSION,
+    migrations,
+    vector_db::{VectorIndex, SEGMENT_INDEX},
+};
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension, Result, Row};
 use serde::{Deserialize, Serialize};
 
 pub struct MetadataDatabase {
     connection: Connection,
+    /// The library's preprocessing configuration, which is part of the identity of every
+    /// feature set stored in it
+    preprocessing: Preprocessing,
+}
+
+/// The settings key of the library's preprocessing configuration
+const PREPROCESSING_SETTING: &str = "preprocessing";
+
+/// Returns the feature set identifier stored alongside vectors of index that were calculated
+/// with preprocessing
+fn feature_set_id(index: &VectorIndex, preprocessing: &Preprocessing) -> String {
+    format!("{}{}", index.feature_set.id(), preprocessing.id())
 }
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct AudioFile {
     id: i64,
     path: String,
+    properties: Option<AudioProperties>,
+    rating: Option<u8>,
+    favorite: bool,
 }
 
 impl AudioFile {
@@ -21,50 +46,543 @@ impl AudioFile {
     pub fn path(&self) -> &str {
         &self.path
     }
+    /// The duration, format and size of the file, or None if it was analyzed before these
+    /// were recorded. Run reanalyze to fill them in.
+    pub fn properties(&self) -> Option<&AudioProperties> {
+        self.properties.as_ref()
+    }
+    /// The user's star rating from 1 to 5, if rated
+    pub fn rating(&self) -> Option<u8> {
+        self.rating
+    }
+    pub fn is_favorite(&self) -> bool {
+        self.favorite
+    }
+}
+
+/// The highest star rating a sample can be given
+pub const MAX_RATING: u8 = 5;
+
+/// The columns read by audio_file_from_row
+const AUDIO_FILE_COLUMNS: &str = "samples.id, samples.file_path, samples.duration,
+    samples.sample_rate, samples.channels, samples.bit_depth, samples.codec, samples.file_size,
+    samples.rating, samples.favorite";
+
+/// Reads an AudioFile from the AUDIO_FILE_COLUMNS of row, starting at column offset
+fn audio_file_from_row(row: &Row, offset: usize) -> Result<AudioFile> {
+    let duration: Option<f32> = row.get(offset + 2)?;
+    let properties = match duration {
+        Some(duration) => Some(AudioProperties {
+            duration,
+            sample_rate: row.get(offset + 3)?,
+            channels: row.get(offset + 4)?,
+            bit_depth: row.get(offset + 5)?,
+            codec: row.get(offset + 6)?,
+            file_size: row.get(offset + 7)?,
+        }),
+        None => None,
+    };
+    Ok(AudioFile {
+        id: row.get(offset)?,
+        path: row.get(offset + 1)?,
+        properties,
+        rating: row.get(offset + 8)?,
+        favorite: row.get(offset + 9)?,
+    })
+}
+
+/// Restricts searches to samples with matching properties. Samples without recorded
+/// properties never match a filter that sets any field.
+#[derive(Clone, Debug, Default)]
+pub struct SampleFilter {
+    /// The minimum duration in seconds
+    pub min_duration: Option<f32>,
+    /// The maximum duration in seconds
+    pub max_duration: Option<f32>,
+    pub sample_rate: Option<u32>,
+    pub channels: Option<u16>,
+    pub bit_depth: Option<u16>,
+    pub codec: Option<String>,
+    /// Embedded tags that must be present, as (key, value) pairs. Values match
+    /// case-insensitively anywhere within the tag's value, e.g. `("genre", "house")` matches
+    /// "Deep House".
+    pub tags: Vec<(String, String)>,
+    /// User tags that must all be present
+    pub user_tags: Vec<String>,
+    /// The minimum star rating. Unrated samples never match.
+    pub min_rating: Option<u8>,
+    pub favorites_only: bool,
+    /// The name of a collection that must contain the sample
+    pub collection: Option<String>,
+    /// Words that must all appear in the sample's file name, directory or tags. Words match
+    /// as prefixes, so `snar` matches "Snare".
+    pub text: Option<String>,
+    /// The analysis root dir the sample was found in, as passed to analyze
+    pub root_dir: Option<String>,
+    /// The ID of the cluster the sample was assigned to by the most recent clustering
+    pub cluster: Option<i64>,
+    /// The sound type the sample was labeled with or predicted to be
+    pub label: Option<String>,
+}
+
+/// Splits text into words the way the search index's unicode61 tokenizer does, at every
+/// character that isn't a letter or digit
+fn text_words(text: &str) -> impl Iterator<Item = &str> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+}
+
+/// Converts free text into an FTS5 query that matches each word as a prefix, joined with
+/// operator. Returns None if the text has no words.
+fn text_query(text: &str, operator: &str) -> Option<String> {
+    let terms: Vec<String> = text_words(text)
+        .map(|word| format!("\"{word}\"*"))
+        .collect();
+    if terms.is_empty() {
+        return None;
+    }
+    Some(terms.join(&format!(" {operator} ")))
+}
+
+/// Returns the fewest single character insertions, deletions and substitutions that turn word
+/// into a prefix of term, so a partially typed word is as close to a term as the whole word
+fn prefix_edit_distance(word: &[char], term: &[char]) -> usize {
+    // Distances from each prefix of term to the word so far
+    let mut row: Vec<usize> = vec![0; term.len() + 1];
+    for (i, w) in word.iter().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, t) in term.iter().enumerate() {
+            let substitution = diagonal + usize::from(w != t);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row.into_iter().min().unwrap_or(0)
+}
+
+/// The number of edits allowed when matching a search word to a differently spelled term.
+/// Words shorter than 3 characters are too ambiguous to correct.
+fn max_edits(word: &[char]) -> usize {
+    match word.len() {
+        0..=2 => 0,
+        3..=5 => 1,
+        _ => 2,
+    }
+}
+
+/// The order samples are listed in
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SortKey {
+    #[default]
+    Path,
+    /// The file name, without its directory
+    Name,
+    Duration,
+    DateAdded,
+    /// The tempo from the file's embedded `bpm` tag
+    Bpm,
+}
+
+impl SortKey {
+    pub const ALL: [SortKey; 5] = [
+        SortKey::Path,
+        SortKey::Name,
+        SortKey::Duration,
+        SortKey::DateAdded,
+        SortKey::Bpm,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SortKey::Path => "path",
+            SortKey::Name => "name",
+            SortKey::Duration => "duration",
+            SortKey::DateAdded => "added",
+            SortKey::Bpm => "bpm",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Result<SortKey, String> {
+        SortKey::ALL
+            .into_iter()
+            .find(|key| key.name() == name)
+            .ok_or_else(|| {
+                let names: Vec<&str> = SortKey::ALL.iter().map(|key| key.name()).collect();
+                format!("Unknown sort key {}. Available keys: {}", name, names.join(", "))
+            })
+    }
+
+    /// Returns the SQL expression samples are sorted by. Missing numeric values are replaced
+    /// with a value that sorts after every real value in the given direction, so that the
+    /// expression is never NULL and can be compared against a cursor.
+    fn expression(&self, direction: SortDirection) -> String {
+        let missing = match direction {
+            SortDirection::Ascending => "1e300",
+            SortDirection::Descending => "-1e300",
+        };
+        match self {
+            SortKey::Path => "samples.file_path".to_string(),
+            SortKey::Name => "replace(samples.file_path, rtrim(samples.file_path,
+                replace(replace(samples.file_path, '/', ''), '\\', '')), '')"
+                .to_string(),
+            SortKey::Duration => format!("coalesce(samples.duration, {missing})"),
+            SortKey::DateAdded => format!("coalesce(samples.added_at, {missing})"),
+            SortKey::Bpm => format!(
+                "coalesce((SELECT CAST(value AS REAL) FROM tags
+                    WHERE tags.sample_id = samples.id AND tags.key = 'bpm' LIMIT 1), {missing})"
+            ),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SortDirection {
+    #[default]
+    Ascending,
+    Descending,
+}
+
+/// Options for listing samples a page at a time
+#[derive(Clone, Debug, Default)]
+pub struct ListOptions {
+    pub sort: SortKey,
+    pub direction: SortDirection,
+    pub filter: SampleFilter,
+    /// The next_cursor of the previous page. Listing starts from the beginning when None.
+    pub cursor: Option<String>,
+    /// The number of samples to skip, counted from the cursor if one is provided
+    pub offset: u32,
+    /// The maximum number of samples per page
+    pub limit: Option<u32>,
+}
+
+/// A page of listed samples
+pub struct Page {
+    pub files: Vec<AudioFile>,
+    /// Pass as the cursor of the next request to continue listing, or None if this is the
+    /// last page
+    pub next_cursor: Option<String>,
+    /// The number of samples matching the filter across all pages
+    pub total: usize,
+}
+
+/// The position of the last sample of a page. Cursors are only valid for the sort order they
+/// were created with.
+#[derive(Serialize, Deserialize)]
+struct Cursor {
+    sort: SortKey,
+    direction: SortDirection,
+    sort_value: CursorValue,
+    id: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+enum CursorValue {
+    Text(String),
+    Real(f64),
+}
+
+impl Cursor {
+    fn encode(&self) -> Result<String, String> {
+        let bytes = bincode::serialize(self).map_err(|e| e.to_string())?;
+        Ok(bytes.iter().map(|b| format!("{b:02x}")).collect())
+    }
+
+    fn decode(cursor: &str) -> Result<Cursor, String> {
+        let bytes = cursor
+            .as_bytes()
+            .chunks(2)
+            .map(|pair| {
+                let pair = std::str::from_utf8(pair).ok().filter(|pair| pair.len() == 2)?;
+                u8::from_str_radix(pair, 16).ok()
+            })
+            .collect::<Option<Vec<u8>>>()
+            .ok_or("Invalid cursor")?;
+        bincode::deserialize(&bytes).map_err(|_| "Invalid cursor".to_string())
+    }
+}
+
+/// Escapes the `%` and `_` wildcards of a LIKE pattern, and the `\` used to escape them, so
+/// value matches literally
+fn escape_like(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// The bm25 weights of the name, directory and tags columns of sample_search. Matches in the
+/// file name are the strongest signal of what a user is looking for.
+const TEXT_SEARCH_RANK: &str = "bm25(sample_search, 10.0, 2.0, 5.0)";
+
+impl SampleFilter {
+    pub fn is_empty(&self) -> bool {
+        self.min_duration.is_none()
+            && self.max_duration.is_none()
+            && self.sample_rate.is_none()
+            && self.channels.is_none()
+            && self.bit_depth.is_none()
+            && self.codec.is_none()
+            && self.tags.is_empty()
+            && self.user_tags.is_empty()
+            && self.min_rating.is_none()
+            && !self.favorites_only
+            && self.collection.is_none()
+            && self.text.is_none()
+            && self.root_dir.is_none()
+            && self.cluster.is_none()
+            && self.label.is_none()
+    }
+
+    /// Returns the SQL conditions on the samples table for this filter and their parameters
+    fn conditions(&self) -> (Vec<&'static str>, Vec<rusqlite::types::Value>) {
+        use rusqlite::types::Value;
+        let mut conditions = Vec::new();
+        let mut values = Vec::new();
+        if let Some(min_duration) = self.min_duration {
+            conditions.push("samples.duration >= ?");
+            values.push(Value::Real(min_duration as f64));
+        }
+        if let Some(max_duration) = self.max_duration {
+            conditions.push("samples.duration <= ?");
+            values.push(Value::Real(max_duration as f64));
+        }
+        if let Some(sample_rate) = self.sample_rate {
+            conditions.push("samples.sample_rate = ?");
+            values.push(Value::Integer(sample_rate as i64));
+        }
+        if let Some(channels) = self.channels {
+            conditions.push("samples.channels = ?");
+            values.push(Value::Integer(channels as i64));
+        }
+        if let Some(bit_depth) = self.bit_depth {
+            conditions.push("samples.bit_depth = ?");
+            values.push(Value::Integer(bit_depth as i64));
+        }
+        if let Some(codec) = &self.codec {
+            conditions.push("samples.codec = ?");
+            values.push(Value::Text(codec.clone()));
+        }
+        for (key, value) in &self.tags {
+            conditions.push(
+                "EXISTS (SELECT 1 FROM tags WHERE tags.sample_id = samples.id
+                    AND tags.key = ? AND tags.value LIKE ? ESCAPE '\\')",
+            );
+            values.push(Value::Text(key.to_lowercase()));
+            values.push(Value::Text(format!("%{}%", escape_like(value))));
+        }
+        for tag in &self.user_tags {
+            conditions.push(
+                "EXISTS (SELECT 1 FROM user_tags
+                    WHERE user_tags.sample_id = samples.id AND user_tags.tag = ?)",
+            );
+            values.push(Value::Text(tag.clone()));
+        }
+        if let Some(min_rating) = self.min_rating {
+            conditions.push("samples.rating >= ?");
+            values.push(Value::Integer(min_rating as i64));
+        }
+        if self.favorites_only {
+            conditions.push("samples.favorite = 1");
+        }
+        if let Some(collection) = &self.collection {
+            conditions.push(
+                "EXISTS (SELECT 1 FROM collection_samples
+                    JOIN collections ON collections.id = collection_samples.collection_id
+                    WHERE collection_samples.sample_id = samples.id AND collections.name = ?)",
+            );
+            values.push(Value::Text(collection.clone()));
+        }
+        if let Some(text) = &self.text {
+            conditions.push(
+                "samples.id IN (SELECT rowid FROM sample_search WHERE sample_search MATCH ?)",
+            );
+            // Text without any words matches nothing
+            values.push(Value::Text(text_query(text, "AND").unwrap_or("\"\"".to_string())));
+        }
+        if let Some(root_dir) = &self.root_dir {
+            conditions.push(
+                "samples.analysis_root_dir_id IN
+                    (SELECT id FROM analysis_root_dirs WHERE dir_path = ?)",
+            );
+            values.push(Value::Text(root_dir.clone()));
+        }
+        if let Some(cluster) = self.cluster {
+            conditions.push(
+                "samples.id IN (SELECT sample_id FROM cluster_assignments WHERE cluster_id = ?)",
+            );
+            values.push(Value::Integer(cluster));
+        }
+        if let Some(label) = &self.label {
+            conditions.push("samples.id IN (SELECT sample_id FROM sample_labels WHERE label = ?)");
+            values.push(Value::Text(label.trim().to_string()));
+        }
+        (conditions, values)
+    }
+
+    /// Returns a WHERE clause for this filter, or an empty string if it's empty
+    fn where_clause(&self) -> (String, Vec<rusqlite::types::Value>) {
+        let (conditions, values) = self.conditions();
+        if conditions.is_empty() {
+            return (String::new(), values);
+        }
+        (format!(" WHERE {}", conditions.join(" AND ")), values)
+    }
+}
+
+/// A time range within an analyzed file
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Segment {
+    id: i64,
+    file: AudioFile,
+    start_time: f32,
+    end_time: f32,
+}
+
+impl Segment {
+    pub fn id(&self) -> i64 {
+        self.id
+    }
+    pub fn file(&self) -> &AudioFile {
+        &self.file
+    }
+    /// The start of the segment in seconds
+    pub fn start_time(&self) -> f32 {
+        self.start_time
+    }
+    /// The end of the segment in seconds
+    pub fn end_time(&self) -> f32 {
+        self.end_time
+    }
+}
+
+/// A group of similar samples found by clustering the library
+pub struct Cluster {
+    id: i64,
+    size: usize,
+    medoid: Option<AudioFile>,
+}
+
+impl Cluster {
+    pub fn id(&self) -> i64 {
+        self.id
+    }
+    /// The number of samples assigned to the cluster
+    pub fn size(&self) -> usize {
+        self.size
+    }
+    /// The sample that best represents the cluster, or None if it has been removed from the
+    /// library
+    pub fn medoid(&self) -> Option<&AudioFile> {
+        self.medoid.as_ref()
+    }
+}
+
+/// A sample's sound type label
+#[derive(Clone, Debug, PartialEq)]
+pub struct SampleLabel {
+    pub label: String,
+    /// How confident the prediction is, from 0 to 1. User labels have a confidence of 1.
+    pub confidence: f32,
+    /// True if the user gave the label, rather than it being predicted
+    pub is_user_label: bool,
+}
+
+/// A sample's position on the 2D map of the library
+#[derive(Clone, Debug, Serialize)]
+pub struct MapPoint {
+    pub id: i64,
+    pub path: String,
+    pub x: f32,
+    pub y: f32,
+    /// The cluster the sample was assigned to by the most recent clustering
+    pub cluster: Option<i64>,
 }
 
 impl MetadataDatabase {
     pub fn load_from_disk() -> Result<MetadataDatabase, String> {
         let file_path = file_utils::metadata_db_path()?;
-        let connection = Connection::open(&file_path)
+        MetadataDatabase::open(&file_path)
+    }
+
+    /// Opens the metadata database at file_path, creating it if necessary, and upgrades its
+    /// schema to the latest version.
+    pub fn open(file_path: &Path) -> Result<MetadataDatabase, String> {
+        let mut connection = Connection::open(file_path)
             .map_err(|e| format!("Failed to create database: {}", e))?;
 
-        Ok(MetadataDatabase { connection })
-    }
+        // Write-ahead logging lets readers proceed while an analysis run is writing, and is
+        // considerably faster for large batches of inserts.
+        connection
+            .pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))
+            .map_err(|e| format!("Failed to enable WAL journaling: {}", e))?;
+        connection
+            .pragma_update(None, "synchronous", "NORMAL")
+            .map_err(|e| e.to_string())?;
+        // Required for sample_vectors rows to be removed along with their sample
+        connection
+            .pragma_update(None, "foreign_keys", "ON")
+            .map_err(|e| e.to_string())?;
 
-    /// Creates necessary db tables and inserts an entry for analysis_root_dir.
-    /// Returns the analysis root dir ID on success.
-    pub fn initialize(&self, analysis_root_dir: &str) -> Result<i64, String> {
-        self.connection
-            .execute(
-                "CREATE TABLE IF NOT EXISTS analysis_root_dirs (
-                    id INTEGER PRIMARY KEY,
-                    dir_path TEXT NOT NULL
-                )",
-                (),
+        migrations::migrate(&mut connection)?;
+
+        let preprocessing = connection
+            .query_row(
+                "SELECT value FROM settings WHERE key = ?1",
+                [PREPROCESSING_SETTING],
+                |row| row.get::<_, Vec<u8>>(0),
             )
-            .map_err(|e| e.to_string())?;
+            .optional()
+            .map_err(|e| e.to_string())?
+            .map(|value| bincode::deserialize(&value).map_err(|e| e.to_string()))
+            .transpose()?
+            .unwrap_or_default();
 
+        Ok(MetadataDatabase {
+            connection,
+            preprocessing,
+        })
+    }
+
+    /// Returns the preprocessing applied to samples before their features are calculated
+    pub fn preprocessing(&self) -> Preprocessing {
+        self.preprocessing
+    }
+
+    /// Changes the library's preprocessing configuration. Vectors calculated with the previous
+    /// configuration are considered outdated until the library is re-analyzed.
+    pub fn set_preprocessing(&mut self, preprocessing: Preprocessing) -> Result<(), String> {
+        let value = bincode::serialize(&preprocessing).map_err(|e| e.to_string())?;
         self.connection
             .execute(
-                "CREATE TABLE IF NOT EXISTS samples (
-                    id INTEGER PRIMARY KEY,
-                    analysis_root_dir_id INTEGER,
-                    file_path TEXT NOT NULL UNIQUE,
-                    feature_vector BLOB NOT NULL,
-                    FOREIGN KEY(analysis_root_dir_id) REFERENCES analysis_root_dirs(id)
-                )",
-                (),
+                "INSERT INTO settings (key, value) VALUES (?1, ?2)
+                ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+                params![PREPROCESSING_SETTING, value],
             )
-            .map_err(|e| format!("Failed to create db table: {}", e))?;
+            .map_err(|e| format!("Failed to update preprocessing settings: {}", e))?;
+        self.preprocessing = preprocessing;
+        Ok(())
+    }
+
+    /// Returns the identifier of the feature set that current vectors of index are stored with
+    pub fn feature_set_id(&self, index: &VectorIndex) -> String {
+        feature_set_id(index, &self.preprocessing)
+    }
 
+    pub fn count_samples(&self) -> Result<usize, String> {
         self.connection
-            .execute(
-                "CREATE INDEX IF NOT EXISTS idx_file_path ON samples (file_path)",
-                (),
-            )
-            .map_err(|e| format!("Failed to create db index: {}", e))?;
+            .query_row("SELECT COUNT(*) FROM samples", [], |row| row.get(0))
+            .map_err(|e| e.to_string())
+    }
 
+    /// Inserts an entry for analysis_root_dir if one doesn't already exist.
+    /// Returns the analysis root dir ID on success.
+    pub fn initialize(&self, analysis_root_dir: &str) -> Result<i64, String> {
         let id = self.get_id_for_analysis_dir(analysis_root_dir)?;
         Ok(id)
     }
@@ -96,103 +614,413 @@ impl MetadataDatabase {
         }
     }
 
-    /// Inserts metadata for a sample and returns the row id
-    pub fn insert_sample_metadata(
-        &self,
-        file_path: &str,
+    /// Inserts or updates metadata for a batch of analyzed files within a single transaction.
+    /// Each feature's id is set to the row id of its sample, and its vector replaces any
+    /// existing vector for the same sample and index. Segment features replace all existing
+    /// segments of their sample, and their id is set to the row id of the segment.
+    pub fn insert_samples(
+        &mut self,
+        files: &mut [AnalyzedFile],
         analysis_root_dir_id: i64,
-        feature_vec: &[f32],
-    ) -> Result<i64, String> {
-        let serialized_vec = bincode::serialize(feature_vec).map_err(|e| e.to_string())?;
-        if self
+    ) -> Result<(), String> {
+        let preprocessing = self.preprocessing;
+        let tx = self
             .connection
-            .execute(
-                     "INSERT INTO samples (file_path, analysis_root_dir_id, feature_vector) VALUES (?1, ?2, ?3)",
-                   params![
-                    &file_path,
-                    &analysis_root_dir_id.to_string().as_str(),
-                    &serialized_vec,
-                ],
-            )
-            .is_ok()
+            .transaction()
+            .map_err(|e| format!("Failed to begin transaction: {}", e))?;
         {
-            Ok(self.connection.last_insert_rowid())
-        } else {
-            // The execution failure is most likely due to inserting a file_path that already
-            // exists. Attempt to get the id for file_path
-            let mut query = self
-                .connection
-                .prepare("SELECT id FROM samples WHERE file_path = ?1")
+            let mut sample_stmt = tx
+                .prepare_cached(
+                    "INSERT INTO samples (file_path, analysis_root_dir_id, duration, sample_rate,
+                        channels, bit_depth, codec, file_size, content_hash, tags_read, segmented,
+                        added_at)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, 1, ?10, unixepoch())
+                    ON CONFLICT(file_path) DO UPDATE SET
+                        analysis_root_dir_id = excluded.analysis_root_dir_id,
+                        duration = excluded.duration,
+                        sample_rate = excluded.sample_rate,
+                        channels = excluded.channels,
+                        bit_depth = excluded.bit_depth,
+                        codec = excluded.codec,
+                        file_size = excluded.file_size,
+                        content_hash = excluded.content_hash,
+                        tags_read = 1,
+                        segmented = segmented OR excluded.segmented
+                    RETURNING id",
+                )
+                .map_err(|e| format!("Failed to prepare sqlite query: {}", e))?;
+            let mut vector_stmt = tx
+                .prepare_cached(
+                    "INSERT INTO sample_vectors (sample_id, index_id, feature_set, vector)
+                    VALUES (?1, ?2, ?3, ?4)
+                    ON CONFLICT(sample_id, index_id) DO UPDATE SET
+                        feature_set = excluded.feature_set,
+                        vector = excluded.vector",
+                )
+                .map_err(|e| format!("Failed to prepare sqlite query: {}", e))?;
+            let mut clear_tags_stmt = tx
+                .prepare_cached("DELETE FROM tags WHERE sample_id = ?1")
+                .map_err(|e| format!("Failed to prepare sqlite query: {}", e))?;
+            let mut tag_stmt = tx
+                .prepare_cached("INSERT INTO tags (sample_id, key, value) VALUES (?1, ?2, ?3)")
+                .map_err(|e| format!("Failed to prepare sqlite query: {}", e))?;
+            let mut fingerprint_stmt = tx
+                .prepare_cached(
+                    "INSERT INTO fingerprints (sample_id, version, fingerprint)
+                    VALUES (?1, ?2, ?3)
+                    ON CONFLICT(sample_id) DO UPDATE SET
+                        version = excluded.version,
+                        fingerprint = excluded.fingerprint",
+                )
+                .map_err(|e| format!("Failed to prepare sqlite query: {}", e))?;
+            let mut clear_segments_stmt = tx
+                .prepare_cached("DELETE FROM segments WHERE sample_id = ?1 RETURNING id")
+                .map_err(|e| format!("Failed to prepare sqlite query: {}", e))?;
+            let mut segment_stmt = tx
+                .prepare_cached(
+                    "INSERT INTO segments (sample_id, start_time, end_time, feature_set, vector)
+                    VALUES (?1, ?2, ?3, ?4, ?5)
+                    RETURNING id",
+                )
                 .map_err(|e| format!("Failed to prepare sqlite query: {}", e))?;
 
-            let mut rows = query
-                .query(rusqlite::params![file_path])
-                .map_err(|e| e.to_string())?;
-            if let Some(row) = rows.next().map_err(|e| e.to_string())? {
-                let id: i64 = row.get(0).unwrap();
-                Ok(id)
-            } else {
-                Err(format!(
-                    "Failed to insert or lookup id for sample {}",
-                    file_path
-                ))
+            for file in files.iter_mut() {
+                let insert_err = |e: rusqlite::Error| {
+                    format!("Failed to insert metadata for sample {}: {}", file.path, e)
+                };
+                let properties = &file.properties;
+                let id: i64 = sample_stmt
+                    .query_row(
+                        params![
+                            file.path,
+                            analysis_root_dir_id,
+                            properties.duration,
+                            properties.sample_rate,
+                            properties.channels,
+                            properties.bit_depth,
+                            properties.codec,
+                            properties.file_size,
+                            file.content_hash.map(|hash| hash as i64),
+                            file.segmented
+                        ],
+                        |row| row.get(0),
+                    )
+                    .map_err(insert_err)?;
+
+                clear_tags_stmt.execute([id]).map_err(insert_err)?;
+                for (key, value) in file.tags.iter() {
+                    tag_stmt
+                        .execute(params![id, key, value])
+                        .map_err(insert_err)?;
+                }
+
+                if let Some(fingerprint) = &file.fingerprint {
+                    let serialized = bincode::serialize(fingerprint).map_err(|e| e.to_string())?;
+                    fingerprint_stmt
+                        .execute(params![id, FINGERPRINT_VERSION, serialized])
+                        .map_err(insert_err)?;
+                }
+
+                // Segmented files replace all of their existing segments, even if they were too
+                // short to produce any this time
+                if file.segmented {
+                    file.replaced_segments = clear_segments_stmt
+                        .query_map([id], |row| row.get(0))
+                        .map_err(insert_err)?
+                        .collect::<Result<_, _>>()
+                        .map_err(insert_err)?;
+                }
+                for feature in file.features.iter_mut() {
+                    let serialized_vec =
+                        bincode::serialize(feature.feature_vector()).map_err(|e| e.to_string())?;
+                    let index = feature.index();
+                    if let Some((start_time, end_time)) = feature.segment() {
+                        let segment_id: i64 = segment_stmt
+                            .query_row(
+                                params![
+                                    id,
+                                    start_time,
+                                    end_time,
+                                    feature_set_id(index, &preprocessing),
+                                    &serialized_vec
+                                ],
+                                |row| row.get(0),
+                            )
+                            .map_err(insert_err)?;
+                        feature.set_id(segment_id);
+                        continue;
+                    }
+                    vector_stmt
+                        .execute(params![
+                            id,
+                            index.id,
+                            feature_set_id(index, &preprocessing),
+                            &serialized_vec
+                        ])
+                        .map_err(insert_err)?;
+                    feature.set_id(id);
+                }
             }
         }
+        tx.commit()
+            .map_err(|e| format!("Failed to commit transaction: {}", e))
     }
 
-    pub fn list_audio_files(
-        &self,
-        start_offset: u32,
-        limit: Option<u32>,
-    ) -> Result<Vec<AudioFile>, String> {
-        let mut query = self
+    /// Lists a page of samples matching options.filter in the requested order. Pages are
+    /// continued from the cursor of the previous page rather than an offset, so samples added
+    /// or removed between requests don't cause entries to be skipped or repeated.
+    pub fn list_audio_files(&self, options: &ListOptions) -> Result<Page, String> {
+        use rusqlite::types::Value;
+
+        let (mut conditions, mut values) = options.filter.conditions();
+        let (where_clause, count_values) = options.filter.where_clause();
+        let total: usize = self
             .connection
-            .prepare("SELECT id, file_path FROM samples WHERE id > ?1 ORDER BY file_path LIMIT ?2")
-            .map_err(|e| format!("Failed to prepare sqlite query: {}", e))?;
+            .query_row(
+                &format!("SELECT COUNT(*) FROM samples{where_clause}"),
+                params_from_iter(count_values),
+                |row| row.get(0),
+            )
+            .map_err(|e| e.to_string())?;
+        if options.limit == Some(0) {
+            return Ok(Page {
+                files: Vec::new(),
+                next_cursor: None,
+                total,
+            });
+        }
 
-        let limit = limit.unwrap_or(u32::MAX);
-        let mut rows = query
-            .query(rusqlite::params![start_offset, limit])
+        let sort_expression = options.sort.expression(options.direction);
+        let (comparison, order) = match options.direction {
+            SortDirection::Ascending => (">", "ASC"),
+            SortDirection::Descending => ("<", "DESC"),
+        };
+        let cursor_condition = format!("({sort_expression}, samples.id) {comparison} (?, ?)");
+        if let Some(cursor) = &options.cursor {
+            let cursor = Cursor::decode(cursor)?;
+            if cursor.sort != options.sort || cursor.direction != options.direction {
+                return Err("The cursor was created with a different sort order".to_string());
+            }
+            conditions.push(&cursor_condition);
+            values.push(match cursor.sort_value {
+                CursorValue::Text(text) => Value::Text(text),
+                CursorValue::Real(real) => Value::Real(real),
+            });
+            values.push(Value::Integer(cursor.id));
+        }
+        let where_clause = if conditions.is_empty() {
+            String::new()
+        } else {
+            format!(" WHERE {}", conditions.join(" AND "))
+        };
+        // Fetch one extra sample to find out whether there's another page
+        let limit = options.limit.map(|limit| limit as i64 + 1).unwrap_or(-1);
+        values.push(Value::Integer(limit));
+        values.push(Value::Integer(options.offset as i64));
+
+        let mut stmt = self
+            .connection
+            .prepare(&format!(
+                "SELECT {AUDIO_FILE_COLUMNS}, {sort_expression} FROM samples{where_clause}
+                ORDER BY {sort_expression} {order}, samples.id {order}
+                LIMIT ? OFFSET ?"
+            ))
+            .map_err(|e| format!("Failed to prepare sqlite query: {}", e))?;
+        let sort_value_column = AUDIO_FILE_COLUMNS.split(',').count();
+        let mut rows = stmt
+            .query(params_from_iter(values))
             .map_err(|e| e.to_string())?;
         let mut files: Vec<AudioFile> = Vec::new();
+        let mut last_sort_value = None;
         while let Some(row) = rows.next().map_err(|e| e.to_string())? {
-            let id: i64 = row.get(0).unwrap();
-            let path: String = row.get(1).unwrap();
-            files.push(AudioFile { id, path });
+            if options.limit.is_some_and(|limit| files.len() == limit as usize) {
+                let last_file = files.last().unwrap();
+                let cursor = Cursor {
+                    sort: options.sort,
+                    direction: options.direction,
+                    sort_value: last_sort_value.take().unwrap(),
+                    id: last_file.id(),
+                };
+                return Ok(Page {
+                    files,
+                    next_cursor: Some(cursor.encode()?),
+                    total,
+                });
+            }
+            files.push(audio_file_from_row(row, 0).map_err(|e| e.to_string())?);
+            last_sort_value = Some(
+                match row.get_ref(sort_value_column).map_err(|e| e.to_string())? {
+                    rusqlite::types::ValueRef::Text(text) => {
+                        CursorValue::Text(String::from_utf8_lossy(text).to_string())
+                    }
+                    rusqlite::types::ValueRef::Integer(integer) => CursorValue::Real(integer as f64),
+                    value => CursorValue::Real(value.as_f64().map_err(|e| e.to_string())?),
+                },
+            );
         }
-        Ok(files)
+        Ok(Page {
+            files,
+            next_cursor: None,
+            total,
+        })
     }
 
-    pub fn get_all_features(&self) -> Result<HashMap<String, Feature>, String> {
-        let query = self
+    /// Returns the features of all samples with a vector from index's current feature set,
+    /// keyed by path
+    pub fn get_all_features(
+        &self,
+        index: &'static VectorIndex,
+    ) -> Result<HashMap<String, Feature>, String> {
+        let mut query = self
             .connection
-            .prepare("SELECT file_path, feature_vector, id FROM samples");
-        // Return an empty hashmap if the query fails, since this will happen when
-        // get_all_features() is called before a metadata db is populated.
-        if query.is_err() {
-            return Ok(HashMap::new());
-        }
-        let mut query = query.unwrap();
+            .prepare(
+                "SELECT samples.file_path, sample_vectors.vector, samples.id
+                FROM sample_vectors JOIN samples ON samples.id = sample_vectors.sample_id
+                WHERE sample_vectors.index_id = ?1 AND sample_vectors.feature_set = ?2",
+            )
+            .map_err(|e| format!("Failed to prepare sqlite query: {}", e))?;
 
         let feature_map: HashMap<String, Feature> = query
-            .query_map([], |row| {
-                let path: String = row.get(0).unwrap();
-                let feature_vec: Vec<u8> = row.get(1).unwrap();
+            .query_map(params![index.id, self.feature_set_id(index)], |row| {
+                let path: String = row.get(0)?;
+                let feature_vec: Vec<u8> = row.get(1)?;
                 let feature_vec: Vec<f32> = bincode::deserialize(&feature_vec).unwrap();
-                let id: i64 = row.get(2).unwrap();
-                Ok((path.clone(), Feature::new(feature_vec, path, Some(id))))
+                let id: i64 = row.get(2)?;
+                Ok((path.clone(), Feature::new(feature_vec, path, index, Some(id))))
             })
             .map_err(|e| e.to_string())?
-            .filter_map(|val| {
-                if val.is_ok() {
-                    return Some(val.unwrap());
-                }
-                None
-            })
+            .filter_map(|val| val.ok())
             .collect();
         Ok(feature_map)
     }
 
+    /// Returns the features of all segments with a vector from the segment index's current
+    /// feature set
+    pub fn get_all_segment_features(&self) -> Result<Vec<Feature>, String> {
+        let mut query = self
+            .connection
+            .prepare(
+                "SELECT samples.file_path, segments.vector, segments.id,
+                    segments.start_time, segments.end_time
+                FROM segments JOIN samples ON samples.id = segments.sample_id
+                WHERE segments.feature_set = ?1",
+            )
+            .map_err(|e| format!("Failed to prepare sqlite query: {}", e))?;
+
+        let features: Vec<Feature> = query
+            .query_map([self.feature_set_id(&SEGMENT_INDEX)], |row| {
+                let path: String = row.get(0)?;
+                let feature_vec: Vec<u8> = row.get(1)?;
+                let feature_vec: Vec<f32> = bincode::deserialize(&feature_vec).unwrap();
+                let id: i64 = row.get(2)?;
+                let mut feature =
+                    Feature::new_segment(feature_vec, path, row.get(3)?, row.get(4)?);
+                feature.set_id(id);
+                Ok(feature)
+            })
+            .map_err(|e| e.to_string())?
+            .filter_map(|val| val.ok())
+            .collect();
+        Ok(features)
+    }
+
+    /// Returns the paths of all samples that have been split into segments, including those
+    /// too short to produce any
+    pub fn get_segmented_files(&self) -> Result<HashSet<String>, String> {
+        let mut query = self
+            .connection
+            .prepare("SELECT file_path FROM samples WHERE segmented = 1")
+            .map_err(|e| format!("Failed to prepare sqlite query: {}", e))?;
+        let paths = query
+            .query_map([], |row| row.get(0))
+            .map_err(|e| e.to_string())?
+            .collect::<Result<HashSet<String>, _>>()
+            .map_err(|e| e.to_string())?;
+        Ok(paths)
+    }
+
+    /// Returns the paths of samples with segments from an outdated feature set, grouped by
+    /// analysis root dir ID
+    pub fn get_samples_with_outdated_segments(&self) -> Result<HashMap<i64, Vec<String>>, String> {
+        let mut query = self
+            .connection
+            .prepare(
+                "SELECT analysis_root_dir_id, file_path FROM samples
+                WHERE EXISTS (
+                    SELECT 1 FROM segments WHERE sample_id = samples.id AND feature_set != ?1
+                )
+                ORDER BY file_path",
+            )
+            .map_err(|e| format!("Failed to prepare sqlite query: {}", e))?;
+        let mut samples: HashMap<i64, Vec<String>> = HashMap::new();
+        let mut rows = query
+            .query([self.feature_set_id(&SEGMENT_INDEX)])
+            .map_err(|e| e.to_string())?;
+        while let Some(row) = rows.next().map_err(|e| e.to_string())? {
+            let dir_id: i64 = row.get(0).map_err(|e| e.to_string())?;
+            let path: String = row.get(1).map_err(|e| e.to_string())?;
+            samples.entry(dir_id).or_default().push(path);
+        }
+        Ok(samples)
+    }
+
+    pub fn count_segments(&self, sample_id: i64) -> Result<usize, String> {
+        self.connection
+            .query_row(
+                "SELECT COUNT(*) FROM segments WHERE sample_id = ?1",
+                [sample_id],
+                |row| row.get(0),
+            )
+            .map_err(|e| e.to_string())
+    }
+
+    /// Returns the segments with the given ids, in the same order as ids
+    pub fn get_segments_for_ids(&self, ids: &[u32]) -> Result<Vec<Segment>, String> {
+        let id_list: String = ids
+            .iter()
+            .map(|id| id.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+        let query = format!(
+            "SELECT segments.id, segments.start_time, segments.end_time, {AUDIO_FILE_COLUMNS}
+            FROM segments JOIN samples ON samples.id = segments.sample_id
+            WHERE segments.id IN ({})",
+            id_list
+        );
+
+        let mut stmt = self.connection.prepare(&query).map_err(|e| e.to_string())?;
+        let segments: HashMap<i64, Segment> = stmt
+            .query_map([], |row| {
+                let id: i64 = row.get(0)?;
+                let segment = Segment {
+                    id,
+                    file: audio_file_from_row(row, 3)?,
+                    start_time: row.get(1)?,
+                    end_time: row.get(2)?,
+                };
+                Ok((id, segment))
+            })
+            .map_err(|e| e.to_string())?
+            .collect::<Result<_, _>>()
+            .map_err(|e| e.to_string())?;
+        Ok(ids
+            .iter()
+            .filter_map(|id| segments.get(&(*id as i64)).cloned())
+            .collect())
+    }
+
+    /// Returns the id of the sample at file_path, or None if it hasn't been analyzed
+    pub fn get_sample_id(&self, file_path: &str) -> Result<Option<i64>, String> {
+        self.connection
+            .query_row(
+                "SELECT id FROM samples WHERE file_path = ?1",
+                [file_path],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(|e| e.to_string())
+    }
+
     pub fn get_audio_files_for_ids(&self, ids: &[u32]) -> Result<Vec<AudioFile>, String> {
         let id_list: String = ids
             .iter()
@@ -200,7 +1028,7 @@ impl MetadataDatabase {
             .collect::<Vec<_>>()
             .join(",");
         let query = format!(
-            "SELECT id, file_path FROM samples WHERE id IN ({})",
+            "SELECT {AUDIO_FILE_COLUMNS} FROM samples WHERE id IN ({})",
             id_list
         );
 
@@ -208,9 +1036,7 @@ impl MetadataDatabase {
         let mut rows = stmt.query([]).map_err(|e| e.to_string())?;
         let mut files = Vec::new();
         while let Some(row) = rows.next().map_err(|e| e.to_string())? {
-            let id: i64 = row.get(0).unwrap();
-            let path: String = row.get(1).unwrap();
-            files.push(AudioFile { id, path });
+            files.push(audio_file_from_row(row, 0).map_err(|e| e.to_string())?);
         }
         // The result of the sql query isn't guaranteed to match the order of ids, which are
         // ranked by most to least similar. So, manually get the AudioFiles into order before
@@ -221,4 +1047,1478 @@ impl MetadataDatabase {
             .collect();
         Ok(ordered_files)
     }
+
+    /// Returns the paths of samples that are missing a vector from the current feature set of
+    /// any of indexes, grouped by analysis root dir ID
+    pub fn get_outdated_samples(
+        &self,
+        indexes: &[&VectorIndex],
+    ) -> Result<HashMap<i64, Vec<String>>, String> {
+        let mut query = self
+            .connection
+            .prepare(
+                "SELECT analysis_root_dir_id, file_path FROM samples
+                WHERE NOT EXISTS (
+                    SELECT 1 FROM sample_vectors
+                    WHERE sample_id = samples.id AND index_id = ?1 AND feature_set = ?2
+                )
+                ORDER BY file_path",
+            )
+            .map_err(|e| format!("Failed to prepare sqlite query: {}", e))?;
+
+        let mut seen: HashSet<String> = HashSet::new();
+        let mut outdated: HashMap<i64, Vec<String>> = HashMap::new();
+        for index in indexes {
+            let mut rows = query
+                .query(params![index.id, self.feature_set_id(index)])
+                .map_err(|e| e.to_string())?;
+            while let Some(row) = rows.next().map_err(|e| e.to_string())? {
+                let dir_id: i64 = row.get(0).map_err(|e| e.to_string())?;
+                let path: String = row.get(1).map_err(|e| e.to_string())?;
+                if seen.insert(path.clone()) {
+                    outdated.entry(dir_id).or_default().push(path);
+                }
+            }
+        }
+        Ok(outdated)
+    }
+
+    /// Returns the embedded tags of the sample with the given ID as (key, value) pairs
+    pub fn get_tags(&self, sample_id: i64) -> Result<Vec<(String, String)>, String> {
+        let mut query = self
+            .connection
+            .prepare("SELECT key, value FROM tags WHERE sample_id = ?1 ORDER BY rowid")
+            .map_err(|e| format!("Failed to prepare sqlite query: {}", e))?;
+        let tags = query
+            .query_map([sample_id], |row| Ok((row.get(0)?, row.get(1)?)))
+            .map_err(|e| e.to_string())?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| e.to_string())?;
+        Ok(tags)
+    }
+
+    fn ensure_sample_exists(&self, sample_id: i64) -> Result<(), String> {
+        let exists: bool = self
+            .connection
+            .query_row(
+                "SELECT EXISTS (SELECT 1 FROM samples WHERE id = ?1)",
+                [sample_id],
+                |row| row.get(0),
+            )
+            .map_err(|e| e.to_string())?;
+        if !exists {
+            return Err(format!("No sample with ID {sample_id}"));
+        }
+        Ok(())
+    }
+
+    /// Adds user tags to a sample. Tags are case-insensitive, and adding a tag the sample
+    /// already has does nothing.
+    pub fn add_user_tags(&mut self, sample_id: i64, tags: &[String]) -> Result<(), String> {
+        if tags.iter().any(|tag| tag.trim().is_empty()) {
+            return Err("Tags can't be empty".to_string());
+        }
+        self.ensure_sample_exists(sample_id)?;
+        let tx = self.connection.transaction().map_err(|e| e.to_string())?;
+        {
+            let mut stmt = tx
+                .prepare_cached("INSERT OR IGNORE INTO user_tags (sample_id, tag) VALUES (?1, ?2)")
+                .map_err(|e| format!("Failed to prepare sqlite query: {}", e))?;
+            for tag in tags {
+                stmt.execute(params![sample_id, tag.trim()])
+                    .map_err(|e| format!("Failed to tag sample {}: {}", sample_id, e))?;
+            }
+        }
+        tx.commit().map_err(|e| e.to_string())
+    }
+
+    /// Removes user tags from a sample, returning how many were removed
+    pub fn remove_user_tags(&mut self, sample_id: i64, tags: &[String]) -> Result<usize, String> {
+        self.ensure_sample_exists(sample_id)?;
+        let tx = self.connection.transaction().map_err(|e| e.to_string())?;
+        let mut num_removed = 0;
+        {
+            let mut stmt = tx
+                .prepare_cached("DELETE FROM user_tags WHERE sample_id = ?1 AND tag = ?2")
+                .map_err(|e| format!("Failed to prepare sqlite query: {}", e))?;
+            for tag in tags {
+                num_removed += stmt
+                    .execute(params![sample_id, tag.trim()])
+                    .map_err(|e| e.to_string())?;
+            }
+        }
+        tx.commit().map_err(|e| e.to_string())?;
+        Ok(num_removed)
+    }
+
+    /// Returns the user tags of the sample with the given ID, sorted alphabetically
+    pub fn get_user_tags(&self, sample_id: i64) -> Result<Vec<String>, String> {
+        let mut query = self
+            .connection
+            .prepare("SELECT tag FROM user_tags WHERE sample_id = ?1 ORDER BY tag")
+            .map_err(|e| format!("Failed to prepare sqlite query: {}", e))?;
+        let tags = query
+            .query_map([sample_id], |row| row.get(0))
+            .map_err(|e| e.to_string())?
+            .collect::<Result<Vec<String>, _>>()
+            .map_err(|e| e.to_string())?;
+        Ok(tags)
+    }
+
+    /// Returns every user tag along with the number of samples it's applied to
+    pub fn list_user_tags(&self) -> Result<Vec<(String, usize)>, String> {
+        let mut query = self
+            .connection
+            .prepare("SELECT tag, COUNT(*) FROM user_tags GROUP BY tag ORDER BY tag")
+            .map_err(|e| format!("Failed to prepare sqlite query: {}", e))?;
+        let tags = query
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .map_err(|e| e.to_string())?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| e.to_string())?;
+        Ok(tags)
+    }
+
+    /// Returns the user tags of every tagged sample
+    pub fn get_all_user_tags(&self) -> Result<HashMap<i64, Vec<String>>, String> {
+        let mut query = self
+            .connection
+            .prepare("SELECT sample_id, tag FROM user_tags ORDER BY sample_id, tag")
+            .map_err(|e| format!("Failed to prepare sqlite query: {}", e))?;
+        let mut tags: HashMap<i64, Vec<String>> = HashMap::new();
+        let rows = query
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .map_err(|e| e.to_string())?;
+        for row in rows {
+            let (sample_id, tag): (i64, String) = row.map_err(|e| e.to_string())?;
+            tags.entry(sample_id).or_default().push(tag);
+        }
+        Ok(tags)
+    }
+
+    /// Returns the samples matching filter, ordered by path
+    pub fn get_audio_files_matching(&self, filter: &SampleFilter) -> Result<Vec<AudioFile>, String> {
+        let (where_clause, values) = filter.where_clause();
+        let query =
+            format!("SELECT {AUDIO_FILE_COLUMNS} FROM samples{where_clause} ORDER BY samples.file_path");
+        let mut stmt = self
+            .connection
+            .prepare(&query)
+            .map_err(|e| format!("Failed to prepare sqlite query: {}", e))?;
+        let files = stmt
+            .query_map(params_from_iter(values), |row| audio_file_from_row(row, 0))
+            .map_err(|e| e.to_string())?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| e.to_string())?;
+        Ok(files)
+    }
+
+    /// Sets the star rating of a sample, or clears it when rating is None
+    pub fn set_rating(&self, sample_id: i64, rating: Option<u8>) -> Result<(), String> {
+        if rating.is_some_and(|rating| rating == 0 || rating > MAX_RATING) {
+            return Err(format!("Ratings must be between 1 and {MAX_RATING}"));
+        }
+        let num_updated = self
+            .connection
+            .execute(
+                "UPDATE samples SET rating = ?1 WHERE id = ?2",
+                params![rating, sample_id],
+            )
+            .map_err(|e| format!("Failed to rate sample {}: {}", sample_id, e))?;
+        if num_updated == 0 {
+            return Err(format!("No sample with ID {sample_id}"));
+        }
+        Ok(())
+    }
+
+    pub fn set_favorite(&self, sample_id: i64, favorite: bool) -> Result<(), String> {
+        let num_updated = self
+            .connection
+            .execute(
+                "UPDATE samples SET favorite = ?1 WHERE id = ?2",
+                params![favorite, sample_id],
+            )
+            .map_err(|e| format!("Failed to update sample {}: {}", sample_id, e))?;
+        if num_updated == 0 {
+            return Err(format!("No sample with ID {sample_id}"));
+        }
+        Ok(())
+    }
+
+    /// Adds samples to the named collection, creating the collection if it doesn't exist
+    pub fn add_to_collection(&mut self, name: &str, sample_ids: &[i64]) -> Result<(), String> {
+        for sample_id in sample_ids {
+            self.ensure_sample_exists(*sample_id)?;
+        }
+        let tx = self.connection.transaction().map_err(|e| e.to_string())?;
+        {
+            let collection_id: i64 = tx
+                .query_row(
+                    "INSERT INTO collections (name) VALUES (?1)
+                    ON CONFLICT(name) DO UPDATE SET name = collections.name
+                    RETURNING id",
+                    [name],
+                    |row| row.get(0),
+                )
+                .map_err(|e| format!("Failed to create collection {}: {}", name, e))?;
+            let mut stmt = tx
+                .prepare_cached(
+                    "INSERT OR IGNORE INTO collection_samples (collection_id, sample_id)
+                    VALUES (?1, ?2)",
+                )
+                .map_err(|e| format!("Failed to prepare sqlite query: {}", e))?;
+            for sample_id in sample_ids {
+                stmt.execute(params![collection_id, sample_id])
+                    .map_err(|e| e.to_string())?;
+            }
+        }
+        tx.commit().map_err(|e| e.to_string())
+    }
+
+    /// Removes samples from the named collection, returning how many were removed
+    pub fn remove_from_collection(&self, name: &str, sample_ids: &[i64]) -> Result<usize, String> {
+        let collection_id: i64 = self
+            .connection
+            .query_row(
+                "SELECT id FROM collections WHERE name = ?1",
+                [name],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(|e| e.to_string())?
+            .ok_or(format!("No collection named {name}"))?;
+        for sample_id in sample_ids {
+            self.ensure_sample_exists(*sample_id)?;
+        }
+        let mut stmt = self
+            .connection
+            .prepare("DELETE FROM collection_samples WHERE collection_id = ?1 AND sample_id = ?2")
+            .map_err(|e| format!("Failed to prepare sqlite query: {}", e))?;
+        let mut num_removed = 0;
+        for sample_id in sample_ids {
+            num_removed += stmt
+                .execute(params![collection_id, sample_id])
+                .map_err(|e| e.to_string())?;
+        }
+        Ok(num_removed)
+    }
+
+    /// Deletes the named collection. The samples in it are left untouched.
+    pub fn delete_collection(&self, name: &str) -> Result<(), String> {
+        let num_deleted = self
+            .connection
+            .execute("DELETE FROM collections WHERE name = ?1", [name])
+            .map_err(|e| e.to_string())?;
+        if num_deleted == 0 {
+            return Err(format!("No collection named {name}"));
+        }
+        Ok(())
+    }
+
+    /// Returns every collection along with the number of samples in it
+    pub fn list_collections(&self) -> Result<Vec<(String, usize)>, String> {
+        let mut query = self
+            .connection
+            .prepare(
+                "SELECT collections.name, COUNT(collection_samples.sample_id)
+                FROM collections
+                LEFT JOIN collection_samples ON collection_samples.collection_id = collections.id
+                GROUP BY collections.id ORDER BY collections.name",
+            )
+            .map_err(|e| format!("Failed to prepare sqlite query: {}", e))?;
+        let collections = query
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .map_err(|e| e.to_string())?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| e.to_string())?;
+        Ok(collections)
+    }
+
+    /// Returns the names of the collections containing the sample with the given ID
+    pub fn get_collections_for_sample(&self, sample_id: i64) -> Result<Vec<String>, String> {
+        let mut query = self
+            .connection
+            .prepare(
+                "SELECT collections.name FROM collections
+                JOIN collection_samples ON collection_samples.collection_id = collections.id
+                WHERE collection_samples.sample_id = ?1 ORDER BY collections.name",
+            )
+            .map_err(|e| format!("Failed to prepare sqlite query: {}", e))?;
+        let names = query
+            .query_map([sample_id], |row| row.get(0))
+            .map_err(|e| e.to_string())?
+            .collect::<Result<Vec<String>, _>>()
+            .map_err(|e| e.to_string())?;
+        Ok(names)
+    }
+
+    /// Searches file names, directories and tags for the words in text, returning up to limit
+    /// samples ordered from best to worst match. Samples matching every word are returned if
+    /// there are any, otherwise samples matching any of the words are. When no sample matches
+    /// any word, e.g. because of a typo, each word is replaced with the indexed terms within a
+    /// couple of edits of it and the search is repeated.
+    pub fn search_text(&self, text: &str, limit: Option<u32>) -> Result<Vec<AudioFile>, String> {
+        let mut stmt = self
+            .connection
+            .prepare(&format!(
+                "SELECT {AUDIO_FILE_COLUMNS} FROM sample_search
+                JOIN samples ON samples.id = sample_search.rowid
+                WHERE sample_search MATCH ?1
+                ORDER BY {TEXT_SEARCH_RANK}
+                LIMIT ?2"
+            ))
+            .map_err(|e| format!("Failed to prepare sqlite query: {}", e))?;
+        let limit = limit.unwrap_or(u32::MAX);
+        let mut search = |query: &str| -> Result<Vec<AudioFile>, String> {
+            stmt.query_map(params![query, limit], |row| audio_file_from_row(row, 0))
+                .map_err(|e| format!("Text search failed: {}", e))?
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| e.to_string())
+        };
+        for operator in ["AND", "OR"] {
+            let query = text_query(text, operator).ok_or("Search text is empty")?;
+            let files = search(&query)?;
+            if !files.is_empty() {
+                return Ok(files);
+            }
+        }
+        for query in self.corrected_text_queries(text)? {
+            let files = search(&query)?;
+            if !files.is_empty() {
+                return Ok(files);
+            }
+        }
+        Ok(Vec::new())
+    }
+
+    /// Returns queries that require every word of text, then any word, with each word replaced
+    /// by the indexed terms it's closest to within max_edits. Words as close to every term as
+    /// to any are left out. Returns no queries if no word is close to a term.
+    fn corrected_text_queries(&self, text: &str) -> Result<Vec<String>, String> {
+        let mut stmt = self
+            .connection
+            .prepare("SELECT term FROM sample_search_vocab")
+            .map_err(|e| format!("Failed to prepare sqlite query: {}", e))?;
+        let terms: Vec<Vec<char>> = stmt
+            .query_map([], |row| row.get::<_, String>(0))
+            .map_err(|e| e.to_string())?
+            .map(|term| term.map(|term| term.chars().collect()))
+            .collect::<Result<_, _>>()
+            .map_err(|e| e.to_string())?;
+
+        let mut alternatives = Vec::new();
+        for word in text_words(text) {
+            let word: Vec<char> = word.to_lowercase().chars().collect();
+            let mut closest: Vec<&[char]> = Vec::new();
+            let mut closest_distance = max_edits(&word) + 1;
+            for term in terms.iter() {
+                let distance = prefix_edit_distance(&word, term);
+                if distance < closest_distance {
+                    closest.clear();
+                    closest_distance = distance;
+                }
+                if distance == closest_distance {
+                    closest.push(term);
+                }
+            }
+            // A word close to every term doesn't narrow the search down
+            if !closest.is_empty() && closest.len() < terms.len() {
+                let terms: Vec<String> = closest
+                    .into_iter()
+                    .map(|term| format!("\"{}\"", term.iter().collect::<String>()))
+                    .collect();
+                alternatives.push(format!("({})", terms.join(" OR ")));
+            }
+        }
+        if alternatives.is_empty() {
+            return Ok(Vec::new());
+        }
+        Ok(vec![alternatives.join(" AND "), alternatives.join(" OR ")])
+    }
+
+    /// Returns the IDs of all samples matching filter
+    pub fn get_sample_ids_matching(&self, filter: &SampleFilter) -> Result<Vec<u32>, String> {
+        let (where_clause, values) = filter.where_clause();
+        let query = format!("SELECT id FROM samples{where_clause}");
+        let mut stmt = self
+            .connection
+            .prepare(&query)
+            .map_err(|e| format!("Failed to prepare sqlite query: {}", e))?;
+        let ids = stmt
+            .query_map(params_from_iter(values), |row| row.get(0))
+            .map_err(|e| e.to_string())?
+            .collect::<Result<Vec<u32>, _>>()
+            .map_err(|e| e.to_string())?;
+        Ok(ids)
+    }
+
+    /// Records whether each of sample_ids is a relevant result for searches for query_id,
+    /// replacing any earlier feedback on them
+    pub fn add_feedback(
+        &mut self,
+        query_id: i64,
+        sample_ids: &[i64],
+        relevant: bool,
+    ) -> Result<(), String> {
+        for sample_id in std::iter::once(&query_id).chain(sample_ids) {
+            self.ensure_sample_exists(*sample_id)?;
+        }
+        let tx = self.connection.transaction().map_err(|e| e.to_string())?;
+        {
+            let mut stmt = tx
+                .prepare_cached(
+                    "INSERT INTO feedback (query_id, sample_id, relevant, created_at)
+                    VALUES (?1, ?2, ?3, unixepoch())
+                    ON CONFLICT(query_id, sample_id) DO UPDATE SET
+                        relevant = excluded.relevant,
+                        created_at = excluded.created_at",
+                )
+                .map_err(|e| format!("Failed to prepare sqlite query: {}", e))?;
+            for sample_id in sample_ids {
+                stmt.execute(params![query_id, sample_id, relevant])
+                    .map_err(|e| format!("Failed to record feedback: {}", e))?;
+            }
+        }
+        tx.commit().map_err(|e| e.to_string())
+    }
+
+    /// Removes all feedback on searches for query_id. Returns the number of removed entries.
+    pub fn clear_feedback(&self, query_id: i64) -> Result<usize, String> {
+        self.connection
+            .execute("DELETE FROM feedback WHERE query_id = ?1", [query_id])
+            .map_err(|e| e.to_string())
+    }
+
+    /// Returns the (sample ID, relevant) feedback on searches for query_id
+    pub fn get_feedback(&self, query_id: i64) -> Result<Vec<(i64, bool)>, String> {
+        let mut stmt = self
+            .connection
+            .prepare("SELECT sample_id, relevant FROM feedback WHERE query_id = ?1 ORDER BY sample_id")
+            .map_err(|e| e.to_string())?;
+        let feedback = stmt
+            .query_map([query_id], |row| Ok((row.get(0)?, row.get(1)?)))
+            .map_err(|e| e.to_string())?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| e.to_string())?;
+        Ok(feedback)
+    }
+
+    /// Returns every (query ID, sample ID, relevant) feedback entry
+    pub fn get_all_feedback(&self) -> Result<Vec<(i64, i64, bool)>, String> {
+        let mut stmt = self
+            .connection
+            .prepare("SELECT query_id, sample_id, relevant FROM feedback")
+            .map_err(|e| e.to_string())?;
+        let feedback = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .map_err(|e| e.to_string())?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| e.to_string())?;
+        Ok(feedback)
+    }
+
+    /// Returns the per-dimension weights learned for index, or None if none have been learned
+    /// from its current feature set
+    pub fn dimension_weights(&self, index: &VectorIndex) -> Result<Option<Vec<f32>>, String> {
+        let weights: Option<Vec<u8>> = self
+            .connection
+            .query_row(
+                "SELECT weights FROM dimension_weights WHERE index_id = ?1 AND feature_set = ?2",
+                params![index.id, self.feature_set_id(index)],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(|e| e.to_string())?;
+        weights
+            .map(|weights| bincode::deserialize(&weights).map_err(|e| e.to_string()))
+            .transpose()
+    }
+
+    /// Stores per-dimension weights for index, or removes them when weights is None
+    pub fn set_dimension_weights(
+        &self,
+        index: &VectorIndex,
+        weights: Option<&[f32]>,
+    ) -> Result<(), String> {
+        match weights {
+            Some(weights) => {
+                let weights = bincode::serialize(weights).map_err(|e| e.to_string())?;
+                self.connection.execute(
+                    "INSERT INTO dimension_weights (index_id, feature_set, weights)
+                    VALUES (?1, ?2, ?3)
+                    ON CONFLICT(index_id) DO UPDATE SET
+                        feature_set = excluded.feature_set,
+                        weights = excluded.weights",
+                    params![index.id, self.feature_set_id(index), weights],
+                )
+            }
+            None => self.connection.execute(
+                "DELETE FROM dimension_weights WHERE index_id = ?1",
+                [index.id],
+            ),
+        }
+        .map_err(|e| format!("Failed to store dimension weights: {}", e))?;
+        Ok(())
+    }
+
+    /// Returns the paths of samples that were analyzed before audio properties were recorded,
+    /// grouped by analysis root dir ID
+    pub fn get_samples_missing_properties(&self) -> Result<HashMap<i64, Vec<String>>, String> {
+        let mut query = self
+            .connection
+            .prepare(
+                "SELECT analysis_root_dir_id, file_path FROM samples
+                WHERE duration IS NULL ORDER BY file_path",
+            )
+            .map_err(|e| format!("Failed to prepare sqlite query: {}", e))?;
+        let mut samples: HashMap<i64, Vec<String>> = HashMap::new();
+        let mut rows = query.query([]).map_err(|e| e.to_string())?;
+        while let Some(row) = rows.next().map_err(|e| e.to_string())? {
+            let dir_id: i64 = row.get(0).map_err(|e| e.to_string())?;
+            let path: String = row.get(1).map_err(|e| e.to_string())?;
+            samples.entry(dir_id).or_default().push(path);
+        }
+        Ok(samples)
+    }
+
+    /// Returns the paths of samples whose embedded tags haven't been read, which are those
+    /// analyzed before tags were introduced, grouped by analysis root dir ID
+    pub fn get_samples_missing_tags(&self) -> Result<HashMap<i64, Vec<String>>, String> {
+        let mut query = self
+            .connection
+            .prepare(
+                "SELECT analysis_root_dir_id, file_path FROM samples
+                WHERE tags_read = 0 ORDER BY file_path",
+            )
+            .map_err(|e| format!("Failed to prepare sqlite query: {}", e))?;
+        let mut samples: HashMap<i64, Vec<String>> = HashMap::new();
+        let mut rows = query.query([]).map_err(|e| e.to_string())?;
+        while let Some(row) = rows.next().map_err(|e| e.to_string())? {
+            let dir_id: i64 = row.get(0).map_err(|e| e.to_string())?;
+            let path: String = row.get(1).map_err(|e| e.to_string())?;
+            samples.entry(dir_id).or_default().push(path);
+        }
+        Ok(samples)
+    }
+
+    /// Returns the IDs and paths of samples whose content hash hasn't been recorded, which are
+    /// those analyzed before content hashes were introduced
+    pub fn get_samples_missing_content_hash(&self) -> Result<Vec<(i64, String)>, String> {
+        let mut query = self
+            .connection
+            .prepare("SELECT id, file_path FROM samples WHERE content_hash IS NULL ORDER BY id")
+            .map_err(|e| format!("Failed to prepare sqlite query: {}", e))?;
+        let samples = query
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .map_err(|e| e.to_string())?
+            .collect::<Result<Vec<(i64, String)>>>()
+            .map_err(|e| e.to_string())?;
+        Ok(samples)
+    }
+
+    /// Records the content hash of each (sample ID, hash) pair
+    pub fn set_content_hashes(&mut self, hashes: &[(i64, u64)]) -> Result<(), String> {
+        let tx = self.connection.transaction().map_err(|e| e.to_string())?;
+        {
+            let mut stmt = tx
+                .prepare_cached("UPDATE samples SET content_hash = ?2 WHERE id = ?1")
+                .map_err(|e| format!("Failed to prepare sqlite query: {}", e))?;
+            for (id, hash) in hashes {
+                stmt.execute(params![id, *hash as i64])
+                    .map_err(|e| e.to_string())?;
+            }
+        }
+        tx.commit().map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    /// Returns the IDs of samples that share a content hash with at least one other sample,
+    /// grouped by hash
+    pub fn get_content_hash_groups(&self) -> Result<Vec<Vec<i64>>, String> {
+        let mut query = self
+            .connection
+            .prepare(
+                "SELECT content_hash, id FROM samples
+                WHERE content_hash IN (
+                    SELECT content_hash FROM samples WHERE content_hash IS NOT NULL
+                    GROUP BY content_hash HAVING COUNT(*) > 1
+                )
+                ORDER BY content_hash, id",
+            )
+            .map_err(|e| format!("Failed to prepare sqlite query: {}", e))?;
+        let mut groups: Vec<Vec<i64>> = Vec::new();
+        let mut last_hash = None;
+        let mut rows = query.query([]).map_err(|e| e.to_string())?;
+        while let Some(row) = rows.next().map_err(|e| e.to_string())? {
+            let hash: i64 = row.get(0).map_err(|e| e.to_string())?;
+            let id: i64 = row.get(1).map_err(|e| e.to_string())?;
+            match groups.last_mut() {
+                Some(group) if last_hash == Some(hash) => group.push(id),
+                _ => groups.push(vec![id]),
+            }
+            last_hash = Some(hash);
+        }
+        Ok(groups)
+    }
+
+    /// Returns the paths of samples without a fingerprint from the current algorithm, grouped
+    /// by analysis root dir ID
+    pub fn get_samples_missing_fingerprint(&self) -> Result<HashMap<i64, Vec<String>>, String> {
+        let mut query = self
+            .connection
+            .prepare(
+                "SELECT analysis_root_dir_id, file_path FROM samples
+                WHERE NOT EXISTS (
+                    SELECT 1 FROM fingerprints WHERE sample_id = samples.id AND version = ?1
+                )
+                ORDER BY file_path",
+            )
+            .map_err(|e| format!("Failed to prepare sqlite query: {}", e))?;
+        let mut samples: HashMap<i64, Vec<String>> = HashMap::new();
+        let mut rows = query
+            .query([FINGERPRINT_VERSION])
+            .map_err(|e| e.to_string())?;
+        while let Some(row) = rows.next().map_err(|e| e.to_string())? {
+            let dir_id: i64 = row.get(0).map_err(|e| e.to_string())?;
+            let path: String = row.get(1).map_err(|e| e.to_string())?;
+            samples.entry(dir_id).or_default().push(path);
+        }
+        Ok(samples)
+    }
+
+    /// Returns the fingerprint of the sample with the given ID, or None if it hasn't been
+    /// fingerprinted with the current algorithm
+    pub fn get_fingerprint(&self, sample_id: i64) -> Result<Option<Vec<u32>>, String> {
+        let serialized: Option<Vec<u8>> = self
+            .connection
+            .query_row(
+                "SELECT fingerprint FROM fingerprints WHERE sample_id = ?1 AND version = ?2",
+                params![sample_id, FINGERPRINT_VERSION],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(|e| e.to_string())?;
+        serialized
+            .map(|serialized| bincode::deserialize(&serialized).map_err(|e| e.to_string()))
+            .transpose()
+    }
+
+    /// Returns the ID and fingerprint of every sample fingerprinted with the current algorithm
+    pub fn get_all_fingerprints(&self) -> Result<Vec<(i64, Vec<u32>)>, String> {
+        let mut query = self
+            .connection
+            .prepare("SELECT sample_id, fingerprint FROM fingerprints WHERE version = ?1")
+            .map_err(|e| format!("Failed to prepare sqlite query: {}", e))?;
+        let mut fingerprints = Vec::new();
+        let mut rows = query
+            .query([FINGERPRINT_VERSION])
+            .map_err(|e| e.to_string())?;
+        while let Some(row) = rows.next().map_err(|e| e.to_string())? {
+            let id: i64 = row.get(0).map_err(|e| e.to_string())?;
+            let serialized: Vec<u8> = row.get(1).map_err(|e| e.to_string())?;
+            let fingerprint = bincode::deserialize(&serialized).map_err(|e| e.to_string())?;
+            fingerprints.push((id, fingerprint));
+        }
+        Ok(fingerprints)
+    }
+
+    /// Replaces the stored clustering of index with clusters, given as (centroid, medoid ID,
+    /// member IDs). Clusters are numbered from 1 in the order given.
+    pub fn set_clusters(
+        &mut self,
+        index: &VectorIndex,
+        clusters: &[(Vec<f32>, i64, Vec<i64>)],
+    ) -> Result<(), String> {
+        let tx = self.connection.transaction().map_err(|e| e.to_string())?;
+        tx.execute("DELETE FROM clusters", [])
+            .map_err(|e| format!("Failed to clear clusters: {}", e))?;
+        {
+            let mut cluster_stmt = tx
+                .prepare_cached(
+                    "INSERT INTO clusters (id, index_id, centroid, medoid_id)
+                    VALUES (?1, ?2, ?3, ?4)",
+                )
+                .map_err(|e| format!("Failed to prepare sqlite query: {}", e))?;
+            let mut assignment_stmt = tx
+                .prepare_cached(
+                    "INSERT INTO cluster_assignments (sample_id, cluster_id) VALUES (?1, ?2)",
+                )
+                .map_err(|e| format!("Failed to prepare sqlite query: {}", e))?;
+            for (i, (centroid, medoid_id, sample_ids)) in clusters.iter().enumerate() {
+                let id = i as i64 + 1;
+                let centroid = bincode::serialize(centroid).map_err(|e| e.to_string())?;
+                cluster_stmt
+                    .execute(params![id, index.id, centroid, medoid_id])
+                    .map_err(|e| format!("Failed to insert cluster: {}", e))?;
+                for sample_id in sample_ids {
+                    assignment_stmt
+                        .execute([sample_id, &id])
+                        .map_err(|e| format!("Failed to insert cluster: {}", e))?;
+                }
+            }
+        }
+        tx.commit().map_err(|e| e.to_string())
+    }
+
+    /// Returns the clusters found by the most recent clustering, ordered by ID
+    pub fn list_clusters(&self) -> Result<Vec<Cluster>, String> {
+        let mut query = self
+            .connection
+            .prepare(&format!(
+                "SELECT clusters.id,
+                    (SELECT COUNT(*) FROM cluster_assignments
+                        WHERE cluster_assignments.cluster_id = clusters.id),
+                    {AUDIO_FILE_COLUMNS}
+                FROM clusters
+                LEFT JOIN samples ON samples.id = clusters.medoid_id
+                ORDER BY clusters.id"
+            ))
+            .map_err(|e| format!("Failed to prepare sqlite query: {}", e))?;
+        let clusters = query
+            .query_map([], |row| {
+                let medoid_id: Option<i64> = row.get(2)?;
+                Ok(Cluster {
+                    id: row.get(0)?,
+                    size: row.get(1)?,
+                    medoid: match medoid_id {
+                        Some(_) => Some(audio_file_from_row(row, 2)?),
+                        None => None,
+                    },
+                })
+            })
+            .map_err(|e| e.to_string())?
+            .collect::<Result<Vec<Cluster>>>()
+            .map_err(|e| e.to_string())?;
+        Ok(clusters)
+    }
+
+    /// Returns the cluster ID of every sample assigned to a cluster
+    pub fn get_cluster_assignments(&self) -> Result<HashMap<i64, i64>, String> {
+        let mut query = self
+            .connection
+            .prepare("SELECT sample_id, cluster_id FROM cluster_assignments")
+            .map_err(|e| format!("Failed to prepare sqlite query: {}", e))?;
+        let assignments = query
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .map_err(|e| e.to_string())?
+            .collect::<Result<HashMap<i64, i64>>>()
+            .map_err(|e| e.to_string())?;
+        Ok(assignments)
+    }
+
+    /// Replaces the map coordinates of every sample with coordinates, given as (sample ID,
+    /// [x, y])
+    pub fn set_map_coordinates(&mut self, coordinates: &[(i64, [f32; 2])]) -> Result<(), String> {
+        let tx = self.connection.transaction().map_err(|e| e.to_string())?;
+        tx.execute("DELETE FROM map_coordinates", [])
+            .map_err(|e| format!("Failed to clear map coordinates: {}", e))?;
+        {
+            let mut stmt = tx
+                .prepare_cached("INSERT INTO map_coordinates (sample_id, x, y) VALUES (?1, ?2, ?3)")
+                .map_err(|e| format!("Failed to prepare sqlite query: {}", e))?;
+            for (id, [x, y]) in coordinates {
+                stmt.execute(params![id, x, y])
+                    .map_err(|e| format!("Failed to insert map coordinates: {}", e))?;
+            }
+        }
+        tx.commit().map_err(|e| e.to_string())
+    }
+
+    /// Returns the map position and cluster of every sample on the map, ordered by ID
+    pub fn get_map_points(&self) -> Result<Vec<MapPoint>, String> {
+        let mut query = self
+            .connection
+            .prepare(
+                "SELECT samples.id, samples.file_path, map_coordinates.x, map_coordinates.y,
+                    cluster_assignments.cluster_id
+                FROM map_coordinates
+                JOIN samples ON samples.id = map_coordinates.sample_id
+                LEFT JOIN cluster_assignments ON cluster_assignments.sample_id = samples.id
+                ORDER BY samples.id",
+            )
+            .map_err(|e| format!("Failed to prepare sqlite query: {}", e))?;
+        let points = query
+            .query_map([], |row| {
+                Ok(MapPoint {
+                    id: row.get(0)?,
+                    path: row.get(1)?,
+                    x: row.get(2)?,
+                    y: row.get(3)?,
+                    cluster: row.get(4)?,
+                })
+            })
+            .map_err(|e| e.to_string())?
+            .collect::<Result<Vec<MapPoint>>>()
+            .map_err(|e| e.to_string())?;
+        Ok(points)
+    }
+
+    /// Labels samples with the user's labels, given as (sample ID, label), replacing any
+    /// earlier labels or predictions
+    pub fn set_user_labels(&mut self, labels: &[(i64, String)]) -> Result<(), String> {
+        let tx = self.connection.transaction().map_err(|e| e.to_string())?;
+        {
+            let mut stmt = tx
+                .prepare_cached(
+                    "INSERT INTO sample_labels (sample_id, label, confidence, is_user_label)
+                    VALUES (?1, ?2, 1.0, 1)
+                    ON CONFLICT (sample_id) DO UPDATE SET
+                        label = excluded.label, confidence = 1.0, is_user_label = 1",
+                )
+                .map_err(|e| format!("Failed to prepare sqlite query: {}", e))?;
+            for (sample_id, label) in labels {
+                stmt.execute(params![sample_id, label])
+                    .map_err(|e| format!("Failed to label sample {}: {}", sample_id, e))?;
+            }
+        }
+        tx.commit().map_err(|e| e.to_string())
+    }
+
+    /// Removes a sample's label, whether the user gave it or it was predicted
+    pub fn remove_label(&self, sample_id: i64) -> Result<(), String> {
+        self.connection
+            .execute(
+                "DELETE FROM sample_labels WHERE sample_id = ?1",
+                [sample_id],
+            )
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    /// Returns the label of every sample the user has labeled
+    pub fn get_user_labels(&self) -> Result<HashMap<i64, String>, String> {
+        let mut query = self
+            .connection
+            .prepare("SELECT sample_id, label FROM sample_labels WHERE is_user_label = 1")
+            .map_err(|e| format!("Failed to prepare sqlite query: {}", e))?;
+        let labels = query
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .map_err(|e| e.to_string())?
+            .collect::<Result<HashMap<i64, String>>>()
+            .map_err(|e| e.to_string())?;
+        Ok(labels)
+    }
+
+    /// Replaces every predicted label with predictions, given as (sample ID, label,
+    /// confidence). Samples the user has labeled keep their labels.
+    pub fn set_predicted_labels(
+        &mut self,
+        predictions: &[(i64, String, f32)],
+    ) -> Result<(), String> {
+        let tx = self.connection.transaction().map_err(|e| e.to_string())?;
+        tx.execute("DELETE FROM sample_labels WHERE is_user_label = 0", [])
+            .map_err(|e| format!("Failed to clear predicted labels: {}", e))?;
+        {
+            let mut stmt = tx
+                .prepare_cached(
+                    "INSERT INTO sample_labels (sample_id, label, confidence, is_user_label)
+                    VALUES (?1, ?2, ?3, 0)
+                    ON CONFLICT (sample_id) DO NOTHING",
+                )
+                .map_err(|e| format!("Failed to prepare sqlite query: {}", e))?;
+            for (sample_id, label, confidence) in predictions {
+                stmt.execute(params![sample_id, label, confidence])
+                    .map_err(|e| format!("Failed to label sample {}: {}", sample_id, e))?;
+            }
+        }
+        tx.commit().map_err(|e| e.to_string())
+    }
+
+    /// Returns a sample's label, if it has one
+    pub fn get_label(&self, sample_id: i64) -> Result<Option<SampleLabel>, String> {
+        self.connection
+            .query_row(
+                "SELECT label, confidence, is_user_label FROM sample_labels WHERE sample_id = ?1",
+                [sample_id],
+                |row| {
+                    Ok(SampleLabel {
+                        label: row.get(0)?,
+                        confidence: row.get(1)?,
+                        is_user_label: row.get(2)?,
+                    })
+                },
+            )
+            .optional()
+            .map_err(|e| e.to_string())
+    }
+
+    /// Returns each label with how many samples the user gave it to and how many it was
+    /// predicted for, ordered by label
+    pub fn count_labels(&self) -> Result<Vec<(String, usize, usize)>, String> {
+        let mut query = self
+            .connection
+            .prepare(
+                "SELECT label, SUM(is_user_label), SUM(1 - is_user_label)
+                FROM sample_labels GROUP BY label ORDER BY label",
+            )
+            .map_err(|e| format!("Failed to prepare sqlite query: {}", e))?;
+        let counts = query
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .map_err(|e| e.to_string())?
+            .collect::<Result<Vec<(String, usize, usize)>>>()
+            .map_err(|e| e.to_string())?;
+        Ok(counts)
+    }
+
+    /// Removes the samples with the given paths, returning their former IDs
+    pub fn delete_samples(&mut self, file_paths: &[String]) -> Result<Vec<i64>, String> {
+        let tx = self.connection.transaction().map_err(|e| e.to_string())?;
+        let mut ids = Vec::with_capacity(file_paths.len());
+        {
+            let mut stmt = tx
+                .prepare_cached("DELETE FROM samples WHERE file_path = ?1 RETURNING id")
+                .map_err(|e| format!("Failed to prepare sqlite query: {}", e))?;
+            for path in file_paths {
+                let mut rows = stmt.query([path]).map_err(|e| e.to_string())?;
+                if let Some(row) = rows.next().map_err(|e| e.to_string())? {
+                    ids.push(row.get(0).map_err(|e| e.to_string())?);
+                }
+            }
+        }
+        tx.commit().map_err(|e| e.to_string())?;
+        Ok(ids)
+    }
+
+    /// Returns the feature set of the vectors stored in the arroy index with the given ID, or
+    /// None if the index hasn't been built yet
+    pub fn get_index_feature_set(&self, index: u16) -> Result<Option<String>, String> {
+        self.connection
+            .query_row(
+                "SELECT feature_set FROM vector_indexes WHERE id = ?1",
+                [index],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(|e| e.to_string())
+    }
+
+    /// Records the feature set, dimensions and metric that index was built with
+    pub fn register_index(&self, index: &VectorIndex) -> Result<(), String> {
+        self.connection
+            .execute(
+                "INSERT INTO vector_indexes (id, name, feature_set, dimensions, metric)
+                VALUES (?1, ?2, ?3, ?4, ?5)
+                ON CONFLICT(id) DO UPDATE SET
+                    name = excluded.name,
+                    feature_set = excluded.feature_set,
+                    dimensions = excluded.dimensions,
+                    metric = excluded.metric",
+                params![
+                    index.id,
+                    index.name,
+                    self.feature_set_id(index),
+                    index.dimensions(),
+                    index.metric.name()
+                ],
+            )
+            .map_err(|e| format!("Failed to update vector index metadata: {}", e))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        feature_extractor::{FeatureSet, MFCC_FEATURE_SET},
+        vector_db::{INDEXES, TIMBRE_INDEX},
+    };
+
+    /// The timbre index as it was before an update to its feature set
+    static OLD_TIMBRE_INDEX: VectorIndex = VectorIndex {
+        feature_set: FeatureSet {
+            version: 0,
+            ..MFCC_FEATURE_SET
+        },
+        ..TIMBRE_INDEX
+    };
+
+    fn in_memory_database() -> MetadataDatabase {
+        MetadataDatabase::open(Path::new(":memory:")).unwrap()
+    }
+
+    /// Opens an empty in-memory library containing a sample for each of paths
+    fn library_with_samples(paths: &[&str]) -> MetadataDatabase {
+        let db = in_memory_database();
+        let dir_id = db.initialize("/lib").unwrap();
+        for path in paths {
+            db.connection
+                .execute(
+                    "INSERT INTO samples (file_path, analysis_root_dir_id) VALUES (?1, ?2)",
+                    params![path, dir_id],
+                )
+                .unwrap();
+        }
+        db
+    }
+
+    /// A one second, 16 bit stereo wav file with a vector in index
+    fn analyzed_file(path: &str, index: &'static VectorIndex, vector: &[f32]) -> AnalyzedFile {
+        AnalyzedFile {
+            path: path.to_string(),
+            properties: AudioProperties {
+                duration: 1.0,
+                sample_rate: 44100,
+                channels: 2,
+                bit_depth: Some(16),
+                codec: "pcm".to_string(),
+                file_size: 176444,
+            },
+            tags: Vec::new(),
+            content_hash: None,
+            fingerprint: None,
+            segmented: false,
+            replaced_segments: Vec::new(),
+            features: vec![Feature::new(vector.to_vec(), path.to_string(), index, None)],
+        }
+    }
+
+    fn sample_id(file: &AnalyzedFile) -> i64 {
+        file.features[0].id().unwrap()
+    }
+
+    #[test]
+    fn batched_samples_keep_their_ids() {
+        let mut db = in_memory_database();
+        let dir_id = db.initialize("/lib").unwrap();
+        let mut first_batch = [
+            analyzed_file("/lib/kick.wav", &TIMBRE_INDEX, &[1.0, 2.0]),
+            analyzed_file("/lib/snare.wav", &TIMBRE_INDEX, &[1.0, 2.0]),
+        ];
+        db.insert_samples(&mut first_batch, dir_id).unwrap();
+        let ids: Vec<i64> = first_batch.iter().map(sample_id).collect();
+        assert_ne!(ids[0], ids[1]);
+
+        // A later batch containing an already stored sample gets its existing id
+        let mut next_batch = [
+            analyzed_file("/lib/snare.wav", &TIMBRE_INDEX, &[1.0, 2.0]),
+            analyzed_file("/lib/hat.wav", &TIMBRE_INDEX, &[3.0, 4.0]),
+        ];
+        db.insert_samples(&mut next_batch, dir_id).unwrap();
+        assert_eq!(sample_id(&next_batch[0]), ids[1]);
+        let features = db.get_all_features(&TIMBRE_INDEX).unwrap();
+        assert_eq!(features.len(), 3);
+        assert_eq!(features["/lib/kick.wav"].id(), &Some(ids[0]));
+    }
+
+    #[test]
+    fn all_features_are_keyed_by_path() {
+        let mut db = in_memory_database();
+        let dir_id = db.initialize("/lib").unwrap();
+        let mut batch = [analyzed_file("/lib/kick.wav", &TIMBRE_INDEX, &[0.5, 0.25])];
+        db.insert_samples(&mut batch, dir_id).unwrap();
+
+        let cached = db.get_all_features(&TIMBRE_INDEX).unwrap();
+        let feature = &cached["/lib/kick.wav"];
+        assert_eq!(feature.id(), &Some(sample_id(&batch[0])));
+        assert_eq!(feature.feature_vector(), &[0.5, 0.25]);
+        assert!(!cached.contains_key("/lib/snare.wav"));
+    }
+
+    #[test]
+    fn reanalyzed_samples_replace_their_features() {
+        let mut db = in_memory_database();
+        let dir_id = db.initialize("/lib").unwrap();
+        let mut batch = [analyzed_file("/lib/kick.wav", &TIMBRE_INDEX, &[1.0, 2.0])];
+        db.insert_samples(&mut batch, dir_id).unwrap();
+        let mut reanalyzed = [analyzed_file("/lib/kick.wav", &TIMBRE_INDEX, &[3.0, 4.0])];
+        db.insert_samples(&mut reanalyzed, dir_id).unwrap();
+
+        assert_eq!(sample_id(&reanalyzed[0]), sample_id(&batch[0]));
+        let features = db.get_all_features(&TIMBRE_INDEX).unwrap();
+        assert_eq!(features.len(), 1);
+        assert_eq!(features["/lib/kick.wav"].feature_vector(), &[3.0, 4.0]);
+    }
+
+    #[test]
+    fn samples_from_other_feature_sets_are_outdated() {
+        let mut db = in_memory_database();
+        let lib_id = db.initialize("/lib").unwrap();
+        let other_id = db.initialize("/other").unwrap();
+        let mut current = [analyzed_file("/lib/kick.wav", &TIMBRE_INDEX, &[1.0])];
+        db.insert_samples(&mut current, lib_id).unwrap();
+        let mut old = [
+            analyzed_file("/lib/snare.wav", &OLD_TIMBRE_INDEX, &[1.0]),
+            analyzed_file("/lib/hat.wav", &OLD_TIMBRE_INDEX, &[1.0]),
+        ];
+        db.insert_samples(&mut old, lib_id).unwrap();
+        let mut other = [analyzed_file("/other/tom.wav", &OLD_TIMBRE_INDEX, &[1.0])];
+        db.insert_samples(&mut other, other_id).unwrap();
+
+        let outdated = db.get_outdated_samples(&[&TIMBRE_INDEX]).unwrap();
+        assert_eq!(outdated.len(), 2);
+        assert_eq!(outdated[&lib_id], ["/lib/hat.wav", "/lib/snare.wav"]);
+        assert_eq!(outdated[&other_id], ["/other/tom.wav"]);
+        // The cache only holds features that can be compared with the current feature set
+        assert_eq!(db.get_all_features(&TIMBRE_INDEX).unwrap().len(), 1);
+
+        let mut reanalyzed = [
+            analyzed_file("/lib/snare.wav", &TIMBRE_INDEX, &[1.0]),
+            analyzed_file("/lib/hat.wav", &TIMBRE_INDEX, &[1.0]),
+        ];
+        db.insert_samples(&mut reanalyzed, lib_id).unwrap();
+        db.delete_samples(&["/other/tom.wav".to_string()]).unwrap();
+        let outdated = db.get_outdated_samples(&[&TIMBRE_INDEX]).unwrap();
+        assert!(outdated.is_empty());
+    }
+
+    #[test]
+    fn each_filter_property_narrows_the_samples() {
+        let mut db = in_memory_database();
+        let dir_id = db.initialize("/lib").unwrap();
+        let plain = analyzed_file("/lib/plain.wav", &TIMBRE_INDEX, &[1.0]);
+        let mut long = analyzed_file("/lib/long.wav", &TIMBRE_INDEX, &[1.0]);
+        long.properties.duration = 10.0;
+        let mut mono = analyzed_file("/lib/mono.wav", &TIMBRE_INDEX, &[1.0]);
+        mono.properties.channels = 1;
+        let mut hi_res = analyzed_file("/lib/hi_res.wav", &TIMBRE_INDEX, &[1.0]);
+        hi_res.properties.sample_rate = 96000;
+        hi_res.properties.bit_depth = Some(24);
+        let mut mp3 = analyzed_file("/lib/lossy.mp3", &TIMBRE_INDEX, &[1.0]);
+        mp3.properties.bit_depth = None;
+        mp3.properties.codec = "mp3".to_string();
+        let mut files = [plain, long, mono, hi_res, mp3];
+        db.insert_samples(&mut files, dir_id).unwrap();
+        // Samples analyzed before properties were recorded never match a filter
+        db.connection
+            .execute(
+                "INSERT INTO samples (file_path, analysis_root_dir_id) VALUES (?1, ?2)",
+                params!["/lib/old.wav", dir_id],
+            )
+            .unwrap();
+
+        let ids: Vec<u32> = files.iter().map(|file| sample_id(file) as u32).collect();
+        let [plain, long, mono, hi_res, mp3] = ids[..] else {
+            unreachable!()
+        };
+        let matching = |filter: SampleFilter| {
+            let mut ids = db.get_sample_ids_matching(&filter).unwrap();
+            ids.sort();
+            ids
+        };
+        let filter = SampleFilter {
+            min_duration: Some(5.0),
+            ..Default::default()
+        };
+        assert_eq!(matching(filter), [long]);
+        let filter = SampleFilter {
+            max_duration: Some(5.0),
+            ..Default::default()
+        };
+        assert_eq!(matching(filter), [plain, mono, hi_res, mp3]);
+        let filter = SampleFilter {
+            sample_rate: Some(96000),
+            ..Default::default()
+        };
+        assert_eq!(matching(filter), [hi_res]);
+        let filter = SampleFilter {
+            channels: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(matching(filter), [mono]);
+        let filter = SampleFilter {
+            bit_depth: Some(24),
+            ..Default::default()
+        };
+        assert_eq!(matching(filter), [hi_res]);
+        let filter = SampleFilter {
+            codec: Some("mp3".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(matching(filter), [mp3]);
+        let combined = SampleFilter {
+            max_duration: Some(5.0),
+            channels: Some(2),
+            bit_depth: Some(16),
+            ..Default::default()
+        };
+        assert_eq!(matching(combined), [plain]);
+        assert_eq!(matching(SampleFilter::default()).len(), 6);
+    }
+
+    #[test]
+    fn list_with_zero_limit_returns_empty_page() {
+        let db = library_with_samples(&["/lib/kick.wav", "/lib/snare.wav"]);
+        let page = db
+            .list_audio_files(&ListOptions {
+                limit: Some(0),
+                ..Default::default()
+            })
+            .unwrap();
+        assert!(page.files.is_empty());
+        assert_eq!(page.next_cursor, None);
+        assert_eq!(page.total, 2);
+    }
+
+    #[test]
+    fn list_pages_continue_from_cursor() {
+        let db = library_with_samples(&["/lib/a.wav", "/lib/b.wav", "/lib/c.wav"]);
+        let options = ListOptions {
+            limit: Some(2),
+            ..Default::default()
+        };
+        let first = db.list_audio_files(&options).unwrap();
+        let paths: Vec<&str> = first.files.iter().map(|file| file.path()).collect();
+        assert_eq!(paths, ["/lib/a.wav", "/lib/b.wav"]);
+
+        let second = db
+            .list_audio_files(&ListOptions {
+                cursor: first.next_cursor,
+                ..options
+            })
+            .unwrap();
+        let paths: Vec<&str> = second.files.iter().map(|file| file.path()).collect();
+        assert_eq!(paths, ["/lib/c.wav"]);
+        assert_eq!(second.next_cursor, None);
+        assert_eq!(second.total, 3);
+    }
+
+    #[test]
+    fn cursors_round_trip_through_their_encoding() {
+        let cursor = Cursor {
+            sort: SortKey::Duration,
+            direction: SortDirection::Descending,
+            sort_value: CursorValue::Real(1.5),
+            id: 42,
+        };
+        let decoded = Cursor::decode(&cursor.encode().unwrap()).unwrap();
+        assert_eq!(decoded.sort, SortKey::Duration);
+        assert_eq!(decoded.direction, SortDirection::Descending);
+        assert!(matches!(decoded.sort_value, CursorValue::Real(value) if value == 1.5));
+        assert_eq!(decoded.id, 42);
+    }
+
+    #[test]
+    fn malformed_cursors_are_rejected() {
+        let encoded = Cursor {
+            sort: SortKey::Path,
+            direction: SortDirection::Ascending,
+            sort_value: CursorValue::Text("/lib/kick.wav".to_string()),
+            id: 1,
+        }
+        .encode()
+        .unwrap();
+        assert!(Cursor::decode(&encoded[..encoded.len() - 1]).is_err());
+        assert!(Cursor::decode(&encoded[..encoded.len() - 2]).is_err());
+        assert!(Cursor::decode("not a cursor").is_err());
+        assert!(Cursor::decode("").is_err());
+    }
+
+    #[test]
+    fn feedback_replaces_earlier_judgements() {
+        let mut db = library_with_samples(&["/lib/a.wav", "/lib/b.wav", "/lib/c.wav"]);
+        db.add_feedback(1, &[2, 3], true).unwrap();
+        db.add_feedback(1, &[3], false).unwrap();
+        assert_eq!(db.get_feedback(1).unwrap(), [(2, true), (3, false)]);
+        assert!(db.add_feedback(1, &[4], true).is_err());
+        assert!(db.add_feedback(4, &[2], true).is_err());
+
+        db.add_feedback(2, &[1], true).unwrap();
+        assert_eq!(db.clear_feedback(1).unwrap(), 2);
+        assert!(db.get_feedback(1).unwrap().is_empty());
+        assert_eq!(db.get_feedback(2).unwrap(), [(1, true)]);
+    }
+
+    #[test]
+    fn tag_filters_match_wildcards_literally() {
+        let db = library_with_samples(&["/lib/a.wav", "/lib/b.wav", "/lib/c.wav"]);
+        for (id, value) in [(1, "100% Techno"), (2, "1000 Techno"), (3, "a_b")] {
+            db.connection
+                .execute(
+                    "INSERT INTO tags (sample_id, key, value) VALUES (?1, 'genre', ?2)",
+                    params![id, value],
+                )
+                .unwrap();
+        }
+        let matching = |value: &str| {
+            let page = db
+                .list_audio_files(&ListOptions {
+                    filter: SampleFilter {
+                        tags: vec![("genre".to_string(), value.to_string())],
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                })
+                .unwrap();
+            page.files
+                .iter()
+                .map(|file| file.path().to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(matching("0%"), ["/lib/a.wav"]);
+        assert_eq!(matching("techno"), ["/lib/a.wav", "/lib/b.wav"]);
+        assert_eq!(matching("a_"), ["/lib/c.wav"]);
+        assert!(matching("ab").is_empty());
+    }
+
+    #[test]
+    fn samples_are_missing_tags_until_analyzed() {
+        let db = library_with_samples(&["/lib/a.wav"]);
+        let missing = db.get_samples_missing_tags().unwrap();
+        assert_eq!(missing.into_values().collect::<Vec<_>>(), [["/lib/a.wav"]]);
+
+        db.connection
+            .execute("UPDATE samples SET tags_read = 1", [])
+            .unwrap();
+        assert!(db.get_samples_missing_tags().unwrap().is_empty());
+    }
+
+    /// An analyzed file with a whole file feature and a segment feature for each of segments
+    fn segmented_file(path: &str, segments: &[(f32, f32)]) -> AnalyzedFile {
+        let index = INDEXES[0];
+        let mut features = vec![Feature::new(
+            vec![0.0; index.dimensions()],
+            path.to_string(),
+            index,
+            None,
+        )];
+        for (start, end) in segments {
+            let vector = vec![0.0; SEGMENT_INDEX.dimensions()];
+            features.push(Feature::new_segment(vector, path.to_string(), *start, *end));
+        }
+        AnalyzedFile {
+            path: path.to_string(),
+            properties: AudioProperties {
+                duration: 1.0,
+                sample_rate: 44100,
+                channels: 1,
+                bit_depth: Some(16),
+                codec: "pcm".to_string(),
+                file_size: 0,
+            },
+            tags: Vec::new(),
+            content_hash: None,
+            fingerprint: None,
+            segmented: true,
+            replaced_segments: Vec::new(),
+            features,
+        }
+    }
+
+    #[test]
+    fn files_too_short_to_segment_are_recorded_as_segmented() {
+        let mut db = MetadataDatabase::open(Path::new(":memory:")).unwrap();
+        let dir_id = db.initialize("/lib").unwrap();
+        let mut files = [
+            segmented_file("/lib/short.wav", &[]),
+            segmented_file("/lib/long.wav", &[(0.0, 1.0), (1.0, 2.0)]),
+        ];
+        db.insert_samples(&mut files, dir_id).unwrap();
+
+        let segmented = db.get_segmented_files().unwrap();
+        assert!(segmented.contains("/lib/short.wav"));
+        assert!(segmented.contains("/lib/long.wav"));
+        assert_eq!(db.get_all_segment_features().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn resegmenting_a_file_reports_its_replaced_segments() {
+        let mut db = MetadataDatabase::open(Path::new(":memory:")).unwrap();
+        let dir_id = db.initialize("/lib").unwrap();
+        let mut first = [segmented_file("/lib/long.wav", &[(0.0, 1.0), (1.0, 2.0)])];
+        db.insert_samples(&mut first, dir_id).unwrap();
+        let old_ids: Vec<i64> = first[0].features[1..]
+            .iter()
+            .map(|feature| feature.id().unwrap())
+            .collect();
+
+        let mut second = [segmented_file("/lib/long.wav", &[])];
+        db.insert_samples(&mut second, dir_id).unwrap();
+        assert_eq!(second[0].replaced_segments, old_ids);
+        assert!(db.get_all_segment_features().unwrap().is_empty());
+        assert!(db.get_segmented_files().unwrap().contains("/lib/long.wav"));
+    }
+
+    #[test]
+    fn prefix_edit_distance_counts_edits_to_the_closest_prefix() {
+        let distance = |word: &str, term: &str| {
+            let word: Vec<char> = word.chars().collect();
+            let term: Vec<char> = term.chars().collect();
+            prefix_edit_distance(&word, &term)
+        };
+        assert_eq!(distance("snar", "snare"), 0);
+        assert_eq!(distance("snrae", "snare"), 2);
+        assert_eq!(distance("kik", "kick"), 1);
+        assert_eq!(distance("hat", "kick"), 3);
+        assert_eq!(distance("", "kick"), 0);
+    }
+
+    #[test]
+    fn misspelled_words_find_similarly_spelled_terms() {
+        let db = library_with_samples(&["/lib/Snare Tight.wav", "/lib/Kick.wav"]);
+        let paths = |text: &str| -> Vec<String> {
+            db.search_text(text, None)
+                .unwrap()
+                .iter()
+                .map(|file| file.path().to_string())
+                .collect()
+        };
+        assert_eq!(paths("snar"), ["/lib/Snare Tight.wav"]);
+        assert_eq!(paths("snere tigt"), ["/lib/Snare Tight.wav"]);
+        assert_eq!(paths("kcik"), ["/lib/Kick.wav"]);
+        assert!(paths("xy").is_empty());
+    }
+
+    #[test]
+    fn misspelled_words_ignore_surrounding_punctuation() {
+        let db = library_with_samples(&["/lib/Snare Tight.wav", "/lib/Kick.wav"]);
+        let paths = |text: &str| -> Vec<String> {
+            db.search_text(text, None)
+                .unwrap()
+                .iter()
+                .map(|file| file.path().to_string())
+                .collect()
+        };
+        assert_eq!(paths("kcik "), ["/lib/Kick.wav"]);
+        assert_eq!(paths("kcik,  "), ["/lib/Kick.wav"]);
+        assert_eq!(paths("snere-tigt!"), ["/lib/Snare Tight.wav"]);
+        assert!(paths("zzzzq ").is_empty());
+        assert!(paths("zzzzq?").is_empty());
+    }
+
+    #[test]
+    fn punctuation_isnt_searched_for() {
+        assert_eq!(
+            text_query("kick - & snare", "AND").as_deref(),
+            Some("\"kick\"* AND \"snare\"*")
+        );
+        assert_eq!(text_query(" - ", "AND"), None);
+
+        let db = library_with_samples(&["/lib/Snare Tight.wav", "/lib/Kick.wav"]);
+        let filter = SampleFilter {
+            text: Some("kick -".to_string()),
+            ..Default::default()
+        };
+        let files = db.get_audio_files_matching(&filter).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path(), "/lib/Kick.wav");
+    }
+
+    #[test]
+    fn empty_user_tags_are_rejected() {
+        let mut db = library_with_samples(&["/lib/kick.wav"]);
+        let tags = ["punchy".to_string(), "  ".to_string()];
+        assert!(db.add_user_tags(1, &tags).is_err());
+        assert!(db.get_user_tags(1).unwrap().is_empty());
+        db.add_user_tags(1, &[" punchy ".to_string()]).unwrap();
+        assert_eq!(db.get_user_tags(1).unwrap(), ["punchy"]);
+    }
+
+    #[test]
+    fn removing_tags_from_an_unknown_sample_fails() {
+        let mut db = library_with_samples(&["/lib/kick.wav"]);
+        db.add_user_tags(1, &["punchy".to_string()]).unwrap();
+        assert!(db.remove_user_tags(2, &["punchy".to_string()]).is_err());
+        assert_eq!(db.remove_user_tags(1, &["punchy".to_string()]).unwrap(), 1);
+    }
+
+    #[test]
+    fn removing_from_an_unknown_collection_or_sample_fails() {
+        let mut db = library_with_samples(&["/lib/kick.wav", "/lib/snare.wav"]);
+        db.add_to_collection("drums", &[1, 2]).unwrap();
+        assert!(db.remove_from_collection("bass", &[1]).is_err());
+        assert!(db.remove_from_collection("drums", &[1, 3]).is_err());
+        assert_eq!(db.remove_from_collection("drums", &[1]).unwrap(), 1);
+        assert_eq!(db.list_collections().unwrap(), [("drums".to_string(), 1)]);
+    }
 }
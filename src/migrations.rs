@@ -0,0 +1,399 @@
+use rusqlite::Connection;
+
+/// Ordered schema migrations for the metadata database. The database's `user_version` pragma
+/// records how many of these have been applied, so existing entries must never be edited or
+/// reordered. Schema changes are made by appending a new migration.
+const MIGRATIONS: &[&str] = &[
+    // 1: The schema used by v0.2.0, which created its tables lazily. IF NOT EXISTS lets this
+    // apply cleanly to both new databases and databases created by v0.2.0.
+    "CREATE TABLE IF NOT EXISTS analysis_root_dirs (
+        id INTEGER PRIMARY KEY,
+        dir_path TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS samples (
+        id INTEGER PRIMARY KEY,
+        analysis_root_dir_id INTEGER,
+        file_path TEXT NOT NULL UNIQUE,
+        feature_vector BLOB NOT NULL,
+        FOREIGN KEY(analysis_root_dir_id) REFERENCES analysis_root_dirs(id)
+    );
+    CREATE INDEX IF NOT EXISTS idx_file_path ON samples (file_path);",
+    // 2: Record the feature set that produced each vector. Existing vectors were all produced
+    // by the v0.2.0 MFCC extractor. vector_indexes tracks the feature set of each arroy index,
+    // where index 0 already exists if any samples were analyzed.
+    "ALTER TABLE samples ADD COLUMN feature_set TEXT;
+    UPDATE samples SET feature_set = 'mfcc-v1-sr22050-fft2048-c13-f40';
+    CREATE INDEX idx_feature_set ON samples (feature_set);
+    CREATE TABLE vector_indexes (
+        id INTEGER PRIMARY KEY,
+        feature_set TEXT NOT NULL,
+        dimensions INTEGER NOT NULL
+    );
+    INSERT INTO vector_indexes (id, feature_set, dimensions)
+        SELECT 0, 'mfcc-v1-sr22050-fft2048-c13-f40', 13 WHERE EXISTS (SELECT 1 FROM samples);",
+    // 3: Support multiple named indexes. Vectors move out of samples into sample_vectors,
+    // which holds one vector per sample per index. The existing vectors belong to index 0,
+    // the timbre index.
+    "CREATE TABLE sample_vectors (
+        sample_id INTEGER NOT NULL REFERENCES samples(id) ON DELETE CASCADE,
+        index_id INTEGER NOT NULL,
+        feature_set TEXT NOT NULL,
+        vector BLOB NOT NULL,
+        PRIMARY KEY (sample_id, index_id)
+    );
+    CREATE INDEX idx_sample_vectors_feature_set ON sample_vectors (index_id, feature_set);
+    INSERT INTO sample_vectors (sample_id, index_id, feature_set, vector)
+        SELECT id, 0, feature_set, feature_vector FROM samples;
+    DROP INDEX idx_feature_set;
+    ALTER TABLE samples DROP COLUMN feature_set;
+    ALTER TABLE samples DROP COLUMN feature_vector;
+    ALTER TABLE vector_indexes ADD COLUMN name TEXT;
+    ALTER TABLE vector_indexes ADD COLUMN metric TEXT;
+    UPDATE vector_indexes SET name = 'timbre', metric = 'angular' WHERE id = 0;",
+    // 4: Segments of long files, each with its own vector and time range in seconds
+    "CREATE TABLE segments (
+        id INTEGER PRIMARY KEY,
+        sample_id INTEGER NOT NULL REFERENCES samples(id) ON DELETE CASCADE,
+        start_time REAL NOT NULL,
+        end_time REAL NOT NULL,
+        feature_set TEXT NOT NULL,
+        vector BLOB NOT NULL
+    );
+    CREATE INDEX idx_segments_sample_id ON segments (sample_id);",
+    // 5: Library-wide settings, such as the preprocessing configuration
+    "CREATE TABLE settings (
+        key TEXT PRIMARY KEY,
+        value BLOB NOT NULL
+    );",
+    // 6: Properties of the original audio file. These are NULL for samples analyzed before
+    // this version until they're re-analyzed.
+    "ALTER TABLE samples ADD COLUMN duration REAL;
+    ALTER TABLE samples ADD COLUMN sample_rate INTEGER;
+    ALTER TABLE samples ADD COLUMN channels INTEGER;
+    ALTER TABLE samples ADD COLUMN bit_depth INTEGER;
+    ALTER TABLE samples ADD COLUMN codec TEXT;
+    ALTER TABLE samples ADD COLUMN file_size INTEGER;
+    CREATE INDEX idx_samples_duration ON samples (duration);",
+    // 7: Tags embedded in the audio files, e.g. ID3 frames and RIFF INFO chunks
+    "CREATE TABLE tags (
+        sample_id INTEGER NOT NULL REFERENCES samples(id) ON DELETE CASCADE,
+        key TEXT NOT NULL,
+        value TEXT NOT NULL
+    );
+    CREATE INDEX idx_tags_sample_id ON tags (sample_id);
+    CREATE INDEX idx_tags_key ON tags (key, value);",
+    // 8: User annotations. Tags and collection names are case-insensitive.
+    "ALTER TABLE samples ADD COLUMN rating INTEGER;
+    ALTER TABLE samples ADD COLUMN favorite INTEGER NOT NULL DEFAULT 0;
+    CREATE TABLE user_tags (
+        sample_id INTEGER NOT NULL REFERENCES samples(id) ON DELETE CASCADE,
+        tag TEXT NOT NULL COLLATE NOCASE,
+        PRIMARY KEY (sample_id, tag)
+    );
+    CREATE INDEX idx_user_tags_tag ON user_tags (tag);
+    CREATE TABLE collections (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL UNIQUE COLLATE NOCASE
+    );
+    CREATE TABLE collection_samples (
+        collection_id INTEGER NOT NULL REFERENCES collections(id) ON DELETE CASCADE,
+        sample_id INTEGER NOT NULL REFERENCES samples(id) ON DELETE CASCADE,
+        PRIMARY KEY (collection_id, sample_id)
+    );",
+    // 9: Full-text search over file names, directories, embedded tag values and user tags.
+    // Triggers keep each sample's row up to date. They delete and reinsert rows rather than
+    // using INSERT OR REPLACE, since an OR IGNORE on the triggering statement would override
+    // it. The view splits paths on the last / or \ by trimming every character that isn't a
+    // separator from the end of the path.
+    "CREATE VIEW sample_search_source AS
+    SELECT
+        samples.id AS id,
+        replace(samples.file_path, rtrim(samples.file_path,
+            replace(replace(samples.file_path, '/', ''), '\\', '')), '') AS name,
+        rtrim(samples.file_path,
+            replace(replace(samples.file_path, '/', ''), '\\', '')) AS directory,
+        coalesce((SELECT group_concat(value, ' ') FROM tags WHERE sample_id = samples.id), '')
+            || ' ' ||
+            coalesce((SELECT group_concat(tag, ' ') FROM user_tags WHERE sample_id = samples.id), '')
+            AS tags
+    FROM samples;
+    CREATE VIRTUAL TABLE sample_search USING fts5(
+        name, directory, tags, tokenize = 'unicode61 remove_diacritics 2'
+    );
+    INSERT INTO sample_search (rowid, name, directory, tags)
+        SELECT id, name, directory, tags FROM sample_search_source;
+    CREATE TRIGGER sample_search_insert AFTER INSERT ON samples BEGIN
+        DELETE FROM sample_search WHERE rowid = new.id;
+        INSERT INTO sample_search (rowid, name, directory, tags)
+            SELECT id, name, directory, tags FROM sample_search_source WHERE id = new.id;
+    END;
+    CREATE TRIGGER sample_search_update AFTER UPDATE OF file_path ON samples BEGIN
+        DELETE FROM sample_search WHERE rowid = new.id;
+        INSERT INTO sample_search (rowid, name, directory, tags)
+            SELECT id, name, directory, tags FROM sample_search_source WHERE id = new.id;
+    END;
+    CREATE TRIGGER sample_search_delete AFTER DELETE ON samples BEGIN
+        DELETE FROM sample_search WHERE rowid = old.id;
+    END;
+    CREATE TRIGGER sample_search_tags_insert AFTER INSERT ON tags BEGIN
+        DELETE FROM sample_search WHERE rowid = new.sample_id;
+        INSERT INTO sample_search (rowid, name, directory, tags)
+            SELECT id, name, directory, tags FROM sample_search_source WHERE id = new.sample_id;
+    END;
+    CREATE TRIGGER sample_search_tags_delete AFTER DELETE ON tags BEGIN
+        DELETE FROM sample_search WHERE rowid = old.sample_id;
+        INSERT INTO sample_search (rowid, name, directory, tags)
+            SELECT id, name, directory, tags FROM sample_search_source WHERE id = old.sample_id;
+    END;
+    CREATE TRIGGER sample_search_user_tags_insert AFTER INSERT ON user_tags BEGIN
+        DELETE FROM sample_search WHERE rowid = new.sample_id;
+        INSERT INTO sample_search (rowid, name, directory, tags)
+            SELECT id, name, directory, tags FROM sample_search_source WHERE id = new.sample_id;
+    END;
+    CREATE TRIGGER sample_search_user_tags_delete AFTER DELETE ON user_tags BEGIN
+        DELETE FROM sample_search WHERE rowid = old.sample_id;
+        INSERT INTO sample_search (rowid, name, directory, tags)
+            SELECT id, name, directory, tags FROM sample_search_source WHERE id = old.sample_id;
+    END;",
+    // 10: When each sample was first analyzed, in seconds since the Unix epoch. Existing
+    // samples are treated as added now.
+    "ALTER TABLE samples ADD COLUMN added_at INTEGER;
+    UPDATE samples SET added_at = unixepoch();",
+    // 11: Relevance feedback on search results, keyed by the sample that was searched for,
+    // and per-dimension weights learned from it. Weights are only valid for the feature set
+    // they were learned from.
+    "CREATE TABLE feedback (
+        query_id INTEGER NOT NULL REFERENCES samples(id) ON DELETE CASCADE,
+        sample_id INTEGER NOT NULL REFERENCES samples(id) ON DELETE CASCADE,
+        relevant INTEGER NOT NULL,
+        created_at INTEGER NOT NULL,
+        PRIMARY KEY (query_id, sample_id)
+    );
+    CREATE INDEX idx_feedback_sample_id ON feedback (sample_id);
+    CREATE TABLE dimension_weights (
+        index_id INTEGER PRIMARY KEY,
+        feature_set TEXT NOT NULL,
+        weights BLOB NOT NULL
+    );",
+    // 12: A hash of each sample's file contents, used to find exact duplicates. It's filled in
+    // for existing samples the first time duplicates are searched for.
+    "ALTER TABLE samples ADD COLUMN content_hash INTEGER;
+    CREATE INDEX idx_samples_content_hash ON samples (content_hash);",
+    // 13: Acoustic fingerprints, stored with the version of the algorithm that calculated
+    // them. Existing samples are fingerprinted by reanalyze.
+    "CREATE TABLE fingerprints (
+        sample_id INTEGER PRIMARY KEY REFERENCES samples(id) ON DELETE CASCADE,
+        version INTEGER NOT NULL,
+        fingerprint BLOB NOT NULL
+    );",
+    // 14: The most recent clustering of the library. Samples without an assignment are
+    // noise, or were added after clustering.
+    "CREATE TABLE clusters (
+        id INTEGER PRIMARY KEY,
+        index_id INTEGER NOT NULL,
+        centroid BLOB NOT NULL,
+        medoid_id INTEGER REFERENCES samples(id) ON DELETE SET NULL
+    );
+    CREATE TABLE cluster_assignments (
+        sample_id INTEGER PRIMARY KEY REFERENCES samples(id) ON DELETE CASCADE,
+        cluster_id INTEGER NOT NULL REFERENCES clusters(id) ON DELETE CASCADE
+    );
+    CREATE INDEX idx_cluster_assignments_cluster_id ON cluster_assignments (cluster_id);",
+    // 15: Each sample's position on the 2D map from the most recent projection
+    "CREATE TABLE map_coordinates (
+        sample_id INTEGER PRIMARY KEY REFERENCES samples(id) ON DELETE CASCADE,
+        x REAL NOT NULL,
+        y REAL NOT NULL
+    );",
+    // 16: Sound type labels, either given by the user or predicted from the user's labels
+    "CREATE TABLE sample_labels (
+        sample_id INTEGER PRIMARY KEY REFERENCES samples(id) ON DELETE CASCADE,
+        label TEXT NOT NULL COLLATE NOCASE,
+        confidence REAL NOT NULL,
+        is_user_label INTEGER NOT NULL
+    );
+    CREATE INDEX idx_sample_labels_label ON sample_labels (label);",
+    // 17: Whether embedded tags have been read from the sample's file. Untagged samples
+    // analyzed since tags were introduced can't be told apart from those analyzed before, so
+    // they're read again once.
+    "ALTER TABLE samples ADD COLUMN tags_read INTEGER NOT NULL DEFAULT 0;
+    UPDATE samples SET tags_read = 1
+        WHERE EXISTS (SELECT 1 FROM tags WHERE sample_id = samples.id);",
+    // 18: Whether the sample has been split into segments. Files too short to produce more
+    // than one segment have none, so this tells them apart from files that haven't been
+    // segmented.
+    "ALTER TABLE samples ADD COLUMN segmented INTEGER NOT NULL DEFAULT 0;
+    UPDATE samples SET segmented = 1
+        WHERE EXISTS (SELECT 1 FROM segments WHERE sample_id = samples.id);",
+    // 19: The terms of the full-text index, which misspelled search words are matched against
+    "CREATE VIRTUAL TABLE sample_search_vocab USING fts5vocab(sample_search, 'row');",
+];
+
+/// The schema version of a fully migrated database
+pub const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+pub fn schema_version(connection: &Connection) -> Result<u32, String> {
+    connection
+        .pragma_query_value(None, "user_version", |row| row.get(0))
+        .map_err(|e| format!("Failed to read schema version: {}", e))
+}
+
+/// Applies any migrations that haven't been run against the database yet. Each migration runs
+/// in its own transaction along with the version bump, so a failed migration leaves the
+/// database at the previous version.
+pub fn migrate(connection: &mut Connection) -> Result<(), String> {
+    migrate_to(connection, SCHEMA_VERSION)
+}
+
+/// Applies the migrations up to target_version that haven't been run against the database yet
+fn migrate_to(connection: &mut Connection, target_version: u32) -> Result<(), String> {
+    let version = schema_version(connection)?;
+    if version > SCHEMA_VERSION {
+        return Err(format!(
+            "Metadata database has schema version {} but this build only supports up to version {}",
+            version, SCHEMA_VERSION
+        ));
+    }
+
+    for (index, migration) in MIGRATIONS
+        .iter()
+        .enumerate()
+        .take(target_version as usize)
+        .skip(version as usize)
+    {
+        let target_version = index as u32 + 1;
+        let tx = connection.transaction().map_err(|e| e.to_string())?;
+        tx.execute_batch(migration)
+            .map_err(|e| format!("Failed to migrate to schema version {}: {}", target_version, e))?;
+        tx.pragma_update(None, "user_version", target_version)
+            .map_err(|e| e.to_string())?;
+        tx.commit().map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The vector v0.2.0 stored for each sample
+    const VECTOR: [u8; 4] = [1, 2, 3, 4];
+
+    /// Creates a database in the format written by v0.2.0, with two analyzed samples
+    fn v0_2_0_database() -> Connection {
+        let connection = Connection::open_in_memory().unwrap();
+        connection
+            .execute_batch(
+                "CREATE TABLE IF NOT EXISTS analysis_root_dirs (
+                    id INTEGER PRIMARY KEY,
+                    dir_path TEXT NOT NULL
+                );
+                CREATE TABLE IF NOT EXISTS samples (
+                    id INTEGER PRIMARY KEY,
+                    analysis_root_dir_id INTEGER,
+                    file_path TEXT NOT NULL UNIQUE,
+                    feature_vector BLOB NOT NULL,
+                    FOREIGN KEY(analysis_root_dir_id) REFERENCES analysis_root_dirs(id)
+                );
+                CREATE INDEX IF NOT EXISTS idx_file_path ON samples (file_path);
+                INSERT INTO analysis_root_dirs (dir_path) VALUES ('/lib');",
+            )
+            .unwrap();
+        for path in ["/lib/kick.wav", "/lib/snare.wav"] {
+            connection
+                .execute(
+                    "INSERT INTO samples (analysis_root_dir_id, file_path, feature_vector)
+                    VALUES (1, ?1, ?2)",
+                    rusqlite::params![path, VECTOR.as_slice()],
+                )
+                .unwrap();
+        }
+        connection
+    }
+
+    fn sample_paths(connection: &Connection) -> Vec<String> {
+        connection
+            .prepare("SELECT file_path FROM samples ORDER BY id")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .map(|path| path.unwrap())
+            .collect()
+    }
+
+    /// Returns the stored vector of each sample, wherever the schema at version keeps them
+    fn sample_vectors(connection: &Connection, version: u32) -> Vec<Vec<u8>> {
+        let query = if version < 3 {
+            "SELECT feature_vector FROM samples ORDER BY id"
+        } else {
+            "SELECT vector FROM sample_vectors WHERE index_id = 0 ORDER BY sample_id"
+        };
+        connection
+            .prepare(query)
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .map(|vector| vector.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn v0_2_0_samples_survive_each_migration() {
+        let mut connection = v0_2_0_database();
+        assert_eq!(schema_version(&connection).unwrap(), 0);
+        for version in 1..=SCHEMA_VERSION {
+            migrate_to(&mut connection, version).unwrap();
+            assert_eq!(schema_version(&connection).unwrap(), version);
+            assert_eq!(
+                sample_paths(&connection),
+                ["/lib/kick.wav", "/lib/snare.wav"],
+                "samples changed by migration {version}"
+            );
+            assert_eq!(
+                sample_vectors(&connection, version),
+                [VECTOR.to_vec(), VECTOR.to_vec()],
+                "vectors changed by migration {version}"
+            );
+        }
+    }
+
+    #[test]
+    fn v0_2_0_vectors_are_attributed_to_the_original_feature_set() {
+        let mut connection = v0_2_0_database();
+        migrate(&mut connection).unwrap();
+        let feature_sets: Vec<String> = connection
+            .prepare("SELECT feature_set FROM sample_vectors ORDER BY sample_id")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .map(|feature_set| feature_set.unwrap())
+            .collect();
+        assert_eq!(feature_sets, ["mfcc-v1-sr22050-fft2048-c13-f40"; 2]);
+        let index: (String, u32) = connection
+            .query_row(
+                "SELECT feature_set, dimensions FROM vector_indexes WHERE id = 0",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(index, ("mfcc-v1-sr22050-fft2048-c13-f40".to_string(), 13));
+    }
+
+    #[test]
+    fn migrating_a_current_database_changes_nothing() {
+        let mut connection = v0_2_0_database();
+        migrate(&mut connection).unwrap();
+        migrate(&mut connection).unwrap();
+        assert_eq!(schema_version(&connection).unwrap(), SCHEMA_VERSION);
+        assert_eq!(sample_paths(&connection).len(), 2);
+    }
+
+    #[test]
+    fn newer_databases_are_rejected() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        connection
+            .pragma_update(None, "user_version", SCHEMA_VERSION + 1)
+            .unwrap();
+        assert!(migrate(&mut connection).is_err());
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+
+use crate::metadata_db::AudioFile;
+
+/// The default number of nearest neighbors each sample is compared with
+pub const DEFAULT_NUM_NEIGHBORS: usize = 10;
+
+/// The default share of a sample's neighbors that must belong to other groups for it to be
+/// reported as misplaced
+pub const DEFAULT_MIN_DISAGREEMENT: f32 = 0.8;
+
+/// The default isolation score above which a sample is reported as isolated
+pub const DEFAULT_MIN_ISOLATION: f32 = 3.0;
+
+/// Groups with fewer samples than this are too small to tell whether a sample belongs in them
+const MIN_GROUP_SIZE: usize = 3;
+
+/// Scales the median absolute deviation to match the standard deviation of normally
+/// distributed values
+const MAD_SCALE: f32 = 1.4826;
+
+pub struct OutlierOptions {
+    /// The name of the index whose nearest neighbors samples are compared with
+    pub index: String,
+    pub num_neighbors: usize,
+    pub grouping: Grouping,
+    pub min_disagreement: f32,
+    pub min_isolation: f32,
+}
+
+impl Default for OutlierOptions {
+    fn default() -> Self {
+        OutlierOptions {
+            index: "timbre".to_string(),
+            num_neighbors: DEFAULT_NUM_NEIGHBORS,
+            grouping: Grouping::Folder,
+            min_disagreement: DEFAULT_MIN_DISAGREEMENT,
+            min_isolation: DEFAULT_MIN_ISOLATION,
+        }
+    }
+}
+
+/// What a sample's neighbors are expected to share with it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Grouping {
+    /// The directory containing the sample
+    Folder,
+    /// Any of the sample's user tags. Untagged samples aren't checked.
+    Tag,
+}
+
+impl Grouping {
+    pub fn from_name(name: &str) -> Result<Grouping, String> {
+        match name {
+            "folder" => Ok(Grouping::Folder),
+            "tag" => Ok(Grouping::Tag),
+            _ => Err(format!(
+                "Unknown grouping {name}. Available groupings: folder, tag"
+            )),
+        }
+    }
+}
+
+/// A sample that's far from every other sample, which often means it's corrupt or silent
+pub struct IsolatedSample {
+    pub file: AudioFile,
+    /// How many deviations the sample's mean distance to its neighbors is above the median
+    pub score: f32,
+    pub mean_distance: f32,
+}
+
+/// A sample whose nearest neighbors mostly belong to a different group than its own, e.g. a
+/// snare in a folder of kicks
+pub struct MisplacedSample {
+    pub file: AudioFile,
+    /// The sample's own group
+    pub group: String,
+    /// The group most of the sample's neighbors from other groups belong to
+    pub neighbor_group: String,
+    /// The share of the sample's neighbors that belong to other groups, from 0 to 1
+    pub disagreement: f32,
+}
+
+pub struct OutlierReport {
+    /// Isolated samples, from the most isolated
+    pub isolated: Vec<IsolatedSample>,
+    /// Misplaced samples, from the most disagreed with
+    pub misplaced: Vec<MisplacedSample>,
+}
+
+/// Returns (id, score) for each of (id, mean distance to neighbors) that's at least
+/// min_score robust z-scores above the median, from the highest score. Medians are used
+/// rather than means so the outliers themselves don't hide each other.
+pub fn isolation_scores(mean_distances: &[(u32, f32)], min_score: f32) -> Vec<(u32, f32)> {
+    let mut distances: Vec<f32> = mean_distances.iter().map(|(_, d)| *d).collect();
+    let median_distance = median(&mut distances);
+    let mut deviations: Vec<f32> = distances
+        .iter()
+        .map(|d| (d - median_distance).abs())
+        .collect();
+    let deviation = median(&mut deviations) * MAD_SCALE;
+    if deviation <= 0.0 {
+        return Vec::new();
+    }
+    let mut scores: Vec<(u32, f32)> = mean_distances
+        .iter()
+        .map(|(id, distance)| (*id, (distance - median_distance) / deviation))
+        .filter(|(_, score)| *score >= min_score)
+        .collect();
+    scores.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+    scores
+}
+
+fn median(values: &mut [f32]) -> f32 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    values.sort_by(f32::total_cmp);
+    let middle = values.len() / 2;
+    if values.len() % 2 == 1 {
+        values[middle]
+    } else {
+        (values[middle - 1] + values[middle]) / 2.0
+    }
+}
+
+/// Returns (id, group, neighbor group, disagreement) for each sample in neighbors whose
+/// neighbors share none of its groups at least min_disagreement of the time, from the
+/// highest disagreement. Samples in groups smaller than MIN_GROUP_SIZE aren't checked.
+pub fn misplaced_samples(
+    groups: &HashMap<u32, Vec<String>>,
+    neighbors: &HashMap<u32, Vec<u32>>,
+    min_disagreement: f32,
+) -> Vec<(u32, String, String, f32)> {
+    let mut group_sizes: HashMap<&str, usize> = HashMap::new();
+    for sample_groups in groups.values() {
+        for group in sample_groups {
+            *group_sizes.entry(group).or_default() += 1;
+        }
+    }
+
+    let mut misplaced = Vec::new();
+    for (id, neighbor_ids) in neighbors {
+        let Some(own_groups) = groups.get(id) else {
+            continue;
+        };
+        let own_groups: Vec<&String> = own_groups
+            .iter()
+            .filter(|group| group_sizes[group.as_str()] >= MIN_GROUP_SIZE)
+            .collect();
+        if own_groups.is_empty() || neighbor_ids.is_empty() {
+            continue;
+        }
+        let mut num_disagreeing = 0;
+        let mut other_groups: HashMap<&str, usize> = HashMap::new();
+        for neighbor in neighbor_ids {
+            let neighbor_groups = groups.get(neighbor).map(Vec::as_slice).unwrap_or_default();
+            if neighbor_groups
+                .iter()
+                .any(|group| own_groups.contains(&group))
+            {
+                continue;
+            }
+            num_disagreeing += 1;
+            for group in neighbor_groups {
+                *other_groups.entry(group).or_default() += 1;
+            }
+        }
+        let disagreement = num_disagreeing as f32 / neighbor_ids.len() as f32;
+        if disagreement < min_disagreement {
+            continue;
+        }
+        let neighbor_group = other_groups
+            .into_iter()
+            .max_by(|(a_group, a), (b_group, b)| a.cmp(b).then(b_group.cmp(a_group)))
+            .map(|(group, _)| group.to_string())
+            .unwrap_or_default();
+        let group = own_groups
+            .iter()
+            .map(|group| group.as_str())
+            .collect::<Vec<&str>>()
+            .join(", ");
+        misplaced.push((*id, group, neighbor_group, disagreement));
+    }
+    misplaced.sort_by(|a, b| b.3.total_cmp(&a.3).then(a.0.cmp(&b.0)));
+    misplaced
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_samples_far_above_the_median_distance_are_isolated() {
+        let mean_distances = [(1, 1.0), (2, 1.1), (3, 0.9), (4, 1.0), (5, 1.2), (6, 9.0)];
+        let scores = isolation_scores(&mean_distances, DEFAULT_MIN_ISOLATION);
+        assert_eq!(scores.len(), 1);
+        assert_eq!(scores[0].0, 6);
+    }
+
+    #[test]
+    fn equal_distances_isolate_nothing() {
+        assert!(isolation_scores(&[(1, 2.0), (2, 2.0), (3, 2.0)], 0.0).is_empty());
+        assert!(isolation_scores(&[], 0.0).is_empty());
+    }
+
+    #[test]
+    fn samples_whose_neighbors_are_in_another_group_are_misplaced() {
+        let group = |name: &str| vec![name.to_string()];
+        let groups: HashMap<u32, Vec<String>> = HashMap::from([
+            (1, group("kicks")),
+            (2, group("kicks")),
+            (3, group("kicks")),
+            (4, group("snares")),
+            (5, group("snares")),
+            (6, group("snares")),
+            (7, group("hats")),
+        ]);
+        let neighbors: HashMap<u32, Vec<u32>> = HashMap::from([
+            // A snare among the kicks
+            (3, vec![4, 5, 6]),
+            (1, vec![2, 3, 4]),
+            // Too small a group to check
+            (7, vec![4, 5, 6]),
+        ]);
+        let misplaced = misplaced_samples(&groups, &neighbors, DEFAULT_MIN_DISAGREEMENT);
+        assert_eq!(
+            misplaced,
+            [(3, "kicks".to_string(), "snares".to_string(), 1.0)]
+        );
+    }
+}
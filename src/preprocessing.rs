@@ -0,0 +1,266 @@
+use std::f32::consts::{FRAC_1_SQRT_2, PI};
+
+use crate::feature_extractor::{Normalization, Preprocessing};
+
+/// The window size used to measure levels when trimming silence
+const TRIM_WINDOW_SIZE: usize = 256;
+
+/// Applies preprocessing to a mono buffer in place, in the order: DC offset removal, silence
+/// trimming, max duration, then normalization. Returns the number of samples trimmed from the
+/// start of the buffer, so positions in the processed buffer can be mapped back to the file.
+pub fn preprocess(buffer: &mut Vec<f32>, sample_rate: u32, preprocessing: &Preprocessing) -> usize {
+    if preprocessing.remove_dc_offset && !buffer.is_empty() {
+        let mean = buffer.iter().sum::<f32>() / buffer.len() as f32;
+        buffer.iter_mut().for_each(|s| *s -= mean);
+    }
+
+    let mut start_offset = 0;
+    if let Some(threshold_db) = preprocessing.trim_silence_below_db {
+        if let Some((start, end)) = non_silent_range(buffer, db_to_gain(threshold_db)) {
+            buffer.truncate(end);
+            buffer.drain(..start);
+            start_offset = start;
+        }
+    }
+
+    if let Some(max_duration) = preprocessing.max_duration {
+        let max_len = (max_duration * sample_rate as f32) as usize;
+        buffer.truncate(max_len);
+    }
+
+    if let Some(normalization) = preprocessing.normalization {
+        let level_db = match normalization {
+            Normalization::Rms { .. } => rms_db(buffer),
+            Normalization::Lufs { .. } => integrated_loudness(buffer, sample_rate),
+        };
+        let target_db = match normalization {
+            Normalization::Rms { target_db } => target_db,
+            Normalization::Lufs { target_lufs } => target_lufs,
+        };
+        // Silence can't be normalized
+        if level_db.is_finite() {
+            let gain = db_to_gain(target_db - level_db);
+            buffer.iter_mut().for_each(|s| *s *= gain);
+        }
+    }
+    start_offset
+}
+
+fn db_to_gain(db: f32) -> f32 {
+    10.0_f32.powf(db / 20.0)
+}
+
+/// Returns the sample range between the first and last windows with an RMS level above
+/// threshold, or None if the whole buffer is below it
+fn non_silent_range(buffer: &[f32], threshold: f32) -> Option<(usize, usize)> {
+    let is_loud = |window: &[f32]| {
+        let mean_square = window.iter().map(|s| s * s).sum::<f32>() / window.len() as f32;
+        mean_square.sqrt() > threshold
+    };
+    let num_windows = buffer.len().div_ceil(TRIM_WINDOW_SIZE);
+    let first = buffer.chunks(TRIM_WINDOW_SIZE).position(is_loud)?;
+    let last = num_windows - 1 - buffer.chunks(TRIM_WINDOW_SIZE).rev().position(is_loud)?;
+    Some((
+        first * TRIM_WINDOW_SIZE,
+        ((last + 1) * TRIM_WINDOW_SIZE).min(buffer.len()),
+    ))
+}
+
+fn rms_db(buffer: &[f32]) -> f32 {
+    if buffer.is_empty() {
+        return f32::NEG_INFINITY;
+    }
+    let mean_square = buffer.iter().map(|s| s * s).sum::<f32>() / buffer.len() as f32;
+    10.0 * mean_square.log10()
+}
+
+/// A biquad filter in direct form I
+struct Biquad {
+    b: [f32; 3],
+    a: [f32; 2],
+}
+
+impl Biquad {
+    fn process(&self, buffer: &mut [f32]) {
+        let (mut x1, mut x2, mut y1, mut y2) = (0.0, 0.0, 0.0, 0.0);
+        for sample in buffer.iter_mut() {
+            let x = *sample;
+            let y = self.b[0] * x + self.b[1] * x1 + self.b[2] * x2 - self.a[0] * y1 - self.a[1] * y2;
+            x2 = x1;
+            x1 = x;
+            y2 = y1;
+            y1 = y;
+            *sample = y;
+        }
+    }
+}
+
+/// Returns the integrated loudness in LUFS as defined by ITU-R BS.1770, with the K-weighting
+/// filters calculated for sample_rate rather than using the published 48 kHz coefficients.
+fn integrated_loudness(buffer: &[f32], sample_rate: u32) -> f32 {
+    let fs = sample_rate as f32;
+    // Stage 1: high shelf modelling the acoustic effect of the head
+    let shelf = {
+        let gain = 10.0_f32.powf(4.0 / 40.0);
+        let w0 = 2.0 * PI * 1500.0 / fs;
+        let alpha = w0.sin() / (2.0 * FRAC_1_SQRT_2);
+        let cos = w0.cos();
+        let sqrt_gain = gain.sqrt();
+        let a0 = (gain + 1.0) - (gain - 1.0) * cos + 2.0 * sqrt_gain * alpha;
+        Biquad {
+            b: [
+                gain * ((gain + 1.0) + (gain - 1.0) * cos + 2.0 * sqrt_gain * alpha) / a0,
+                -2.0 * gain * ((gain - 1.0) + (gain + 1.0) * cos) / a0,
+                gain * ((gain + 1.0) + (gain - 1.0) * cos - 2.0 * sqrt_gain * alpha) / a0,
+            ],
+            a: [
+                2.0 * ((gain - 1.0) - (gain + 1.0) * cos) / a0,
+                ((gain + 1.0) - (gain - 1.0) * cos - 2.0 * sqrt_gain * alpha) / a0,
+            ],
+        }
+    };
+    // Stage 2: high pass (the RLB filter)
+    let high_pass = {
+        let w0 = 2.0 * PI * 38.0 / fs;
+        let alpha = w0.sin() / (2.0 * 0.5);
+        let cos = w0.cos();
+        let a0 = 1.0 + alpha;
+        Biquad {
+            b: [
+                (1.0 + cos) / 2.0 / a0,
+                -(1.0 + cos) / a0,
+                (1.0 + cos) / 2.0 / a0,
+            ],
+            a: [-2.0 * cos / a0, (1.0 - alpha) / a0],
+        }
+    };
+
+    let mut weighted = buffer.to_vec();
+    shelf.process(&mut weighted);
+    high_pass.process(&mut weighted);
+
+    // Mean square of 400ms blocks overlapping by 75%. Files shorter than one block are
+    // measured as a single block.
+    let block_size = ((0.4 * fs) as usize).min(weighted.len()).max(1);
+    let step = (block_size / 4).max(1);
+    let block_powers: Vec<f32> = (0..=weighted.len().saturating_sub(block_size))
+        .step_by(step)
+        .map(|start| {
+            let block = &weighted[start..(start + block_size).min(weighted.len())];
+            block.iter().map(|s| s * s).sum::<f32>() / block_size as f32
+        })
+        .collect();
+
+    let loudness = |power: f32| -0.691 + 10.0 * power.log10();
+    let gated_mean = |threshold: f32| -> Option<f32> {
+        let gated: Vec<f32> = block_powers
+            .iter()
+            .copied()
+            .filter(|power| loudness(*power) > threshold)
+            .collect();
+        if gated.is_empty() {
+            return None;
+        }
+        Some(gated.iter().sum::<f32>() / gated.len() as f32)
+    };
+
+    const ABSOLUTE_GATE: f32 = -70.0;
+    const RELATIVE_GATE: f32 = -10.0;
+    let Some(absolute_gated_power) = gated_mean(ABSOLUTE_GATE) else {
+        return f32::NEG_INFINITY;
+    };
+    let relative_threshold = (loudness(absolute_gated_power) + RELATIVE_GATE).max(ABSOLUTE_GATE);
+    gated_mean(relative_threshold)
+        .map(loudness)
+        .unwrap_or(f32::NEG_INFINITY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tone(amplitude: f32, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|i| amplitude * (2.0 * PI * 440.0 * i as f32 / 44100.0).sin())
+            .collect()
+    }
+
+    #[test]
+    fn no_preprocessing_leaves_the_buffer_unchanged() {
+        let mut buffer = tone(0.5, 1000);
+        let original = buffer.clone();
+        assert_eq!(preprocess(&mut buffer, 44100, &Preprocessing::default()), 0);
+        assert_eq!(buffer, original);
+    }
+
+    #[test]
+    fn dc_offset_is_removed() {
+        let mut buffer: Vec<f32> = tone(0.5, 1000).iter().map(|s| s + 0.25).collect();
+        let preprocessing = Preprocessing {
+            remove_dc_offset: true,
+            ..Default::default()
+        };
+        preprocess(&mut buffer, 44100, &preprocessing);
+        let mean = buffer.iter().sum::<f32>() / buffer.len() as f32;
+        assert!(mean.abs() < 1e-4);
+    }
+
+    #[test]
+    fn silence_is_trimmed_to_whole_windows_and_the_offset_returned() {
+        let mut buffer = vec![0.0; 3 * TRIM_WINDOW_SIZE];
+        buffer.extend(tone(0.5, 2 * TRIM_WINDOW_SIZE));
+        buffer.extend(vec![0.0; 5 * TRIM_WINDOW_SIZE]);
+        let preprocessing = Preprocessing {
+            trim_silence_below_db: Some(-60.0),
+            ..Default::default()
+        };
+        let start_offset = preprocess(&mut buffer, 44100, &preprocessing);
+        assert_eq!(start_offset, 3 * TRIM_WINDOW_SIZE);
+        assert_eq!(buffer.len(), 2 * TRIM_WINDOW_SIZE);
+    }
+
+    #[test]
+    fn silent_buffers_are_left_untrimmed_and_unnormalized() {
+        let mut buffer = vec![0.0; 1000];
+        let preprocessing = Preprocessing {
+            trim_silence_below_db: Some(-60.0),
+            normalization: Some(Normalization::Rms { target_db: -20.0 }),
+            ..Default::default()
+        };
+        assert_eq!(preprocess(&mut buffer, 44100, &preprocessing), 0);
+        assert_eq!(buffer, vec![0.0; 1000]);
+    }
+
+    #[test]
+    fn buffers_are_capped_at_the_max_duration() {
+        let mut buffer = tone(0.5, 44100);
+        let preprocessing = Preprocessing {
+            max_duration: Some(0.5),
+            ..Default::default()
+        };
+        preprocess(&mut buffer, 44100, &preprocessing);
+        assert_eq!(buffer.len(), 22050);
+    }
+
+    #[test]
+    fn rms_normalization_reaches_the_target_level() {
+        let mut buffer = tone(0.01, 44100);
+        let preprocessing = Preprocessing {
+            normalization: Some(Normalization::Rms { target_db: -12.0 }),
+            ..Default::default()
+        };
+        preprocess(&mut buffer, 44100, &preprocessing);
+        assert!((rms_db(&buffer) + 12.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn lufs_normalization_reaches_the_target_loudness() {
+        let mut buffer = tone(0.01, 2 * 44100);
+        let preprocessing = Preprocessing {
+            normalization: Some(Normalization::Lufs { target_lufs: -14.0 }),
+            ..Default::default()
+        };
+        preprocess(&mut buffer, 44100, &preprocessing);
+        assert!((integrated_loudness(&buffer, 44100) + 14.0).abs() < 0.01);
+    }
+}
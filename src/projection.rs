@@ -0,0 +1,370 @@
+use std::collections::HashMap;
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+/// The seed for UMAP's negative sampling, so projecting the same library twice gives the
+/// same map
+const SEED: u64 = 0;
+
+/// The default number of nearest neighbors UMAP preserves the structure of. More neighbors
+/// favor the global layout of the library over its local detail.
+pub const DEFAULT_NUM_NEIGHBORS: usize = 15;
+
+/// Power iteration stops after this many iterations if a principal component hasn't converged
+const MAX_PCA_ITERATIONS: usize = 200;
+
+/// UMAP's curve parameters for a minimum distance of 0.1 and a spread of 1, which control how
+/// tightly neighbors are packed together in the layout
+const UMAP_A: f32 = 1.577;
+const UMAP_B: f32 = 0.8951;
+
+/// The number of points each edge is pushed away from per optimization step
+const NUM_NEGATIVE_SAMPLES: usize = 5;
+
+/// The extent of the initial UMAP layout, which matches the scale of the attractive and
+/// repulsive forces
+const UMAP_INITIAL_EXTENT: f32 = 10.0;
+
+/// Each gradient step moves a point at most this far
+const MAX_GRADIENT: f32 = 4.0;
+
+pub struct ProjectionOptions {
+    /// The name of the index whose vectors are projected
+    pub index: String,
+    pub method: ProjectionMethod,
+}
+
+impl Default for ProjectionOptions {
+    fn default() -> Self {
+        ProjectionOptions {
+            index: "timbre".to_string(),
+            method: ProjectionMethod::Umap {
+                num_neighbors: DEFAULT_NUM_NEIGHBORS,
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum ProjectionMethod {
+    /// Projects vectors onto their two principal components. Fast, and distances across the
+    /// whole map are meaningful, but groups of similar samples tend to overlap.
+    Pca,
+    /// Lays out the nearest neighbor graph of the vectors, which separates groups of similar
+    /// samples but only preserves distances between neighbors
+    Umap { num_neighbors: usize },
+}
+
+/// Projects vectors onto their first two principal components
+pub fn pca(vectors: &[Vec<f32>]) -> Vec<[f32; 2]> {
+    let Some(dimensions) = vectors.first().map(|vector| vector.len()) else {
+        return Vec::new();
+    };
+    let mut mean = vec![0.0f64; dimensions];
+    for vector in vectors {
+        for (total, x) in mean.iter_mut().zip(vector) {
+            *total += *x as f64;
+        }
+    }
+    for total in mean.iter_mut() {
+        *total /= vectors.len() as f64;
+    }
+    let mut covariance = vec![vec![0.0f64; dimensions]; dimensions];
+    for vector in vectors {
+        let centered: Vec<f64> = vector
+            .iter()
+            .zip(&mean)
+            .map(|(x, m)| *x as f64 - m)
+            .collect();
+        for (row, a) in covariance.iter_mut().zip(&centered) {
+            for (value, b) in row.iter_mut().zip(&centered) {
+                *value += a * b;
+            }
+        }
+    }
+
+    let first = principal_component(&covariance);
+    // Deflate the covariance so power iteration converges to the second component
+    let variance = rayleigh_quotient(&covariance, &first);
+    for (i, row) in covariance.iter_mut().enumerate() {
+        for (j, value) in row.iter_mut().enumerate() {
+            *value -= variance * first[i] * first[j];
+        }
+    }
+    let second = principal_component(&covariance);
+
+    vectors
+        .iter()
+        .map(|vector| {
+            let project = |component: &[f64]| -> f32 {
+                vector
+                    .iter()
+                    .zip(&mean)
+                    .zip(component)
+                    .map(|((x, m), c)| (*x as f64 - m) * c)
+                    .sum::<f64>() as f32
+            };
+            [project(&first), project(&second)]
+        })
+        .collect()
+}
+
+/// Returns the unit eigenvector of the symmetric matrix with the largest eigenvalue, or a zero
+/// vector if the matrix is zero
+fn principal_component(matrix: &[Vec<f64>]) -> Vec<f64> {
+    let dimensions = matrix.len();
+    // Start from a vector that's unlikely to be orthogonal to the component
+    let mut component: Vec<f64> = (0..dimensions).map(|i| 1.0 + i as f64 * 0.1).collect();
+    for _ in 0..MAX_PCA_ITERATIONS {
+        let product: Vec<f64> = matrix
+            .iter()
+            .map(|row| row.iter().zip(&component).map(|(a, b)| a * b).sum())
+            .collect();
+        let norm = product.iter().map(|x| x * x).sum::<f64>().sqrt();
+        if norm == 0.0 {
+            return vec![0.0; dimensions];
+        }
+        let next: Vec<f64> = product.iter().map(|x| x / norm).collect();
+        let change: f64 = next
+            .iter()
+            .zip(&component)
+            .map(|(a, b)| (a - b).abs())
+            .sum();
+        component = next;
+        if change < 1e-9 {
+            break;
+        }
+    }
+    component
+}
+
+fn rayleigh_quotient(matrix: &[Vec<f64>], vector: &[f64]) -> f64 {
+    matrix
+        .iter()
+        .zip(vector)
+        .map(|(row, x)| x * row.iter().zip(vector).map(|(a, b)| a * b).sum::<f64>())
+        .sum()
+}
+
+/// Lays out num_points points in 2D with UMAP, from the (point, neighbor, distance) edges of
+/// their nearest neighbor graph, starting from the initial layout. Points without neighbors
+/// keep their initial position.
+pub fn umap(
+    num_points: usize,
+    edges: &[(usize, usize, f32)],
+    initial: &[[f32; 2]],
+) -> Vec<[f32; 2]> {
+    let weights = fuzzy_neighbor_weights(num_points, edges);
+    let mut layout = scaled(initial, UMAP_INITIAL_EXTENT);
+    let Some(max_weight) = weights.iter().map(|(_, _, w)| *w).max_by(f32::total_cmp) else {
+        return layout;
+    };
+
+    // Stronger edges are sampled more often: an edge with the largest weight is sampled every
+    // epoch, and one with half of it every other epoch
+    let num_epochs = if num_points > 10_000 { 200 } else { 500 };
+    let epochs_per_sample: Vec<f32> = weights.iter().map(|(_, _, w)| max_weight / w).collect();
+    let mut next_sample = epochs_per_sample.clone();
+    let mut rng = StdRng::seed_from_u64(SEED);
+    for epoch in 0..num_epochs {
+        let learning_rate = 1.0 - epoch as f32 / num_epochs as f32;
+        for (edge, (a, b, _)) in weights.iter().enumerate() {
+            if next_sample[edge] > epoch as f32 + 1.0 {
+                continue;
+            }
+            next_sample[edge] += epochs_per_sample[edge];
+
+            let (delta, squared_distance) = difference(&layout[*a], &layout[*b]);
+            if squared_distance > 0.0 {
+                let coefficient = -2.0 * UMAP_A * UMAP_B * squared_distance.powf(UMAP_B - 1.0)
+                    / (UMAP_A * squared_distance.powf(UMAP_B) + 1.0);
+                for d in 0..2 {
+                    let gradient = (coefficient * delta[d]).clamp(-MAX_GRADIENT, MAX_GRADIENT);
+                    layout[*a][d] += gradient * learning_rate;
+                    layout[*b][d] -= gradient * learning_rate;
+                }
+            }
+
+            for _ in 0..NUM_NEGATIVE_SAMPLES {
+                let other = rng.gen_range(0..num_points);
+                if other == *a {
+                    continue;
+                }
+                let (delta, squared_distance) = difference(&layout[*a], &layout[other]);
+                let coefficient = 2.0 * UMAP_B
+                    / ((0.001 + squared_distance) * (UMAP_A * squared_distance.powf(UMAP_B) + 1.0));
+                for d in 0..2 {
+                    let gradient = if squared_distance > 0.0 {
+                        (coefficient * delta[d]).clamp(-MAX_GRADIENT, MAX_GRADIENT)
+                    } else {
+                        MAX_GRADIENT
+                    };
+                    layout[*a][d] += gradient * learning_rate;
+                }
+            }
+        }
+    }
+    layout
+}
+
+/// Converts nearest neighbor distances into symmetric edge weights between 0 and 1. Each
+/// point's distances are scaled so its nearest neighbor has weight 1 and its weights sum to
+/// log2 of its number of neighbors, which adapts the graph to the local density of points.
+fn fuzzy_neighbor_weights(
+    num_points: usize,
+    edges: &[(usize, usize, f32)],
+) -> Vec<(usize, usize, f32)> {
+    let mut neighbors = vec![Vec::new(); num_points];
+    for (a, b, distance) in edges {
+        if a != b {
+            neighbors[*a].push((*b, *distance));
+        }
+    }
+
+    let mut weights: HashMap<(usize, usize), (f32, f32)> = HashMap::new();
+    for (a, neighbors) in neighbors.iter().enumerate() {
+        if neighbors.is_empty() {
+            continue;
+        }
+        let nearest = neighbors
+            .iter()
+            .map(|(_, distance)| *distance)
+            .filter(|distance| *distance > 0.0)
+            .min_by(f32::total_cmp)
+            .unwrap_or(0.0);
+        let target = (neighbors.len() as f32).log2().max(1.0);
+        let total_weight = |scale: f32| -> f32 {
+            neighbors
+                .iter()
+                .map(|(_, distance)| (-(distance - nearest).max(0.0) / scale).exp())
+                .sum()
+        };
+        // Binary search for the scale whose weights sum to the target
+        let (mut low, mut high) = (0.0f32, f32::INFINITY);
+        let mut scale = 1.0;
+        for _ in 0..64 {
+            if total_weight(scale) > target {
+                high = scale;
+                scale = (low + high) / 2.0;
+            } else {
+                low = scale;
+                scale = if high.is_infinite() {
+                    scale * 2.0
+                } else {
+                    (low + high) / 2.0
+                };
+            }
+        }
+        for (b, distance) in neighbors {
+            let weight = (-(distance - nearest).max(0.0) / scale).exp();
+            let key = (a.min(*b), a.max(*b));
+            let entry = weights.entry(key).or_default();
+            if a < *b {
+                entry.0 = entry.0.max(weight);
+            } else {
+                entry.1 = entry.1.max(weight);
+            }
+        }
+    }
+
+    // An edge's weights from both of its ends are combined as a fuzzy union
+    let mut symmetric: Vec<(usize, usize, f32)> = weights
+        .into_iter()
+        .map(|((a, b), (forward, backward))| (a, b, forward + backward - forward * backward))
+        .filter(|(_, _, weight)| *weight > 0.0)
+        .collect();
+    symmetric.sort_by_key(|(a, b, _)| (*a, *b));
+    symmetric
+}
+
+fn difference(a: &[f32; 2], b: &[f32; 2]) -> ([f32; 2], f32) {
+    let delta = [a[0] - b[0], a[1] - b[1]];
+    (delta, delta[0] * delta[0] + delta[1] * delta[1])
+}
+
+/// Translates and uniformly scales points so they fit in a square from 0 to extent, keeping
+/// their aspect ratio
+pub fn scaled(points: &[[f32; 2]], extent: f32) -> Vec<[f32; 2]> {
+    let mut min = [f32::INFINITY; 2];
+    let mut max = [f32::NEG_INFINITY; 2];
+    for point in points {
+        for d in 0..2 {
+            min[d] = min[d].min(point[d]);
+            max[d] = max[d].max(point[d]);
+        }
+    }
+    let range = (max[0] - min[0]).max(max[1] - min[1]);
+    let scale = if range > 0.0 { extent / range } else { 0.0 };
+    points
+        .iter()
+        .map(|point| [(point[0] - min[0]) * scale, (point[1] - min[1]) * scale])
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pca_lays_points_along_their_widest_direction() {
+        // Points along (1, 2, 0), nudged slightly off it
+        let vectors: Vec<Vec<f32>> = (0..10)
+            .map(|i| {
+                let t = i as f32;
+                let nudge = if i % 2 == 0 { 0.05 } else { -0.05 };
+                vec![t, 2.0 * t, nudge]
+            })
+            .collect();
+        let points = pca(&vectors);
+        let increasing = points.windows(2).all(|pair| pair[1][0] > pair[0][0]);
+        let decreasing = points.windows(2).all(|pair| pair[1][0] < pair[0][0]);
+        assert!(increasing || decreasing);
+        assert!(points.iter().all(|point| point[1].abs() < 0.1));
+        assert!(pca(&[]).is_empty());
+    }
+
+    #[test]
+    fn scaling_fits_points_in_the_extent_and_keeps_their_shape() {
+        let points = scaled(&[[-1.0, 5.0], [3.0, 6.0], [1.0, 7.0]], 1.0);
+        assert_eq!(points, [[0.0, 0.0], [1.0, 0.25], [0.5, 0.5]]);
+        assert_eq!(scaled(&[[2.0, 2.0]], 1.0), [[0.0, 0.0]]);
+    }
+
+    #[test]
+    fn nearer_neighbors_get_stronger_weights() {
+        let edges = [(0, 1, 1.0), (0, 2, 2.0), (0, 3, 3.0), (0, 4, 4.0)];
+        let weights: Vec<f32> = fuzzy_neighbor_weights(5, &edges)
+            .into_iter()
+            .map(|(_, _, weight)| weight)
+            .collect();
+        assert_eq!(weights[0], 1.0);
+        assert!(weights.windows(2).all(|pair| pair[1] < pair[0]));
+        // The weights of 4 neighbors sum to log2(4)
+        assert!((weights.iter().sum::<f32>() - 2.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn umap_keeps_separate_groups_apart() {
+        // Two groups of 5 points, each point linked to the rest of its group
+        let mut edges = Vec::new();
+        for group in 0..2 {
+            for a in group * 5..group * 5 + 5 {
+                for b in group * 5..group * 5 + 5 {
+                    if a != b {
+                        edges.push((a, b, 1.0 + (a + b) as f32 * 0.01));
+                    }
+                }
+            }
+        }
+        let initial: Vec<[f32; 2]> = (0..10).map(|i| [i as f32, (i * 7 % 10) as f32]).collect();
+        let layout = umap(10, &edges, &initial);
+        let distance = |a: usize, b: usize| difference(&layout[a], &layout[b]).1.sqrt();
+        let mean = |pairs: &[(usize, usize)]| {
+            pairs.iter().map(|(a, b)| distance(*a, *b)).sum::<f32>() / pairs.len() as f32
+        };
+        let within: Vec<(usize, usize)> = edges.iter().map(|(a, b, _)| (*a, *b)).collect();
+        let between: Vec<(usize, usize)> =
+            (0..5).flat_map(|a| (5..10).map(move |b| (a, b))).collect();
+        assert!(mean(&within) < mean(&between));
+    }
+}
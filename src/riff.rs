@@ -0,0 +1,137 @@
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// A top level chunk of a RIFF file
+pub struct Chunk {
+    pub id: [u8; 4],
+    pub data: Vec<u8>,
+}
+
+/// Reads the top level chunks of the RIFF/WAVE file at path. The `data` chunk, which holds the
+/// audio itself, is skipped rather than read into memory.
+pub fn read_chunks(path: &Path) -> Result<Vec<Chunk>, String> {
+    let mut reader = BufReader::new(File::open(path).map_err(|e| e.to_string())?);
+    let mut header = [0u8; 12];
+    reader
+        .read_exact(&mut header)
+        .map_err(|e| format!("Failed to read RIFF header: {}", e))?;
+    if &header[0..4] != b"RIFF" || &header[8..12] != b"WAVE" {
+        return Err("Not a RIFF/WAVE file".to_string());
+    }
+
+    let mut chunks = Vec::new();
+    let mut chunk_header = [0u8; 8];
+    // Files truncated mid-chunk still yield the chunks before the truncation
+    while reader.read_exact(&mut chunk_header).is_ok() {
+        let id: [u8; 4] = chunk_header[0..4].try_into().unwrap();
+        let size = u32::from_le_bytes(chunk_header[4..8].try_into().unwrap()) as u64;
+        // Chunks are padded to an even number of bytes
+        let padded_size = size + size % 2;
+        if &id == b"data" {
+            if reader.seek(SeekFrom::Current(padded_size as i64)).is_err() {
+                break;
+            }
+            continue;
+        }
+        // The size comes from the file, so it's read through take rather than trusted to size
+        // the buffer
+        let mut data = Vec::new();
+        match (&mut reader).take(size).read_to_end(&mut data) {
+            Ok(read) if read as u64 == size => {}
+            _ => break,
+        }
+        if size % 2 == 1 && reader.seek(SeekFrom::Current(1)).is_err() {
+            break;
+        }
+        chunks.push(Chunk { id, data });
+    }
+    Ok(chunks)
+}
+
+/// Returns the codec name and bit depth described by a WAVE `fmt ` chunk
+pub fn wave_format(chunks: &[Chunk]) -> Option<(&'static str, u16)> {
+    let fmt = chunks.iter().find(|chunk| &chunk.id == b"fmt ")?;
+    if fmt.data.len() < 16 {
+        return None;
+    }
+    let read_u16 = |offset: usize| u16::from_le_bytes([fmt.data[offset], fmt.data[offset + 1]]);
+    let mut format_tag = read_u16(0);
+    let bit_depth = read_u16(14);
+    // WAVE_FORMAT_EXTENSIBLE stores the actual format in the first two bytes of the subformat
+    // GUID
+    if format_tag == 0xFFFE && fmt.data.len() >= 26 {
+        format_tag = read_u16(24);
+    }
+    let codec = match format_tag {
+        0x0001 => "pcm",
+        0x0003 => "float",
+        0x0006 => "alaw",
+        0x0007 => "ulaw",
+        0x0011 => "adpcm",
+        _ => "unknown",
+    };
+    Some((codec, bit_depth))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut chunk = id.to_vec();
+        chunk.extend((data.len() as u32).to_le_bytes());
+        chunk.extend(data);
+        if data.len() % 2 == 1 {
+            chunk.push(0);
+        }
+        chunk
+    }
+
+    fn write_wave(name: &str, chunks: &[Vec<u8>]) -> std::path::PathBuf {
+        let body: Vec<u8> = chunks.concat();
+        let mut contents = b"RIFF".to_vec();
+        contents.extend((body.len() as u32 + 4).to_le_bytes());
+        contents.extend(b"WAVE");
+        contents.extend(body);
+        let path = std::env::temp_dir().join(format!("riff_test_{}_{name}", std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn chunks_are_read_around_the_data_chunk() {
+        let mut fmt = vec![0u8; 16];
+        fmt[0] = 1;
+        fmt[14] = 24;
+        let path = write_wave(
+            "chunks.wav",
+            &[
+                chunk(b"fmt ", &fmt),
+                chunk(b"data", &[0; 7]),
+                chunk(b"bext", b"odd"),
+                chunk(b"LIST", b"INFO"),
+            ],
+        );
+        let chunks = read_chunks(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let ids: Vec<&[u8; 4]> = chunks.iter().map(|chunk| &chunk.id).collect();
+        assert_eq!(ids, [b"fmt ", b"bext", b"LIST"]);
+        assert_eq!(chunks[1].data, b"odd");
+        assert_eq!(wave_format(&chunks), Some(("pcm", 24)));
+    }
+
+    #[test]
+    fn oversized_chunks_end_the_chunk_list() {
+        let mut oversized = b"LIST".to_vec();
+        oversized.extend(u32::MAX.to_le_bytes());
+        oversized.extend(b"INFO");
+        let path = write_wave("oversized.wav", &[chunk(b"bext", b"ok"), oversized]);
+        let chunks = read_chunks(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(&chunks[0].id, b"bext");
+    }
+}
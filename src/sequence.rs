@@ -0,0 +1,135 @@
+use serde::Serialize;
+
+/// The default number of samples in a sequence, including its start and end
+pub const DEFAULT_LENGTH: usize = 10;
+
+/// The default number of nearest neighbors each sample is linked to when walking the nearest
+/// neighbor graph
+pub const DEFAULT_NUM_NEIGHBORS: usize = 10;
+
+pub struct SequenceOptions {
+    /// The name of the index whose vectors the sequence moves through
+    pub index: String,
+    /// The number of samples in the sequence, including its start and end
+    pub length: usize,
+    pub method: SequenceMethod,
+}
+
+impl Default for SequenceOptions {
+    fn default() -> Self {
+        SequenceOptions {
+            index: "timbre".to_string(),
+            length: DEFAULT_LENGTH,
+            method: SequenceMethod::Interpolate,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum SequenceMethod {
+    /// Steps evenly along the straight line between the start and end vectors, taking the
+    /// nearest unused sample at each step. Steps can jump across sparse parts of the library.
+    Interpolate,
+    /// Follows the shortest path between the start and end through the nearest neighbor
+    /// graph, so each sample is one of its predecessor's neighbors. Long paths are thinned
+    /// out evenly, and short ones are filled in by interpolating between their samples.
+    GraphWalk { num_neighbors: usize },
+}
+
+/// A sample in a sequence
+#[derive(Clone, Debug, Serialize)]
+pub struct SequenceStep {
+    pub id: i64,
+    pub path: String,
+    /// The duration in seconds, if it was recorded
+    pub duration: Option<f32>,
+    /// The distance from the previous sample in the sequence, or 0 for its first sample
+    pub distance: f32,
+}
+
+/// Returns the point t of the way from a to b, where t is between 0 and 1
+pub fn interpolate(a: &[f32], b: &[f32], t: f32) -> Vec<f32> {
+    a.iter().zip(b).map(|(a, b)| a + (b - a) * t).collect()
+}
+
+/// Returns the indices of length steps spread evenly along a path whose consecutive steps are
+/// gaps apart, always keeping its first and last steps. The path must have at least length
+/// steps.
+pub fn evenly_spaced(gaps: &[f32], length: usize) -> Vec<usize> {
+    let num_steps = gaps.len() + 1;
+    if length >= num_steps {
+        return (0..num_steps).collect();
+    }
+    let mut positions = vec![0.0];
+    for gap in gaps {
+        positions.push(positions.last().unwrap_or(&0.0) + gap);
+    }
+    let total = positions[num_steps - 1];
+
+    let mut steps = vec![0];
+    for i in 1..length.saturating_sub(1) {
+        let target = total * i as f32 / (length - 1) as f32;
+        // Leave enough steps after this one for the rest of the sequence
+        let first = steps.last().unwrap_or(&0) + 1;
+        let last = num_steps - (length - i);
+        let nearest = (first..=last)
+            .min_by(|a, b| {
+                (positions[*a] - target)
+                    .abs()
+                    .total_cmp(&(positions[*b] - target).abs())
+            })
+            .unwrap_or(first);
+        steps.push(nearest);
+    }
+    if length > 1 {
+        steps.push(num_steps - 1);
+    }
+    steps
+}
+
+/// Returns how many of num_extra steps to insert into each of gaps, giving them to the widest
+/// gaps so the inserted steps are spread evenly along the path
+pub fn split_gaps(gaps: &[f32], num_extra: usize) -> Vec<usize> {
+    let mut counts = vec![0; gaps.len()];
+    for _ in 0..num_extra {
+        let widest = (0..gaps.len()).max_by(|a, b| {
+            let a_width = gaps[*a] / (counts[*a] + 1) as f32;
+            let b_width = gaps[*b] / (counts[*b] + 1) as f32;
+            a_width.total_cmp(&b_width).then(b.cmp(a))
+        });
+        match widest {
+            Some(gap) => counts[gap] += 1,
+            None => break,
+        }
+    }
+    counts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evenly_spaced_steps_keep_the_ends() {
+        // Steps at 0, 1, 2, 5, 9, 10
+        let gaps = [1.0, 1.0, 3.0, 4.0, 1.0];
+        assert_eq!(evenly_spaced(&gaps, 3), [0, 3, 5]);
+        assert_eq!(evenly_spaced(&gaps, 2), [0, 5]);
+        assert_eq!(evenly_spaced(&gaps, 10), [0, 1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn evenly_spaced_steps_leave_room_for_the_rest() {
+        // Steps at 0, 1, 2, 3, 13. The middle steps' targets of 4.3 and 8.7 are nearest 3
+        // and 13, but those would leave no room for the second middle step and the end.
+        let gaps = [1.0, 1.0, 1.0, 10.0];
+        assert_eq!(evenly_spaced(&gaps, 4), [0, 2, 3, 4]);
+    }
+
+    #[test]
+    fn split_gaps_fills_the_widest_gaps() {
+        assert_eq!(split_gaps(&[1.0, 4.0, 2.0], 3), [0, 2, 1]);
+        assert_eq!(split_gaps(&[1.0, 1.0], 3), [2, 1]);
+        assert!(split_gaps(&[], 3).is_empty());
+    }
+}
@@ -0,0 +1,416 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::{flac, riff};
+
+/// Reads the tags embedded in the audio file at path: ID3 tags in MP3 and WAV files, RIFF
+/// `LIST/INFO` and Broadcast WAV `bext` chunks in WAV files, and Vorbis comments in FLAC and
+/// Ogg Vorbis files. Well known fields are given common keys such as `title`, `artist`,
+/// `album`, `genre`, `bpm` and `key`, and other fields keep their lowercased name.
+pub fn read_tags(path: &Path) -> Result<Vec<(String, String)>, String> {
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+    let mut tags = match extension.as_str() {
+        "wav" => riff_tags(&riff::read_chunks(path)?),
+        "mp3" => mp3_tags(path)?,
+        "flac" => flac::read_metadata_blocks(path)?
+            .iter()
+            .filter(|block| block.block_type == flac::VORBIS_COMMENT)
+            .flat_map(|block| vorbis_comments(&block.data))
+            .collect(),
+        "ogg" => ogg_vorbis_comment_packet(path)?
+            .map(|packet| vorbis_comments(&packet))
+            .unwrap_or_default(),
+        _ => Vec::new(),
+    };
+    tags.retain(|(key, value)| !key.is_empty() && !value.is_empty());
+    tags.dedup();
+    Ok(tags)
+}
+
+/// Trims whitespace and the null padding used by fixed length fields
+fn clean(value: &str) -> String {
+    value.trim_matches(|c: char| c == '\0' || c.is_whitespace()).to_string()
+}
+
+/// Reads a null terminated or padded Latin-1 string
+fn latin1(bytes: &[u8]) -> String {
+    clean(&bytes.iter().map(|b| *b as char).collect::<String>())
+}
+
+fn riff_tags(chunks: &[riff::Chunk]) -> Vec<(String, String)> {
+    let mut tags = Vec::new();
+    for chunk in chunks {
+        match &chunk.id {
+            b"LIST" if chunk.data.starts_with(b"INFO") => {
+                let mut data = &chunk.data[4..];
+                while data.len() >= 8 {
+                    let id: [u8; 4] = data[0..4].try_into().unwrap();
+                    let size = u32::from_le_bytes(data[4..8].try_into().unwrap()) as usize;
+                    let end = (8 + size).min(data.len());
+                    let key = match &id {
+                        b"INAM" => "title".to_string(),
+                        b"IART" => "artist".to_string(),
+                        b"IPRD" => "album".to_string(),
+                        b"IGNR" => "genre".to_string(),
+                        b"ICMT" => "comment".to_string(),
+                        b"ICRD" => "date".to_string(),
+                        b"ICOP" => "copyright".to_string(),
+                        b"IKEY" => "keywords".to_string(),
+                        b"ISFT" => "software".to_string(),
+                        _ => String::from_utf8_lossy(&id).to_lowercase(),
+                    };
+                    tags.push((key, latin1(&data[8..end])));
+                    // Sub-chunks are padded to an even number of bytes
+                    data = &data[(end + size % 2).min(data.len())..];
+                }
+            }
+            b"bext" if chunk.data.len() >= 338 => {
+                let fields = [
+                    ("description", 0..256),
+                    ("originator", 256..288),
+                    ("originator_reference", 288..320),
+                    ("origination_date", 320..330),
+                    ("origination_time", 330..338),
+                ];
+                for (key, range) in fields {
+                    tags.push((key.to_string(), latin1(&chunk.data[range])));
+                }
+            }
+            b"id3 " | b"ID3 " => tags.extend(id3v2_tags(&chunk.data)),
+            _ => {}
+        }
+    }
+    tags
+}
+
+fn mp3_tags(path: &Path) -> Result<Vec<(String, String)>, String> {
+    let mut file = File::open(path).map_err(|e| e.to_string())?;
+    let mut header = [0u8; 10];
+    if file.read_exact(&mut header).is_ok() && header.starts_with(b"ID3") {
+        let size = syncsafe(&header[6..10]) as u64;
+        let mut tag = header.to_vec();
+        // A tag claiming to be longer than the file is truncated at the end of the file
+        file.take(size)
+            .read_to_end(&mut tag)
+            .map_err(|e| e.to_string())?;
+        return Ok(id3v2_tags(&tag));
+    }
+
+    // Fall back to the fixed length ID3v1 tag at the end of the file
+    let mut tag = [0u8; 128];
+    if file.seek(SeekFrom::End(-128)).is_err() || file.read_exact(&mut tag).is_err() {
+        return Ok(Vec::new());
+    }
+    if !tag.starts_with(b"TAG") {
+        return Ok(Vec::new());
+    }
+    Ok(vec![
+        ("title".to_string(), latin1(&tag[3..33])),
+        ("artist".to_string(), latin1(&tag[33..63])),
+        ("album".to_string(), latin1(&tag[63..93])),
+        ("date".to_string(), latin1(&tag[93..97])),
+        ("comment".to_string(), latin1(&tag[97..127])),
+    ])
+}
+
+/// Decodes a 28 bit integer stored in 4 bytes with the high bit of each byte cleared
+fn syncsafe(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0, |size, b| (size << 7) | (*b & 0x7F) as u32)
+}
+
+/// Parses the text and comment frames of an ID3v2 tag, including its 10 byte header
+fn id3v2_tags(tag: &[u8]) -> Vec<(String, String)> {
+    let mut tags = Vec::new();
+    if tag.len() < 10 || !tag.starts_with(b"ID3") {
+        return tags;
+    }
+    let version = tag[3];
+    let flags = tag[5];
+    let end = (10 + syncsafe(&tag[6..10]) as usize).min(tag.len());
+    let mut position = 10;
+    if flags & 0x40 != 0 && version >= 3 && end >= 14 {
+        // Skip the extended header, whose size excludes itself in v2.3 and includes itself in
+        // v2.4
+        position += match version {
+            3 => u32::from_be_bytes(tag[10..14].try_into().unwrap()) as usize + 4,
+            _ => syncsafe(&tag[10..14]) as usize,
+        };
+    }
+
+    // ID3v2.2 uses 3 character frame IDs and 3 byte sizes
+    let (id_length, header_length) = if version == 2 { (3, 6) } else { (4, 10) };
+    while position + header_length <= end {
+        let header = &tag[position..position + header_length];
+        if header[0] == 0 {
+            // Padding
+            break;
+        }
+        let id = String::from_utf8_lossy(&header[..id_length]).to_string();
+        let size = match version {
+            2 => u32::from_be_bytes([0, header[3], header[4], header[5]]) as usize,
+            3 => u32::from_be_bytes(header[4..8].try_into().unwrap()) as usize,
+            _ => syncsafe(&header[4..8]) as usize,
+        };
+        let start = position + header_length;
+        let frame_end = (start + size).min(end);
+        position = frame_end;
+        let frame = &tag[start..frame_end];
+        if frame.is_empty() {
+            continue;
+        }
+
+        let key = match id.as_str() {
+            "TIT2" | "TT2" => "title",
+            "TPE1" | "TP1" => "artist",
+            "TALB" | "TAL" => "album",
+            "TCON" | "TCO" => "genre",
+            "TBPM" | "TBP" => "bpm",
+            "TKEY" | "TKE" => "key",
+            "TYER" | "TDRC" | "TYE" => "date",
+            "COMM" | "COM" => "comment",
+            "TXXX" | "TXX" => "",
+            _ => continue,
+        };
+        let encoding = frame[0];
+        let text = match key {
+            // Comments are prefixed with a language code and a short description
+            "comment" if frame.len() > 4 => {
+                decode_id3_text(encoding, &frame[4..]).into_iter().nth(1)
+            }
+            // User defined text frames hold a description followed by the value
+            "" => {
+                let strings = decode_id3_text(encoding, &frame[1..]);
+                if let [description, value, ..] = strings.as_slice() {
+                    tags.push((description.to_lowercase(), value.clone()));
+                }
+                continue;
+            }
+            _ => decode_id3_text(encoding, &frame[1..]).into_iter().next(),
+        };
+        if let Some(text) = text {
+            tags.push((key.to_string(), text));
+        }
+    }
+    tags
+}
+
+/// Decodes the null separated strings of an ID3v2 text frame in the given encoding
+fn decode_id3_text(encoding: u8, bytes: &[u8]) -> Vec<String> {
+    let text = match encoding {
+        // UTF-16 with a byte order mark, or UTF-16BE without one
+        1 | 2 => {
+            let mut big_endian = encoding == 2;
+            let units: Vec<u16> = bytes
+                .chunks_exact(2)
+                .filter_map(|pair| match pair {
+                    [0xFF, 0xFE] => {
+                        big_endian = false;
+                        None
+                    }
+                    [0xFE, 0xFF] => {
+                        big_endian = true;
+                        None
+                    }
+                    [a, b] if big_endian => Some(u16::from_be_bytes([*a, *b])),
+                    [a, b] => Some(u16::from_le_bytes([*a, *b])),
+                    _ => None,
+                })
+                .collect();
+            String::from_utf16_lossy(&units)
+        }
+        3 => String::from_utf8_lossy(bytes).to_string(),
+        _ => bytes.iter().map(|b| *b as char).collect(),
+    };
+    text.split('\0').map(clean).collect()
+}
+
+/// Parses a Vorbis comment block: a vendor string followed by `KEY=value` comments
+fn vorbis_comments(data: &[u8]) -> Vec<(String, String)> {
+    let mut tags = Vec::new();
+    let read_u32 = |position: usize| -> Option<usize> {
+        let bytes = data.get(position..position + 4)?;
+        Some(u32::from_le_bytes(bytes.try_into().unwrap()) as usize)
+    };
+    let Some(vendor_length) = read_u32(0) else {
+        return tags;
+    };
+    let mut position = 4 + vendor_length;
+    let Some(num_comments) = read_u32(position) else {
+        return tags;
+    };
+    position += 4;
+    for _ in 0..num_comments {
+        let Some(length) = read_u32(position) else {
+            break;
+        };
+        let Some(comment) = data.get(position + 4..position + 4 + length) else {
+            break;
+        };
+        position += 4 + length;
+        let comment = String::from_utf8_lossy(comment);
+        if let Some((key, value)) = comment.split_once('=') {
+            let key = match key.to_lowercase().as_str() {
+                "tempo" => "bpm".to_string(),
+                "initialkey" => "key".to_string(),
+                "description" => "comment".to_string(),
+                key => key.to_string(),
+            };
+            tags.push((key, clean(value)));
+        }
+    }
+    tags
+}
+
+/// Returns the comment header packet of the Ogg Vorbis file at path, without its packet type
+/// and `vorbis` signature. It's the second packet of the stream, and may span several pages.
+fn ogg_vorbis_comment_packet(path: &Path) -> Result<Option<Vec<u8>>, String> {
+    let mut file = File::open(path).map_err(|e| e.to_string())?;
+    let mut packets: Vec<Vec<u8>> = vec![Vec::new()];
+    let mut header = [0u8; 27];
+    while file.read_exact(&mut header).is_ok() && header.starts_with(b"OggS") {
+        let mut segment_table = vec![0u8; header[26] as usize];
+        file.read_exact(&mut segment_table)
+            .map_err(|e| e.to_string())?;
+        for segment_length in segment_table {
+            let mut segment = vec![0u8; segment_length as usize];
+            file.read_exact(&mut segment).map_err(|e| e.to_string())?;
+            packets.last_mut().unwrap().extend(segment);
+            // A segment shorter than 255 bytes ends the packet
+            if segment_length < 255 {
+                if packets.len() == 2 {
+                    let packet = packets.pop().unwrap();
+                    return Ok(packet.strip_prefix(b"\x03vorbis").map(|c| c.to_vec()));
+                }
+                packets.push(Vec::new());
+            }
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pairs(tags: &[(&str, &str)]) -> Vec<(String, String)> {
+        tags.iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    /// Builds an ID3v2.3 tag from (frame ID, frame contents) pairs
+    fn id3v2_3(frames: &[(&[u8; 4], &[u8])]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for (id, contents) in frames {
+            body.extend(*id);
+            body.extend((contents.len() as u32).to_be_bytes());
+            body.extend([0, 0]);
+            body.extend(*contents);
+        }
+        let size = body.len() as u32;
+        let mut tag = b"ID3\x03\x00\x00".to_vec();
+        tag.extend((0..4).rev().map(|i| ((size >> (7 * i)) & 0x7F) as u8));
+        tag.extend(body);
+        tag
+    }
+
+    #[test]
+    fn id3v2_text_frames_are_given_common_keys() {
+        let tag = id3v2_3(&[
+            (b"TIT2", b"\x00Kick 01\x00"),
+            (b"TBPM", b"\x03128"),
+            (b"COMM", b"\x00engdesc\x00Punchy"),
+            (b"TXXX", b"\x03Pack\x00Drums Vol 1"),
+            (b"APIC", b"\x00ignored"),
+            (b"TPE1", b"\x01\xFF\xFEA\x00B\x00"),
+        ]);
+        assert_eq!(
+            id3v2_tags(&tag),
+            pairs(&[
+                ("title", "Kick 01"),
+                ("bpm", "128"),
+                ("comment", "Punchy"),
+                ("pack", "Drums Vol 1"),
+                ("artist", "AB"),
+            ])
+        );
+    }
+
+    #[test]
+    fn id3v2_frames_are_clamped_to_the_tag() {
+        let mut tag = id3v2_3(&[(b"TIT2", b"\x00Kick")]);
+        // Claim the frame is far longer than the tag
+        tag[14..18].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert_eq!(id3v2_tags(&tag), pairs(&[("title", "Kick")]));
+    }
+
+    #[test]
+    fn id3v2_tags_longer_than_the_file_are_truncated() {
+        let mut tag = id3v2_3(&[(b"TALB", b"\x00Pack")]);
+        // The largest syncsafe size
+        tag[6..10].copy_from_slice(&[0x7F; 4]);
+        let path = std::env::temp_dir().join(format!("tags_test_{}.mp3", std::process::id()));
+        std::fs::write(&path, &tag).unwrap();
+        let tags = read_tags(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(tags.unwrap(), pairs(&[("album", "Pack")]));
+    }
+
+    #[test]
+    fn riff_info_and_bext_fields_are_read() {
+        let mut info = b"INFO".to_vec();
+        for (id, value) in [(b"INAM", &b"Snare\0"[..]), (b"IGNR", b"Trap")] {
+            info.extend(id);
+            info.extend((value.len() as u32).to_le_bytes());
+            info.extend(value);
+        }
+        let mut bext = vec![0u8; 338];
+        bext[..4].copy_from_slice(b"Desc");
+        bext[256..259].copy_from_slice(b"Org");
+        let chunks = [
+            riff::Chunk {
+                id: *b"LIST",
+                data: info,
+            },
+            riff::Chunk {
+                id: *b"bext",
+                data: bext,
+            },
+        ];
+        let mut tags = riff_tags(&chunks);
+        tags.retain(|(_, value)| !value.is_empty());
+        assert_eq!(
+            tags,
+            pairs(&[
+                ("title", "Snare"),
+                ("genre", "Trap"),
+                ("description", "Desc"),
+                ("originator", "Org"),
+            ])
+        );
+    }
+
+    #[test]
+    fn vorbis_comments_stop_at_the_end_of_the_block() {
+        let mut block = Vec::new();
+        block.extend(3u32.to_le_bytes());
+        block.extend(b"lib");
+        // Claims three comments, but only two are present
+        block.extend(3u32.to_le_bytes());
+        for comment in [&b"TEMPO=90"[..], b"GENRE=Dub"] {
+            block.extend((comment.len() as u32).to_le_bytes());
+            block.extend(comment);
+        }
+        block.extend(u32::MAX.to_le_bytes());
+        assert_eq!(
+            vorbis_comments(&block),
+            pairs(&[("bpm", "90"), ("genre", "Dub")])
+        );
+    }
+}
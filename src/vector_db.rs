@@ -1,14 +1,19 @@
+use std::collections::HashMap;
 use std::num::NonZeroUsize;
 
-use heed::Env;
+use heed::{Env, RoTxn, RwTxn};
 use rand::rngs::StdRng;
 use rand::SeedableRng;
+use roaring::RoaringBitmap;
 
 use crate::feature::Feature;
-use crate::feature_extractor::NUM_DIMENSIONS;
+use crate::feature_extractor::{
+    FeatureSet, CHROMA_FEATURE_SET, MFCC_FEATURE_SET, RHYTHM_FEATURE_SET,
+};
 use crate::file_utils;
-use arroy::distances::Angular;
-use arroy::{Database as ArroyDatabase, Reader, Writer};
+use arroy::distances::{Angular, Euclidean, Manhattan};
+use arroy::internals::NodeCodec;
+use arroy::{Database as ArroyDatabase, Distance, Reader, Writer};
 
 /// That's the 200MiB size limit we allow LMDB to grow.
 const TWENTY_HUNDRED_MIB: usize = 2 * 1024 * 1024 * 1024;
@@ -24,6 +29,131 @@ unsafe fn create_env() -> Result<Env, String> {
     Ok(env)
 }
 
+/// The distance metric used to compare vectors within an index
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Metric {
+    Angular,
+    Euclidean,
+    Manhattan,
+}
+
+impl Metric {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Metric::Angular => "angular",
+            Metric::Euclidean => "euclidean",
+            Metric::Manhattan => "manhattan",
+        }
+    }
+
+    /// Returns the distance between a and b, on the same scale as the distances arroy
+    /// returns for this metric
+    pub fn distance(&self, a: &[f32], b: &[f32]) -> f32 {
+        match self {
+            Metric::Angular => {
+                let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+                let norms = (a.iter().map(|x| x * x).sum::<f32>()
+                    * b.iter().map(|y| y * y).sum::<f32>())
+                .sqrt();
+                // The euclidean distance between the normalized vectors
+                if norms > 0.0 {
+                    (2.0 - 2.0 * dot / norms).max(0.0).sqrt()
+                } else {
+                    2.0_f32.sqrt()
+                }
+            }
+            Metric::Euclidean => a
+                .iter()
+                .zip(b)
+                .map(|(x, y)| (x - y) * (x - y))
+                .sum::<f32>()
+                .sqrt(),
+            Metric::Manhattan => a.iter().zip(b).map(|(x, y)| (x - y).abs()).sum(),
+        }
+    }
+}
+
+/// A named arroy index. All indexes live in the same LMDB environment under their own arroy
+/// index ID, and each stores vectors from a single feature set.
+#[derive(Debug, PartialEq)]
+pub struct VectorIndex {
+    pub name: &'static str,
+    pub id: u16,
+    pub feature_set: FeatureSet,
+    pub metric: Metric,
+}
+
+impl VectorIndex {
+    pub fn dimensions(&self) -> usize {
+        self.feature_set.dimensions()
+    }
+}
+
+/// The default index. Its ID is 0 since it predates support for multiple indexes.
+pub static TIMBRE_INDEX: VectorIndex = VectorIndex {
+    name: "timbre",
+    id: 0,
+    feature_set: MFCC_FEATURE_SET,
+    metric: Metric::Angular,
+};
+
+pub static RHYTHM_INDEX: VectorIndex = VectorIndex {
+    name: "rhythm",
+    id: 1,
+    feature_set: RHYTHM_FEATURE_SET,
+    metric: Metric::Euclidean,
+};
+
+pub static PITCH_INDEX: VectorIndex = VectorIndex {
+    name: "pitch",
+    id: 2,
+    feature_set: CHROMA_FEATURE_SET,
+    metric: Metric::Angular,
+};
+
+/// Every index with one vector per sample
+pub static INDEXES: [&VectorIndex; 3] = [&TIMBRE_INDEX, &RHYTHM_INDEX, &PITCH_INDEX];
+
+/// Holds one vector per segment of long files, keyed by segment ID rather than sample ID. It
+/// uses the timbre feature set so whole samples can be used to query it.
+pub static SEGMENT_INDEX: VectorIndex = VectorIndex {
+    name: "segments",
+    id: 16,
+    feature_set: MFCC_FEATURE_SET,
+    metric: Metric::Angular,
+};
+
+fn all_indexes() -> impl Iterator<Item = &'static VectorIndex> {
+    INDEXES.into_iter().chain(std::iter::once(&SEGMENT_INDEX))
+}
+
+/// Returns the index with the given name
+pub fn index_named(name: &str) -> Result<&'static VectorIndex, String> {
+    INDEXES
+        .iter()
+        .find(|index| index.name == name)
+        .copied()
+        .ok_or_else(|| {
+            let names: Vec<&str> = INDEXES.iter().map(|index| index.name).collect();
+            format!(
+                "Unknown index {}. Available indexes: {}",
+                name,
+                names.join(", ")
+            )
+        })
+}
+
+/// Calls a method that's generic over the arroy distance using the distance for metric
+macro_rules! with_distance {
+    ($metric:expr, $self:ident.$method:ident($($arg:expr),*)) => {
+        match $metric {
+            Metric::Angular => $self.$method::<Angular>($($arg),*),
+            Metric::Euclidean => $self.$method::<Euclidean>($($arg),*),
+            Metric::Manhattan => $self.$method::<Manhattan>($($arg),*),
+        }
+    };
+}
+
 pub struct VectorDatabase {
     db: ArroyDatabase<Angular>,
 }
@@ -36,68 +166,520 @@ impl VectorDatabase {
         let db: ArroyDatabase<Angular> = env
             .create_database(&mut write_txn, None)
             .map_err(|e| e.to_string())?;
-        let writer = Writer::<Angular>::new(db, 0, NUM_DIMENSIONS);
-        let mut rng = StdRng::from_entropy();
+        let vector_db = VectorDatabase { db };
         // Note: we still need to call build() after loading the db from disk. Even if
         // the index was previous built.
-        writer
-            .build(&mut write_txn, &mut rng, None)
-            .map_err(|e| e.to_string())?;
+        for index in all_indexes() {
+            with_distance!(index.metric, vector_db.build(&mut write_txn, index))?;
+        }
         write_txn.commit().map_err(|e| e.to_string())?;
-        Ok(VectorDatabase { db })
+        Ok(vector_db)
     }
 
-    /// Adds features to the vector db and saves it on disk.
+    /// Adds features to their indexes in the vector db and saves it on disk. The segments with
+    /// IDs in stale_segments, which have been replaced in the metadata db, are removed from the
+    /// segment index.
     pub fn add_features_to_index(
         &self,
         features: &[Feature],
-        dimensions: usize,
+        stale_segments: &[u32],
     ) -> Result<(), String> {
         let env = unsafe { create_env()? };
         let mut write_txn = env.write_txn().map_err(|e| e.to_string())?;
 
-        let index = 0;
-        // Build index
-        let writer = Writer::<Angular>::new(self.db, index, dimensions);
-        for feature in features.iter() {
-            let id = feature.id().unwrap();
-            // Write to the arroy vector db using the id from the sqlite table
-            writer
-                .add_item(&mut write_txn, id as u32, feature.feature_vector())
-                .map_err(|e| e.to_string())?;
+        for index in all_indexes() {
+            let index_features: Vec<&Feature> = features
+                .iter()
+                .filter(|feature| feature.index().id == index.id)
+                .collect();
+            let stale_ids = if index.id == SEGMENT_INDEX.id {
+                stale_segments
+            } else {
+                &[]
+            };
+            if index_features.is_empty() && stale_ids.is_empty() {
+                continue;
+            }
+            // Stale IDs are removed first, since new segments can reuse their IDs
+            with_distance!(
+                index.metric,
+                self.remove_items(&mut write_txn, index, stale_ids)
+            )?;
+            with_distance!(
+                index.metric,
+                self.add_items(&mut write_txn, index, &index_features)
+            )?;
+            // Build index
+            with_distance!(index.metric, self.build(&mut write_txn, index))?;
         }
 
-        // Build index
-        let mut rng = StdRng::from_entropy();
-        let num_trees = None;
-        writer
-            .build(&mut write_txn, &mut rng, num_trees)
-            .map_err(|e| e.to_string())?;
-
         // Commit the built index to the db
         write_txn.commit().map_err(|e| e.to_string())?;
         Ok(())
     }
 
-    /// Returns a vector of file ids to the top k similar results
-    pub fn find_similar(&self, id: u32, num_results: usize) -> Result<Vec<u32>, String> {
+    /// Replaces the entire contents of index with features and saves it on disk. This is
+    /// required when the feature set changes, since vectors from different feature sets can't
+    /// be mixed in one index.
+    pub fn rebuild_index(&self, index: &VectorIndex, features: &[Feature]) -> Result<(), String> {
+        let env = unsafe { create_env()? };
+        let mut write_txn = env.write_txn().map_err(|e| e.to_string())?;
+
+        let features: Vec<&Feature> = features.iter().collect();
+        with_distance!(index.metric, self.clear(&mut write_txn, index))?;
+        with_distance!(
+            index.metric,
+            self.add_items(&mut write_txn, index, &features)
+        )?;
+        with_distance!(index.metric, self.build(&mut write_txn, index))?;
+        write_txn.commit().map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    /// Returns a vector of file ids to the top k similar results. When candidates is provided,
+    /// only those ids are considered.
+    pub fn find_similar(
+        &self,
+        index: &VectorIndex,
+        id: u32,
+        num_results: usize,
+        candidates: Option<&RoaringBitmap>,
+    ) -> Result<Vec<u32>, String> {
+        let env = unsafe { create_env()? };
+        let rtxn = env.read_txn().map_err(|e| e.to_string())?;
+        let search_results = with_distance!(
+            index.metric,
+            self.nns_by_item(&rtxn, index, id, num_results, candidates)
+        )?
+        .iter()
+        .map(|result| result.0)
+        .collect();
+        Ok(search_results)
+    }
+
+    /// Returns the vector stored for id in index, if any
+    pub fn item_vector(&self, index: &VectorIndex, id: u32) -> Result<Option<Vec<f32>>, String> {
+        let env = unsafe { create_env()? };
+        let rtxn = env.read_txn().map_err(|e| e.to_string())?;
+        with_distance!(index.metric, self.read_item_vector(&rtxn, index, id))
+    }
+
+    /// Returns the vector stored in index for each of ids, if any
+    pub fn item_vectors(
+        &self,
+        index: &VectorIndex,
+        ids: &[u32],
+    ) -> Result<Vec<Option<Vec<f32>>>, String> {
+        let env = unsafe { create_env()? };
+        let rtxn = env.read_txn().map_err(|e| e.to_string())?;
+        ids.iter()
+            .map(|id| with_distance!(index.metric, self.read_item_vector(&rtxn, index, *id)))
+            .collect()
+    }
+
+    /// Returns (id, neighbor id, distance) for each of the num_neighbors nearest neighbors of
+    /// every id in ids that's within max_distance of it. Ids that aren't in index are skipped.
+    pub fn neighbors_within(
+        &self,
+        index: &VectorIndex,
+        ids: &[u32],
+        num_neighbors: usize,
+        max_distance: f32,
+    ) -> Result<Vec<(u32, u32, f32)>, String> {
+        self.neighbors_within_batched(
+            index,
+            ids,
+            num_neighbors,
+            max_distance,
+            ids.len().max(1),
+            |_| {},
+        )
+    }
+
+    /// Like neighbors_within, but queries batch_size ids per read transaction so no single
+    /// transaction stays open for the whole library. progress_callback is called with the
+    /// number of ids done after each batch.
+    pub fn neighbors_within_batched(
+        &self,
+        index: &VectorIndex,
+        ids: &[u32],
+        num_neighbors: usize,
+        max_distance: f32,
+        batch_size: usize,
+        progress_callback: impl Fn(usize),
+    ) -> Result<Vec<(u32, u32, f32)>, String> {
+        let env = unsafe { create_env()? };
+        let mut neighbors = Vec::new();
+        let mut done = 0;
+        for batch in ids.chunks(batch_size) {
+            let rtxn = env.read_txn().map_err(|e| e.to_string())?;
+            neighbors.extend(with_distance!(
+                index.metric,
+                self.nns_within(&rtxn, index, batch, num_neighbors, max_distance, None)
+            )?);
+            done += batch.len();
+            progress_callback(done);
+        }
+        Ok(neighbors)
+    }
+
+    /// Returns the result of search, which is given a function that returns the
+    /// (neighbor id, distance) of each of an id's num_neighbors nearest neighbors in index.
+    /// Every lookup shares one read transaction, so search can walk outward through the
+    /// nearest neighbor graph without querying the whole library up front.
+    pub fn with_neighbor_lookup<T>(
+        &self,
+        index: &VectorIndex,
+        num_neighbors: usize,
+        search: impl FnOnce(&mut dyn FnMut(u32) -> Result<Vec<(u32, f32)>, String>) -> T,
+    ) -> Result<T, String> {
+        let env = unsafe { create_env()? };
+        let rtxn = env.read_txn().map_err(|e| e.to_string())?;
+        let mut lookup = |id: u32| -> Result<Vec<(u32, f32)>, String> {
+            let neighbors = with_distance!(
+                index.metric,
+                self.nns_within(&rtxn, index, &[id], num_neighbors, f32::INFINITY, None)
+            )?;
+            Ok(neighbors
+                .into_iter()
+                .map(|(_, neighbor, distance)| (neighbor, distance))
+                .collect())
+        };
+        Ok(search(&mut lookup))
+    }
+
+    /// Returns (id, neighbor id, distance) for each of the num_neighbors nearest neighbors of
+    /// every id in ids among candidates. Ids that aren't in index are skipped.
+    pub fn neighbors_among(
+        &self,
+        index: &VectorIndex,
+        ids: &[u32],
+        num_neighbors: usize,
+        candidates: &RoaringBitmap,
+    ) -> Result<Vec<(u32, u32, f32)>, String> {
+        let env = unsafe { create_env()? };
+        let rtxn = env.read_txn().map_err(|e| e.to_string())?;
+        with_distance!(
+            index.metric,
+            self.nns_within(
+                &rtxn,
+                index,
+                ids,
+                num_neighbors,
+                f32::INFINITY,
+                Some(candidates)
+            )
+        )
+    }
+
+    /// Returns ids of the top k items in index that are most similar to vector. When
+    /// candidates is provided, only those ids are considered.
+    pub fn find_similar_to_vector(
+        &self,
+        index: &VectorIndex,
+        vector: &[f32],
+        num_results: usize,
+        candidates: Option<&RoaringBitmap>,
+    ) -> Result<Vec<u32>, String> {
+        let env = unsafe { create_env()? };
+        let rtxn = env.read_txn().map_err(|e| e.to_string())?;
+        let search_results = with_distance!(
+            index.metric,
+            self.nns_by_vector(&rtxn, index, vector, num_results, candidates)
+        )?
+        .iter()
+        .map(|result| result.0)
+        .collect();
+        Ok(search_results)
+    }
+
+    /// Returns file ids of the top k results across several indexes, ranked by the weighted sum
+    /// of their distances to id in each index. Distances are normalized per index, since
+    /// their scale depends on the metric and feature set. When candidates is provided, only
+    /// those ids are considered.
+    pub fn find_similar_weighted(
+        &self,
+        index_weights: &[(&VectorIndex, f32)],
+        id: u32,
+        num_results: usize,
+        candidates: Option<&RoaringBitmap>,
+    ) -> Result<Vec<u32>, String> {
+        let env = unsafe { create_env()? };
+        let rtxn = env.read_txn().map_err(|e| e.to_string())?;
+        let mut per_index_results = Vec::with_capacity(index_weights.len());
+        for (index, weight) in index_weights.iter() {
+            let results = with_distance!(
+                index.metric,
+                self.nns_by_item(&rtxn, index, id, num_results * OVERSAMPLING, candidates)
+            )?;
+            per_index_results.push((*weight, results));
+        }
+        Ok(rank_weighted(per_index_results, num_results))
+    }
+
+    /// Returns file ids of the top k results across several indexes, ranked by the weighted sum
+    /// of their distances to a query vector for each index, as in find_similar_weighted. When
+    /// candidates is provided, only those ids are considered.
+    pub fn find_similar_to_vectors_weighted(
+        &self,
+        index_vectors: &[(&VectorIndex, f32, &[f32])],
+        num_results: usize,
+        candidates: Option<&RoaringBitmap>,
+    ) -> Result<Vec<u32>, String> {
         let env = unsafe { create_env()? };
         let rtxn = env.read_txn().map_err(|e| e.to_string())?;
-        let index = 0;
-        let reader = Reader::<Angular>::open(&rtxn, index, self.db).map_err(|e| e.to_string())?;
+        let mut per_index_results = Vec::with_capacity(index_vectors.len());
+        for (index, weight, vector) in index_vectors.iter() {
+            let results = with_distance!(
+                index.metric,
+                self.nns_by_vector(&rtxn, index, vector, num_results * OVERSAMPLING, candidates)
+            )?;
+            per_index_results.push((*weight, results));
+        }
+        Ok(rank_weighted(per_index_results, num_results))
+    }
+
+    /// The arroy database shares a single LMDB database across all indexes. Items are keyed by
+    /// index ID, so each index can be read with its own distance type.
+    fn database<D: Distance>(&self) -> ArroyDatabase<D> {
+        self.db.remap_data_type::<NodeCodec<D>>()
+    }
+
+    fn build<D: Distance>(&self, write_txn: &mut RwTxn, index: &VectorIndex) -> Result<(), String> {
+        let writer = Writer::<D>::new(self.database(), index.id, index.dimensions());
+        let mut rng = StdRng::from_entropy();
+        let num_trees = None;
+        writer
+            .build(write_txn, &mut rng, num_trees)
+            .map_err(|e| e.to_string())
+    }
+
+    fn clear<D: Distance>(&self, write_txn: &mut RwTxn, index: &VectorIndex) -> Result<(), String> {
+        let writer = Writer::<D>::new(self.database(), index.id, index.dimensions());
+        writer.clear(write_txn).map_err(|e| e.to_string())
+    }
+
+    fn remove_items<D: Distance>(
+        &self,
+        write_txn: &mut RwTxn,
+        index: &VectorIndex,
+        ids: &[u32],
+    ) -> Result<(), String> {
+        let writer = Writer::<D>::new(self.database(), index.id, index.dimensions());
+        for id in ids {
+            writer.del_item(write_txn, *id).map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    fn add_items<D: Distance>(
+        &self,
+        write_txn: &mut RwTxn,
+        index: &VectorIndex,
+        features: &[&Feature],
+    ) -> Result<(), String> {
+        let writer = Writer::<D>::new(self.database(), index.id, index.dimensions());
+        for feature in features.iter() {
+            if feature.feature_vector().len() != index.dimensions() {
+                return Err(format!(
+                    "Feature vector for {} has {} dimensions but the {} index expects {}",
+                    feature.source_file(),
+                    feature.feature_vector().len(),
+                    index.name,
+                    index.dimensions()
+                ));
+            }
+            let id = feature.id().unwrap();
+            // Write to the arroy vector db using the id from the sqlite table
+            writer
+                .add_item(write_txn, id as u32, feature.feature_vector())
+                .map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    fn nns_by_item<D: Distance>(
+        &self,
+        rtxn: &RoTxn,
+        index: &VectorIndex,
+        id: u32,
+        num_results: usize,
+        candidates: Option<&RoaringBitmap>,
+    ) -> Result<Vec<(u32, f32)>, String> {
+        let reader =
+            Reader::<D>::open(rtxn, index.id, self.database()).map_err(|e| e.to_string())?;
 
         // You can increase the quality of the results by forcing arroy to search into more nodes.
         // This multiplier is arbitrary but basically the higher, the better the results, the slower the query.
         let search_k = NonZeroUsize::new(num_results * reader.n_trees() * 15);
 
         // Similar searching can be achieved by requesting the nearest neighbors of a given item.
-        let search_results = reader
-            .nns_by_item(&rtxn, id, num_results, search_k, None)
+        reader
+            .nns_by_item(rtxn, id, num_results, search_k, candidates)
             .map_err(|e| e.to_string())?
-            .ok_or("Unexpected similarity search error".to_string())?
+            .ok_or("Unexpected similarity search error".to_string())
+    }
+
+    fn nns_within<D: Distance>(
+        &self,
+        rtxn: &RoTxn,
+        index: &VectorIndex,
+        ids: &[u32],
+        num_neighbors: usize,
+        max_distance: f32,
+        candidates: Option<&RoaringBitmap>,
+    ) -> Result<Vec<(u32, u32, f32)>, String> {
+        let reader =
+            Reader::<D>::open(rtxn, index.id, self.database()).map_err(|e| e.to_string())?;
+        // An item is usually its own nearest neighbor, so ask for one more
+        let num_results = num_neighbors + 1;
+        let search_k = NonZeroUsize::new(num_results * reader.n_trees() * 15);
+        let mut neighbors = Vec::new();
+        for id in ids {
+            let Some(results) = reader
+                .nns_by_item(rtxn, *id, num_results, search_k, candidates)
+                .map_err(|e| e.to_string())?
+            else {
+                continue;
+            };
+            neighbors.extend(nearest_others(*id, results, num_neighbors, max_distance));
+        }
+        Ok(neighbors)
+    }
+
+    fn nns_by_vector<D: Distance>(
+        &self,
+        rtxn: &RoTxn,
+        index: &VectorIndex,
+        vector: &[f32],
+        num_results: usize,
+        candidates: Option<&RoaringBitmap>,
+    ) -> Result<Vec<(u32, f32)>, String> {
+        let reader =
+            Reader::<D>::open(rtxn, index.id, self.database()).map_err(|e| e.to_string())?;
+        let search_k = NonZeroUsize::new(num_results * reader.n_trees() * 15);
+        reader
+            .nns_by_vector(rtxn, vector, num_results, search_k, candidates)
+            .map_err(|e| e.to_string())
+    }
+
+    fn read_item_vector<D: Distance>(
+        &self,
+        rtxn: &RoTxn,
+        index: &VectorIndex,
+        id: u32,
+    ) -> Result<Option<Vec<f32>>, String> {
+        let reader =
+            Reader::<D>::open(rtxn, index.id, self.database()).map_err(|e| e.to_string())?;
+        reader.item_vector(rtxn, id).map_err(|e| e.to_string())
+    }
+}
+
+/// Returns (id, neighbor id, distance) for the num_neighbors nearest of results that aren't id
+/// itself and are within max_distance. id is missing from results when candidates excluded it,
+/// e.g. when an unlabeled sample is searched among labeled ones.
+fn nearest_others(
+    id: u32,
+    results: Vec<(u32, f32)>,
+    num_neighbors: usize,
+    max_distance: f32,
+) -> impl Iterator<Item = (u32, u32, f32)> {
+    results
+        .into_iter()
+        .filter(move |(neighbor, _)| *neighbor != id)
+        .take(num_neighbors)
+        .filter(move |(_, distance)| *distance <= max_distance)
+        .map(move |(neighbor, distance)| (id, neighbor, distance))
+}
+
+/// Each index only contributes its nearest candidates to a weighted search, so they're
+/// oversampled to give results that rank well overall a chance to appear in every candidate
+/// list
+const OVERSAMPLING: usize = 10;
+
+/// Ranks the union of the (id, distance) results of several indexes by the weighted sum of
+/// their distances. Distances are normalized per index, since their scale depends on the
+/// metric and feature set.
+fn rank_weighted(per_index_results: Vec<(f32, Vec<(u32, f32)>)>, num_results: usize) -> Vec<u32> {
+    let mut per_index_distances: Vec<(f32, HashMap<u32, f32>)> = Vec::new();
+    for (weight, results) in per_index_results {
+        let max_distance = results
             .iter()
-            .map(|result| result.0)
+            .map(|(_, distance)| *distance)
+            .fold(0.0, f32::max);
+        let scale = if max_distance > 0.0 {
+            1.0 / max_distance
+        } else {
+            1.0
+        };
+        let distances = results
+            .into_iter()
+            .map(|(item, distance)| (item, distance * scale))
             .collect();
-        Ok(search_results)
+        per_index_distances.push((weight, distances));
+    }
+
+    // Candidates missing from an index's results are at least as far as its furthest
+    // candidate, which is 1.0 after normalization
+    let mut combined: HashMap<u32, f32> = HashMap::new();
+    for (_, distances) in per_index_distances.iter() {
+        for item in distances.keys() {
+            combined.entry(*item).or_insert(0.0);
+        }
+    }
+    for (item, total) in combined.iter_mut() {
+        *total = per_index_distances
+            .iter()
+            .map(|(weight, distances)| weight * distances.get(item).copied().unwrap_or(1.0))
+            .sum();
+    }
+
+    let mut ranked: Vec<(u32, f32)> = combined.into_iter().collect();
+    ranked.sort_by(|a, b| a.1.total_cmp(&b.1));
+    ranked
+        .into_iter()
+        .take(num_results)
+        .map(|(item, _)| item)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nearest_others_skip_the_item_itself() {
+        let results = vec![(4, 0.0), (7, 0.1), (2, 0.2), (9, 0.3)];
+        let neighbors: Vec<_> = nearest_others(4, results, 2, f32::INFINITY).collect();
+        assert_eq!(neighbors, [(4, 7, 0.1), (4, 2, 0.2)]);
+    }
+
+    #[test]
+    fn nearest_others_of_an_excluded_item_keep_num_neighbors() {
+        // Searching among candidates that don't include the item itself
+        let results = vec![(7, 0.1), (2, 0.2), (9, 0.3)];
+        let neighbors: Vec<_> = nearest_others(4, results, 2, f32::INFINITY).collect();
+        assert_eq!(neighbors, [(4, 7, 0.1), (4, 2, 0.2)]);
+    }
+
+    #[test]
+    fn nearest_others_are_limited_to_max_distance() {
+        let results = vec![(4, 0.0), (7, 0.1), (2, 0.2)];
+        let neighbors: Vec<_> = nearest_others(4, results, 5, 0.15).collect();
+        assert_eq!(neighbors, [(4, 7, 0.1)]);
+    }
+
+    #[test]
+    fn weighted_ranking_treats_missing_results_as_the_farthest() {
+        let ranked = rank_weighted(
+            vec![
+                (1.0, vec![(1, 0.0), (2, 1.0), (3, 2.0)]),
+                (1.0, vec![(2, 0.0), (4, 4.0)]),
+            ],
+            3,
+        );
+        // 1: 0 + 1, 2: 0.5 + 0, 3: 1 + 1, 4: 1 + 1
+        assert_eq!(ranked[..2], [2, 1]);
+        assert_eq!(ranked.len(), 3);
     }
 }